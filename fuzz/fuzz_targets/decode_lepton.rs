@@ -0,0 +1,24 @@
+#![no_main]
+
+// Feeds arbitrary data straight into the Lepton decoder. Any input has to end up as either Ok
+// or Err, so every crash found here is a bug. Start from the seeds with
+// `cargo fuzz run decode_lepton fuzz/corpus/decode_lepton fuzz/seeds/decode_lepton`
+
+use std::io::Cursor;
+
+use lepton_jpeg::{decode_lepton, EnabledFeatures};
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // keep the jpeg dimensions small otherwise the fuzzer gets really slow
+    let features = EnabledFeatures {
+        progressive: true,
+        max_jpeg_height: 1024,
+        max_jpeg_width: 1024,
+        ..EnabledFeatures::compat_lepton_vector_read()
+    };
+
+    let mut output = Vec::new();
+    let _ = decode_lepton(&mut Cursor::new(data), &mut output, 8, &features);
+});
@@ -20,6 +20,7 @@ fuzz_target!(|data: &[u8]| {
         max_jpeg_height: 1024,
         max_jpeg_width: 1024,
         use_16bit_dc_estimate: use_16bit_dc_estimate,
+        ..EnabledFeatures::compat_lepton_vector_write()
     };
 
     {
@@ -0,0 +1,130 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Conversions between the orders that the 64 coefficients of a block are kept in.
+//!
+//! - **zigzag**: the order the coefficients are stored in the JPEG file, starting with the DC
+//!   and running diagonally through the block.
+//! - **raster**: row by row, so index `row * 8 + column`, with the horizontal frequency
+//!   increasing along the row. The quantization tables of the JPEG file are in this order
+//!   once they have been unzigzagged.
+//! - **aligned**: the order the coefficients are stored in memory while coding, which is the
+//!   raster order transposed, so index `column * 8 + row`. Keeping the coefficients transposed
+//!   lets the predictions of the codec work on whole SIMD registers.
+
+use crate::consts::{RASTER_TO_ZIGZAG, ZIGZAG_TO_TRANSPOSED};
+
+/// Converts the coefficients of a block from the aligned order to the zigzag order.
+pub fn aligned_to_zigzag(aligned: &[i16; 64]) -> [i16; 64] {
+    let mut zigzag = [0; 64];
+    for i in 0..64 {
+        zigzag[i] = aligned[usize::from(ZIGZAG_TO_TRANSPOSED[i])];
+    }
+    zigzag
+}
+
+/// Converts the coefficients of a block from the zigzag order to the aligned order.
+pub fn zigzag_to_aligned(zigzag: &[i16; 64]) -> [i16; 64] {
+    let mut aligned = [0; 64];
+    for i in 0..64 {
+        aligned[usize::from(ZIGZAG_TO_TRANSPOSED[i])] = zigzag[i];
+    }
+    aligned
+}
+
+/// Converts the coefficients of a block from the aligned order to the raster order.
+pub fn aligned_to_raster(aligned: &[i16; 64]) -> [i16; 64] {
+    transpose(aligned)
+}
+
+/// Converts the coefficients of a block from the raster order to the aligned order.
+pub fn raster_to_aligned(raster: &[i16; 64]) -> [i16; 64] {
+    transpose(raster)
+}
+
+/// Converts the coefficients of a block from the raster order to the zigzag order.
+pub fn raster_to_zigzag(raster: &[i16; 64]) -> [i16; 64] {
+    let mut zigzag = [0; 64];
+    for i in 0..64 {
+        zigzag[usize::from(RASTER_TO_ZIGZAG[i])] = raster[i];
+    }
+    zigzag
+}
+
+/// Converts the coefficients of a block from the zigzag order to the raster order.
+pub fn zigzag_to_raster(zigzag: &[i16; 64]) -> [i16; 64] {
+    let mut raster = [0; 64];
+    for i in 0..64 {
+        raster[i] = zigzag[usize::from(RASTER_TO_ZIGZAG[i])];
+    }
+    raster
+}
+
+fn transpose(block: &[i16; 64]) -> [i16; 64] {
+    let mut transposed = [0; 64];
+    for i in 0..64 {
+        transposed[(i & 7) * 8 + (i >> 3)] = block[i];
+    }
+    transposed
+}
+
+#[cfg(test)]
+fn random_block(rng: &mut impl rand::Rng) -> [i16; 64] {
+    let mut block = [0; 64];
+    rng.fill(&mut block[..]);
+    block
+}
+
+// every conversion followed by its inverse has to give back the original block
+#[test]
+fn conversions_roundtrip() {
+    let mut rng = crate::helpers::get_rand_from_seed([7u8; 32]);
+
+    for _ in 0..100 {
+        let block = random_block(&mut rng);
+
+        assert_eq!(zigzag_to_aligned(&aligned_to_zigzag(&block)), block);
+        assert_eq!(aligned_to_zigzag(&zigzag_to_aligned(&block)), block);
+        assert_eq!(raster_to_aligned(&aligned_to_raster(&block)), block);
+        assert_eq!(aligned_to_raster(&raster_to_aligned(&block)), block);
+        assert_eq!(zigzag_to_raster(&raster_to_zigzag(&block)), block);
+        assert_eq!(raster_to_zigzag(&zigzag_to_raster(&block)), block);
+
+        // going through the raster order ends up in the same place as converting directly
+        assert_eq!(
+            raster_to_zigzag(&aligned_to_raster(&block)),
+            aligned_to_zigzag(&block)
+        );
+    }
+}
+
+// the conversions must agree with the way the codec itself reads and writes
+// the coefficients of an AlignedBlock
+#[test]
+fn conversions_match_aligned_block() {
+    use crate::structs::block_based_image::AlignedBlock;
+
+    let mut rng = crate::helpers::get_rand_from_seed([8u8; 32]);
+
+    for _ in 0..100 {
+        let block = random_block(&mut rng);
+
+        let aligned = AlignedBlock::new(block);
+        assert_eq!(
+            aligned.zigzag_from_transposed().get_block(),
+            &aligned_to_zigzag(&block)
+        );
+
+        let mut from_zigzag = AlignedBlock::default();
+        for (i, &v) in block.iter().enumerate() {
+            from_zigzag.set_transposed_from_zigzag(i, v);
+        }
+        assert_eq!(from_zigzag.get_block(), &zigzag_to_aligned(&block));
+
+        // the raster order is the transpose of the aligned order
+        assert_eq!(aligned.transpose().get_block(), &aligned_to_raster(&block));
+    }
+}
@@ -4,22 +4,147 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+//! Constant tables of the codec. The coefficient orderings and the IDCT basis values are public
+//! so that other code can reproduce the math exactly, everything else is internal.
+
 #[derive(PartialEq, Debug)]
-pub enum JPegDecodeStatus {
+pub(crate) enum JPegDecodeStatus {
     DecodeInProgress,
     RestartIntervalExpired,
     ScanCompleted,
 }
 
 #[derive(PartialEq, Debug)]
-pub enum JPegType {
+pub(crate) enum JPegType {
     Unknown,
     Sequential,
     Progressive,
 }
 
-pub const COLOR_CHANNEL_NUM_BLOCK_TYPES: usize = 3;
+pub(crate) const COLOR_CHANNEL_NUM_BLOCK_TYPES: usize = 3;
+
+/// Probability estimation state machine of the JPEG arithmetic coder (Table D.3 of ITU T.81) as
+/// (Qe_Value, Next_Index_LPS, Next_Index_MPS, Switch_MPS). The last entry is not part of the
+/// standard, it is a fixed probability of 0.5 that is used for coding sign bits and refinements.
+pub(crate) const ARITHMETIC_STATES: [(u16, u8, u8, bool); 114] = [
+    (0x5a1d, 1, 1, true),
+    (0x2586, 14, 2, false),
+    (0x1114, 16, 3, false),
+    (0x080b, 18, 4, false),
+    (0x03d8, 20, 5, false),
+    (0x01da, 23, 6, false),
+    (0x00e5, 25, 7, false),
+    (0x006f, 28, 8, false),
+    (0x0036, 30, 9, false),
+    (0x001a, 33, 10, false),
+    (0x000d, 35, 11, false),
+    (0x0006, 9, 12, false),
+    (0x0003, 10, 13, false),
+    (0x0001, 12, 13, false),
+    (0x5a7f, 15, 15, true),
+    (0x3f25, 36, 16, false),
+    (0x2cf2, 38, 17, false),
+    (0x207c, 39, 18, false),
+    (0x17b9, 40, 19, false),
+    (0x1182, 42, 20, false),
+    (0x0cef, 43, 21, false),
+    (0x09a1, 45, 22, false),
+    (0x072f, 46, 23, false),
+    (0x055c, 48, 24, false),
+    (0x0406, 49, 25, false),
+    (0x0303, 51, 26, false),
+    (0x0240, 52, 27, false),
+    (0x01b1, 54, 28, false),
+    (0x0144, 56, 29, false),
+    (0x00f5, 57, 30, false),
+    (0x00b7, 59, 31, false),
+    (0x008a, 60, 32, false),
+    (0x0068, 62, 33, false),
+    (0x004e, 63, 34, false),
+    (0x003b, 32, 35, false),
+    (0x002c, 33, 9, false),
+    (0x5ae1, 37, 37, true),
+    (0x484c, 64, 38, false),
+    (0x3a0d, 65, 39, false),
+    (0x2ef1, 67, 40, false),
+    (0x261f, 68, 41, false),
+    (0x1f33, 69, 42, false),
+    (0x19a8, 70, 43, false),
+    (0x1518, 72, 44, false),
+    (0x1177, 73, 45, false),
+    (0x0e74, 74, 46, false),
+    (0x0bfb, 75, 47, false),
+    (0x09f8, 77, 48, false),
+    (0x0861, 78, 49, false),
+    (0x0706, 79, 50, false),
+    (0x05cd, 48, 51, false),
+    (0x04de, 50, 52, false),
+    (0x040f, 50, 53, false),
+    (0x0363, 51, 54, false),
+    (0x02d4, 52, 55, false),
+    (0x025c, 53, 56, false),
+    (0x01f8, 54, 57, false),
+    (0x01a4, 55, 58, false),
+    (0x0160, 56, 59, false),
+    (0x0125, 57, 60, false),
+    (0x00f6, 58, 61, false),
+    (0x00cb, 59, 62, false),
+    (0x00ab, 61, 63, false),
+    (0x008f, 61, 32, false),
+    (0x5b12, 65, 65, true),
+    (0x4d04, 80, 66, false),
+    (0x412c, 81, 67, false),
+    (0x37d8, 82, 68, false),
+    (0x2fe8, 83, 69, false),
+    (0x293c, 84, 70, false),
+    (0x2379, 86, 71, false),
+    (0x1edf, 87, 72, false),
+    (0x1aa9, 87, 73, false),
+    (0x174e, 72, 74, false),
+    (0x1424, 72, 75, false),
+    (0x119c, 74, 76, false),
+    (0x0f6b, 74, 77, false),
+    (0x0d51, 75, 78, false),
+    (0x0bb6, 77, 79, false),
+    (0x0a40, 77, 48, false),
+    (0x5832, 80, 81, true),
+    (0x4d1c, 88, 82, false),
+    (0x438e, 89, 83, false),
+    (0x3bdd, 90, 84, false),
+    (0x34ee, 91, 85, false),
+    (0x2eae, 92, 86, false),
+    (0x299a, 93, 87, false),
+    (0x2516, 86, 71, false),
+    (0x5570, 88, 89, true),
+    (0x4ca9, 95, 90, false),
+    (0x44d9, 96, 91, false),
+    (0x3e22, 97, 92, false),
+    (0x3824, 99, 93, false),
+    (0x32b4, 99, 94, false),
+    (0x2e17, 93, 86, false),
+    (0x56a8, 95, 96, true),
+    (0x4f46, 101, 97, false),
+    (0x47e5, 102, 98, false),
+    (0x41cf, 103, 99, false),
+    (0x3c3d, 104, 100, false),
+    (0x375e, 99, 93, false),
+    (0x5231, 105, 102, false),
+    (0x4c0f, 106, 103, false),
+    (0x4639, 107, 104, false),
+    (0x415e, 103, 99, false),
+    (0x5627, 105, 106, true),
+    (0x50e7, 108, 107, false),
+    (0x4b85, 109, 103, false),
+    (0x5597, 110, 109, false),
+    (0x504f, 111, 107, false),
+    (0x5a10, 110, 111, true),
+    (0x5522, 112, 109, false),
+    (0x59eb, 112, 111, true),
+    (0x5a1d, 113, 113, false),
+];
 
+/// Position in zigzag order of each coefficient of a block in raster order, so the index is
+/// `row * 8 + column` and the value is the zigzag index, both in `0..64`.
 pub const RASTER_TO_ZIGZAG: [u8; 64] = [
     0, 1, 5, 6, 14, 15, 27, 28, 2, 4, 7, 13, 16, 26, 29, 42, 3, 8, 12, 17, 25, 30, 41, 43, 9, 11,
     18, 24, 31, 40, 44, 53, 10, 19, 23, 32, 39, 45, 52, 54, 20, 22, 33, 38, 46, 51, 55, 60, 21, 34,
@@ -32,6 +157,8 @@ pub const RASTER_TO_ZIGZAG: [u8; 64] = [
 //     52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
 // ];
 
+/// Position in the transposed (aligned) order, `column * 8 + row`, of each coefficient in zigzag
+/// order. The index is the zigzag index and the value the transposed index, both in `0..64`.
 pub const ZIGZAG_TO_TRANSPOSED: [u8; 64] = [
     0, 8, 1, 2, 9, 16, 24, 17, 10, 3, 4, 11, 18, 25, 32, 40, 33, 26, 19, 12, 5, 6, 13, 20, 27, 34,
     41, 48, 56, 49, 42, 35, 28, 21, 14, 7, 15, 22, 29, 36, 43, 50, 57, 58, 51, 44, 37, 30, 23, 31,
@@ -44,64 +171,111 @@ pub const ZIGZAG_TO_TRANSPOSED: [u8; 64] = [
 //     63,
 // ];
 
+/// Transposed indices (`column * 8 + row`) of the 49 coefficients of the 7x7 block, that is
+/// all the coefficients with a non-zero row and column, in the zigzag order they are coded in.
+/// The values are in `9..64`.
 pub const UNZIGZAG_49_TR: [u8; 49] = [
     9, 17, 10, 11, 18, 25, 33, 26, 19, 12, 13, 20, 27, 34, 41, 49, 42, 35, 28, 21, 14, 15, 22, 29,
     36, 43, 50, 57, 58, 51, 44, 37, 30, 23, 31, 38, 45, 52, 59, 60, 53, 46, 39, 47, 54, 61, 62, 55,
     63,
 ];
 
-// precalculated int base values for 8x8 IDCT scaled by 8192
-// DC coef is zeroed intentionally
+const fn is_permutation(table: &[u8; 64]) -> bool {
+    let mut seen = [false; 64];
+    let mut i = 0;
+    while i < 64 {
+        let v = table[i] as usize;
+        if v >= 64 || seen[v] {
+            return false;
+        }
+        seen[v] = true;
+        i += 1;
+    }
+    true
+}
+
+const fn is_7x7_order(table: &[u8; 49]) -> bool {
+    let mut seen = [false; 64];
+    let mut i = 0;
+    while i < 49 {
+        let v = table[i] as usize;
+        if v >= 64 || v & 7 == 0 || v >> 3 == 0 || seen[v] {
+            return false;
+        }
+        seen[v] = true;
+        i += 1;
+    }
+    true
+}
+
+// the ordering tables are checked when compiling
+const _: () = assert!(is_permutation(&RASTER_TO_ZIGZAG));
+const _: () = assert!(is_permutation(&ZIGZAG_TO_TRANSPOSED));
+const _: () = assert!(is_7x7_order(&UNZIGZAG_49_TR));
+
+/// Values of the 8 cosine basis functions of the IDCT at the first pixel of a block,
+/// `8192 * sqrt(2) * cos(k * PI / 16)` for frequency `k`, which the edge predictions multiply
+/// the dequantized coefficients with. The DC is zeroed intentionally.
 pub const ICOS_BASED_8192_SCALED: [i32; 8] = [0, 11363, 10703, 9633, 8192, 6436, 4433, 2260];
 
+/// Same basis values at the last pixel of a block, which flips the sign of the odd frequencies.
+/// Here the DC is included with its basis value of 8192.
 pub const ICOS_BASED_8192_SCALED_PM: [i32; 8] =
     [8192, -11363, 10703, -9633, 8192, -6436, 4433, -2260];
 
-pub const FREQ_MAX: [u16; 14] = [
+pub(crate) const FREQ_MAX: [u16; 14] = [
     931, 985, 968, 1020, 968, 1020, 1020, 932, 985, 967, 1020, 969, 1020, 1020,
 ];
 
 // used to get prediction branches basing on nonzero-number predictor `num_non_zeros_context`
-pub const NON_ZERO_TO_BIN: [u8; 26] = [
+pub(crate) const NON_ZERO_TO_BIN: [u8; 26] = [
     0, 1, 2, 3, 4, 4, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7, 7, 7, 7, 7, 8, 8, 8, 8, 8,
 ];
 
 // used to get prediction branches basing on current `num_non_zeros_left_7x7`, 0th element is not used
-pub const NON_ZERO_TO_BIN_7X7: [u8; 50] = [
+pub(crate) const NON_ZERO_TO_BIN_7X7: [u8; 50] = [
     0, 0, 1, 2, 3, 3, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
     8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
 ];
 
 //pub const MAX_FILE_SIZE_BYTES : i32 = 128 * 1024 * 1024;
-pub const MAX_THREADS: usize = 8;
+pub(crate) const MAX_THREADS: usize = 8;
 
-pub const RESIDUAL_NOISE_FLOOR: usize = 7;
+pub(crate) const RESIDUAL_NOISE_FLOOR: usize = 7;
 
 // IDCT of Lepton provides pixels multiplied by that amount
-pub const X_IDCT_SCALE: i32 = 8;
+pub(crate) const X_IDCT_SCALE: i32 = 8;
 
-pub const LEPTON_VERSION: u8 = 1; // Lepton version, same as used by Lepton C++ since we support the same format
-pub const MAX_FILE_SIZE_BYTES: i32 = 128 * 1024 * 1024;
+pub(crate) const LEPTON_VERSION: u8 = 1; // Lepton version, same as used by Lepton C++ since we support the same format
+pub(crate) const LEPTON_VERSION_CRC32_TRAILER: u8 = 2; // same format, with a CRC32 of the JPEG in front of the file size trailer
+pub(crate) const MAX_FILE_SIZE_BYTES: i32 = 128 * 1024 * 1024;
 //pub const LogMaxNumerator : i32 = 18;
 //pub const DefaultEncodingThreads : usize = 8;
-pub const SMALL_FILE_BYTES_PER_ENCDOING_THREAD: usize = 125000;
+pub(crate) const SMALL_FILE_BYTES_PER_ENCDOING_THREAD: usize = 125000;
 //pub const TailGarbageBufferLength : i32 = 1024;
-pub const MAX_THREADS_SUPPORTED_BY_LEPTON_FORMAT: usize = 16; // Number of threads minus 1 should fit in 4 bits
+pub(crate) const MAX_THREADS_SUPPORTED_BY_LEPTON_FORMAT: usize = 16; // Number of threads minus 1 should fit in 4 bits
 
 //pub const SingleFFByte : [u8;1] = [ 0xFF ];
-pub const EOI: [u8; 2] = [0xFF, crate::jpeg_code::EOI]; // EOI segment
-pub const SOI: [u8; 2] = [0xFF, crate::jpeg_code::SOI]; // SOI segment
-pub const LEPTON_FILE_HEADER: [u8; 2] = [0xcf, 0x84]; // the tau symbol for a tau lepton in utf-8
-pub const LEPTON_HEADER_BASELINE_JPEG_TYPE: [u8; 1] = [b'Z'];
-pub const LEPTON_HEADER_PROGRESSIVE_JPEG_TYPE: [u8; 1] = [b'X'];
-pub const LEPTON_HEADER_MARKER: [u8; 3] = *b"HDR";
-pub const LEPTON_HEADER_PAD_MARKER: [u8; 3] = *b"P0D";
-pub const LEPTON_HEADER_JPG_RESTARTS_MARKER: [u8; 3] = *b"CRS";
-pub const LEPTON_HEADER_JPG_RESTART_ERRORS_MARKER: [u8; 3] = *b"FRS";
-pub const LEPTON_HEADER_LUMA_SPLIT_MARKER: [u8; 2] = *b"HH";
-pub const LEPTON_HEADER_EARLY_EOF_MARKER: [u8; 3] = *b"EEE";
-pub const LEPTON_HEADER_PREFIX_GARBAGE_MARKER: [u8; 3] = *b"PGR";
-pub const LEPTON_HEADER_GARBAGE_MARKER: [u8; 3] = *b"GRB";
-pub const LEPTON_HEADER_COMPLETION_MARKER: [u8; 3] = *b"CMP";
+pub(crate) const EOI: [u8; 2] = [0xFF, crate::jpeg_code::EOI]; // EOI segment
+pub(crate) const SOI: [u8; 2] = [0xFF, crate::jpeg_code::SOI]; // SOI segment
+pub(crate) const LEPTON_FILE_HEADER: [u8; 2] = [0xcf, 0x84]; // the tau symbol for a tau lepton in utf-8
+pub(crate) const LEPTON_HEADER_BASELINE_JPEG_TYPE: [u8; 1] = [b'Z'];
+pub(crate) const LEPTON_HEADER_PROGRESSIVE_JPEG_TYPE: [u8; 1] = [b'X'];
+pub(crate) const LEPTON_HEADER_MARKER: [u8; 3] = *b"HDR";
+pub(crate) const LEPTON_HEADER_PAD_MARKER: [u8; 3] = *b"P0D";
+pub(crate) const LEPTON_HEADER_JPG_RESTARTS_MARKER: [u8; 3] = *b"CRS";
+pub(crate) const LEPTON_HEADER_JPG_RESTART_ERRORS_MARKER: [u8; 3] = *b"FRS";
+pub(crate) const LEPTON_HEADER_LUMA_SPLIT_MARKER: [u8; 2] = *b"HH";
+pub(crate) const LEPTON_HEADER_EARLY_EOF_MARKER: [u8; 3] = *b"EEE";
+pub(crate) const LEPTON_HEADER_PREFIX_GARBAGE_MARKER: [u8; 3] = *b"PGR";
+pub(crate) const LEPTON_HEADER_GARBAGE_MARKER: [u8; 3] = *b"GRB";
+pub(crate) const LEPTON_HEADER_COMPLETION_MARKER: [u8; 3] = *b"CMP";
+pub(crate) const LEPTON_HEADER_ROW_INDEX_MARKER: [u8; 3] = *b"RSI"; // only in row seekable files, unknown to c++ lepton
+
+// bits of the feature flags byte stored in the reserved git revision area of the header.
+// Files written by c++ lepton (or older versions of this library) don't have the present bit set.
+pub(crate) const LEPTON_HEADER_FLAGS_PRESENT: u8 = 0x80;
+pub(crate) const LEPTON_HEADER_FLAG_16BIT_DC_ESTIMATE: u8 = 0x01;
+pub(crate) const LEPTON_HEADER_FLAG_16BIT_ADV_PREDICT: u8 = 0x02;
 //pub const ChunkedLeptonHeaderSizeMarker : [u8;3] = *b"SIZ" ;
 //pub const ChunkedLeptonHeaderJpgHeaderDataRangeMarker : [u8;3] = *b"JHR";
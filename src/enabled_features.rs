@@ -1,3 +1,101 @@
+use crate::helpers::u16_bit_length;
+use crate::lepton_error::{ExitCode, LeptonError};
+
+// limits that are generous enough for any legitimate photo (a gigapixel image),
+// but stop malicious headers from allocating enormous buffers. The block limit
+// leaves room for the padding of the blocks up to full MCUs.
+const DEFAULT_MAX_TOTAL_PIXELS: u64 = 1 << 30;
+const DEFAULT_MAX_BLOCKS_PER_COMPONENT: u64 = 2 * DEFAULT_MAX_TOTAL_PIXELS / 64;
+
+/// Number of bins of the DC model for the `uncertainty` of the DC prediction
+pub const DC_UNCERTAINTY_BINS: usize = 12;
+
+/// Number of bins of the DC model for the `uncertainty2` of the DC prediction
+pub const DC_UNCERTAINTY2_BINS: usize = 17;
+
+/// Maps the two uncertainties of the DC prediction to the bins that select the branches
+/// of the DC model. This is a seam for experimenting with other DC context models. Since
+/// the mapping is not stored in the Lepton file, the decoder has to use the same mapping as
+/// the encoder.
+pub trait DcUncertaintyBins: core::fmt::Debug + Sync {
+    /// Returns the bin for `uncertainty`, below `DC_UNCERTAINTY_BINS`, and the bin for
+    /// `uncertainty2`, below `DC_UNCERTAINTY2_BINS`. Larger bins are clamped to the last one.
+    fn get_bins(&self, uncertainty: i16, uncertainty2: i16) -> (usize, usize);
+}
+
+/// The mapping used by Lepton: the bit length of the absolute value of each uncertainty,
+/// with the first one clamped to the last bin.
+#[derive(Debug)]
+pub struct DefaultDcUncertaintyBins;
+
+impl DcUncertaintyBins for DefaultDcUncertaintyBins {
+    fn get_bins(&self, uncertainty: i16, uncertainty2: i16) -> (usize, usize) {
+        let len_abs_mxm = u16_bit_length(uncertainty.unsigned_abs());
+        let len_abs_offset_to_closest_edge = u16_bit_length(uncertainty2.unsigned_abs());
+
+        (
+            core::cmp::min(usize::from(len_abs_mxm), DC_UNCERTAINTY_BINS - 1),
+            usize::from(len_abs_offset_to_closest_edge),
+        )
+    }
+}
+
+/// Generation of the probability model and of the derivation of its contexts that the
+/// coefficients are coded with. The Lepton header records the version a file was written
+/// with, so that a change of the model makes older files select the logic they were
+/// written with instead of decoding to garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelVersion {
+    /// the model of c++ lepton, which every file written so far uses
+    V1,
+
+    /// the V1 model with an escape for coefficients of 12 up to 15 bits, which only 12 bit
+    /// images have. The additional length and the bits above the residual branches are coded
+    /// as bypass bits, so coefficients that fit V1 are coded exactly the same.
+    V1WideCoefficients,
+}
+
+impl ModelVersion {
+    /// the version that new files are written with
+    pub const CURRENT: ModelVersion = ModelVersion::V1;
+
+    /// value stored in the Lepton header, zero is reserved for files that don't record
+    /// the version
+    pub(crate) fn to_header_byte(self) -> u8 {
+        match self {
+            ModelVersion::V1 => 1,
+            ModelVersion::V1WideCoefficients => 2,
+        }
+    }
+
+    /// the version stored in the Lepton header, None for versions unknown to this library
+    pub(crate) fn from_header_byte(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ModelVersion::V1),
+            2 => Some(ModelVersion::V1WideCoefficients),
+            _ => None,
+        }
+    }
+
+    /// the version the encoder writes an image with the given sample precision with, if
+    /// this version was requested. 12 bit images can have coefficients that are too long
+    /// for V1, so they are written with the wide version, which older decoders reject.
+    pub(crate) fn for_precision(self, precision: u8) -> Self {
+        match self {
+            ModelVersion::V1 if precision > 8 => ModelVersion::V1WideCoefficients,
+            _ => self,
+        }
+    }
+
+    /// the length in bits of the largest coefficient magnitude the model can code
+    pub(crate) const fn max_coefficient_bits(self) -> usize {
+        match self {
+            ModelVersion::V1 => 11,
+            ModelVersion::V1WideCoefficients => 15,
+        }
+    }
+}
+
 // features that are enabled in the encoder. Turn off for potential backward compat issues.
 #[derive(Debug, Clone, Copy)]
 pub struct EnabledFeatures {
@@ -16,14 +114,102 @@ pub struct EnabledFeatures {
     /// Sadly C++ version has a bug where it uses 16 bit math in the SIMD path and 32 bit math in the scalar path
     pub use_16bit_dc_estimate: bool,
 
-    /// Sadly C++ version has a bug where it uses 16 bit math in the SIMD path and 32 bit math in the scalar path
+    /// Sadly C++ version has a bug where it uses 16 bit math in the SIMD path and 32 bit math in the scalar path.
+    /// The predictions only differ for blocks where the difference between the edge pixels overflows 16 bits,
+    /// but a file has to be decoded with the same setting that it was encoded with.
     pub use_16bit_adv_predict: bool,
 
     /// Accept JPEG files that have invalid DHT tables
     pub accept_invalid_dht: bool,
+
+    /// maximum number of 8x8 blocks in a single component, checked before allocating the image
+    pub max_blocks_per_component: u64,
+
+    /// maximum number of pixels in the image, checked before allocating the image
+    pub max_total_pixels: u64,
+
+    /// ANALYSIS ONLY: store the DC residuals from the stream in the decoded blocks instead of
+    /// the reconstructed DC. The output does not round-trip, and the encoder refuses to run
+    /// with this set.
+    pub decode_dc_residuals: bool,
+
+    /// Fail with VersionUnsupported if the feature flags stored in the Lepton header disagree with
+    /// use_16bit_dc_estimate/use_16bit_adv_predict, instead of silently taking the flags from the file.
+    /// Files without stored flags (c++ lepton) are always decoded with the flags given here.
+    pub require_matching_stream_flags: bool,
+
+    /// mapping of the DC prediction uncertainties to the bins of the DC model. Anything other
+    /// than `DefaultDcUncertaintyBins` gives files that only decode with the same mapping.
+    pub dc_uncertainty_bins: &'static dyn DcUncertaintyBins,
+
+    /// Append a CRC32 of the original JPEG to the Lepton file, which the decoder verifies after
+    /// the whole file has been decoded. Files with the trailer are written with a newer version
+    /// number, so c++ lepton and older versions of this library refuse them instead of misreading them.
+    pub write_crc32_trailer: bool,
+
+    /// Encode every MCU row with a fresh model as a separate stream and store the size of each
+    /// row in the header, so that any row can be decoded on its own with `decode_mcu_row`. This
+    /// makes the file larger, and c++ lepton and older versions of this library can't read it.
+    pub row_seekable: bool,
+
+    /// LOSSY: before encoding, zero every AC coefficient whose magnitude is below this
+    /// threshold and store the JPEG that is written from the remaining coefficients. The file
+    /// then decodes to that cleaned up JPEG instead of the original. None keeps the encoding
+    /// lossless. Fails with UnsupportedJpeg if the huffman tables of the image have no codes for
+    /// the longer runs of zeros.
+    pub lossy_ac_threshold: Option<i16>,
+
+    /// Version of the model that files are encoded with. When decoding, the version stored in
+    /// the Lepton header is used instead, and files without one (c++ lepton and older versions
+    /// of this library) are decoded with the version given here. 12 bit images are encoded
+    /// with the wide version of V1, see `ModelVersion::V1WideCoefficients`. With
+    /// `require_matching_stream_flags` a different version in the file than the encoder would
+    /// have used fails with VersionUnsupported.
+    pub model_version: ModelVersion,
 }
 
 impl EnabledFeatures {
+    /// Checks that the combination of settings makes sense. This is called at the start of
+    /// `encode_lepton` and `decode_lepton`, and returns a `SyntaxError` for settings that
+    /// could never work. The allowed combinations are:
+    ///
+    /// | setting                                   | constraint                                   |
+    /// |-------------------------------------------|----------------------------------------------|
+    /// | `max_jpeg_width`, `max_jpeg_height`       | greater than zero                            |
+    /// | `max_blocks_per_component`, `max_total_pixels` | greater than zero                       |
+    /// | `use_16bit_dc_estimate`, `use_16bit_adv_predict` | any combination, but the decoder has to use the one the file was encoded with |
+    /// | `write_crc32_trailer`, `row_seekable`     | any combination, they only apply to encoding |
+    /// | `lossy_ac_threshold`                      | not negative                                 |
+    ///
+    /// Limits of zero would reject every image that has any pixels, and the threshold is
+    /// compared with the magnitude of the coefficients, so its sign has no meaning. Encoding
+    /// additionally fails with `decode_dc_residuals` set, since the DC residuals don't
+    /// round-trip, but the same settings can be used for decoding.
+    pub fn validate(&self) -> Result<(), LeptonError> {
+        if self.max_jpeg_width <= 0 || self.max_jpeg_height <= 0 {
+            return Err(LeptonError::new(
+                ExitCode::SyntaxError,
+                "max_jpeg_width and max_jpeg_height have to be greater than zero",
+            ));
+        }
+
+        if self.max_blocks_per_component == 0 || self.max_total_pixels == 0 {
+            return Err(LeptonError::new(
+                ExitCode::SyntaxError,
+                "max_blocks_per_component and max_total_pixels have to be greater than zero",
+            ));
+        }
+
+        if self.lossy_ac_threshold.is_some_and(|t| t < 0) {
+            return Err(LeptonError::new(
+                ExitCode::SyntaxError,
+                "lossy_ac_threshold can't be negative",
+            ));
+        }
+
+        Ok(())
+    }
+
     /// parameters that allow everything for encoding that is compatible with c++ lepton compiled with SIMD
     #[allow(dead_code)]
     pub fn compat_lepton_vector_write() -> Self {
@@ -35,6 +221,15 @@ impl EnabledFeatures {
             use_16bit_dc_estimate: true,
             use_16bit_adv_predict: true,
             accept_invalid_dht: false,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: false,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::CURRENT,
         }
     }
 
@@ -50,6 +245,15 @@ impl EnabledFeatures {
             use_16bit_dc_estimate: false,
             use_16bit_adv_predict: false,
             accept_invalid_dht: true,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: false,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::CURRENT,
         }
     }
 
@@ -65,6 +269,217 @@ impl EnabledFeatures {
             use_16bit_dc_estimate: true,
             use_16bit_adv_predict: true,
             accept_invalid_dht: true,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: false,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::CURRENT,
+        }
+    }
+
+    /// Same settings as the Dropbox release of c++ lepton (which was compiled with SIMD),
+    /// so the files can be exchanged with it in both directions. Unlike
+    /// `compat_lepton_vector_write` the model is pinned to V1 instead of following
+    /// `ModelVersion::CURRENT`, since c++ lepton only knows V1. Files written with this
+    /// preset can be read with this preset, `compat_lepton_vector_read` or `conservative`.
+    #[allow(dead_code)]
+    pub fn compat_lepton_dropbox() -> Self {
+        Self {
+            progressive: true,
+            reject_dqts_with_zeros: true,
+            max_jpeg_height: 16386,
+            max_jpeg_width: 16386,
+            use_16bit_dc_estimate: true,
+            use_16bit_adv_predict: true,
+            accept_invalid_dht: false,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: false,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::V1,
+        }
+    }
+
+    /// Best settings for files that are only ever read by this library. Uses the full
+    /// 32 bit math for the predictions instead of emulating the 16 bit overflow of the
+    /// SIMD version, and only relies on the pixel/block limits for the image size.
+    ///
+    /// NOT wire-compatible with the vector presets: files written with this preset have
+    /// to be read with this preset or `compat_lepton_scalar_read`.
+    #[allow(dead_code)]
+    pub fn modern_best() -> Self {
+        Self {
+            progressive: true,
+            reject_dqts_with_zeros: true,
+            max_jpeg_height: i32::MAX,
+            max_jpeg_width: i32::MAX,
+            use_16bit_dc_estimate: false,
+            use_16bit_adv_predict: false,
+            accept_invalid_dht: false,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: false,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::CURRENT,
+        }
+    }
+
+    /// Strictest settings: only baseline images within the c++ lepton size limits and
+    /// without any of the tolerated malformations. Uses the same math as the SIMD
+    /// version, so it is wire-compatible with `compat_lepton_dropbox`, and refuses to decode
+    /// files whose header says they were written with different math.
+    #[allow(dead_code)]
+    pub fn conservative() -> Self {
+        Self {
+            progressive: false,
+            reject_dqts_with_zeros: true,
+            max_jpeg_height: 16386,
+            max_jpeg_width: 16386,
+            use_16bit_dc_estimate: true,
+            use_16bit_adv_predict: true,
+            accept_invalid_dht: false,
+            max_blocks_per_component: DEFAULT_MAX_BLOCKS_PER_COMPONENT,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            decode_dc_residuals: false,
+            require_matching_stream_flags: true,
+            dc_uncertainty_bins: &DefaultDcUncertaintyBins,
+            write_crc32_trailer: false,
+            row_seekable: false,
+            lossy_ac_threshold: None,
+            model_version: ModelVersion::CURRENT,
+        }
+    }
+}
+
+// the default mapping has to give the bins that the DC model has always used
+#[test]
+fn default_dc_uncertainty_bins() {
+    let bins = DefaultDcUncertaintyBins;
+
+    assert_eq!(bins.get_bins(0, 0), (0, 0));
+    assert_eq!(bins.get_bins(1, -1), (1, 1));
+    assert_eq!(bins.get_bins(-3, 4), (2, 3));
+    assert_eq!(bins.get_bins(255, -256), (8, 9));
+    assert_eq!(bins.get_bins(1024, 1023), (11, 10));
+    assert_eq!(bins.get_bins(2047, 2048), (11, 12));
+    assert_eq!(bins.get_bins(i16::MAX, i16::MIN), (11, 16));
+    assert_eq!(bins.get_bins(i16::MIN, i16::MAX), (11, 15));
+
+    // all the presets use the default mapping
+    assert_eq!(
+        EnabledFeatures::modern_best()
+            .dc_uncertainty_bins
+            .get_bins(-300, 70),
+        (9, 7)
+    );
+}
+
+// all the presets are valid
+#[test]
+fn validate_presets() {
+    for features in [
+        EnabledFeatures::compat_lepton_vector_write(),
+        EnabledFeatures::compat_lepton_vector_read(),
+        EnabledFeatures::compat_lepton_scalar_read(),
+        EnabledFeatures::compat_lepton_dropbox(),
+        EnabledFeatures::modern_best(),
+        EnabledFeatures::conservative(),
+    ] {
+        assert!(features.validate().is_ok());
+    }
+}
+
+#[test]
+fn validate_rejects_zero_dimension_limits() {
+    for features in [
+        EnabledFeatures {
+            max_jpeg_width: 0,
+            ..EnabledFeatures::modern_best()
+        },
+        EnabledFeatures {
+            max_jpeg_height: -1,
+            ..EnabledFeatures::modern_best()
+        },
+    ] {
+        assert_eq!(
+            features.validate().unwrap_err().exit_code,
+            ExitCode::SyntaxError
+        );
+    }
+}
+
+#[test]
+fn validate_rejects_zero_size_limits() {
+    for features in [
+        EnabledFeatures {
+            max_blocks_per_component: 0,
+            ..EnabledFeatures::modern_best()
+        },
+        EnabledFeatures {
+            max_total_pixels: 0,
+            ..EnabledFeatures::modern_best()
+        },
+    ] {
+        assert_eq!(
+            features.validate().unwrap_err().exit_code,
+            ExitCode::SyntaxError
+        );
+    }
+}
+
+#[test]
+fn validate_rejects_negative_lossy_threshold() {
+    let features = EnabledFeatures {
+        lossy_ac_threshold: Some(-1),
+        ..EnabledFeatures::modern_best()
+    };
+
+    assert_eq!(
+        features.validate().unwrap_err().exit_code,
+        ExitCode::SyntaxError
+    );
+
+    assert!(EnabledFeatures {
+        lossy_ac_threshold: Some(0),
+        ..EnabledFeatures::modern_best()
+    }
+    .validate()
+    .is_ok());
+}
+
+// flags that are independent of each other are accepted in every combination
+#[test]
+fn validate_accepts_independent_flags() {
+    for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+        assert!(EnabledFeatures {
+            use_16bit_dc_estimate: a,
+            use_16bit_adv_predict: b,
+            ..EnabledFeatures::modern_best()
+        }
+        .validate()
+        .is_ok());
+
+        // the encoding options are ignored when decoding, so they don't conflict with the
+        // analysis option that only works for decoding
+        assert!(EnabledFeatures {
+            decode_dc_residuals: true,
+            write_crc32_trailer: a,
+            row_seekable: b,
+            ..EnabledFeatures::modern_best()
         }
+        .validate()
+        .is_ok());
     }
 }
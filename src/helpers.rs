@@ -4,14 +4,18 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::lepton_error::{ExitCode, LeptonError};
+use alloc::string::ToString;
 
+use crate::lepton_error::{ErrorLocation, ExitCode, LeptonError, Result};
+
+#[cfg(feature = "std")]
 macro_rules! here {
     () => {
         concat!("at ", file!(), " line ", line!())
     };
 }
 
+#[cfg(feature = "std")]
 pub(crate) use here;
 
 #[inline(always)]
@@ -25,11 +29,26 @@ pub const fn u32_bit_length(v: u32) -> u8 {
 }
 
 #[cold]
-pub fn err_exit_code<T>(_error_code: ExitCode, message: &str) -> anyhow::Result<T> {
-    return Err(anyhow::Error::new(LeptonError {
-        exit_code: _error_code,
+pub fn err_exit_code<T>(_error_code: ExitCode, message: &str) -> Result<T> {
+    err_exit_code_at(_error_code, message, ErrorLocation::default())
+}
+
+/// same as err_exit_code, but also records where in the image the error happened
+pub fn err_exit_code_at<T>(
+    error_code: ExitCode,
+    message: &str,
+    location: ErrorLocation,
+) -> Result<T> {
+    let e = LeptonError {
+        exit_code: error_code,
         message: message.to_string(),
-    }));
+        location,
+    };
+
+    #[cfg(feature = "std")]
+    let e = anyhow::Error::new(e);
+
+    return Err(e);
 }
 
 pub fn buffer_prefix_matches_marker<const BS: usize, const MS: usize>(
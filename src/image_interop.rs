@@ -0,0 +1,38 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Conversion between the raw bytes of a JPEG file, as the `image` crate loads them, and the
+//! decoded DCT coefficients, so that an existing pipeline can hand its JPEGs to the
+//! recompression without writing them out first.
+
+use std::io::Cursor;
+
+use crate::enabled_features::EnabledFeatures;
+use crate::lepton_error::LeptonError;
+use crate::structs::block_based_image::BlockBasedImage;
+use crate::structs::lepton_format::{read_jpeg, LeptonHeader};
+use crate::translate_error;
+
+/// Decodes the JPEG file into the coefficients of each component. The returned header holds
+/// the parsed JPEG header (`jpeg_header`) together with everything else that is needed to
+/// recreate the file exactly, such as the raw header segments and any data after the image.
+pub fn from_jpeg_bytes(jpeg: &[u8]) -> Result<(LeptonHeader, Vec<BlockBasedImage>), LeptonError> {
+    let features = EnabledFeatures::compat_lepton_vector_write();
+    read_jpeg(&mut Cursor::new(jpeg), &features, 1, |_| {}).map_err(translate_error)
+}
+
+/// Recreates the JPEG file from the header and coefficients returned by `from_jpeg_bytes`.
+/// Unless the coefficients were changed, the result is identical to the original file.
+pub fn to_jpeg_bytes(
+    header: &LeptonHeader,
+    image_data: &[BlockBasedImage],
+) -> Result<Vec<u8>, LeptonError> {
+    let mut jpeg = Vec::new();
+    header
+        .reconstruct_jpeg(image_data, &mut jpeg)
+        .map_err(translate_error)?;
+    Ok(jpeg)
+}
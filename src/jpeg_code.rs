@@ -16,6 +16,15 @@ pub const SOF2: u8 = 0xC2;
 /// Huffman Table
 pub const DHT: u8 = 0xC4;
 
+/// Start of Frame (size information), coding process: extended sequential DCT, arithmetic coding
+pub const SOF9: u8 = 0xC9;
+
+/// Start of Frame (size information), coding process: progressive DCT, arithmetic coding
+pub const SOF10: u8 = 0xCA;
+
+/// Define Arithmetic Coding conditioning
+pub const DAC: u8 = 0xCC;
+
 /// Restart 0 segment
 pub const RST0: u8 = 0xD0;
 
@@ -4,11 +4,11 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use std::fmt::Display;
+use alloc::string::String;
+use core::fmt::Display;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)]
-
 /// Well-defined errors for bad things that are expected to happen as part of compression/decompression
 pub enum ExitCode {
     //AssertionFailure = 1,
@@ -19,7 +19,7 @@ pub enum ExitCode {
     StreamInconsistent = 7,
     ProgressiveUnsupported = 8,
     SamplingBeyondTwoUnsupported = 10,
-    //SamplingBeyondFourUnsupported = 11,
+    SamplingBeyondFourUnsupported = 11,
     //ThreadingPartialMcu = 12,
     VersionUnsupported = 13,
     //OnlyGarbageNoJpeg = 14,
@@ -39,10 +39,14 @@ pub enum ExitCode {
     VerificationContentMismatch = 1005,
     SyntaxError = 1006,
     FileNotFound = 1007,
+    /// the JPEG uses a coding process (lossless, differential or hierarchical) that cannot be recompressed
+    UnsupportedCompression = 1008,
+    /// the CRC32 stored in the Lepton file doesn't match the decoded JPEG
+    ChecksumMismatch = 1009,
 }
 
 impl Display for ExitCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
@@ -55,12 +59,166 @@ pub struct LeptonError {
 
     /// diagnostic message including location. Content should not be relied on.
     pub message: String,
+
+    /// where in the image the error happened, as far as it is known
+    pub location: ErrorLocation,
+}
+
+impl LeptonError {
+    /// creates an error with the given exit code that isn't tied to a location in the image
+    pub fn new(exit_code: ExitCode, message: &str) -> Self {
+        LeptonError {
+            exit_code,
+            message: message.into(),
+            location: ErrorLocation::default(),
+        }
+    }
 }
 
 impl Display for LeptonError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{0}: {1}", self.exit_code, self.message)
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{0}: {1}", self.exit_code, self.message)?;
+        if self.location != ErrorLocation::default() {
+            write!(f, " ({0})", self.location)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by `decode_lepton_classified`, which tells failures of the reader or
+/// writer supplied by the caller apart from problems with the Lepton stream itself.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum DecodeError {
+    /// the reader or writer failed. Retrying with a working stream may succeed.
+    Io(std::io::Error),
+    /// the Lepton stream is corrupt or unsupported, retrying won't help
+    Stream(LeptonError),
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for DecodeError {
+    fn from(e: std::io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<LeptonError> for DecodeError {
+    fn from(e: LeptonError) -> Self {
+        DecodeError::Stream(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "I/O error: {0}", e),
+            DecodeError::Stream(e) => write!(f, "{0}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            DecodeError::Stream(e) => Some(e),
+        }
+    }
+}
+
+/// Position of the block (and coefficient) that failed to decode. Each level of the decoder
+/// fills in what it knows, so any of the fields may be missing depending on where the error
+/// came from.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ErrorLocation {
+    /// component of the block
+    pub component: Option<usize>,
+    /// luma row of the MCU row that was being decoded
+    pub luma_y: Option<i32>,
+    /// column of the block within the component
+    pub block_x: Option<i32>,
+    /// row of the block within the component
+    pub block_y: Option<i32>,
+    /// index of the coefficient in JPEG zigzag order
+    pub zigzag_index: Option<u8>,
+    /// offset of the first byte of the decoded JPEG that differs from the original
+    pub jpeg_offset: Option<u64>,
+}
+
+impl ErrorLocation {
+    /// fills in the fields that are not known yet from `other`
+    pub fn merge(&mut self, other: &ErrorLocation) {
+        self.component = self.component.or(other.component);
+        self.luma_y = self.luma_y.or(other.luma_y);
+        self.block_x = self.block_x.or(other.block_x);
+        self.block_y = self.block_y.or(other.block_y);
+        self.zigzag_index = self.zigzag_index.or(other.zigzag_index);
+        self.jpeg_offset = self.jpeg_offset.or(other.jpeg_offset);
     }
 }
 
+impl Display for ErrorLocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut separator = "";
+        let mut field = |f: &mut core::fmt::Formatter<'_>, name: &str, value: Option<i64>| {
+            if let Some(v) = value {
+                write!(f, "{0}{1} {2}", separator, name, v)?;
+                separator = ", ";
+            }
+            Ok(())
+        };
+
+        field(f, "component", self.component.map(|v| v as i64))?;
+        field(f, "luma_y", self.luma_y.map(i64::from))?;
+        field(f, "block_x", self.block_x.map(i64::from))?;
+        field(f, "block_y", self.block_y.map(i64::from))?;
+        field(f, "zigzag_index", self.zigzag_index.map(i64::from))?;
+        field(f, "jpeg_offset", self.jpeg_offset.map(|v| v as i64))
+    }
+}
+
+/// Adds the known parts of the location to the error if it is a LeptonError, keeping
+/// anything that was already filled in closer to where the error happened. Other
+/// errors are returned unchanged.
+#[cfg(feature = "std")]
+pub(crate) fn add_error_location(mut e: anyhow::Error, location: ErrorLocation) -> anyhow::Error {
+    if let Some(lepton_error) = e.downcast_mut::<LeptonError>() {
+        lepton_error.location.merge(&location);
+    }
+    e
+}
+
+/// translates internal anyhow based exception into externally visible exception
+#[cfg(feature = "std")]
+pub(crate) fn translate_error(e: anyhow::Error) -> LeptonError {
+    match e.root_cause().downcast_ref::<LeptonError>() {
+        // try to extract the exit code if it was a well known error
+        Some(x) => LeptonError {
+            exit_code: x.exit_code,
+            message: x.message.to_owned(),
+            location: x.location,
+        },
+        None => LeptonError {
+            exit_code: ExitCode::GeneralFailure,
+            message: format!("unexpected error {0:?}", e),
+            location: ErrorLocation::default(),
+        },
+    }
+}
+
+#[cfg(feature = "std")]
 impl std::error::Error for LeptonError {}
+
+/// Result used internally. With std the errors are anyhow errors that carry a LeptonError
+/// as their root cause. Without std anyhow isn't available, so the core modules return
+/// the LeptonError with its exit code directly.
+#[cfg(feature = "std")]
+pub(crate) type Result<T> = anyhow::Result<T>;
+
+#[cfg(not(feature = "std"))]
+pub(crate) type Result<T> = core::result::Result<T, LeptonError>;
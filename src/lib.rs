@@ -4,48 +4,79 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-mod consts;
+// Without the std feature only the entropy coding and block storage core is built,
+// which only needs alloc. Everything that deals with files, threads or compression of
+// the header is std only. The core has no public interface of its own yet, so most
+// of it is unused in that configuration.
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+extern crate alloc;
+
+pub mod coeff_order;
+pub mod consts;
 mod helpers;
 mod jpeg_code;
 pub mod metrics;
 mod structs;
 
 pub mod enabled_features;
+#[cfg(feature = "image-interop")]
+pub mod image_interop;
 pub mod lepton_error;
 
 pub use crate::enabled_features::EnabledFeatures;
-pub use crate::lepton_error::{ExitCode, LeptonError};
+#[cfg(feature = "std")]
+pub use crate::lepton_error::DecodeError;
+pub use crate::lepton_error::{ErrorLocation, ExitCode, LeptonError};
 pub use metrics::Metrics;
 
+#[cfg(feature = "std")]
 use core::result::Result;
+#[cfg(feature = "std")]
 use std::panic::catch_unwind;
 
+#[cfg(feature = "std")]
 use std::io::{Cursor, Read, Seek, Write};
 
+#[cfg(feature = "std")]
+use crate::lepton_error::translate_error;
+#[cfg(feature = "std")]
+pub use crate::structs::batch_decoder::BatchDecoder;
+#[cfg(feature = "std")]
+use crate::structs::io_error_tracker::IoErrorTracker;
+#[cfg(feature = "tokio")]
+pub use crate::structs::lepton_decoder::lepton_decode_row_range_async;
+#[cfg(feature = "std")]
+pub use crate::structs::lepton_decoder::{
+    lepton_decode_row_range, lepton_decode_row_range_truncated, BlockNeighbors, BlockVisitor,
+    DecodeSession, RowRangeOptions, TruncationInfo,
+};
+#[cfg(feature = "std")]
+pub use crate::structs::lepton_file_info::{LeptonComponentInfo, LeptonFileInfo, LeptonJpegType};
+#[cfg(feature = "std")]
 use crate::structs::lepton_format::{
-    decode_lepton_wrapper, encode_lepton_wrapper, encode_lepton_wrapper_verify,
+    decode_lepton_dropbox_wrapper, decode_lepton_wrapper, encode_lepton_wrapper,
+    encode_lepton_wrapper_size, encode_lepton_wrapper_verify, encode_lepton_wrapper_with_tap,
+    is_legacy_dropbox_header, verify_lepton_wrapper,
+};
+#[cfg(feature = "std")]
+pub use crate::structs::probability_tables::nonzeros_7x7_context_bin;
+#[cfg(feature = "std")]
+pub use crate::structs::{
+    block_based_image::{AlignedBlock, BlockBasedImage},
+    block_context::{BlockContext, NeighborBlocks, NeighborData},
+    model::Model,
+    neighbor_summary::NeighborSummary,
+    probability_tables::ProbabilityTables,
+    probability_tables_set::ProbabilityTablesSet,
+    quantization_tables::QuantizationTables,
+    truncate_components::TruncateComponents,
+    vpx_bool_reader::VPXBoolReader,
 };
-
-/// translates internal anyhow based exception into externally visible exception
-fn translate_error(e: anyhow::Error) -> LeptonError {
-    match e.root_cause().downcast_ref::<LeptonError>() {
-        // try to extract the exit code if it was a well known error
-        Some(x) => {
-            return LeptonError {
-                exit_code: x.exit_code,
-                message: x.message.to_owned(),
-            };
-        }
-        None => {
-            return LeptonError {
-                exit_code: ExitCode::GeneralFailure,
-                message: format!("unexpected error {0:?}", e),
-            };
-        }
-    }
-}
 
 /// Decodes Lepton container and recreates the original JPEG file
+#[cfg(feature = "std")]
 pub fn decode_lepton<R: Read + Seek, W: Write>(
     reader: &mut R,
     writer: &mut W,
@@ -55,7 +86,145 @@ pub fn decode_lepton<R: Read + Seek, W: Write>(
     decode_lepton_wrapper(reader, writer, num_threads, enabled_features).map_err(translate_error)
 }
 
+/// Decodes Lepton container written by the c++ lepton released by Dropbox or by this library
+/// and recreates the original JPEG file. The legacy header of the Dropbox version doesn't record
+/// whether the encoder used its SIMD or its scalar math, so the SIMD math is tried first and the
+/// scalar math if the stream is inconsistent with it.
+#[cfg(feature = "std")]
+pub fn decode_lepton_dropbox<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    num_threads: usize,
+) -> Result<Metrics, LeptonError> {
+    decode_lepton_dropbox_wrapper(reader, writer, num_threads).map_err(translate_error)
+}
+
+/// Returns true if the Lepton file at the current position of the reader has the legacy header
+/// of the c++ lepton released by Dropbox, which is best decoded with `decode_lepton_dropbox`.
+/// The position of the reader is restored.
+#[cfg(feature = "std")]
+pub fn is_legacy_dropbox_lepton<R: Read + Seek>(reader: &mut R) -> Result<bool, LeptonError> {
+    is_legacy_dropbox_header(reader).map_err(translate_error)
+}
+
+/// Cheap check whether `data` looks like the start of a JPEG file: the SOI marker followed
+/// by the marker of the first segment. Only the first 4 bytes are looked at, so a true result
+/// doesn't mean that the file can be parsed.
+pub fn is_likely_jpeg(data: &[u8]) -> bool {
+    data.len() >= 4
+        && data[..2] == crate::consts::SOI
+        && data[2] == 0xFF
+        // SOI, EOI and the restart markers can't start the first segment
+        && matches!(data[3], 0xC0..=0xCF | 0xDA..=0xFE)
+}
+
+/// Cheap check whether `data` looks like the start of a Lepton file: the magic number, a
+/// supported version and the JPEG type. Only the first 4 bytes are looked at, so a true result
+/// doesn't mean that the file can be decoded.
+pub fn is_likely_lepton(data: &[u8]) -> bool {
+    use crate::consts::*;
+
+    data.len() >= 4
+        && data[..2] == LEPTON_FILE_HEADER
+        && (data[2] == LEPTON_VERSION || data[2] == LEPTON_VERSION_CRC32_TRAILER)
+        && (data[3] == LEPTON_HEADER_BASELINE_JPEG_TYPE[0]
+            || data[3] == LEPTON_HEADER_PROGRESSIVE_JPEG_TYPE[0])
+}
+
+/// Reads only the header of the Lepton file at the current position of the reader and describes
+/// the stored JPEG and the layout of the file, without decoding any coefficients.
+#[cfg(feature = "std")]
+pub fn read_lepton_file_info<R: Read>(reader: &mut R) -> Result<LeptonFileInfo, LeptonError> {
+    crate::structs::lepton_file_info::read_lepton_file_info(reader).map_err(translate_error)
+}
+
+/// Decodes a single coefficient block from `bool_reader` given its left, above and above-left
+/// neighbors (in the transposed order used during coding), and returns the block together with
+/// its neighbor summary that predicts the blocks to its right and below. Neighbors that are
+/// not present according to `pt` are ignored.
+///
+/// For example, the top-left block of an image has no neighbors. A stream of zero bytes
+/// codes a block without any non-zero coefficients:
+///
+/// ```
+/// use lepton_jpeg::{
+///     decode_block_standalone, AlignedBlock, BlockNeighbors, EnabledFeatures, Model,
+///     ProbabilityTables, QuantizationTables, VPXBoolReader,
+/// };
+///
+/// let empty = AlignedBlock::default();
+/// let pt = ProbabilityTables::new(0, false, false);
+/// let qt = QuantizationTables::new_from_table(&[1; 64]);
+/// let stream = [0u8; 16];
+///
+/// let neighbors = BlockNeighbors {
+///     left: &empty,
+///     above: &empty,
+///     above_left: &empty,
+/// };
+///
+/// let (block, _summary) = decode_block_standalone(
+///     neighbors,
+///     &pt,
+///     &mut Model::new_for_components(1),
+///     &mut VPXBoolReader::new(&stream[..]).unwrap(),
+///     &qt,
+///     &EnabledFeatures::compat_lepton_vector_read(),
+/// )
+/// .unwrap();
+///
+/// assert_eq!(block, empty);
+/// ```
+#[cfg(feature = "std")]
+pub fn decode_block_standalone<R: Read>(
+    neighbors: BlockNeighbors,
+    pt: &ProbabilityTables,
+    model: &mut Model,
+    bool_reader: &mut VPXBoolReader<R>,
+    qt: &QuantizationTables,
+    features: &EnabledFeatures,
+) -> Result<(AlignedBlock, NeighborSummary), LeptonError> {
+    crate::structs::lepton_decoder::decode_block_standalone(
+        neighbors,
+        pt,
+        model,
+        bool_reader,
+        qt,
+        features,
+    )
+    .map_err(translate_error)
+}
+
+/// Decodes Lepton container and recreates the original JPEG file like `decode_lepton`, but
+/// reports failures of the reader or writer as `DecodeError::Io` with the original I/O error,
+/// and everything else as `DecodeError::Stream`. The former may be worth retrying, the
+/// latter won't succeed with the same data.
+#[cfg(feature = "std")]
+pub fn decode_lepton_classified<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    num_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<Metrics, DecodeError> {
+    let mut reader = IoErrorTracker::new(reader);
+    let mut writer = IoErrorTracker::new(writer);
+
+    decode_lepton_wrapper(&mut reader, &mut writer, num_threads, enabled_features).map_err(|e| {
+        if reader.failed() || writer.failed() {
+            // the codec attaches context to the error on the way up, but the root cause
+            // is still the error returned by the stream
+            match e.downcast::<std::io::Error>() {
+                Ok(io_error) => DecodeError::Io(io_error),
+                Err(e) => DecodeError::Stream(translate_error(e)),
+            }
+        } else {
+            DecodeError::Stream(translate_error(e))
+        }
+    })
+}
+
 /// Encodes JPEG as compressed Lepton format.
+#[cfg(feature = "std")]
 pub fn encode_lepton<R: Read + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
@@ -65,7 +234,35 @@ pub fn encode_lepton<R: Read + Seek, W: Write + Seek>(
     encode_lepton_wrapper(reader, writer, max_threads, enabled_features).map_err(translate_error)
 }
 
+/// Encodes JPEG as compressed Lepton format, passing the bytes of the JPEG to `tap` in order as
+/// they are read, so that for example a hash of the original file can be computed without
+/// reading it a second time. The output is the same as for `encode_lepton`.
+#[cfg(feature = "std")]
+pub fn encode_lepton_with_tap<R: Read + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    max_threads: usize,
+    enabled_features: &EnabledFeatures,
+    tap: &mut dyn FnMut(&[u8]),
+) -> Result<Metrics, LeptonError> {
+    encode_lepton_wrapper_with_tap(reader, writer, max_threads, enabled_features, tap)
+        .map_err(translate_error)
+}
+
+/// Encodes JPEG as compressed Lepton format without storing the output, and returns the exact
+/// size in bytes that `encode_lepton` would write with the same settings. Useful to decide
+/// whether recompressing a file saves enough to be worth it.
+#[cfg(feature = "std")]
+pub fn encode_lepton_size<R: Read + Seek>(
+    reader: &mut R,
+    max_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<(u64, Metrics), LeptonError> {
+    encode_lepton_wrapper_size(reader, max_threads, enabled_features).map_err(translate_error)
+}
+
 /// Compresses JPEG into Lepton format and compares input to output to verify that compression roundtrip is OK
+#[cfg(feature = "std")]
 pub fn encode_lepton_verify(
     input_data: &[u8],
     max_threads: usize,
@@ -74,7 +271,21 @@ pub fn encode_lepton_verify(
     encode_lepton_wrapper_verify(input_data, max_threads, enabled_features).map_err(translate_error)
 }
 
+/// Decodes a Lepton file and checks that it recreates exactly the original JPEG. On a mismatch
+/// the `jpeg_offset` of the error location is the offset of the first byte that differs.
+#[cfg(feature = "std")]
+pub fn verify_lepton(
+    lepton_data: &[u8],
+    jpeg_data: &[u8],
+    num_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<Metrics, LeptonError> {
+    verify_lepton_wrapper(lepton_data, jpeg_data, num_threads, enabled_features)
+        .map_err(translate_error)
+}
+
 /// C ABI interface for compressing image, exposed from DLL
+#[cfg(feature = "std")]
 #[no_mangle]
 pub unsafe extern "C" fn WrapperCompressImage(
     input_buffer: *const u8,
@@ -124,6 +335,7 @@ pub unsafe extern "C" fn WrapperCompressImage(
 }
 
 /// C ABI interface for decompressing image, exposed from DLL
+#[cfg(feature = "std")]
 #[no_mangle]
 pub unsafe extern "C" fn WrapperDecompressImage(
     input_buffer: *const u8,
@@ -147,6 +359,7 @@ pub unsafe extern "C" fn WrapperDecompressImage(
 /// C ABI interface for decompressing image, exposed from DLL.
 /// use_16bit_dc_estimate argument should be set to true only for images
 /// that were compressed by C++ version of Leptron (see comments below).
+#[cfg(feature = "std")]
 #[no_mangle]
 pub unsafe extern "C" fn WrapperDecompressImageEx(
     input_buffer: *const u8,
@@ -4,12 +4,16 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+extern crate alloc;
+
 mod consts;
 mod enabled_features;
 mod helpers;
 mod jpeg_code;
 mod lepton_error;
 mod metrics;
+// also compiled into the library, whose public interface uses much more of it than the utility
+#[allow(dead_code)]
 mod structs;
 
 use anyhow;
@@ -71,6 +75,8 @@ fn main_with_result() -> anyhow::Result<()> {
                 enabled_features.max_jpeg_width = x;
             } else if let Some(x) = parse_numeric_parameter(args[i].as_str(), "-max-height:") {
                 enabled_features.max_jpeg_height = x;
+            } else if let Some(x) = parse_numeric_parameter(args[i].as_str(), "-lossyac:") {
+                enabled_features.lossy_ac_threshold = Some(x as i16);
             } else if args[i] == "-dump" {
                 dump = true;
             } else if args[i] == "-all" {
@@ -125,6 +131,12 @@ fn main_with_result() -> anyhow::Result<()> {
                 // lepton files that were encoded by the dropbox c++ version compiled in AVX2/SSE2 mode
                 enabled_features.use_16bit_adv_predict = true;
                 enabled_features.use_16bit_dc_estimate = true;
+            } else if args[i] == "-strictfeatures" {
+                enabled_features.require_matching_stream_flags = true;
+            } else if args[i] == "-crc32" {
+                enabled_features.write_crc32_trailer = true;
+            } else if args[i] == "-rowseekable" {
+                enabled_features.row_seekable = true;
             } else {
                 return err_exit_code(
                     ExitCode::SyntaxError,
@@ -222,6 +234,7 @@ fn main_with_result() -> anyhow::Result<()> {
             .map_err(|e| LeptonError {
                 exit_code: ExitCode::FileNotFound,
                 message: e.to_string(),
+                location: Default::default(),
             })
             .context(here!())?;
 
@@ -1,9 +1,14 @@
-use std::{collections::HashMap, time::Duration};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::time::Duration;
 
-#[cfg(windows)]
+use crate::consts::COLOR_CHANNEL_NUM_BLOCK_TYPES;
+
+#[cfg(all(windows, feature = "std"))]
 use cpu_time::ThreadTime;
 
 /// platform independent threadtime measurement
+#[cfg(feature = "std")]
 pub struct CpuTimeMeasure {
     #[cfg(windows)]
     start: ThreadTime,
@@ -11,6 +16,7 @@ pub struct CpuTimeMeasure {
     start: std::time::SystemTime,
 }
 
+#[cfg(feature = "std")]
 impl CpuTimeMeasure {
     pub fn new() -> Self {
         Self {
@@ -33,7 +39,7 @@ impl CpuTimeMeasure {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Hash, Eq)]
+#[derive(Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
 pub enum ModelSubComponent {
     Exp,
     Sign,
@@ -41,7 +47,7 @@ pub enum ModelSubComponent {
     Noise,
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Hash, Eq)]
+#[derive(Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum ModelComponent {
     Dummy,
@@ -52,23 +58,119 @@ pub enum ModelComponent {
     NonZeroEdgeCount,
 }
 
+/// Phases of decoding a block that are timed with the `phase_timing` feature
+#[derive(Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
+pub enum DecodePhase {
+    /// all of `read_coefficient_block`, which includes the other phases
+    ReadCoefficientBlock,
+    /// reading the edge coefficients in `decode_edge`
+    DecodeEdge,
+    /// the DC prediction of `adv_predict_dc_pix`
+    PredictDC,
+}
+
+const DECODE_PHASE_COUNT: usize = 3;
+
+#[cfg(feature = "phase_timing")]
+std::thread_local! {
+    /// time spent in each phase on this thread since the last `take_phase_times`
+    static PHASE_TIMES: core::cell::Cell<[Duration; DECODE_PHASE_COUNT]> =
+        const { core::cell::Cell::new([Duration::ZERO; DECODE_PHASE_COUNT]) };
+}
+
+/// Adds the time from its creation until it is dropped to the total of the phase on this
+/// thread. Without the `phase_timing` feature it is empty and doesn't implement `Drop`, so
+/// it compiles to nothing.
+pub(crate) struct PhaseTimer {
+    #[cfg(feature = "phase_timing")]
+    phase: DecodePhase,
+    #[cfg(feature = "phase_timing")]
+    start: std::time::Instant,
+}
+
+impl PhaseTimer {
+    #[inline(always)]
+    pub(crate) fn start(_phase: DecodePhase) -> Self {
+        PhaseTimer {
+            #[cfg(feature = "phase_timing")]
+            phase: _phase,
+            #[cfg(feature = "phase_timing")]
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "phase_timing")]
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        PHASE_TIMES.with(|t| {
+            let mut times = t.get();
+            times[self.phase as usize] += elapsed;
+            t.set(times);
+        });
+    }
+}
+
+/// returns and resets the time spent in each phase on this thread, always zero
+/// without the `phase_timing` feature
+#[allow(dead_code)]
+pub(crate) fn take_phase_times() -> [Duration; DECODE_PHASE_COUNT] {
+    #[cfg(feature = "phase_timing")]
+    {
+        PHASE_TIMES.with(|t| t.replace([Duration::ZERO; DECODE_PHASE_COUNT]))
+    }
+    #[cfg(not(feature = "phase_timing"))]
+    {
+        [Duration::ZERO; DECODE_PHASE_COUNT]
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct ModelComponentStatistics {
     pub total_bits: i64,
     pub total_compressed: i64,
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct Metrics {
-    map: HashMap<ModelComponent, ModelComponentStatistics>,
+    map: BTreeMap<ModelComponent, ModelComponentStatistics>,
+    per_color: [i64; COLOR_CHANNEL_NUM_BLOCK_TYPES],
+    per_zigzag: [i64; 64],
+    edge_noise_coefficients: [i64; COLOR_CHANNEL_NUM_BLOCK_TYPES],
     cpu_time_worker_time: Duration,
+    blocks_processed: u64,
+    block_processing_time: Duration,
+    branch_visits: Vec<u64>,
+    branch_renormalizations: Vec<u64>,
+    phase_times: [Duration; DECODE_PHASE_COUNT],
+}
+
+// arrays longer than 32 elements don't implement Default
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            map: BTreeMap::new(),
+            per_color: [0; COLOR_CHANNEL_NUM_BLOCK_TYPES],
+            per_zigzag: [0; 64],
+            edge_noise_coefficients: [0; COLOR_CHANNEL_NUM_BLOCK_TYPES],
+            cpu_time_worker_time: Duration::default(),
+            blocks_processed: 0,
+            block_processing_time: Duration::default(),
+            branch_visits: Vec::new(),
+            branch_renormalizations: Vec::new(),
+            phase_times: [Duration::ZERO; DECODE_PHASE_COUNT],
+        }
+    }
 }
 
 impl Metrics {
     #[allow(dead_code)]
-    fn record_compression_stats(
+    pub(crate) fn record_compression_stats(
         &mut self,
         cmp: ModelComponent,
+        color_index: usize,
+        zigzag_index: Option<usize>,
         total_bits: i64,
         total_compressed: i64,
     ) {
@@ -78,13 +180,55 @@ impl Metrics {
             .or_insert(ModelComponentStatistics::default());
         e.total_bits += total_bits;
         e.total_compressed += total_compressed;
+
+        self.per_color[color_index] += total_compressed;
+
+        if let Some(zigzag_index) = zigzag_index {
+            self.per_zigzag[zigzag_index] += total_compressed;
+        }
+    }
+
+    /// records edge coefficients of a component that were decoded with their lower bits
+    /// in the noise part of the model (below the quantization dependent noise threshold)
+    pub fn record_edge_noise_coefficients(&mut self, component: usize, count: i64) {
+        self.edge_noise_coefficients[component] += count;
     }
 
     pub fn record_cpu_worker_time(&mut self, duration: Duration) {
         self.cpu_time_worker_time += duration;
     }
 
+    /// records the number of blocks coded by a row range and the (wall clock) time it took.
+    /// This is recorded once per range, so it is always on.
+    pub fn record_blocks_processed(&mut self, blocks: u64, duration: Duration) {
+        self.blocks_processed += blocks;
+        self.block_processing_time += duration;
+    }
+
+    /// adds the number of bits coded with each branch of a model, in the order of the
+    /// serialized model. All the models of a file have the same layout.
+    #[allow(dead_code)]
+    pub(crate) fn record_branch_visits(&mut self, visits: &[u64]) {
+        add_per_branch(&mut self.branch_visits, visits);
+    }
+
+    /// adds the number of times the counts of each branch of a model overflowed, in the
+    /// order of the serialized model
+    #[allow(dead_code)]
+    pub(crate) fn record_branch_renormalizations(&mut self, renormalizations: &[u64]) {
+        add_per_branch(&mut self.branch_renormalizations, renormalizations);
+    }
+
+    /// adds the time spent in each decoding phase, see `take_phase_times`
+    #[allow(dead_code)]
+    pub(crate) fn record_phase_times(&mut self, times: [Duration; DECODE_PHASE_COUNT]) {
+        for (total, t) in self.phase_times.iter_mut().zip(times) {
+            *total += t;
+        }
+    }
+
     #[allow(dead_code)]
+    #[cfg(feature = "std")]
     pub fn print_metrics(&self) {
         let mut sort_vec = Vec::new();
         for x in &self.map {
@@ -115,13 +259,57 @@ impl Metrics {
             total_compressed,
             total_compressed / 8
         );
+
+        for (i, bits) in self.per_color.iter().enumerate() {
+            println!(
+                "color {0} compressed_bits={1:9} storage={2:0.1}%",
+                i,
+                bits,
+                (*bits as f64) * 100f64 / (total_compressed as f64)
+            );
+        }
+
+        for (i, count) in self.edge_noise_coefficients.iter().enumerate() {
+            println!("color {0} edge_noise_coefficients={1:9}", i, count);
+        }
+
         println!("worker_cpu={0}ms", self.cpu_time_worker_time.as_millis());
+
+        if let Some(throughput) = self.throughput() {
+            println!(
+                "blocks={0} throughput={1:0.0} blocks/s",
+                self.blocks_processed, throughput
+            );
+        }
+
+        for phase in [
+            DecodePhase::ReadCoefficientBlock,
+            DecodePhase::DecodeEdge,
+            DecodePhase::PredictDC,
+        ] {
+            let time = self.phase_time(phase);
+            if !time.is_zero() {
+                println!("{0:?}={1}ms", phase, time.as_millis());
+            }
+        }
+
+        for (bucket, count) in self.branch_visit_histogram().iter().enumerate() {
+            println!("branches visited < 2^{0:<2} times={1:9}", bucket, count);
+        }
     }
 
     pub fn drain(&mut self) -> Metrics {
         Metrics {
-            map: self.map.drain().collect(),
+            map: core::mem::take(&mut self.map),
+            per_color: core::mem::take(&mut self.per_color),
+            per_zigzag: core::mem::replace(&mut self.per_zigzag, [0; 64]),
+            edge_noise_coefficients: core::mem::take(&mut self.edge_noise_coefficients),
             cpu_time_worker_time: self.cpu_time_worker_time,
+            blocks_processed: core::mem::take(&mut self.blocks_processed),
+            block_processing_time: core::mem::take(&mut self.block_processing_time),
+            branch_visits: core::mem::take(&mut self.branch_visits),
+            branch_renormalizations: core::mem::take(&mut self.branch_renormalizations),
+            phase_times: core::mem::take(&mut self.phase_times),
         }
     }
 
@@ -129,8 +317,118 @@ impl Metrics {
         self.cpu_time_worker_time
     }
 
+    /// number of 8x8 blocks that were encoded or decoded
+    #[allow(dead_code)]
+    pub fn blocks_processed(&self) -> u64 {
+        self.blocks_processed
+    }
+
+    /// Blocks processed per second. The time is summed over all row ranges, so with several
+    /// threads this is the throughput of a single thread rather than of the whole file.
+    /// None if nothing was timed.
+    #[allow(dead_code)]
+    pub fn throughput(&self) -> Option<f64> {
+        if self.block_processing_time.is_zero() {
+            return None;
+        }
+
+        Some(self.blocks_processed as f64 / self.block_processing_time.as_secs_f64())
+    }
+
+    /// compressed bits attributed to each color plane (Y, Cb, Cr), only collected
+    /// if the `compression_stats` feature is enabled
+    #[allow(dead_code)]
+    pub fn per_color_bits(&self) -> [i64; COLOR_CHANNEL_NUM_BLOCK_TYPES] {
+        self.per_color
+    }
+
+    /// Compressed bits spent on the coefficients at each zigzag index (0 is the DC) while
+    /// decoding, only collected if the `compression_stats` feature is enabled. The counts of
+    /// the non-zero coefficients are not attributed to any index, so the sum is the total of
+    /// the Coef, Edge and DC model components.
+    #[allow(dead_code)]
+    pub fn bits_per_zigzag(&self) -> [i64; 64] {
+        self.per_zigzag
+    }
+
+    /// total compressed bits over all model components, only collected
+    /// if the `compression_stats` feature is enabled
+    #[allow(dead_code)]
+    pub fn get_total_compressed_bits(&self) -> i64 {
+        self.map.values().map(|x| x.total_compressed).sum()
+    }
+
+    /// compressed bits of a single model component, only collected
+    /// if the `compression_stats` feature is enabled
+    #[allow(dead_code)]
+    pub fn get_compressed_bits(&self, component: ModelComponent) -> i64 {
+        self.map.get(&component).map_or(0, |x| x.total_compressed)
+    }
+
+    /// Compressed bits of the whole model that the component belongs to, summed over its sub
+    /// components, so `bits_for(ModelComponent::DC(ModelSubComponent::Exp))` gives the bits of
+    /// the DC model and `get_compressed_bits` gives the bits of a single sub component. The
+    /// models add up to the total. Only collected if the `compression_stats` feature is enabled.
+    #[allow(dead_code)]
+    pub fn bits_for(&self, component: ModelComponent) -> i64 {
+        let model = core::mem::discriminant(&component);
+
+        self.map
+            .iter()
+            .filter(|(c, _)| core::mem::discriminant(*c) == model)
+            .map(|(_, x)| x.total_compressed)
+            .sum()
+    }
+
+    /// number of edge coefficients for each component (Y, Cb, Cr) that were decoded
+    /// using the noise threshold path of the model
+    #[allow(dead_code)]
+    pub fn edge_noise_coefficients(&self) -> [i64; COLOR_CHANNEL_NUM_BLOCK_TYPES] {
+        self.edge_noise_coefficients
+    }
+
+    /// Number of bits decoded with each branch of the model, in the order of the serialized
+    /// model and summed over all threads. Only collected if the `branch_visits` feature is
+    /// enabled, otherwise empty.
+    #[allow(dead_code)]
+    pub fn branch_visits(&self) -> &[u64] {
+        &self.branch_visits
+    }
+
+    /// Number of times the counts of each branch of the model overflowed, in the same order
+    /// as `branch_visits`. Branches that overflow often compared to their visits see long runs
+    /// of the same bit. Only collected if the `branch_visits` feature is enabled, otherwise
+    /// empty.
+    #[allow(dead_code)]
+    pub fn branch_renormalizations(&self) -> &[u64] {
+        &self.branch_renormalizations
+    }
+
+    /// Wall clock time spent in a phase of decoding the blocks, summed over all threads. Only
+    /// collected if the `phase_timing` feature is enabled, otherwise zero. The time of
+    /// `ReadCoefficientBlock` includes the other phases.
+    #[allow(dead_code)]
+    pub fn phase_time(&self, phase: DecodePhase) -> Duration {
+        self.phase_times[phase as usize]
+    }
+
+    /// Histogram of `branch_visits`: entry 0 is the number of branches that were never used,
+    /// entry n the number of branches used between 2^(n-1) and 2^n - 1 times.
+    #[allow(dead_code)]
+    pub fn branch_visit_histogram(&self) -> Vec<u64> {
+        let mut histogram = Vec::new();
+        for &v in &self.branch_visits {
+            let bucket = (u64::BITS - v.leading_zeros()) as usize;
+            if histogram.len() <= bucket {
+                histogram.resize(bucket + 1, 0);
+            }
+            histogram[bucket] += 1;
+        }
+        histogram
+    }
+
     pub fn merge_from(&mut self, mut source_metrics: Metrics) {
-        for x in source_metrics.map.drain() {
+        for x in core::mem::take(&mut source_metrics.map) {
             let e = self
                 .map
                 .entry(x.0)
@@ -139,6 +437,42 @@ impl Metrics {
             e.total_compressed += x.1.total_compressed;
         }
 
+        for i in 0..64 {
+            self.per_zigzag[i] += source_metrics.per_zigzag[i];
+        }
+
+        for i in 0..COLOR_CHANNEL_NUM_BLOCK_TYPES {
+            self.per_color[i] += source_metrics.per_color[i];
+            self.edge_noise_coefficients[i] += source_metrics.edge_noise_coefficients[i];
+        }
+
         self.cpu_time_worker_time += source_metrics.cpu_time_worker_time;
+        self.blocks_processed += source_metrics.blocks_processed;
+        self.block_processing_time += source_metrics.block_processing_time;
+        self.record_branch_visits(&source_metrics.branch_visits);
+        self.record_branch_renormalizations(&source_metrics.branch_renormalizations);
+        self.record_phase_times(source_metrics.phase_times);
     }
 }
+
+/// adds per branch counts to the totals, growing the totals if needed
+fn add_per_branch(totals: &mut Vec<u64>, values: &[u64]) {
+    if totals.len() < values.len() {
+        totals.resize(values.len(), 0);
+    }
+
+    for (total, v) in totals.iter_mut().zip(values) {
+        *total += v;
+    }
+}
+
+// without the feature the timers must not cost anything, not even a drop
+#[cfg(not(feature = "phase_timing"))]
+#[test]
+fn phase_timer_is_free_without_feature() {
+    assert_eq!(core::mem::size_of::<PhaseTimer>(), 0);
+    assert!(!core::mem::needs_drop::<PhaseTimer>());
+
+    let _timer = PhaseTimer::start(DecodePhase::DecodeEdge);
+    assert_eq!(take_phase_times(), [Duration::ZERO; DECODE_PHASE_COUNT]);
+}
@@ -0,0 +1,158 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use anyhow::Result;
+
+use crate::consts::ARITHMETIC_STATES;
+use crate::helpers::err_exit_code;
+use crate::lepton_error::ExitCode;
+
+/// Decoder for the arithmetic coding (QM coder) of JPEG files as described in Annex D of
+/// ITU T.81. This follows the decoder in libjpeg exactly, including the handling of markers
+/// in the middle of the data, after which the decoder is fed with zeros.
+///
+/// A statistics bin (`st`) holds the index into `ARITHMETIC_STATES` in the lower 7 bits
+/// and the current more probable symbol in the top bit.
+pub struct ArithmeticReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    c: u32,
+    a: u32,
+    ct: i32,
+    unread_marker: Option<u8>,
+}
+
+impl<'a> ArithmeticReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ArithmeticReader {
+            data,
+            pos: 0,
+            c: 0,
+            a: 0,
+            ct: -16, // force reading 2 initial bytes to fill C
+            unread_marker: None,
+        }
+    }
+
+    /// number of bytes of the entropy coded data consumed so far
+    pub fn get_position(&self) -> usize {
+        self.pos
+    }
+
+    /// reads the next byte of the entropy coded data, returning zeros once we hit a marker
+    fn get_byte(&mut self) -> u32 {
+        if self.unread_marker.is_some() {
+            return 0;
+        }
+
+        if self.pos >= self.data.len() {
+            // treat the end of the data the same as a marker
+            self.unread_marker = Some(0);
+            return 0;
+        }
+
+        let data = self.data[self.pos];
+        self.pos += 1;
+
+        if data != 0xFF {
+            return u32::from(data);
+        }
+
+        // zero stuff or marker code
+        if self.pos < self.data.len() && self.data[self.pos] == 0 {
+            self.pos += 1;
+            return 0xFF;
+        }
+
+        self.unread_marker = Some(*self.data.get(self.pos).unwrap_or(&0));
+        self.pos += 1;
+        0
+    }
+
+    pub fn decode(&mut self, st: &mut u8) -> bool {
+        // renormalization & data input per section D.2.6
+        while self.a < 0x8000 {
+            self.ct -= 1;
+            if self.ct < 0 {
+                let data = self.get_byte();
+                self.c = (self.c << 8) | data;
+                self.ct += 8;
+                if self.ct < 0 {
+                    // need more initial bytes
+                    self.ct += 1;
+                    if self.ct == 0 {
+                        // got 2 initial bytes, re-init A and exit loop
+                        self.a = 0x8000;
+                    }
+                }
+            }
+            self.a <<= 1;
+        }
+
+        let sv = *st;
+        let (qe, next_lps, next_mps, switch_mps) = ARITHMETIC_STATES[usize::from(sv & 0x7F)];
+        let qe = u32::from(qe);
+        let next_lps = next_lps | if switch_mps { 0x80 } else { 0 };
+
+        // decode & estimation procedures per sections D.2.4 & D.2.5
+        self.a -= qe;
+        let temp = self.a << self.ct;
+
+        let mut sv = sv;
+        if self.c >= temp {
+            self.c -= temp;
+
+            // conditional LPS (less probable symbol) exchange
+            if self.a < qe {
+                self.a = qe;
+                *st = (sv & 0x80) ^ next_mps;
+            } else {
+                self.a = qe;
+                *st = (sv & 0x80) ^ next_lps;
+                sv ^= 0x80;
+            }
+        } else if self.a < 0x8000 {
+            // conditional MPS (more probable symbol) exchange
+            if self.a < qe {
+                *st = (sv & 0x80) ^ next_lps;
+                sv ^= 0x80;
+            } else {
+                *st = (sv & 0x80) ^ next_mps;
+            }
+        }
+
+        (sv >> 7) != 0
+    }
+
+    /// reads the restart marker at the end of a restart interval and resets the decoder
+    pub fn process_restart(&mut self, expected_marker: u8) -> Result<()> {
+        if self.unread_marker.is_none() {
+            // skip any remaining data until the marker
+            while self.pos + 1 < self.data.len()
+                && (self.data[self.pos] != 0xFF || self.data[self.pos + 1] == 0)
+            {
+                self.pos += if self.data[self.pos] == 0xFF { 2 } else { 1 };
+            }
+
+            self.unread_marker = self.data.get(self.pos + 1).copied();
+            self.pos += 2;
+        }
+
+        if self.unread_marker != Some(expected_marker) {
+            return err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "missing restart marker in arithmetic coded scan",
+            );
+        }
+
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+        self.unread_marker = None;
+
+        Ok(())
+    }
+}
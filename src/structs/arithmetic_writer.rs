@@ -0,0 +1,201 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::consts::ARITHMETIC_STATES;
+use crate::jpeg_code;
+
+/// Encoder for the arithmetic coding (QM coder) of JPEG files as described in Annex D of
+/// ITU T.81. This follows the encoder in libjpeg exactly (including the way the data
+/// is terminated) so that files created by libjpeg can be recreated bit for bit.
+pub struct ArithmeticWriter {
+    data: Vec<u8>,
+    c: u32,
+    a: u32,
+    /// number of stacked 0xFF bytes that might still overflow
+    sc: u32,
+    /// number of pending zero bytes that are only written if something follows them
+    zc: u32,
+    ct: i32,
+    /// byte waiting to be written since it might still overflow, -1 if empty
+    buffer: i32,
+}
+
+impl ArithmeticWriter {
+    pub fn new() -> Self {
+        ArithmeticWriter {
+            data: Vec::new(),
+            c: 0,
+            a: 0x10000,
+            sc: 0,
+            zc: 0,
+            ct: 11,
+            buffer: -1,
+        }
+    }
+
+    pub fn detach_buffer(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn emit_byte(&mut self, b: u8) {
+        self.data.push(b);
+    }
+
+    fn emit_pending_zeros(&mut self) {
+        while self.zc > 0 {
+            self.emit_byte(0);
+            self.zc -= 1;
+        }
+    }
+
+    /// writes a byte that was in the buffer with a carry, converting stacked 0xFF bytes to zeros
+    fn emit_overflow(&mut self) {
+        if self.buffer >= 0 {
+            self.emit_pending_zeros();
+            let b = (self.buffer + 1) as u8;
+            self.emit_byte(b);
+            if b == 0xFF {
+                self.emit_byte(0);
+            }
+        }
+
+        self.zc += self.sc;
+        self.sc = 0;
+    }
+
+    /// writes the byte in the buffer and any stacked 0xFF bytes, which can no longer overflow
+    fn emit_buffer_and_stacked(&mut self) {
+        if self.buffer == 0 {
+            self.zc += 1;
+        } else if self.buffer >= 0 {
+            self.emit_pending_zeros();
+            self.emit_byte(self.buffer as u8);
+        }
+
+        if self.sc > 0 {
+            self.emit_pending_zeros();
+            while self.sc > 0 {
+                self.emit_byte(0xFF);
+                self.emit_byte(0);
+                self.sc -= 1;
+            }
+        }
+    }
+
+    pub fn encode(&mut self, st: &mut u8, val: bool) {
+        let sv = *st;
+        let (qe, next_lps, next_mps, switch_mps) = ARITHMETIC_STATES[usize::from(sv & 0x7F)];
+        let qe = u32::from(qe);
+        let next_lps = next_lps | if switch_mps { 0x80 } else { 0 };
+
+        // encode & estimation procedures per sections D.1.4 & D.1.5
+        self.a -= qe;
+        if val != ((sv >> 7) != 0) {
+            // encode the less probable symbol, exchanging the symbols if the
+            // interval for the LPS is larger than the interval for the MPS
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            *st = (sv & 0x80) ^ next_lps;
+        } else {
+            // encode the more probable symbol
+            if self.a >= 0x8000 {
+                return; // no renormalization required
+            }
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            *st = (sv & 0x80) ^ next_mps;
+        }
+
+        // renormalization & data output per section D.1.6
+        loop {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                // another byte is ready for output
+                let temp = self.c >> 19;
+                if temp > 0xFF {
+                    // handle overflow over all stacked 0xFF bytes
+                    self.emit_overflow();
+
+                    // the 3 spacer bits in C guarantee that the new byte can't be 0xFF
+                    self.buffer = (temp & 0xFF) as i32;
+                } else if temp == 0xFF {
+                    // stack 0xFF byte which might overflow later
+                    self.sc += 1;
+                } else {
+                    self.emit_buffer_and_stacked();
+                    self.buffer = temp as i32;
+                }
+
+                self.c &= 0x7FFFF;
+                self.ct += 8;
+            }
+
+            if self.a >= 0x8000 {
+                break;
+            }
+        }
+    }
+
+    /// terminates the encoding per section D.1.8, dropping any trailing zero bytes
+    pub fn finish(&mut self) {
+        // find the C in the coding interval with the largest number of trailing zero bits
+        let temp = (self.a - 1 + self.c) & 0xFFFF0000;
+        if temp < self.c {
+            self.c = temp + 0x8000;
+        } else {
+            self.c = temp;
+        }
+
+        // send remaining bytes to output
+        self.c <<= self.ct;
+        if self.c & 0xF8000000 != 0 {
+            // one final overflow has to be handled
+            self.emit_overflow();
+        } else {
+            self.emit_buffer_and_stacked();
+        }
+
+        // output final bytes only if they are not 0x00
+        if self.c & 0x7FFF800 != 0 {
+            self.emit_pending_zeros();
+
+            let b = ((self.c >> 19) & 0xFF) as u8;
+            self.emit_byte(b);
+            if b == 0xFF {
+                self.emit_byte(0);
+            }
+
+            if self.c & 0x7F800 != 0 {
+                let b = ((self.c >> 11) & 0xFF) as u8;
+                self.emit_byte(b);
+                if b == 0xFF {
+                    self.emit_byte(0);
+                }
+            }
+        }
+
+        self.c = 0;
+        self.a = 0x10000;
+        self.sc = 0;
+        self.zc = 0;
+        self.ct = 11;
+        self.buffer = -1;
+    }
+
+    /// terminates the current restart interval and writes the restart marker
+    pub fn emit_restart(&mut self, restart_num: u8) {
+        self.finish();
+
+        self.emit_byte(0xFF);
+        self.emit_byte(jpeg_code::RST0 + (restart_num & 7));
+    }
+}
@@ -0,0 +1,73 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Upper bound of the number of bytes the bool decoder can consume for a single block.
+///
+/// Every decoded bit shrinks the range by at most a factor of 256, so it consumes less than a
+/// byte of the stream. A block decodes at most 6 bits for the number of non-zeros in the 7x7
+/// block, 22 bits (unary exponent, sign and residual) for each of the 49 coefficients,
+/// 3 + 7 * 22 bits for each of the two edges and 22 bits for the DC, 1420 bits in total.
+pub const MAX_BYTES_PER_BLOCK: usize = 2048;
+
+/// Byte source for `VPXBoolReader` that is filled from an `AsyncRead`.
+///
+/// The bool decoding itself can't wait for data in the middle of a block, so before decoding
+/// a row the caller awaits `vpx_reader_fill` with enough bytes for the whole row, and the
+/// decoder then reads from the buffered bytes synchronously. The decoding math is exactly the
+/// same as for a synchronous reader, only the fetching of the bytes is async.
+pub struct AsyncVPXBoolReader<R> {
+    upstream_reader: R,
+    buffer: VecDeque<u8>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncVPXBoolReader<R> {
+    pub fn new(reader: R) -> Self {
+        AsyncVPXBoolReader {
+            upstream_reader: reader,
+            buffer: VecDeque::new(),
+            eof: false,
+        }
+    }
+
+    /// Reads from the upstream reader until at least `min_bytes` are buffered or the end of
+    /// the stream is reached.
+    pub async fn vpx_reader_fill(&mut self, min_bytes: usize) -> std::io::Result<()> {
+        let mut chunk = [0u8; 4096];
+
+        while self.buffer.len() < min_bytes && !self.eof {
+            let bytes_read = self.upstream_reader.read(&mut chunk).await?;
+            if bytes_read == 0 {
+                self.eof = true;
+                break;
+            }
+
+            self.buffer.extend(&chunk[..bytes_read]);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R> Read for AsyncVPXBoolReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.buffer.is_empty() && !self.eof {
+            // the decoder got ahead of the data that was fetched, treating this as the end of
+            // the stream would silently decode garbage
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                "async reader was not filled far enough ahead",
+            ));
+        }
+
+        self.buffer.read(buf)
+    }
+}
@@ -0,0 +1,93 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::Cursor;
+
+use rayon::prelude::*;
+
+use crate::enabled_features::EnabledFeatures;
+use crate::helpers::err_exit_code;
+use crate::lepton_error::{translate_error, ExitCode, LeptonError};
+
+use super::lepton_decoder::enable_workspace_reuse;
+use super::lepton_format::decode_lepton_wrapper;
+
+/// Decodes many Lepton files on a dedicated pool of threads.
+///
+/// Every decode normally allocates a fresh model and neighbor caches for each of its threads,
+/// which adds up when decoding a stream of small images. The worker threads of the pool keep
+/// these allocations and reset them for the next file instead. The number of threads, and
+/// with it the memory held by the decoder, is fixed when the decoder is created.
+#[allow(dead_code)]
+pub struct BatchDecoder {
+    pool: rayon::ThreadPool,
+    enabled_features: EnabledFeatures,
+}
+
+#[allow(dead_code)]
+impl BatchDecoder {
+    /// Creates a decoder that runs at most `num_threads` threads at the same time
+    pub fn new(
+        num_threads: usize,
+        enabled_features: &EnabledFeatures,
+    ) -> Result<BatchDecoder, LeptonError> {
+        if num_threads == 0 {
+            return err_exit_code(ExitCode::SyntaxError, "num_threads must be at least 1")
+                .map_err(translate_error);
+        }
+
+        let pool = match rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("lepton-batch-{0}", i))
+            .start_handler(|_| enable_workspace_reuse())
+            .build()
+        {
+            Ok(pool) => pool,
+            Err(e) => {
+                return err_exit_code(
+                    ExitCode::GeneralFailure,
+                    &format!("failed to create thread pool {0:?}", e),
+                )
+                .map_err(translate_error)
+            }
+        };
+
+        Ok(BatchDecoder {
+            pool,
+            enabled_features: *enabled_features,
+        })
+    }
+
+    /// Decodes a single Lepton file on the threads of the pool and returns the JPEG
+    pub fn decode(&self, lepton: &[u8]) -> Result<Vec<u8>, LeptonError> {
+        self.pool.install(|| self.decode_in_pool(lepton))
+    }
+
+    /// Decodes several Lepton files at the same time, the results are in the same order as
+    /// the input. A file that fails to decode doesn't affect the others.
+    pub fn decode_batch(&self, files: &[&[u8]]) -> Vec<Result<Vec<u8>, LeptonError>> {
+        self.pool.install(|| {
+            files
+                .par_iter()
+                .map(|lepton| self.decode_in_pool(lepton))
+                .collect()
+        })
+    }
+
+    fn decode_in_pool(&self, lepton: &[u8]) -> Result<Vec<u8>, LeptonError> {
+        let mut output = Vec::new();
+
+        decode_lepton_wrapper(
+            &mut Cursor::new(lepton),
+            &mut output,
+            self.pool.current_num_threads(),
+            &self.enabled_features,
+        )
+        .map_err(translate_error)?;
+
+        Ok(output)
+    }
+}
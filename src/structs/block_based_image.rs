@@ -4,12 +4,23 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use bytemuck::{cast, cast_ref};
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use bytemuck::{cast, cast_mut, cast_ref};
+use core::ops::Range;
 use log::info;
-use wide::i16x8;
+use wide::{i16x8, i32x8};
 
-use crate::consts::ZIGZAG_TO_TRANSPOSED;
+use crate::consts::{X_IDCT_SCALE, ZIGZAG_TO_TRANSPOSED};
+use crate::enabled_features::EnabledFeatures;
+use crate::helpers::err_exit_code;
+use crate::lepton_error::{ExitCode, Result};
 
+use super::component_info::ComponentInfo;
+use super::idct::{run_idct, run_idct_f32};
+use super::quantization_tables::QuantizationTables;
+#[cfg(feature = "std")]
 use super::{block_context::BlockContext, jpeg_header::JPegHeader};
 
 use unroll::unroll_for_loops;
@@ -25,44 +36,269 @@ pub struct BlockBasedImage {
     dpos_offset: i32,
 
     image: Vec<AlignedBlock>,
+
+    /// number of blocks reserved for this image. The buffer may have more capacity if it
+    /// came from a pool, but no more blocks than this are ever stored.
+    max_blocks: usize,
+}
+
+/// Source of the buffers that hold the blocks of an image. Every image allocates a buffer for
+/// all of its blocks, so a pool that keeps the buffers of finished images and hands them out
+/// again takes the allocator out of the loop when decoding one file after another.
+///
+/// The pool is shared by the threads of a decode, so it has to do its own locking.
+pub trait BlockBufferPool {
+    /// returns a buffer with room for at least `capacity` blocks. The buffer is cleared before
+    /// it is used, and grown if it is too small.
+    fn take(&self, capacity: usize) -> Vec<AlignedBlock>;
+
+    /// gives back the buffer of an image that is no longer needed
+    fn recycle(&self, buffer: Vec<AlignedBlock>);
+}
+
+/// allocates a new buffer for every image, used when no pool is given
+struct HeapBlockBufferPool;
+
+impl BlockBufferPool for HeapBlockBufferPool {
+    fn take(&self, capacity: usize) -> Vec<AlignedBlock> {
+        Vec::with_capacity(capacity)
+    }
+
+    fn recycle(&self, _buffer: Vec<AlignedBlock>) {}
+}
+
+impl Clone for BlockBasedImage {
+    /// Clones the image including the capacity reserved for the blocks that were not decoded
+    /// yet, so decoding can continue on the clone exactly as on the original.
+    fn clone(&self) -> Self {
+        let mut image = Vec::with_capacity(self.max_blocks);
+        image.extend(self.image.iter().cloned());
+
+        BlockBasedImage {
+            block_width: self.block_width,
+            original_height: self.original_height,
+            dpos_offset: self.dpos_offset,
+            image,
+            max_blocks: self.max_blocks,
+        }
+    }
 }
 
 static EMPTY: AlignedBlock = AlignedBlock { raw_data: [0; 64] };
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a over the given bytes. This is used instead of the std hasher since the
+/// result has to stay the same across builds and platforms.
+fn fnv1a_update(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
 impl BlockBasedImage {
-    // constructs new block image for the given y-coordinate range
+    // constructs new block image for the given y-coordinate range, checking the size
+    // against the limits in the enabled features before allocating anything.
+    //
+    // Apart from those limits, the blocks of the rows have to fit into the address space:
+    // at 128 bytes per block a Vec holds just under 2^24 blocks (a 4096x4096 block component)
+    // on 32-bit targets, which is less than the default max_blocks_per_component, while 64-bit
+    // targets are only limited by the 65535x65535 pixels a JPEG can have. Anything larger
+    // is rejected with StreamInconsistent rather than overflowing.
+    #[cfg(feature = "std")]
     pub fn new(
         jpeg_header: &JPegHeader,
         component: usize,
         luma_y_start: i32,
         luma_y_end: i32,
-    ) -> Self {
-        let block_width = jpeg_header.cmp_info[component].bch;
-        let original_height = jpeg_header.cmp_info[component].bcv;
-        let max_size = block_width * original_height;
-
-        let image_capcity = usize::try_from(
-            (i64::from(max_size) * i64::from(luma_y_end - luma_y_start)
-                + i64::from(jpeg_header.cmp_info[0].bcv - 1 /* round up */))
-                / i64::from(jpeg_header.cmp_info[0].bcv),
+        enabled_features: &EnabledFeatures,
+    ) -> Result<Self> {
+        Self::new_in(
+            jpeg_header,
+            component,
+            luma_y_start,
+            luma_y_end,
+            enabled_features,
+            &HeapBlockBufferPool,
         )
-        .unwrap();
+    }
 
-        let dpos_offset = i32::try_from(
-            i64::from(max_size) * i64::from(luma_y_start) / i64::from(jpeg_header.cmp_info[0].bcv),
+    // same as new, but takes the buffer for the blocks from the pool
+    #[cfg(feature = "std")]
+    pub fn new_in(
+        jpeg_header: &JPegHeader,
+        component: usize,
+        luma_y_start: i32,
+        luma_y_end: i32,
+        enabled_features: &EnabledFeatures,
+        pool: &dyn BlockBufferPool,
+    ) -> Result<Self> {
+        Self::new_from_components_in(
+            &jpeg_header.cmp_info,
+            jpeg_header.img_width,
+            jpeg_header.img_height,
+            component,
+            luma_y_start..luma_y_end,
+            enabled_features,
+            pool,
         )
-        .unwrap();
+    }
+
+    // same as new, but takes the component geometry directly instead of the JPEG header,
+    // which is only available with std
+    #[allow(dead_code)]
+    pub fn new_from_components(
+        cmp_info: &[ComponentInfo],
+        img_width: i32,
+        img_height: i32,
+        component: usize,
+        luma_y_start: i32,
+        luma_y_end: i32,
+        enabled_features: &EnabledFeatures,
+    ) -> Result<Self> {
+        Self::new_from_components_in(
+            cmp_info,
+            img_width,
+            img_height,
+            component,
+            luma_y_start..luma_y_end,
+            enabled_features,
+            &HeapBlockBufferPool,
+        )
+    }
+
+    fn new_from_components_in(
+        cmp_info: &[ComponentInfo],
+        img_width: i32,
+        img_height: i32,
+        component: usize,
+        luma_rows: Range<i32>,
+        enabled_features: &EnabledFeatures,
+        pool: &dyn BlockBufferPool,
+    ) -> Result<Self> {
+        let block_width = cmp_info[component].bch;
+        let original_height = cmp_info[component].bcv;
+        let max_size = i64::from(block_width) * i64::from(original_height);
+
+        if block_width < 0
+            || original_height < 0
+            || max_size as u64 > enabled_features.max_blocks_per_component
+        {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "too many blocks in image component",
+            );
+        }
+
+        let total_pixels = i64::from(img_width) * i64::from(img_height);
+        if total_pixels < 0 || total_pixels as u64 > enabled_features.max_total_pixels {
+            return err_exit_code(ExitCode::StreamInconsistent, "too many pixels in image");
+        }
+
+        // the block dimensions of every component have to come from the same MCU geometry
+        // as the luma component, otherwise the row ranges calculated below are garbage.
+        // Luma is only used as the unit for the rows, it doesn't need the highest sampling
+        // factors, since the blocks of every component are a multiple of the MCU rows.
+        let luma = &cmp_info[0];
+        let info = &cmp_info[component];
+        if i64::from(info.bch) * i64::from(luma.sfv) != i64::from(luma.bch) * i64::from(info.sfv)
+            || i64::from(info.bcv) * i64::from(luma.sfh)
+                != i64::from(luma.bcv) * i64::from(info.sfh)
+        {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                format!(
+                    "component {0} has {1}x{2} blocks with sampling factors {3}x{4}, which is inconsistent with {5}x{6} luma blocks with sampling factors {7}x{8}",
+                    component, info.bch, info.bcv, info.sfv, info.sfh, luma.bch, luma.bcv, luma.sfv, luma.sfh
+                )
+                .as_str(),
+            );
+        }
+
+        // a component without any blocks (like the one of a zero height image) is represented
+        // by an empty image, which returns EMPTY for every block
+        if max_size == 0 {
+            return Ok(BlockBasedImage {
+                block_width,
+                original_height,
+                image: Vec::new(),
+                dpos_offset: 0,
+                max_blocks: 0,
+            });
+        }
+
+        let Range {
+            start: luma_y_start,
+            end: luma_y_end,
+        } = luma_rows;
+
+        let luma_bcv = i64::from(luma.bcv);
+        if luma_bcv <= 0 || luma_y_start < 0 || luma_y_end < luma_y_start {
+            return err_exit_code(ExitCode::StreamInconsistent, "invalid luma row range");
+        }
 
-        return BlockBasedImage {
+        let Some((image_capcity, dpos_offset)) =
+            Self::block_range(max_size, luma_bcv, luma_y_start, luma_y_end)
+        else {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "image component too large for the address space",
+            );
+        };
+
+        let mut image = pool.take(image_capcity);
+        image.clear();
+        image.reserve(image_capcity);
+
+        return Ok(BlockBasedImage {
             block_width: block_width,
             original_height: original_height,
-            image: Vec::with_capacity(image_capcity),
+            image,
             dpos_offset: dpos_offset,
-        };
+            max_blocks: image_capcity,
+        });
+    }
+
+    // returns the number of blocks of the component in the luma row range and the position of
+    // the first one, or None if they can't be addressed. The limits in the features can be
+    // raised arbitrarily, so none of this can be trusted not to overflow, especially with a
+    // 32-bit usize.
+    fn block_range(
+        max_size: i64,
+        luma_bcv: i64,
+        luma_y_start: i32,
+        luma_y_end: i32,
+    ) -> Option<(usize, i32)> {
+        let capacity = max_size
+            .checked_mul(i64::from(luma_y_end - luma_y_start))?
+            .checked_add(luma_bcv - 1)? // round up
+            / luma_bcv;
+        let capacity = usize::try_from(capacity).ok()?;
+
+        // a Vec can't hold more than isize::MAX bytes
+        if capacity
+            .checked_mul(core::mem::size_of::<AlignedBlock>())
+            .map_or(true, |bytes| bytes > isize::MAX as usize)
+        {
+            return None;
+        }
+
+        let dpos_offset = max_size.checked_mul(i64::from(luma_y_start))? / luma_bcv;
+
+        Some((capacity, i32::try_from(dpos_offset).ok()?))
+    }
+
+    /// gives the buffer of the blocks back to the pool so the next image can use it
+    #[allow(dead_code)]
+    pub fn recycle(self, pool: &dyn BlockBufferPool) {
+        pool.recycle(self.image);
     }
 
     /// merges a bunch of block images generated by different threads into a single one used by progressive decoding
-    pub fn merge(images: &mut Vec<Vec<BlockBasedImage>>, index: usize) -> Self {
+    pub fn merge(images: &mut Vec<Vec<BlockBasedImage>>, index: usize) -> Result<Self> {
         // figure out the total size of all the blocks so we can set the capacity correctly
         let total_size = images.iter().map(|x| x[index].image.len()).sum();
 
@@ -71,35 +307,102 @@ impl BlockBasedImage {
         let mut original_height = None;
 
         for v in images {
-            assert!(
-                v[index].dpos_offset == contents.len() as i32,
-                "previous content should match new content"
-            );
+            if v[index].dpos_offset != contents.len() as i32 {
+                return err_exit_code(
+                    ExitCode::StreamInconsistent,
+                    "previous content should match new content",
+                );
+            }
 
-            if let Some(w) = block_width {
-                assert_eq!(w, v[index].block_width, "all block_width must match")
-            } else {
-                block_width = Some(v[index].block_width);
+            if *block_width.get_or_insert(v[index].block_width) != v[index].block_width {
+                return err_exit_code(ExitCode::StreamInconsistent, "all block_width must match");
             }
 
-            if let Some(w) = original_height {
-                assert_eq!(
-                    w, v[index].original_height,
-                    "all original_height must match"
-                )
-            } else {
-                original_height = Some(v[index].original_height);
+            if *original_height.get_or_insert(v[index].original_height) != v[index].original_height
+            {
+                return err_exit_code(
+                    ExitCode::StreamInconsistent,
+                    "all original_height must match",
+                );
             }
 
             contents.append(&mut v[index].image);
         }
 
-        return BlockBasedImage {
-            block_width: block_width.unwrap(),
-            original_height: original_height.unwrap(),
+        let (Some(block_width), Some(original_height)) = (block_width, original_height) else {
+            return err_exit_code(ExitCode::StreamInconsistent, "no images to merge");
+        };
+
+        return Ok(BlockBasedImage {
+            block_width,
+            original_height,
             image: contents,
             dpos_offset: 0,
+            max_blocks: total_size,
+        });
+    }
+
+    /// Splits the images of a component that were decoded by different threads into bands of
+    /// `band_rows` rows each, the last band holding whatever rows are left. The blocks are moved
+    /// rather than copied, and like the images of the threads every band keeps its position in
+    /// the full image, so merging the bands gives the same image as merging the threads.
+    #[allow(dead_code)]
+    pub fn split_into_bands(
+        images: Vec<BlockBasedImage>,
+        band_rows: i32,
+    ) -> Result<Vec<BlockBasedImage>> {
+        let (Some(block_width), Some(original_height)) = (
+            images.first().map(|x| x.block_width),
+            images.first().map(|x| x.original_height),
+        ) else {
+            return err_exit_code(ExitCode::StreamInconsistent, "no images to split");
         };
+
+        if band_rows <= 0 {
+            return err_exit_code(ExitCode::SyntaxError, "band height must be positive");
+        }
+
+        let band_size = (band_rows * block_width) as usize;
+        let mut contents = Vec::new();
+
+        for mut v in images {
+            if v.dpos_offset != contents.len() as i32 {
+                return err_exit_code(
+                    ExitCode::StreamInconsistent,
+                    "previous content should match new content",
+                );
+            }
+
+            if v.block_width != block_width || v.original_height != original_height {
+                return err_exit_code(
+                    ExitCode::StreamInconsistent,
+                    "all images must have the same size",
+                );
+            }
+
+            contents.append(&mut v.image);
+        }
+
+        let mut blocks = contents.into_iter();
+        let mut bands = Vec::new();
+        for y in (0..original_height).step_by(band_rows as usize) {
+            let start = (y * block_width) as usize;
+            let end = ((y + band_rows).min(original_height) * block_width) as usize;
+
+            // blocks that weren't decoded are left out like in the images of the threads
+            let mut image = Vec::with_capacity(band_size);
+            image.extend(blocks.by_ref().take(end - start));
+
+            bands.push(BlockBasedImage {
+                block_width,
+                original_height,
+                image,
+                dpos_offset: start as i32,
+                max_blocks: end - start,
+            });
+        }
+
+        Ok(bands)
     }
 
     #[allow(dead_code)]
@@ -107,12 +410,13 @@ impl BlockBasedImage {
         info!(
             "size = {0}, capacity = {1}, dpos_offset = {2}",
             self.image.len(),
-            self.image.capacity(),
+            self.max_blocks,
             self.dpos_offset
         );
     }
 
     // blocks above the first line are never dereferenced
+    #[cfg(feature = "std")]
     pub fn off_y(&self, y: i32) -> BlockContext {
         return BlockContext::new(
             self.block_width * y,
@@ -122,6 +426,18 @@ impl BlockBasedImage {
         );
     }
 
+    /// same as `off_y`, but checks that `y` is a row of the image, for rows that come from
+    /// outside of the coding loop
+    #[cfg(feature = "std")]
+    #[allow(dead_code)]
+    pub fn try_off_y(&self, y: i32) -> Result<BlockContext> {
+        if y < 0 || y >= self.original_height {
+            return err_exit_code(ExitCode::StreamInconsistent, "row is outside of the image");
+        }
+
+        Ok(self.off_y(y))
+    }
+
     pub fn get_block_width(&self) -> i32 {
         self.block_width
     }
@@ -130,25 +446,61 @@ impl BlockBasedImage {
         self.original_height
     }
 
-    fn fill_up_to_dpos(&mut self, dpos: i32) {
+    /// number of blocks stored so far, starting at the dpos offset
+    pub fn get_stored_block_count(&self) -> usize {
+        self.image.len()
+    }
+
+    // None of the block accessors below panic, whatever the stream says, since they are
+    // reachable with untrusted input. Positions outside of the range reserved for this image
+    // are reported as errors instead.
+    fn fill_up_to_dpos(&mut self, dpos: i32) -> Result<usize> {
         // set our dpos the first time we get set, since we should be seeing our data in order
-        if self.image.len() == 0 {
-            assert!(self.dpos_offset == dpos);
+        if self.image.len() == 0 && self.dpos_offset != dpos {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "first block is not at the start of the image",
+            );
+        }
+
+        if dpos < self.dpos_offset {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "block is before the start of the image",
+            );
         }
 
-        assert!(dpos >= self.dpos_offset);
+        let index = (dpos - self.dpos_offset) as usize;
+        if index >= self.max_blocks {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "block is beyond the end of the image",
+            );
+        }
 
-        while self.image.len() <= (dpos - self.dpos_offset) as usize {
-            if self.image.len() >= self.image.capacity() {
-                panic!("out of memory");
-            }
-            self.image.push(AlignedBlock { raw_data: [0; 64] });
+        if self.image.len() <= index {
+            self.image.resize(index + 1, AlignedBlock::default());
         }
+
+        Ok(index)
     }
 
-    pub fn set_block_data(&mut self, dpos: i32, block_data: &AlignedBlock) {
-        self.fill_up_to_dpos(dpos);
-        *self.image[(dpos - self.dpos_offset) as usize].get_block_mut() = *block_data.get_block();
+    pub fn set_block_data(&mut self, dpos: i32, block_data: &AlignedBlock) -> Result<()> {
+        let index = self.fill_up_to_dpos(dpos)?;
+        *self.image[index].get_block_mut() = *block_data.get_block();
+        Ok(())
+    }
+
+    /// Same as `set_block_data`, but takes the coefficients in the zigzag order they are stored
+    /// in the JPEG file, as most JPEG parsers produce them.
+    #[allow(dead_code)]
+    pub fn set_block_zigzag(&mut self, dpos: i32, zigzag: &[i16; 64]) -> Result<()> {
+        let index = self.fill_up_to_dpos(dpos)?;
+        let block = &mut self.image[index];
+        for (i, &v) in zigzag.iter().enumerate() {
+            block.set_transposed_from_zigzag(i, v);
+        }
+        Ok(())
     }
 
     pub fn get_block(&self, dpos: i32) -> &AlignedBlock {
@@ -160,27 +512,259 @@ impl BlockBasedImage {
     }
 
     #[inline(always)]
-    pub fn append_block(&mut self, block: AlignedBlock) {
-        assert!(
-            self.image.len() < self.image.capacity(),
-            "capacity should be set correctly"
-        );
+    pub fn append_block(&mut self, block: AlignedBlock) -> Result<()> {
+        if self.image.len() >= self.max_blocks {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "block is beyond the end of the image",
+            );
+        }
         self.image.push(block);
+        Ok(())
+    }
+
+    pub fn get_block_mut(&mut self, dpos: i32) -> Result<&mut AlignedBlock> {
+        let index = self.fill_up_to_dpos(dpos)?;
+        Ok(&mut self.image[index])
+    }
+
+    /// Copies the `w` x `h` blocks starting at block column `x` and block row `y` into a
+    /// new image of that size. Blocks that were not decoded come out as zero.
+    #[allow(dead_code)]
+    pub fn crop(&self, x: i32, y: i32, w: i32, h: i32) -> Result<BlockBasedImage> {
+        if x < 0 || y < 0 || w < 0 || h < 0 {
+            return err_exit_code(ExitCode::SyntaxError, "crop region must not be negative");
+        }
+        if x > self.block_width - w || y > self.original_height - h {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                "crop region must be inside the image",
+            );
+        }
+
+        let mut image = Vec::with_capacity((w * h) as usize);
+        for by in y..y + h {
+            for bx in x..x + w {
+                let block = self.get_block(by * self.block_width + bx);
+                image.push(AlignedBlock::new(*block.get_block()));
+            }
+        }
+
+        Ok(BlockBasedImage {
+            block_width: w,
+            original_height: h,
+            dpos_offset: 0,
+            image,
+            max_blocks: (w * h) as usize,
+        })
+    }
+
+    /// stable hash of all the coefficients stored in this image in storage order,
+    /// so it can be used to verify that decoding gives the same result across versions
+    pub fn content_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for block in &self.image {
+            for c in block.raw_data {
+                hash = fnv1a_update(hash, &c.to_le_bytes());
+            }
+        }
+        hash
+    }
+
+    /// Returns the coefficients of every block of the component in zigzag order, which is the
+    /// layout libjpeg expects in `jpeg_write_coefficients`. The blocks are in raster order,
+    /// `get_block_width()` blocks per row for `get_original_height()` rows, and blocks that
+    /// were not decoded are all zero.
+    #[allow(dead_code)]
+    pub fn export_zigzag_blocks(&self) -> Vec<[i16; 64]> {
+        (0..self.block_width * self.original_height)
+            .map(|dpos| *self.get_block(dpos).zigzag_from_transposed().get_block())
+            .collect()
     }
 
-    pub fn get_block_mut(&mut self, dpos: i32) -> &mut AlignedBlock {
-        self.fill_up_to_dpos(dpos);
-        return &mut self.image[(dpos - self.dpos_offset) as usize];
+    /// Reconstructs the 8-bit samples of the component by dequantizing each block and
+    /// running the IDCT. The plane is `get_block_width() * 8` samples wide and
+    /// `get_original_height() * 8` samples high, so it includes the padding up to whole
+    /// blocks and has to be cropped to the size of the component. Blocks that were not
+    /// decoded come out as flat gray.
+    #[allow(dead_code)]
+    pub fn to_pixels(&self, qt: &QuantizationTables) -> Vec<u8> {
+        let stride = (self.block_width * 8) as usize;
+        let mut pixels = vec![0u8; stride * (self.original_height * 8) as usize];
+
+        if stride > 0 {
+            for (by, row) in pixels.chunks_exact_mut(stride * 8).enumerate() {
+                self.block_row_to_pixels(qt, by as i32, row);
+            }
+        }
+
+        pixels
+    }
+
+    /// Same as `to_pixels`, but reconstructs the rows of blocks in parallel on the rayon
+    /// thread pool. Each row of blocks writes its own slice of the output, so the result is
+    /// identical to `to_pixels`.
+    #[cfg(feature = "std")]
+    #[allow(dead_code)]
+    pub fn to_pixels_parallel(&self, qt: &QuantizationTables) -> Vec<u8> {
+        use rayon::prelude::*;
+
+        let stride = (self.block_width * 8) as usize;
+        let mut pixels = vec![0u8; stride * (self.original_height * 8) as usize];
+
+        if stride > 0 {
+            pixels
+                .par_chunks_exact_mut(stride * 8)
+                .enumerate()
+                .for_each(|(by, row)| self.block_row_to_pixels(qt, by as i32, row));
+        }
+
+        pixels
+    }
+
+    /// reconstructs the 8 rows of samples of the row of blocks `by` into `pixels`
+    fn block_row_to_pixels(&self, qt: &QuantizationTables, by: i32, pixels: &mut [u8]) {
+        let stride = (self.block_width * 8) as usize;
+
+        for bx in 0..self.block_width {
+            let block = self.get_block(by * self.block_width + bx);
+
+            let mut raster = [i32x8::ZERO; 8];
+            let raster_col: &mut [i32; 64] = cast_mut(&mut raster);
+            for (i, r) in raster_col.iter_mut().enumerate() {
+                *r = i32::from(block.get_coefficient(i))
+                    * i32::from(qt.get_quantization_table_transposed()[i]);
+            }
+
+            // the IDCT output is scaled by X_IDCT_SCALE and centered around zero
+            let idct = run_idct(&raster);
+            for y in 0..8 {
+                let row = y * stride + bx as usize * 8;
+                for x in 0..8 {
+                    let v = i32::from(idct.get_coefficient(y * 8 + x));
+                    let sample = (v + X_IDCT_SCALE / 2).div_euclid(X_IDCT_SCALE) + 128;
+                    pixels[row + x] = sample.clamp(0, 255) as u8;
+                }
+            }
+        }
+    }
+
+    /// Like `to_pixels`, but runs a floating point IDCT and returns the samples level
+    /// shifted by 128 without rounding or clamping them to 0..=255. Useful for processing
+    /// the image further without quantizing the samples to 8 bits first.
+    #[allow(dead_code)]
+    pub fn to_pixels_f32(&self, qt: &QuantizationTables) -> Vec<f32> {
+        let stride = (self.block_width * 8) as usize;
+        let mut pixels = vec![0f32; stride * (self.original_height * 8) as usize];
+
+        for by in 0..self.original_height {
+            for bx in 0..self.block_width {
+                let block = self.get_block(by * self.block_width + bx);
+
+                let mut dequantized = [0f32; 64];
+                for (i, d) in dequantized.iter_mut().enumerate() {
+                    *d = f32::from(block.get_coefficient(i))
+                        * f32::from(qt.get_quantization_table_transposed()[i]);
+                }
+
+                let idct = run_idct_f32(&dequantized);
+                for y in 0..8 {
+                    let row = (by as usize * 8 + y) * stride + bx as usize * 8;
+                    for x in 0..8 {
+                        pixels[row + x] = idct[y * 8 + x] + 128.0;
+                    }
+                }
+            }
+        }
+
+        pixels
+    }
+
+    /// Lossy transcoding of the component to a different quantization: every coefficient
+    /// is dequantized with `from` and quantized again with `to`, rounding to the nearest
+    /// value. The result is clamped to the 11 bit range the JPEG entropy coding can hold.
+    /// Writing a JPEG from the result also needs the new table in the header, and it will
+    /// of course not round-trip to the original file.
+    #[allow(dead_code)]
+    pub fn requantize(
+        &self,
+        from: &QuantizationTables,
+        to: &QuantizationTables,
+    ) -> Result<BlockBasedImage> {
+        let from_table = from.get_quantization_table_transposed();
+        let to_table = to.get_quantization_table_transposed();
+
+        if to_table.contains(&0) {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                "target quantization table contains zeros",
+            );
+        }
+
+        let mut result = self.clone();
+        for block in result.image.iter_mut() {
+            for (i, c) in block.raw_data.iter_mut().enumerate() {
+                let value = i32::from(*c) * i32::from(from_table[i]);
+                let q = i32::from(to_table[i]);
+
+                // round half away from zero
+                let requantized = (value.abs() + q / 2) / q * value.signum();
+                *c = requantized.clamp(-2047, 2047) as i16;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Lossy cleanup of the component: zeros every AC coefficient whose magnitude is below
+    /// `threshold`, leaving the DC alone. Returns the number of coefficients that were zeroed.
+    pub fn zero_small_ac(&mut self, threshold: i16) -> usize {
+        let mut zeroed = 0;
+        for block in self.image.iter_mut() {
+            // the DC is the first coefficient in both the zigzag and the transposed order
+            for c in block.raw_data[1..].iter_mut() {
+                if *c != 0 && c.unsigned_abs() < threshold.unsigned_abs() {
+                    *c = 0;
+                    zeroed += 1;
+                }
+            }
+        }
+        zeroed
+    }
+
+    /// combines the content hashes of all the components of an image
+    pub fn combined_content_hash(images: &[BlockBasedImage]) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for image in images {
+            hash = fnv1a_update(hash, &image.content_hash().to_le_bytes());
+        }
+        hash
     }
 }
 
 /// block of 64 coefficients in the aligned order, which is similar to zigzag except that the 7x7 lower right square comes first,
 /// followed by the DC, followed by the edges
+#[derive(Clone, Copy, PartialEq, Eq, bytemuck::Zeroable, bytemuck::Pod)]
 #[repr(C, align(32))]
 pub struct AlignedBlock {
     raw_data: [i16; 64],
 }
 
+/// prints the coefficients as an 8x8 grid in the stored (transposed) order, one row per line
+impl core::fmt::Debug for AlignedBlock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "AlignedBlock [")?;
+        for row in self.raw_data.chunks_exact(8) {
+            write!(f, "   ")?;
+            for c in row {
+                write!(f, " {0:5}", c)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "]")
+    }
+}
+
 pub static EMPTY_BLOCK: AlignedBlock = AlignedBlock { raw_data: [0; 64] };
 
 impl Default for AlignedBlock {
@@ -194,6 +778,20 @@ impl AlignedBlock {
         AlignedBlock { raw_data: block }
     }
 
+    /// Views the coefficients of an external buffer as a block without copying them. The layout
+    /// is the same, so this only checks that the buffer is aligned to 32 bytes like a block.
+    ///
+    /// # Panics
+    ///
+    /// If `block` is not aligned to 32 bytes.
+    #[allow(dead_code)]
+    pub fn from_ref(block: &[i16; 64]) -> &AlignedBlock {
+        match bytemuck::try_cast_ref(block) {
+            Ok(b) => b,
+            Err(e) => panic!("coefficients are not aligned like a block: {0:?}", e),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn as_i16x8(&self, index: usize) -> i16x8 {
         let v: &[i16x8; 8] = cast_ref(&self.raw_data);
@@ -213,7 +811,12 @@ impl AlignedBlock {
         self.raw_data[0] = value
     }
 
-    /// gets underlying array of 64 coefficients (guaranteed to be 32-byte aligned)
+    /// returns a copy of the block with the coefficients in zigzag order
+    ///
+    /// This stays a scalar gather: the zigzag order isn't a fixed lane pattern like the
+    /// transpose, and `wide` has no arbitrary lane shuffle, so a vectorized version would need
+    /// a byte shuffle per target feature (pshufb, tbl) and a scalar fallback anyway. Unrolled
+    /// with the constant indices this is just 64 loads and stores.
     #[unroll_for_loops]
     pub fn zigzag_from_transposed(&self) -> AlignedBlock {
         let mut block = AlignedBlock::default();
@@ -272,7 +875,15 @@ impl AlignedBlock {
         return self.raw_data[usize::from(ZIGZAG_TO_TRANSPOSED[index])];
     }
 
+    /// Gathers the 8 coefficients at `offset + i * stride`, which must all lie inside the block.
     pub fn from_stride(&self, offset: usize, stride: usize) -> i16x8 {
+        debug_assert!(
+            offset + 7 * stride < 64,
+            "from_stride offset {0} stride {1} reads past the end of the block",
+            offset,
+            stride
+        );
+
         return i16x8::new([
             self.raw_data[offset],
             self.raw_data[offset + (1 * stride)],
@@ -285,3 +896,658 @@ impl AlignedBlock {
         ]);
     }
 }
+
+// a header claiming billions of blocks should be rejected before anything is allocated
+#[test]
+fn reject_huge_image() {
+    use crate::lepton_error::LeptonError;
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 65535;
+    jpeg_header.img_height = 65535;
+    jpeg_header.cmp_info[0].bch = 1 << 16;
+    jpeg_header.cmp_info[0].bcv = 1 << 16;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+
+    let r = BlockBasedImage::new(&jpeg_header, 0, 0, 1 << 16, &features);
+    let e = r.err().unwrap();
+    assert_eq!(
+        e.root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code,
+        ExitCode::StreamInconsistent
+    );
+
+    // a reasonably sized image is fine
+    jpeg_header.img_width = 1024;
+    jpeg_header.img_height = 1024;
+    jpeg_header.cmp_info[0].bch = 128;
+    jpeg_header.cmp_info[0].bcv = 128;
+
+    BlockBasedImage::new(&jpeg_header, 0, 0, 128, &features).unwrap();
+}
+
+// dimensions beyond what the limits would normally allow have to be rejected cleanly when the
+// limits are lifted, instead of overflowing the calculation of the capacity and offset or
+// panicking on a capacity that doesn't fit into the address space
+#[test]
+fn reject_image_beyond_address_space() {
+    use crate::lepton_error::LeptonError;
+
+    let features = EnabledFeatures {
+        max_blocks_per_component: u64::MAX,
+        max_total_pixels: u64::MAX,
+        ..EnabledFeatures::compat_lepton_vector_read()
+    };
+
+    let exit_code = |r: Result<BlockBasedImage>| {
+        r.err()
+            .unwrap()
+            .root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code
+    };
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 65535;
+    jpeg_header.img_height = 65535;
+    jpeg_header.cmp_info[0].bch = i32::MAX;
+    jpeg_header.cmp_info[0].bcv = i32::MAX;
+
+    // both the capacity and the offset of the last rows overflow an i64
+    for (start, end) in [(0, i32::MAX), (i32::MAX - 1, i32::MAX)] {
+        assert_eq!(
+            exit_code(BlockBasedImage::new(&jpeg_header, 0, start, end, &features)),
+            ExitCode::StreamInconsistent
+        );
+    }
+
+    // negative block counts whose product is positive
+    jpeg_header.cmp_info[0].bch = -2;
+    jpeg_header.cmp_info[0].bcv = -2;
+    assert_eq!(
+        exit_code(BlockBasedImage::new(&jpeg_header, 0, 0, 1, &features)),
+        ExitCode::StreamInconsistent
+    );
+
+    // the largest component a JPEG can have only fits into a 64-bit address space, while
+    // one block less than 4096x4096 fits either way. Checked without allocating anything.
+    let largest = BlockBasedImage::block_range(8192 * 8192, 8192, 4096, 8192);
+    if cfg!(target_pointer_width = "32") {
+        assert_eq!(largest, None);
+    } else {
+        assert_eq!(largest, Some((4096 * 8192, 4096 * 8192)));
+    }
+
+    assert_eq!(
+        BlockBasedImage::block_range(4096 * 4096 - 1, 4095, 0, 4095),
+        Some((4096 * 4096 - 1, 0))
+    );
+}
+
+// chroma block dimensions that don't match the luma sampling geometry should be rejected
+// with an error naming the component
+#[test]
+fn reject_inconsistent_component_geometry() {
+    use crate::lepton_error::LeptonError;
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 3;
+    jpeg_header.img_width = 64;
+    jpeg_header.img_height = 64;
+
+    // 4:2:0, luma has 2x2 sampling and twice the blocks in each direction
+    for (cmp, sf, blocks) in [(0, 2, 8), (1, 1, 4), (2, 1, 4)] {
+        jpeg_header.cmp_info[cmp].sfv = sf;
+        jpeg_header.cmp_info[cmp].sfh = sf;
+        jpeg_header.cmp_info[cmp].bch = blocks;
+        jpeg_header.cmp_info[cmp].bcv = blocks;
+    }
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    for cmp in 0..3 {
+        BlockBasedImage::new(&jpeg_header, cmp, 0, 8, &features).unwrap();
+    }
+
+    // Cr claims as many blocks as luma even though it is subsampled
+    jpeg_header.cmp_info[2].bcv = 8;
+
+    let e = BlockBasedImage::new(&jpeg_header, 2, 0, 8, &features)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code,
+        ExitCode::StreamInconsistent
+    );
+    assert!(e.root_cause().to_string().contains("component 2"));
+
+    BlockBasedImage::new(&jpeg_header, 1, 0, 8, &features).unwrap();
+}
+
+// the reconstructed samples should match a straightforward floating point IDCT
+// of the coefficients of a generated image
+#[test]
+fn to_pixels_matches_reference() {
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::f64::consts::PI;
+    use std::io::Cursor;
+
+    let file = synthetic_jpeg(&[0x11], 96, 64);
+    let (lh, image_data) = read_jpeg(
+        &mut Cursor::new(file),
+        &EnabledFeatures::compat_lepton_vector_write(),
+        1,
+        |_| {},
+    )
+    .unwrap();
+
+    let qt = QuantizationTables::new(&lh.jpeg_header, 0);
+    let image = &image_data[0];
+    let pixels = image.to_pixels(&qt);
+
+    let stride = (image.get_block_width() * 8) as usize;
+    assert_eq!(
+        pixels.len(),
+        stride * (image.get_original_height() * 8) as usize
+    );
+
+    let c = |u: usize| if u == 0 { 1.0 / 2f64.sqrt() } else { 1.0 };
+
+    let mut max_diff = 0;
+    for (bx, by) in [(0, 0), (10, 3), (5, 6), (11, 7)] {
+        let block = image.get_block(by * image.get_block_width() + bx);
+        for y in 0..8 {
+            for x in 0..8 {
+                // coefficients are stored transposed, with the horizontal frequency first
+                let mut sum = 0.0;
+                for i in 0..64 {
+                    let (u, v) = (i >> 3, i & 7);
+                    sum += c(u)
+                        * c(v)
+                        * f64::from(block.get_coefficient(i))
+                        * f64::from(qt.get_quantization_table_transposed()[i])
+                        * ((2 * x + 1) as f64 * u as f64 * PI / 16.0).cos()
+                        * ((2 * y + 1) as f64 * v as f64 * PI / 16.0).cos();
+                }
+                let expected = (sum / 4.0 + 128.0).round().clamp(0.0, 255.0) as i32;
+
+                let actual = pixels[(by as usize * 8 + y) * stride + bx as usize * 8 + x];
+                max_diff = max_diff.max((i32::from(actual) - expected).abs());
+            }
+        }
+    }
+
+    assert!(max_diff <= 1, "max difference {0}", max_diff);
+}
+
+// reconstructing the rows of blocks in parallel gives the same samples as doing it serially
+#[test]
+fn to_pixels_parallel_matches_serial() {
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::io::Cursor;
+
+    let file = synthetic_jpeg(&[0x22, 0x11, 0x11], 120, 136);
+    let (lh, image_data) = read_jpeg(
+        &mut Cursor::new(file),
+        &EnabledFeatures::compat_lepton_vector_write(),
+        1,
+        |_| {},
+    )
+    .unwrap();
+
+    for (cmp, image) in image_data.iter().enumerate() {
+        let qt = QuantizationTables::new(&lh.jpeg_header, cmp);
+        assert_eq!(image.to_pixels_parallel(&qt), image.to_pixels(&qt));
+    }
+}
+
+// the floating point samples should match the 8-bit samples wherever those
+// aren't clamped, and the clamped ones should be at or beyond the range
+#[test]
+fn to_pixels_f32_matches_to_pixels() {
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::io::Cursor;
+
+    let file = synthetic_jpeg(&[0x22, 0x11, 0x11], 120, 136);
+    let (lh, mut image_data) = read_jpeg(
+        &mut Cursor::new(file),
+        &EnabledFeatures::compat_lepton_vector_write(),
+        1,
+        |_| {},
+    )
+    .unwrap();
+
+    // the DC of the generated image stays close to mid gray, so push two blocks out of range
+    image_data[0].get_block_mut(0).unwrap().set_dc(1200);
+    image_data[0].get_block_mut(1).unwrap().set_dc(-1200);
+
+    let qt = QuantizationTables::new(&lh.jpeg_header, 0);
+    let image = &image_data[0];
+    let pixels = image.to_pixels(&qt);
+    let pixels_f32 = image.to_pixels_f32(&qt);
+
+    assert_eq!(pixels.len(), pixels_f32.len());
+
+    let mut mid_range = 0;
+    for (&p, &f) in pixels.iter().zip(pixels_f32.iter()) {
+        match p {
+            // the fixed point IDCT can be off by one
+            0 => assert!(f < 1.5, "{0} clamped to 0", f),
+            255 => assert!(f > 253.5, "{0} clamped to 255", f),
+            _ => {
+                assert!((f32::from(p) - f).abs() <= 1.5, "{0} vs {1}", p, f);
+                mid_range += 1;
+            }
+        }
+    }
+
+    assert!(mid_range > pixels.len() / 2);
+
+    // the image has some samples that are clamped
+    assert!(pixels_f32.iter().any(|&f| !(0.0..=255.0).contains(&f)));
+}
+
+// a clone should return the same blocks as the original and keep its capacity, and
+// appending to the clone must not change the original
+#[test]
+fn clone_is_independent() {
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 64;
+    jpeg_header.img_height = 64;
+    jpeg_header.cmp_info[0].bch = 8;
+    jpeg_header.cmp_info[0].bcv = 8;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 2, 6, &features).unwrap();
+
+    // fill the first half of the range, starting at the offset of the first row
+    for dpos in 16..32 {
+        let mut block = AlignedBlock::default();
+        block.set_coefficient((dpos % 64) as usize, dpos as i16);
+        block.set_dc(-dpos as i16);
+        image.append_block(block).unwrap();
+    }
+
+    let mut clone = image.clone();
+    assert_eq!(clone.image.capacity(), image.image.capacity());
+    assert_eq!(clone.get_block_width(), image.get_block_width());
+    assert_eq!(clone.get_original_height(), image.get_original_height());
+
+    for dpos in 0..64 {
+        assert_eq!(
+            clone.get_block(dpos).get_block(),
+            image.get_block(dpos).get_block()
+        );
+    }
+
+    let (a, b) = (clone.off_y(3), image.off_y(3));
+    assert_eq!(a.get_here_index(), b.get_here_index());
+    assert_eq!(a.here(&clone).get_block(), b.here(&image).get_block());
+
+    // the clone can be filled up to the full capacity without touching the original
+    let mut block = AlignedBlock::default();
+    block.set_dc(1000);
+    for _ in 32..48 {
+        clone.append_block(block.clone()).unwrap();
+    }
+    assert_eq!(clone.get_block(40).get_dc(), 1000);
+    assert_eq!(image.get_block(40).get_dc(), 0);
+    assert_eq!(image.image.len(), 16);
+
+    clone.get_block_mut(20).unwrap().set_dc(7);
+    assert_eq!(image.get_block(20).get_dc(), -20);
+}
+
+// positions outside of the reserved range come from corrupt input, so they have to be
+// reported as errors rather than panicking
+#[test]
+fn out_of_range_blocks_are_errors() {
+    use crate::lepton_error::LeptonError;
+
+    let exit_code = |e: anyhow::Error| {
+        e.root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code
+    };
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 32;
+    jpeg_header.img_height = 32;
+    jpeg_header.cmp_info[0].bch = 4;
+    jpeg_header.cmp_info[0].bcv = 4;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 1, 3, &features).unwrap();
+    let capacity = image.image.capacity() as i32;
+
+    // the first block has to be at the start of the range
+    let e = image.get_block_mut(5).err().unwrap();
+    assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+
+    image.set_block_data(4, &AlignedBlock::default()).unwrap();
+    image.get_block_mut(4 + capacity - 1).unwrap().set_dc(1);
+
+    let e = image.get_block_mut(4 + capacity).err().unwrap();
+    assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+    let e = image
+        .set_block_data(3, &AlignedBlock::default())
+        .err()
+        .unwrap();
+    assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+    let e = image.append_block(AlignedBlock::default()).err().unwrap();
+    assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+
+    // rows outside of the image
+    assert_eq!(image.try_off_y(3).unwrap().get_here_index(), 12);
+    for y in [-1, 4, i32::MAX, i32::MIN] {
+        let e = image.try_off_y(y).err().unwrap();
+        assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+    }
+
+    // merging images that don't line up
+    let other = BlockBasedImage::new(&jpeg_header, 0, 0, 1, &features).unwrap();
+    let e = BlockBasedImage::merge(&mut vec![vec![image], vec![other]], 0)
+        .err()
+        .unwrap();
+    assert_eq!(exit_code(e), ExitCode::StreamInconsistent);
+}
+
+// requantizing to the same table leaves the blocks unchanged, and doubling the step halves
+// the coefficients with the rounding going away from zero
+#[test]
+fn requantize_blocks() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([9u8; 32]);
+
+    let mut table = [0u16; 64];
+    rng.fill(&mut table[..]);
+    for t in table.iter_mut() {
+        *t = *t % 50 + 1;
+    }
+    let qt = QuantizationTables::new_from_table(&table);
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 16;
+    jpeg_header.img_height = 24;
+    jpeg_header.cmp_info[0].bch = 2;
+    jpeg_header.cmp_info[0].bcv = 3;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 0, 3, &features).unwrap();
+    for dpos in 0..6 {
+        let mut block = [0i16; 64];
+        for c in block.iter_mut() {
+            *c = rng.gen_range(-40..=40);
+        }
+        image
+            .set_block_data(dpos, &AlignedBlock::new(block))
+            .unwrap();
+    }
+
+    let same = image.requantize(&qt, &qt).unwrap();
+    assert_eq!(same.content_hash(), image.content_hash());
+
+    let fine = QuantizationTables::new_from_table(&[2; 64]);
+    let coarse = QuantizationTables::new_from_table(&[4; 64]);
+    let halved = image.requantize(&fine, &coarse).unwrap();
+    for dpos in 0..6 {
+        for i in 0..64 {
+            let c = i32::from(image.get_block(dpos).get_coefficient(i));
+            assert_eq!(
+                i32::from(halved.get_block(dpos).get_coefficient(i)),
+                (c.abs() + 1) / 2 * c.signum()
+            );
+        }
+    }
+
+    // a table with zeros can't be quantized to
+    let mut zeros = table;
+    zeros[5] = 0;
+    assert!(image
+        .requantize(&qt, &QuantizationTables::new_from_table(&zeros))
+        .is_err());
+}
+
+// the rows and columns gathered by the edge prediction and neighbor summaries all stay inside
+// the block, including the last row and column
+#[test]
+fn from_stride_valid_offsets() {
+    let mut block = AlignedBlock::default();
+    for i in 0..64 {
+        block.set_coefficient(i, i as i16);
+    }
+
+    for (offset, stride) in [
+        (0, 8),
+        (1, 8),
+        (6, 8),
+        (7, 8),
+        (0, 1),
+        (8, 1),
+        (48, 1),
+        (56, 1),
+    ] {
+        let expected: [i16; 8] = core::array::from_fn(|i| (offset + i * stride) as i16);
+        assert_eq!(block.from_stride(offset, stride).to_array(), expected);
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "reads past the end of the block")]
+fn from_stride_rejects_out_of_range() {
+    // the last element would be 57 + 7, one past the end
+    AlignedBlock::default().from_stride(57, 1);
+}
+
+// blocks compare by their coefficients and print as a grid so that failed assertions are readable
+#[test]
+fn aligned_block_eq_and_debug() {
+    let block = AlignedBlock::new(core::array::from_fn(|i| i as i16 - 8));
+
+    assert_eq!(
+        block,
+        AlignedBlock::new(core::array::from_fn(|i| i as i16 - 8))
+    );
+    assert_ne!(block, AlignedBlock::default());
+
+    assert_eq!(
+        format!("{0:?}", block),
+        "AlignedBlock [
+       -8    -7    -6    -5    -4    -3    -2    -1
+        0     1     2     3     4     5     6     7
+        8     9    10    11    12    13    14    15
+       16    17    18    19    20    21    22    23
+       24    25    26    27    28    29    30    31
+       32    33    34    35    36    37    38    39
+       40    41    42    43    44    45    46    47
+       48    49    50    51    52    53    54    55
+]"
+    );
+}
+
+// the view shares the memory of the buffer, so it sees every change made to the buffer
+#[test]
+fn from_ref_views_the_buffer() {
+    #[repr(C, align(32))]
+    struct Buffer([i16; 64]);
+
+    let mut buffer = Buffer([0; 64]);
+    buffer.0[5] = 7;
+
+    let view = AlignedBlock::from_ref(&buffer.0);
+    assert!(core::ptr::eq(view.get_block(), &buffer.0));
+    assert_eq!(view.get_coefficient(5), 7);
+
+    buffer.0[5] = -3;
+    buffer.0[63] = 100;
+
+    let view = AlignedBlock::from_ref(&buffer.0);
+    assert_eq!(view.get_coefficient(5), -3);
+    assert_eq!(view.get_coefficient(63), 100);
+}
+
+#[test]
+#[should_panic(expected = "coefficients are not aligned like a block")]
+fn from_ref_rejects_unaligned_buffer() {
+    #[repr(C, align(32))]
+    struct Buffer([i16; 65]);
+
+    let buffer = Buffer([0; 65]);
+
+    // one coefficient into a 32 byte aligned buffer is only 2 byte aligned
+    AlignedBlock::from_ref(buffer.0[1..].try_into().unwrap());
+}
+
+// the casts between the SIMD vectors and the flat coefficient arrays only reinterpret lanes of
+// the same width, so the coefficient order doesn't depend on the byte order of the host. The
+// big endian job in the CI runs this on s390x.
+#[test]
+fn casts_are_independent_of_byte_order() {
+    let block = AlignedBlock::new(core::array::from_fn(|i| (i as i16 - 32) * 257));
+
+    let rows: &[i16x8; 8] = cast_ref(block.get_block());
+    for (r, row) in rows.iter().enumerate() {
+        assert_eq!(row.to_array(), block.get_block()[r * 8..r * 8 + 8]);
+    }
+
+    let transposed = block.transpose();
+    for i in 0..64 {
+        assert_eq!(
+            transposed.get_coefficient(i),
+            block.get_coefficient((i % 8) * 8 + i / 8)
+        );
+    }
+
+    let mut raster = [i32x8::ZERO; 8];
+    let raster_col: &mut [i32; 64] = cast_mut(&mut raster);
+    raster_col[9] = -0x12345;
+    assert_eq!(raster[1].to_array()[1], -0x12345);
+}
+
+// the exported blocks are in the standard JPEG zigzag order, and blocks outside of the decoded
+// range are exported as zeros
+#[test]
+fn export_zigzag_blocks_layout() {
+    const ZIGZAG_TO_RASTER: [i16; 64] = [
+        0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+        20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+        58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+    ];
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 16;
+    jpeg_header.img_height = 16;
+    jpeg_header.cmp_info[0].bch = 2;
+    jpeg_header.cmp_info[0].bcv = 2;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 0, 2, &features).unwrap();
+
+    // each coefficient holds its raster index, stored transposed
+    let mut block = AlignedBlock::default();
+    for row in 0..8 {
+        for col in 0..8 {
+            block.set_coefficient(col * 8 + row, (row * 8 + col) as i16);
+        }
+    }
+    image.append_block(block).unwrap();
+
+    let blocks = image.export_zigzag_blocks();
+    assert_eq!(blocks.len(), 4);
+    assert_eq!(blocks[0], ZIGZAG_TO_RASTER);
+    assert_eq!(&blocks[1..], &[[0i16; 64]; 3]);
+}
+
+// blocks set in zigzag order read back the same in zigzag order, are stored transposed like
+// the blocks of set_block_data and fill the skipped blocks with zeros
+#[test]
+fn set_block_zigzag_roundtrip() {
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 16;
+    jpeg_header.img_height = 16;
+    jpeg_header.cmp_info[0].bch = 2;
+    jpeg_header.cmp_info[0].bcv = 2;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 0, 2, &features).unwrap();
+
+    let first: [i16; 64] = core::array::from_fn(|i| 64 - i as i16);
+    let zigzag: [i16; 64] = core::array::from_fn(|i| i as i16 * 3 - 100);
+    image.set_block_zigzag(0, &first).unwrap();
+    image.set_block_zigzag(2, &zigzag).unwrap();
+
+    assert_eq!(image.get_stored_block_count(), 3);
+    assert_eq!(image.get_block(1), &AlignedBlock::default());
+    for i in 0..64 {
+        assert_eq!(image.get_block(0).get_transposed_from_zigzag(i), first[i]);
+        assert_eq!(image.get_block(2).get_transposed_from_zigzag(i), zigzag[i]);
+    }
+
+    // the block is stored transposed, so the coefficient at row r and column c of the raster
+    // is at c * 8 + r
+    for row in 0..8 {
+        for col in 0..8 {
+            let zigzag_index = usize::from(crate::consts::RASTER_TO_ZIGZAG[row * 8 + col]);
+            assert_eq!(
+                image.get_block(2).get_coefficient(col * 8 + row),
+                zigzag[zigzag_index]
+            );
+        }
+    }
+
+    // positions outside of the image are rejected like in set_block_data
+    assert!(image.set_block_zigzag(4, &zigzag).is_err());
+}
+
+// the cleanup zeros only the small AC coefficients and never the DC, and a threshold of zero
+// or one leaves everything as it is
+#[test]
+fn zero_small_ac_keeps_dc_and_large_coefficients() {
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 1;
+    jpeg_header.img_width = 8;
+    jpeg_header.img_height = 8;
+    jpeg_header.cmp_info[0].bch = 1;
+    jpeg_header.cmp_info[0].bcv = 1;
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let mut image = BlockBasedImage::new(&jpeg_header, 0, 0, 1, &features).unwrap();
+
+    let mut block = [0i16; 64];
+    block[0] = 1;
+    block[1] = 2;
+    block[2] = -2;
+    block[3] = 3;
+    block[63] = -1;
+    image.set_block_data(0, &AlignedBlock::new(block)).unwrap();
+
+    let original = image.clone();
+    assert_eq!(image.zero_small_ac(0), 0);
+    assert_eq!(image.zero_small_ac(1), 0);
+    assert_eq!(image.content_hash(), original.content_hash());
+
+    assert_eq!(image.zero_small_ac(3), 3);
+    let mut expected = [0i16; 64];
+    expected[0] = 1;
+    expected[3] = 3;
+    assert_eq!(image.get_block(0).get_block(), &expected);
+}
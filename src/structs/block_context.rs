@@ -15,24 +15,137 @@ pub struct BlockContext {
     cur_neighbor_summary_index: i32,
     above_neighbor_summary_index: i32,
 }
+/// The already coded neighbors of the current block that the predictions are based on.
+/// Neighbors that don't exist at the edges of the image are `EMPTY_BLOCK` and
+/// `NEIGHBOR_DATA_EMPTY`, and the `ProbabilityTables` of the block decide which of the
+/// neighbors are looked at.
 pub struct NeighborData<'a> {
+    /// block above the current one, in the transposed order used during coding
     pub above: &'a AlignedBlock,
+    /// block to the left of the current one, in the transposed order used during coding
     pub left: &'a AlignedBlock,
+    /// block above and to the left of the current one, in the transposed order used during coding
     pub above_left: &'a AlignedBlock,
+    /// summary of the block above, which provides the edge pixels and coefficients for the
+    /// predictions of the top edge and the DC
     pub neighbor_context_above: &'a NeighborSummary,
+    /// summary of the block to the left, which provides the edge pixels and coefficients for
+    /// the predictions of the left edge and the DC
     pub neighbor_context_left: &'a NeighborSummary,
 }
 
+impl<'a> NeighborData<'a> {
+    /// Creates the neighbor data from explicitly chosen blocks and summaries, for example to
+    /// evaluate the edge predictions in isolation:
+    ///
+    /// ```
+    /// use lepton_jpeg::{
+    ///     AlignedBlock, EnabledFeatures, NeighborData, NeighborSummary, ProbabilityTables,
+    ///     QuantizationTables,
+    /// };
+    /// use wide::i32x8;
+    ///
+    /// let features = EnabledFeatures::compat_lepton_vector_read();
+    /// let qt = QuantizationTables::new_from_table(&[1; 64]);
+    ///
+    /// let mut above = AlignedBlock::default();
+    /// above.set_coefficient(9, 10);
+    /// let mut left = AlignedBlock::default();
+    /// left.set_coefficient(10, -4);
+    /// let above_left = AlignedBlock::default();
+    ///
+    /// let above_summary = NeighborSummary::from_block(&above, &qt, &features);
+    /// let left_summary = NeighborSummary::from_block(&left, &qt, &features);
+    ///
+    /// let neighbor_data =
+    ///     NeighborData::new(&above, &left, &above_left, &above_summary, &left_summary);
+    ///
+    /// // a block without coefficients of its own gets the edges of its neighbors
+    /// let raster = [i32x8::ZERO; 8];
+    /// let (horiz_pred, vert_pred) = ProbabilityTables::predict_current_edges(&neighbor_data, &raster);
+    /// assert_eq!(horiz_pred, above_summary.get_horizontal_coef());
+    /// assert_eq!(vert_pred, left_summary.get_vertical_coef());
+    /// ```
+    pub fn new(
+        above: &'a AlignedBlock,
+        left: &'a AlignedBlock,
+        above_left: &'a AlignedBlock,
+        neighbor_context_above: &'a NeighborSummary,
+        neighbor_context_left: &'a NeighborSummary,
+    ) -> Self {
+        NeighborData {
+            above,
+            left,
+            above_left,
+            neighbor_context_above,
+            neighbor_context_left,
+        }
+    }
+}
+
+/// The already coded neighbor blocks of a block, see `BlockContext::get_neighbor_blocks`.
+/// Neighbors outside of the image are `EMPTY_BLOCK`.
+pub struct NeighborBlocks<'a> {
+    /// block above, in the transposed order used during coding
+    pub above: &'a AlignedBlock,
+    /// block to the left, in the transposed order used during coding
+    pub left: &'a AlignedBlock,
+    /// block above and to the left, in the transposed order used during coding
+    pub above_left: &'a AlignedBlock,
+}
+
 impl BlockContext {
-    // for debugging
-    #[allow(dead_code)]
+    /// Context for the block at `dpos`, the same as the decoder gets by starting the row with
+    /// `off_y` and calling `advance` up to the block. Useful for looking at the neighbors of any
+    /// block of an already decoded image without walking through the image.
+    #[cfg(feature = "std")]
+    pub fn at(image_data: &BlockBasedImage, dpos: i32) -> Self {
+        let block_width = image_data.get_block_width();
+        let x = dpos % block_width;
+
+        let row = image_data.off_y(dpos / block_width);
+        BlockContext::new(
+            row.cur_block_index + x,
+            row.above_block_index + x,
+            row.cur_neighbor_summary_index + x,
+            row.above_neighbor_summary_index + x,
+        )
+    }
+
+    /// Returns the neighbor blocks that the predictions of the current block are based on,
+    /// without needing the neighbor summaries or the probability tables of the decoder.
+    pub fn get_neighbor_blocks<'a>(&self, image_data: &'a BlockBasedImage) -> NeighborBlocks<'a> {
+        let block_width = image_data.get_block_width();
+        let left_present = self.cur_block_index % block_width != 0;
+        let above_present = self.cur_block_index >= block_width;
+
+        NeighborBlocks {
+            above: if above_present {
+                image_data.get_block(self.above_block_index)
+            } else {
+                &EMPTY_BLOCK
+            },
+            left: if left_present {
+                image_data.get_block(self.cur_block_index - 1)
+            } else {
+                &EMPTY_BLOCK
+            },
+            above_left: if above_present && left_present {
+                image_data.get_block(self.above_block_index - 1)
+            } else {
+                &EMPTY_BLOCK
+            },
+        }
+    }
+
+    // index of the current block in the image
     pub fn get_here_index(&self) -> i32 {
         self.cur_block_index
     }
 
     // as each new line BlockContext is set by `off_y`, no edge cases with dereferencing
     // out of bounds indices is possilbe, therefore no special treatment is needed
-    pub fn next(&mut self) -> i32 {
+    pub fn advance(&mut self) -> i32 {
         self.cur_block_index += 1;
         self.above_block_index += 1;
         self.cur_neighbor_summary_index += 1;
@@ -103,3 +216,51 @@ impl BlockContext {
         neighbor_summary_cache[self.cur_neighbor_summary_index as usize] = neighbor_summary;
     }
 }
+
+// the neighbors looked up for a random block have to be the same blocks that the
+// decoder gets by walking along the row
+#[test]
+fn neighbor_blocks_match_decode_context() {
+    use crate::enabled_features::EnabledFeatures;
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::io::Cursor;
+
+    let file = synthetic_jpeg(&[0x22, 0x11, 0x11], 160, 128);
+    let (_lh, image_data) = read_jpeg(
+        &mut Cursor::new(file),
+        &EnabledFeatures::compat_lepton_vector_write(),
+        1,
+        |_| {},
+    )
+    .unwrap();
+
+    let image = &image_data[0];
+    let block_width = image.get_block_width();
+    let neighbor_summary = vec![NeighborSummary::default(); (block_width * 2) as usize];
+
+    for (x, y) in [(0, 0), (5, 0), (0, 7), (block_width - 1, 3), (17, 12)] {
+        let mut context = image.off_y(y);
+        for _ in 0..x {
+            context.advance();
+        }
+
+        let pt = ProbabilityTables::new(0, x > 0, y > 0);
+        let expected = if pt.is_all_present() {
+            context.get_neighbor_data::<true>(image, &neighbor_summary, &pt)
+        } else {
+            context.get_neighbor_data::<false>(image, &neighbor_summary, &pt)
+        };
+
+        let at = BlockContext::at(image, y * block_width + x);
+        assert_eq!(at.get_here_index(), context.get_here_index());
+
+        let actual = at.get_neighbor_blocks(image);
+        assert!(core::ptr::eq(actual.above, expected.above), "({x}, {y})");
+        assert!(core::ptr::eq(actual.left, expected.left), "({x}, {y})");
+        assert!(
+            core::ptr::eq(actual.above_left, expected.above_left),
+            "({x}, {y})"
+        );
+    }
+}
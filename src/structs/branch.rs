@@ -20,6 +20,15 @@ pub struct Branch {
     ///
     /// Both counts are never less than 1, so we start off with 0x0101.
     counts: u16,
+
+    /// number of bits coded with this branch, only kept with the `branch_visits` feature
+    #[cfg(feature = "branch_visits")]
+    visits: u32,
+
+    /// number of times one of the counts overflowed and the counts were normalized, only kept
+    /// with the `branch_visits` feature
+    #[cfg(feature = "branch_visits")]
+    renormalizations: u32,
 }
 
 impl Default for Branch {
@@ -46,23 +55,116 @@ const fn problookup() -> [u8; 65536] {
 /// precalculated probabilities for the next bit being false
 static PROB_LOOKUP: [u8; 65536] = problookup();
 
+/// sum of the false and true counts
+const fn total_count(counts: u16) -> u16 {
+    (counts >> 8) + (counts & 0xff)
+}
+
+/// of two candidate counts, where 0 means there is no candidate, the one with the smaller total
+/// or the smaller value if the totals are the same
+const fn better_counts(a: u16, b: u16) -> u16 {
+    if a == 0 {
+        b
+    } else if b == 0 {
+        a
+    } else if total_count(b) < total_count(a) || (total_count(b) == total_count(a) && b < a) {
+        b
+    } else {
+        a
+    }
+}
+
+/// used to precalculate the counts returned by `Branch::with_probability` for each probability
+const fn probability_counts() -> [u16; 256] {
+    // the best counts for each probability that some counts give exactly
+    let mut exact = [0u16; 256];
+    let mut f = 1u32;
+    while f <= 255 {
+        let mut t = 1u32;
+        while t <= 255 {
+            let counts = ((f << 8) | t) as u16;
+            let p = ((f << 8) / (f + t)) as usize;
+            exact[p] = better_counts(exact[p], counts);
+            t += 1;
+        }
+        f += 1;
+    }
+
+    // the best counts of the closest probabilities that are given exactly
+    let mut retval = [0u16; 256];
+    let mut p = 0;
+    while p < 256 {
+        let mut distance = 0;
+        while retval[p] == 0 {
+            let below = if distance <= p {
+                exact[p - distance]
+            } else {
+                0
+            };
+            let above = if p + distance < 256 {
+                exact[p + distance]
+            } else {
+                0
+            };
+            retval[p] = better_counts(below, above);
+            distance += 1;
+        }
+        p += 1;
+    }
+
+    return retval;
+}
+
+/// precalculated counts of `Branch::with_probability`
+static PROBABILITY_COUNTS: [u16; 256] = probability_counts();
+
 impl Branch {
     pub fn new() -> Self {
-        Branch { counts: 0x0101 }
+        Branch::from_counts(0x0101)
+    }
+
+    const fn from_counts(counts: u16) -> Self {
+        Branch {
+            counts,
+            #[cfg(feature = "branch_visits")]
+            visits: 0,
+            #[cfg(feature = "branch_visits")]
+            renormalizations: 0,
+        }
+    }
+
+    /// Creates a branch whose probability of the next bit being false is as close as possible
+    /// to `probability` (in 1/256 units). Of the counts that are equally close, the ones with
+    /// the smallest total are used so the branch still adapts quickly to the actual data.
+    #[allow(dead_code)]
+    pub fn with_probability(probability: u8) -> Self {
+        Branch::from_counts(PROBABILITY_COUNTS[usize::from(probability)])
     }
 
-    /// used for testing to set counts to a specific value
-    #[cfg(test)]
+    /// used for testing and restoring a model snapshot to set counts to a specific value
     pub fn set_count(&mut self, count: u16) {
         self.counts = count;
     }
 
-    /// used for testing to set counts to a specific value
-    #[cfg(test)]
+    /// used for testing and model snapshots to get the raw counts
     pub fn get_count(&self) -> u16 {
         self.counts
     }
 
+    /// number of bits that were coded with this branch so far
+    #[cfg(feature = "branch_visits")]
+    pub fn get_visits(&self) -> u32 {
+        self.visits
+    }
+
+    /// Number of times a count of this branch overflowed so far. This includes the updates
+    /// of a branch that has seen 255 or more of the same bit in a row, where the counts stay
+    /// saturated, so a high number indicates a nearly deterministic context.
+    #[cfg(feature = "branch_visits")]
+    pub fn get_renormalizations(&self) -> u32 {
+        self.renormalizations
+    }
+
     /// used for debugging to keep the state for hashing
     #[allow(dead_code)]
     pub fn get_u64(&self) -> u64 {
@@ -120,15 +222,25 @@ impl Branch {
 
             // upper byte is 0 since we incremented 0xffxx so we don't have to mask it
             sum = ((1 + sum) >> 1) | mask;
+
+            #[cfg(feature = "branch_visits")]
+            {
+                self.renormalizations = self.renormalizations.saturating_add(1);
+            }
         }
 
         self.counts = sum.rotate_left(bit as u32 * 8);
+
+        #[cfg(feature = "branch_visits")]
+        {
+            self.visits = self.visits.saturating_add(1);
+        }
     }
 }
 
 #[test]
 fn test_branch_update_false() {
-    let mut b = Branch { counts: 0x0101 };
+    let mut b = Branch::new();
     b.record_and_update_bit(false);
     assert_eq!(b.counts, 0x0201);
 
@@ -151,7 +263,7 @@ fn test_branch_update_false() {
 
 #[test]
 fn test_branch_update_true() {
-    let mut b = Branch { counts: 0x0101 };
+    let mut b = Branch::new();
     b.record_and_update_bit(true);
     assert_eq!(b.counts, 0x0102);
 
@@ -231,7 +343,7 @@ fn test_all_probabilities() {
             continue;
         }
 
-        let mut new_f = Branch { counts: i as u16 };
+        let mut new_f = Branch::from_counts(i as u16);
 
         for _k in 0..10 {
             old_f.record_obs_and_update(false);
@@ -243,7 +355,7 @@ fn test_all_probabilities() {
             counts: [(i >> 8) as u8, i as u8],
             probability: 0,
         };
-        let mut new_t = Branch { counts: i as u16 };
+        let mut new_t = Branch::from_counts(i as u16);
 
         for _k in 0..10 {
             old_t.record_obs_and_update(true);
@@ -261,3 +373,58 @@ fn test_all_probabilities() {
         }
     }
 }
+
+#[test]
+fn test_with_probability() {
+    for p in 0..=255u8 {
+        // the table has to give the same counts as searching all of them
+        let mut best = (u8::MAX, u16::MAX, 0);
+        for f in 1..=255u16 {
+            for t in 1..=255u16 {
+                let counts = (f << 8) | t;
+                best = best.min((PROB_LOOKUP[counts as usize].abs_diff(p), f + t, counts));
+            }
+        }
+        assert_eq!(
+            Branch::with_probability(p).counts,
+            best.2,
+            "probability {p}"
+        );
+    }
+
+    for p in 1..=255u8 {
+        let b = Branch::with_probability(p);
+        assert!(
+            b.get_probability().abs_diff(p) <= 1,
+            "probability {0} got {1}",
+            p,
+            b.get_probability()
+        );
+        assert!(b.counts >> 8 != 0 && b.counts & 0xff != 0);
+    }
+
+    assert_eq!(Branch::with_probability(128).counts, Branch::new().counts);
+    assert_eq!(Branch::with_probability(0).get_probability(), 1);
+}
+
+// the true count reaches 255 after 254 trues, and every true after that overflows it
+#[cfg(feature = "branch_visits")]
+#[test]
+fn test_renormalizations() {
+    let mut b = Branch::new();
+    for _ in 0..254 {
+        b.record_and_update_bit(true);
+    }
+    assert_eq!(b.get_renormalizations(), 0);
+
+    for _ in 254..300 {
+        b.record_and_update_bit(true);
+    }
+    assert_eq!(b.get_renormalizations(), 46);
+    assert_eq!(b.get_visits(), 300);
+
+    // a false after the run just increments the false count
+    b.record_and_update_bit(false);
+    assert_eq!(b.get_count(), 0x02ff);
+    assert_eq!(b.get_renormalizations(), 46);
+}
@@ -0,0 +1,61 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+
+/// Writer that throws the data away and only keeps track of the position and the length of
+/// the stream, so that the size of an output can be measured without storing it.
+#[derive(Default)]
+#[allow(dead_code)]
+pub struct CountingWriter {
+    position: u64,
+    len: u64,
+}
+
+#[allow(dead_code)]
+impl CountingWriter {
+    pub fn new() -> Self {
+        CountingWriter::default()
+    }
+
+    /// number of bytes the stream would have, including any gaps left by seeking past the end
+    pub fn stream_len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for CountingWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match new_position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -128,6 +128,78 @@ pub fn run_idct(block: &[i32x8; 8]) -> AlignedBlock {
     ]))
 }
 
+/// cos(k * pi / 16) for k in 0..=8
+const COS_PI_16: [f32; 9] = [
+    1.0,
+    0.980_785_3,
+    0.923_879_5,
+    0.831_469_6,
+    core::f32::consts::FRAC_1_SQRT_2,
+    0.555_570_2,
+    0.382_683_43,
+    0.195_090_32,
+    0.0,
+];
+
+/// C(u) / 2 * cos((2x + 1) * u * pi / 16), the weight of frequency u for sample x
+fn idct_f32_basis(x: usize, u: usize) -> f32 {
+    let mut k = ((2 * x + 1) * u) % 32;
+    if k > 16 {
+        k = 32 - k;
+    }
+
+    let cos = if k > 8 {
+        -COS_PI_16[16 - k]
+    } else {
+        COS_PI_16[k]
+    };
+
+    if u == 0 {
+        cos * core::f32::consts::FRAC_1_SQRT_2 / 2.0
+    } else {
+        cos / 2.0
+    }
+}
+
+/// Floating point IDCT of dequantized coefficients in the transposed order (horizontal
+/// frequency first, like `AlignedBlock`). Unlike `run_idct`, the result is not scaled, so
+/// it is the samples in raster order centered around zero, without any rounding.
+#[allow(dead_code)]
+pub fn run_idct_f32(block: &[f32; 64]) -> [f32; 64] {
+    let mut basis = [[0f32; 8]; 8];
+    for (x, b) in basis.iter_mut().enumerate() {
+        for (u, w) in b.iter_mut().enumerate() {
+            *w = idct_f32_basis(x, u);
+        }
+    }
+
+    // horizontal pass, for each sample column and vertical frequency
+    let mut columns = [0f32; 64];
+    for x in 0..8 {
+        for v in 0..8 {
+            let mut sum = 0.0;
+            for u in 0..8 {
+                sum += basis[x][u] * block[u * 8 + v];
+            }
+            columns[x * 8 + v] = sum;
+        }
+    }
+
+    // vertical pass
+    let mut samples = [0f32; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let mut sum = 0.0;
+            for v in 0..8 {
+                sum += basis[y][v] * columns[x * 8 + v];
+            }
+            samples[y * 8 + x] = sum;
+        }
+    }
+
+    samples
+}
+
 #[cfg(test)]
 use bytemuck::cast_ref;
 
@@ -0,0 +1,69 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Wraps the reader or writer supplied by the caller and remembers if it ever returned an error.
+///
+/// The codec itself also produces `io::Error`s for corrupt data (for example from the zlib
+/// decoder of the header), so the type of the error alone can't tell whether the stream
+/// failed or its content was bad.
+#[allow(dead_code)]
+pub struct IoErrorTracker<T> {
+    inner: T,
+    failed: bool,
+}
+
+#[allow(dead_code)]
+impl<T> IoErrorTracker<T> {
+    pub fn new(inner: T) -> Self {
+        IoErrorTracker {
+            inner,
+            failed: false,
+        }
+    }
+
+    /// true if any operation on the wrapped reader or writer returned an error
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    fn track<V>(&mut self, result: std::io::Result<V>) -> std::io::Result<V> {
+        if let Err(e) = &result {
+            // interrupted calls are retried by read_exact and write_all
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                self.failed = true;
+            }
+        }
+        result
+    }
+}
+
+impl<T: Read> Read for IoErrorTracker<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.track(result)
+    }
+}
+
+impl<T: Seek> Seek for IoErrorTracker<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let result = self.inner.seek(pos);
+        self.track(result)
+    }
+}
+
+impl<T: Write> Write for IoErrorTracker<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.inner.flush();
+        self.track(result)
+    }
+}
@@ -0,0 +1,909 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+// Reading and writing of arithmetic coded scans (SOF9 / SOF10). The coefficients are modeled by
+// Lepton the same way as for Huffman coded images, so all we need is to be able to decode the
+// scans and then recreate exactly the same bytes when we write the JPEG back out. The coding
+// procedures follow Annex F and G of ITU T.81 in the same way as libjpeg does.
+
+use anyhow::{Context, Result};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::consts::{JPegDecodeStatus, JPegType};
+use crate::helpers::{err_exit_code, here};
+use crate::jpeg_code;
+use crate::lepton_error::ExitCode;
+
+use super::arithmetic_reader::ArithmeticReader;
+use super::arithmetic_writer::ArithmeticWriter;
+use super::block_based_image::{AlignedBlock, BlockBasedImage};
+use super::jpeg_header::JPegHeader;
+use super::jpeg_position_state::JpegPositionState;
+use super::lepton_format::LeptonHeader;
+use super::thread_handoff::ThreadHandoff;
+
+/// the first statistics bin used for the DC magnitude category (Table F.4)
+const DC_X1_CONTEXT: usize = 20;
+
+/// the first statistics bins used for the AC magnitude category depending on whether
+/// the coefficient index is below or above the conditioning value Kx (Table F.5)
+const AC_X2_CONTEXT_LOW: usize = 189;
+const AC_X2_CONTEXT_HIGH: usize = 217;
+
+/// the statistics bin of the fixed 0.5 probability in the state table
+const FIXED_BIN: u8 = 113;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ArithmeticScanType {
+    Sequential,
+    DcFirst,
+    DcRefine,
+    AcFirst,
+    AcRefine,
+}
+
+/// adaptive state of the arithmetic coder for a scan. Everything is reset at the start of
+/// a scan and after every restart marker.
+struct ArithmeticStatistics {
+    dc_stats: [[u8; 64]; 4],
+    ac_stats: [[u8; 256]; 4],
+    fixed_bin: u8,
+    last_dc: [i32; 4],
+    dc_context: [usize; 4],
+}
+
+impl ArithmeticStatistics {
+    fn new() -> Self {
+        ArithmeticStatistics {
+            dc_stats: [[0; 64]; 4],
+            ac_stats: [[0; 256]; 4],
+            fixed_bin: FIXED_BIN,
+            last_dc: [0; 4],
+            dc_context: [0; 4],
+        }
+    }
+}
+
+fn get_scan_type(jf: &JPegHeader) -> Result<ArithmeticScanType> {
+    if jf.jpeg_type != JPegType::Progressive {
+        if jf.cs_from != 0 || jf.cs_sah != 0 || jf.cs_sal != 0 {
+            return err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "sequential scan must start with DC and can't use successive approximation",
+            );
+        }
+
+        return Ok(ArithmeticScanType::Sequential);
+    }
+
+    if jf.cs_from == 0 {
+        if jf.cs_to != 0 {
+            return err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "progressive DC scan cannot contain AC coefficients",
+            );
+        }
+
+        Ok(if jf.cs_sah == 0 {
+            ArithmeticScanType::DcFirst
+        } else {
+            ArithmeticScanType::DcRefine
+        })
+    } else {
+        if jf.cs_cmpc != 1 {
+            return err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "Progressive AC encoding cannot be interleaved",
+            );
+        }
+
+        if jf.cs_sah == 0 {
+            Ok(ArithmeticScanType::AcFirst)
+        } else if jf.cs_sah == jf.cs_sal + 1 {
+            Ok(ArithmeticScanType::AcRefine)
+        } else {
+            err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "successive approximation must refine one bit at a time",
+            )
+        }
+    }
+}
+
+/// first and last AC coefficient coded in the scan, and the point transform applied to them
+fn get_ac_band(jf: &JPegHeader) -> (u8, u8, u8) {
+    if jf.jpeg_type == JPegType::Progressive {
+        (jf.cs_from, jf.cs_to, jf.cs_sal)
+    } else {
+        (1, jf.cs_to, 0)
+    }
+}
+
+/// conditioning category of the next DC difference based on the magnitude of this one (F.1.4.4.1.2)
+fn get_dc_context(m: u32, sign: bool, jf: &JPegHeader, tbl: usize) -> usize {
+    if m < (1u32 << jf.arith_dc_l[tbl]) >> 1 {
+        0 // zero diff category
+    } else if m > (1u32 << jf.arith_dc_u[tbl]) >> 1 {
+        12 + 4 * usize::from(sign) // large diff category
+    } else {
+        4 + 4 * usize::from(sign) // small diff category
+    }
+}
+
+/// reads an arithmetic coded scan, including the restart markers inside of it. Since there
+/// is no guarantee that we can recreate the same bytes from the coefficients (for example
+/// if the encoder terminated the coding differently), the scan is verified by encoding it again.
+pub fn read_arithmetic_scan<R: Read + Seek>(
+    lp: &mut LeptonHeader,
+    reader: &mut R,
+    thread_handoff: Option<&mut Vec<ThreadHandoff>>,
+    image_data: &mut [BlockBasedImage],
+) -> Result<()> {
+    let scan_data = read_scan_data(reader).context(here!())?;
+
+    decode_arithmetic_scan(&scan_data, &lp.jpeg_header, thread_handoff, image_data)
+        .context(here!())?;
+
+    if encode_arithmetic_scan(image_data, &lp.jpeg_header).context(here!())? != scan_data {
+        return err_exit_code(
+            ExitCode::UnsupportedJpeg,
+            "arithmetic coded scan cannot be recreated exactly",
+        );
+    }
+
+    lp.scnc += 1; // increment scan counter
+    Ok(())
+}
+
+/// writes an entire arithmetic coded scan for the current scan in the header
+pub fn jpeg_write_arithmetic_scan<W: Write>(
+    writer: &mut W,
+    framebuffer: &[BlockBasedImage],
+    jf: &JPegHeader,
+) -> Result<()> {
+    let scan_data = encode_arithmetic_scan(framebuffer, jf).context(here!())?;
+
+    writer.write_all(&scan_data[..]).context(here!())?;
+
+    Ok(())
+}
+
+/// reads the entropy coded data up to the next marker that isn't a restart marker, and
+/// leaves the reader positioned at that marker.
+fn read_scan_data<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut chunk = vec![0u8; 65536];
+
+    loop {
+        let n = reader.read(&mut chunk[..]).context(here!())?;
+        if n == 0 {
+            return err_exit_code(
+                ExitCode::UnsupportedJpeg,
+                "unexpected end of file in arithmetic coded scan",
+            );
+        }
+
+        // start one byte back in case the previous chunk ended with 0xFF
+        let mut i = data.len().saturating_sub(1);
+        data.extend_from_slice(&chunk[..n]);
+
+        while i + 1 < data.len() {
+            let next = data[i + 1];
+            if data[i] == 0xFF
+                && next != 0
+                && !(jpeg_code::RST0..=jpeg_code::RST0 + 7).contains(&next)
+            {
+                reader
+                    .seek(SeekFrom::Current(-((data.len() - i) as i64)))
+                    .context(here!())?;
+                data.truncate(i);
+                return Ok(data);
+            }
+
+            i += 1;
+        }
+    }
+}
+
+/// returns the MCU row of the current position, which is used to split the image between threads
+fn get_mcu_row(state: &JpegPositionState, jf: &JPegHeader) -> i32 {
+    if jf.cs_cmpc > 1 {
+        state.get_mcu() / jf.mcuh
+    } else {
+        let cmp_info = &jf.cmp_info[state.get_cmp()];
+        state.get_dpos() / cmp_info.bch / cmp_info.sfh
+    }
+}
+
+fn decode_arithmetic_scan(
+    scan_data: &[u8],
+    jf: &JPegHeader,
+    mut thread_handoff: Option<&mut Vec<ThreadHandoff>>,
+    image_data: &mut [BlockBasedImage],
+) -> Result<()> {
+    let scan_type = get_scan_type(jf).context(here!())?;
+
+    let mut reader = ArithmeticReader::new(scan_data);
+    let mut stats = ArithmeticStatistics::new();
+    let mut state = JpegPositionState::new(jf, 0);
+    let mut restart_num = 0u8;
+
+    let luma_mul = jf.cmp_info[0].bcv / jf.mcuv;
+    let mut last_mcu_row = -1;
+
+    let mut sta = JPegDecodeStatus::DecodeInProgress;
+    while sta != JPegDecodeStatus::ScanCompleted {
+        state.reset_rstw(jf);
+
+        while sta == JPegDecodeStatus::DecodeInProgress {
+            // collect the handoffs for each MCU row. There is no way to restart the arithmetic
+            // decoder in the middle of an interval, but we need the rows to split the image between threads
+            if let Some(handoffs) = thread_handoff.as_deref_mut() {
+                let mcu_row = get_mcu_row(&state, jf);
+                if mcu_row != last_mcu_row {
+                    last_mcu_row = mcu_row;
+
+                    handoffs.push(ThreadHandoff {
+                        segment_offset_in_file: reader.get_position() as i32,
+                        luma_y_start: luma_mul * mcu_row,
+                        luma_y_end: luma_mul * (mcu_row + 1),
+                        overhang_byte: 0,
+                        num_overhang_bits: 0,
+                        last_dc: [0; 4],
+                        segment_size: 0, // initialized later
+                    });
+                }
+            }
+
+            let cmp = state.get_cmp();
+            decode_block(
+                &mut reader,
+                &mut stats,
+                jf,
+                scan_type,
+                cmp,
+                image_data[cmp]
+                    .get_block_mut(state.get_dpos())
+                    .context(here!())?,
+            )
+            .context(here!())?;
+
+            sta = state.next_mcu_pos(jf);
+        }
+
+        if sta == JPegDecodeStatus::RestartIntervalExpired {
+            reader
+                .process_restart(jpeg_code::RST0 + (restart_num & 7))
+                .context(here!())?;
+            restart_num = restart_num.wrapping_add(1);
+
+            stats = ArithmeticStatistics::new();
+            sta = JPegDecodeStatus::DecodeInProgress;
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_arithmetic_scan(framebuffer: &[BlockBasedImage], jf: &JPegHeader) -> Result<Vec<u8>> {
+    let scan_type = get_scan_type(jf).context(here!())?;
+
+    let mut writer = ArithmeticWriter::new();
+    let mut stats = ArithmeticStatistics::new();
+    let mut state = JpegPositionState::new(jf, 0);
+    let mut restart_num = 0u8;
+
+    let mut sta = JPegDecodeStatus::DecodeInProgress;
+    while sta != JPegDecodeStatus::ScanCompleted {
+        state.reset_rstw(jf);
+
+        while sta == JPegDecodeStatus::DecodeInProgress {
+            let cmp = state.get_cmp();
+            encode_block(
+                &mut writer,
+                &mut stats,
+                jf,
+                scan_type,
+                cmp,
+                framebuffer[cmp].get_block(state.get_dpos()),
+            );
+
+            sta = state.next_mcu_pos(jf);
+        }
+
+        if sta == JPegDecodeStatus::RestartIntervalExpired {
+            writer.emit_restart(restart_num);
+            restart_num = restart_num.wrapping_add(1);
+
+            stats = ArithmeticStatistics::new();
+            sta = JPegDecodeStatus::DecodeInProgress;
+        }
+    }
+
+    writer.finish();
+
+    Ok(writer.detach_buffer())
+}
+
+fn decode_block(
+    reader: &mut ArithmeticReader,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    scan_type: ArithmeticScanType,
+    cmp: usize,
+    block: &mut AlignedBlock,
+) -> Result<()> {
+    match scan_type {
+        ArithmeticScanType::Sequential => {
+            let dc = decode_dc(reader, stats, jf, cmp).context(here!())?;
+            block.set_transposed_from_zigzag(0, dc as i16);
+
+            if jf.cs_to > 0 {
+                decode_ac_first(reader, stats, jf, cmp, block).context(here!())?;
+            }
+        }
+        ArithmeticScanType::DcFirst => {
+            let dc = decode_dc(reader, stats, jf, cmp).context(here!())?;
+            block.set_transposed_from_zigzag(0, dc.wrapping_shl(u32::from(jf.cs_sal)) as i16);
+        }
+        ArithmeticScanType::DcRefine => {
+            if reader.decode(&mut stats.fixed_bin) {
+                block.set_transposed_from_zigzag(
+                    0,
+                    block.get_transposed_from_zigzag(0) | (1 << jf.cs_sal),
+                );
+            }
+        }
+        ArithmeticScanType::AcFirst => {
+            decode_ac_first(reader, stats, jf, cmp, block).context(here!())?;
+        }
+        ArithmeticScanType::AcRefine => {
+            decode_ac_refine(reader, stats, jf, cmp, block).context(here!())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_block(
+    writer: &mut ArithmeticWriter,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    scan_type: ArithmeticScanType,
+    cmp: usize,
+    block: &AlignedBlock,
+) {
+    match scan_type {
+        ArithmeticScanType::Sequential => {
+            encode_dc(
+                writer,
+                stats,
+                jf,
+                cmp,
+                i32::from(block.get_transposed_from_zigzag(0)),
+            );
+
+            if jf.cs_to > 0 {
+                encode_ac_first(writer, stats, jf, cmp, block);
+            }
+        }
+        ArithmeticScanType::DcFirst => {
+            encode_dc(
+                writer,
+                stats,
+                jf,
+                cmp,
+                i32::from(block.get_transposed_from_zigzag(0)) >> jf.cs_sal,
+            );
+        }
+        ArithmeticScanType::DcRefine => {
+            writer.encode(
+                &mut stats.fixed_bin,
+                (block.get_transposed_from_zigzag(0) >> jf.cs_sal) & 1 != 0,
+            );
+        }
+        ArithmeticScanType::AcFirst => {
+            encode_ac_first(writer, stats, jf, cmp, block);
+        }
+        ArithmeticScanType::AcRefine => {
+            encode_ac_refine(writer, stats, jf, cmp, block);
+        }
+    }
+}
+
+/// decodes the low bits of a magnitude once the magnitude category is known (Figure F.24)
+fn decode_magnitude_bits(reader: &mut ArithmeticReader, st: &mut u8, mut m: u32) -> i32 {
+    let mut v = m;
+
+    m >>= 1;
+    while m != 0 {
+        if reader.decode(st) {
+            v |= m;
+        }
+        m >>= 1;
+    }
+
+    v as i32 + 1
+}
+
+/// encodes the low bits of a magnitude once the magnitude category is known (Figure F.9)
+fn encode_magnitude_bits(writer: &mut ArithmeticWriter, st: &mut u8, mut m: u32, v: u32) {
+    m >>= 1;
+    while m != 0 {
+        writer.encode(st, (m & v) != 0);
+        m >>= 1;
+    }
+}
+
+/// decodes the DC difference and returns the new DC value (Figure F.19)
+fn decode_dc(
+    reader: &mut ArithmeticReader,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+) -> Result<i32> {
+    let tbl = usize::from(jf.cmp_info[cmp].huff_dc);
+    let dc_stats = &mut stats.dc_stats[tbl];
+
+    let mut st = stats.dc_context[cmp];
+    if !reader.decode(&mut dc_stats[st]) {
+        stats.dc_context[cmp] = 0;
+        return Ok(stats.last_dc[cmp]);
+    }
+
+    let sign = reader.decode(&mut dc_stats[st + 1]);
+    st += 2 + usize::from(sign);
+
+    // magnitude category
+    let mut m = 0;
+    if reader.decode(&mut dc_stats[st]) {
+        m = 1;
+        st = DC_X1_CONTEXT;
+        while reader.decode(&mut dc_stats[st]) {
+            m <<= 1;
+            if m == 0x8000 {
+                return err_exit_code(
+                    ExitCode::UnsupportedJpeg,
+                    "invalid arithmetic code, DC magnitude too large",
+                );
+            }
+            st += 1;
+        }
+    }
+
+    stats.dc_context[cmp] = get_dc_context(m, sign, jf, tbl);
+
+    let v = decode_magnitude_bits(reader, &mut dc_stats[st + 14], m);
+
+    stats.last_dc[cmp] = stats.last_dc[cmp].wrapping_add(if sign { -v } else { v });
+    Ok(stats.last_dc[cmp])
+}
+
+/// encodes the difference of the DC value to the previous one (Figure F.4)
+fn encode_dc(
+    writer: &mut ArithmeticWriter,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+    dc: i32,
+) {
+    let tbl = usize::from(jf.cmp_info[cmp].huff_dc);
+    let dc_stats = &mut stats.dc_stats[tbl];
+
+    let mut st = stats.dc_context[cmp];
+    let diff = dc - stats.last_dc[cmp];
+    if diff == 0 {
+        writer.encode(&mut dc_stats[st], false);
+        stats.dc_context[cmp] = 0;
+        return;
+    }
+
+    stats.last_dc[cmp] = dc;
+    writer.encode(&mut dc_stats[st], true);
+
+    let sign = diff < 0;
+    writer.encode(&mut dc_stats[st + 1], sign);
+    st += 2 + usize::from(sign);
+
+    // magnitude category
+    let v = diff.unsigned_abs() - 1;
+    let mut m = 0;
+    if v != 0 {
+        writer.encode(&mut dc_stats[st], true);
+        m = 1;
+        st = DC_X1_CONTEXT;
+
+        let mut v2 = v >> 1;
+        while v2 != 0 {
+            writer.encode(&mut dc_stats[st], true);
+            m <<= 1;
+            st += 1;
+            v2 >>= 1;
+        }
+    }
+    writer.encode(&mut dc_stats[st], false);
+
+    stats.dc_context[cmp] = get_dc_context(m, sign, jf, tbl);
+
+    encode_magnitude_bits(writer, &mut dc_stats[st + 14], m, v);
+}
+
+/// decodes the value of a non-zero AC coefficient (Figure F.21)
+fn decode_ac_value(
+    reader: &mut ArithmeticReader,
+    ac_stats: &mut [u8; 256],
+    fixed_bin: &mut u8,
+    mut st: usize,
+    k: u8,
+    kx: u8,
+) -> Result<i32> {
+    let sign = reader.decode(fixed_bin);
+    st += 2;
+
+    // magnitude category
+    let mut m = 0;
+    if reader.decode(&mut ac_stats[st]) {
+        m = 1;
+        if reader.decode(&mut ac_stats[st]) {
+            m = 2;
+            st = if k <= kx {
+                AC_X2_CONTEXT_LOW
+            } else {
+                AC_X2_CONTEXT_HIGH
+            };
+
+            while reader.decode(&mut ac_stats[st]) {
+                m <<= 1;
+                if m == 0x8000 {
+                    return err_exit_code(
+                        ExitCode::UnsupportedJpeg,
+                        "invalid arithmetic code, AC magnitude too large",
+                    );
+                }
+                st += 1;
+            }
+        }
+    }
+
+    let v = decode_magnitude_bits(reader, &mut ac_stats[st + 14], m);
+
+    Ok(if sign { -v } else { v })
+}
+
+/// encodes the value of a non-zero AC coefficient after the sign was already written (Figure F.7)
+fn encode_ac_value(
+    writer: &mut ArithmeticWriter,
+    ac_stats: &mut [u8; 256],
+    mut st: usize,
+    k: u8,
+    kx: u8,
+    v: u32,
+) {
+    st += 2;
+
+    // magnitude category
+    let v = v - 1;
+    let mut m = 0;
+    if v != 0 {
+        writer.encode(&mut ac_stats[st], true);
+        m = 1;
+
+        let mut v2 = v >> 1;
+        if v2 != 0 {
+            writer.encode(&mut ac_stats[st], true);
+            m = 2;
+            st = if k <= kx {
+                AC_X2_CONTEXT_LOW
+            } else {
+                AC_X2_CONTEXT_HIGH
+            };
+
+            v2 >>= 1;
+            while v2 != 0 {
+                writer.encode(&mut ac_stats[st], true);
+                m <<= 1;
+                st += 1;
+                v2 >>= 1;
+            }
+        }
+    }
+    writer.encode(&mut ac_stats[st], false);
+
+    encode_magnitude_bits(writer, &mut ac_stats[st + 14], m, v);
+}
+
+/// decodes the AC coefficients of a block in a sequential scan or first progressive scan (Figure F.20)
+fn decode_ac_first(
+    reader: &mut ArithmeticReader,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+    block: &mut AlignedBlock,
+) -> Result<()> {
+    let (from, to, al) = get_ac_band(jf);
+    let tbl = usize::from(jf.cmp_info[cmp].huff_ac);
+    let kx = jf.arith_ac_k[tbl];
+    let ac_stats = &mut stats.ac_stats[tbl];
+
+    let mut k = from;
+    while k <= to {
+        let mut st = 3 * usize::from(k - 1);
+        if reader.decode(&mut ac_stats[st]) {
+            break; // EOB
+        }
+
+        // skip zero coefficients
+        while !reader.decode(&mut ac_stats[st + 1]) {
+            st += 3;
+            k += 1;
+            if k > to {
+                return err_exit_code(
+                    ExitCode::UnsupportedJpeg,
+                    "invalid arithmetic code, zero run past the end of the block",
+                );
+            }
+        }
+
+        let v =
+            decode_ac_value(reader, ac_stats, &mut stats.fixed_bin, st, k, kx).context(here!())?;
+        block.set_transposed_from_zigzag(usize::from(k), (v << al) as i16);
+
+        k += 1;
+    }
+
+    Ok(())
+}
+
+/// encodes the AC coefficients of a block in a sequential scan or first progressive scan (Figure F.5)
+fn encode_ac_first(
+    writer: &mut ArithmeticWriter,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+    block: &AlignedBlock,
+) {
+    let (from, to, al) = get_ac_band(jf);
+    let tbl = usize::from(jf.cmp_info[cmp].huff_ac);
+    let kx = jf.arith_ac_k[tbl];
+    let ac_stats = &mut stats.ac_stats[tbl];
+
+    let coef = |k: u8| block.get_transposed_from_zigzag(usize::from(k));
+
+    // the EOB is after the last coefficient that is non-zero after the point transform
+    let mut ke = to;
+    while ke > 0 && (coef(ke).unsigned_abs() >> al) == 0 {
+        ke -= 1;
+    }
+
+    let mut k = from;
+    while k <= ke {
+        let mut st = 3 * usize::from(k - 1);
+        writer.encode(&mut ac_stats[st], false);
+
+        let v = loop {
+            let v = u32::from(coef(k).unsigned_abs() >> al);
+            if v != 0 {
+                writer.encode(&mut ac_stats[st + 1], true);
+                writer.encode(&mut stats.fixed_bin, coef(k) < 0);
+                break v;
+            }
+
+            writer.encode(&mut ac_stats[st + 1], false);
+            st += 3;
+            k += 1;
+        };
+
+        encode_ac_value(writer, ac_stats, st, k, kx, v);
+
+        k += 1;
+    }
+
+    if k <= to {
+        writer.encode(&mut ac_stats[3 * usize::from(k - 1)], true);
+    }
+}
+
+/// decodes the refinement bits of the AC coefficients of a block (Figure G.11)
+fn decode_ac_refine(
+    reader: &mut ArithmeticReader,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+    block: &mut AlignedBlock,
+) -> Result<()> {
+    let (from, to, al) = (jf.cs_from, jf.cs_to, jf.cs_sal);
+    let tbl = usize::from(jf.cmp_info[cmp].huff_ac);
+    let ac_stats = &mut stats.ac_stats[tbl];
+
+    let p1: i16 = 1 << al;
+    let m1: i16 = -1 << al;
+
+    // EOB of the previous stage
+    let mut kex = to;
+    while kex > 0 && block.get_transposed_from_zigzag(usize::from(kex)) == 0 {
+        kex -= 1;
+    }
+
+    let mut k = from;
+    while k <= to {
+        let mut st = 3 * usize::from(k - 1);
+        if k > kex && reader.decode(&mut ac_stats[st]) {
+            break; // EOB
+        }
+
+        loop {
+            let coef = block.get_transposed_from_zigzag(usize::from(k));
+            if coef != 0 {
+                // previously non-zero coefficient, read the correction bit
+                if reader.decode(&mut ac_stats[st + 2]) {
+                    block.set_transposed_from_zigzag(
+                        usize::from(k),
+                        coef.wrapping_add(if coef < 0 { m1 } else { p1 }),
+                    );
+                }
+                break;
+            }
+
+            if reader.decode(&mut ac_stats[st + 1]) {
+                // newly non-zero coefficient
+                let v = if reader.decode(&mut stats.fixed_bin) {
+                    m1
+                } else {
+                    p1
+                };
+                block.set_transposed_from_zigzag(usize::from(k), v);
+                break;
+            }
+
+            st += 3;
+            k += 1;
+            if k > to {
+                return err_exit_code(
+                    ExitCode::UnsupportedJpeg,
+                    "invalid arithmetic code, zero run past the end of the block",
+                );
+            }
+        }
+
+        k += 1;
+    }
+
+    Ok(())
+}
+
+/// encodes the refinement bits of the AC coefficients of a block (Figure G.10)
+fn encode_ac_refine(
+    writer: &mut ArithmeticWriter,
+    stats: &mut ArithmeticStatistics,
+    jf: &JPegHeader,
+    cmp: usize,
+    block: &AlignedBlock,
+) {
+    let (from, to, al) = (jf.cs_from, jf.cs_to, jf.cs_sal);
+    let tbl = usize::from(jf.cmp_info[cmp].huff_ac);
+    let ac_stats = &mut stats.ac_stats[tbl];
+
+    let coef = |k: u8| block.get_transposed_from_zigzag(usize::from(k));
+
+    // EOB of this stage
+    let mut ke = to;
+    while ke > 0 && (coef(ke).unsigned_abs() >> al) == 0 {
+        ke -= 1;
+    }
+
+    // EOB of the previous stage
+    let mut kex = ke;
+    while kex > 0 && (coef(kex).unsigned_abs() >> (al + 1)) == 0 {
+        kex -= 1;
+    }
+
+    let mut k = from;
+    while k <= ke {
+        let mut st = 3 * usize::from(k - 1);
+        if k > kex {
+            writer.encode(&mut ac_stats[st], false);
+        }
+
+        loop {
+            let v = coef(k).unsigned_abs() >> al;
+            if v != 0 {
+                if (v >> 1) != 0 {
+                    // previously non-zero coefficient, write the correction bit
+                    writer.encode(&mut ac_stats[st + 2], (v & 1) != 0);
+                } else {
+                    // newly non-zero coefficient
+                    writer.encode(&mut ac_stats[st + 1], true);
+                    writer.encode(&mut stats.fixed_bin, coef(k) < 0);
+                }
+                break;
+            }
+
+            writer.encode(&mut ac_stats[st + 1], false);
+            st += 3;
+            k += 1;
+        }
+
+        k += 1;
+    }
+
+    if k <= to {
+        writer.encode(&mut ac_stats[3 * usize::from(k - 1)], true);
+    }
+}
+
+// encodes random blocks as a sequential scan with restart intervals and ensures
+// that decoding them gives back the same coefficients
+#[test]
+fn roundtrip_sequential_scan() {
+    use crate::enabled_features::EnabledFeatures;
+    use rand::Rng;
+    use std::io::Cursor;
+
+    // 32x24 pixel grayscale image (4x3 blocks) with a restart interval of 5 blocks
+    let mut header_data = vec![0xFF, jpeg_code::DQT, 0, 67, 0];
+    header_data.extend([1u8; 64]);
+    header_data.extend([0xFF, jpeg_code::DRI, 0, 4, 0, 5]);
+    header_data.extend([0xFF, jpeg_code::DAC, 0, 6, 0x00, 0x52, 0x10, 0x08]);
+    header_data.extend([0xFF, jpeg_code::SOF9, 0, 11, 8, 0, 24, 0, 32, 1, 1, 0x11, 0]);
+    header_data.extend([0xFF, jpeg_code::SOS, 0, 8, 1, 1, 0x00, 0, 63, 0]);
+
+    let enabled_features = EnabledFeatures::compat_lepton_vector_read();
+
+    let mut jf = JPegHeader::new();
+    assert!(jf
+        .parse(&mut Cursor::new(&header_data), &enabled_features)
+        .unwrap());
+    assert!(jf.arithmetic);
+    assert_eq!(jf.arith_dc_l[0], 2);
+    assert_eq!(jf.arith_dc_u[0], 5);
+    assert_eq!(jf.arith_ac_k[0], 8);
+
+    let mut rng = crate::helpers::get_rand_from_seed([3u8; 32]);
+
+    let mut images =
+        [BlockBasedImage::new(&jf, 0, 0, jf.cmp_info[0].bcv, &enabled_features).unwrap()];
+    for dpos in 0..jf.cmp_info[0].bc {
+        let mut block = AlignedBlock::default();
+        block.set_transposed_from_zigzag(0, rng.gen_range(-1000..=1000));
+        for k in 1..64 {
+            // mostly zeros with the occasional large value
+            let v = match rng.gen_range(0..10) {
+                0 => rng.gen_range(-1000..=1000),
+                1 | 2 => rng.gen_range(-3..=3),
+                _ => 0,
+            };
+            block.set_transposed_from_zigzag(k, v);
+        }
+        images[0].set_block_data(dpos, &block).unwrap();
+    }
+
+    let scan_data = encode_arithmetic_scan(&images, &jf).unwrap();
+
+    let mut decoded =
+        [BlockBasedImage::new(&jf, 0, 0, jf.cmp_info[0].bcv, &enabled_features).unwrap()];
+    let mut handoffs = Vec::new();
+    decode_arithmetic_scan(&scan_data, &jf, Some(&mut handoffs), &mut decoded).unwrap();
+
+    for dpos in 0..jf.cmp_info[0].bc {
+        assert_eq!(
+            images[0].get_block(dpos).get_block(),
+            decoded[0].get_block(dpos).get_block()
+        );
+    }
+
+    // one handoff per row of blocks
+    assert_eq!(handoffs.len(), 3);
+
+    // verify that the restart markers were written
+    for n in 0..2 {
+        assert!(scan_data
+            .windows(2)
+            .any(|w| w[0] == 0xFF && w[1] == jpeg_code::RST0 + n));
+    }
+
+    // the encoding must also be stable if we re-encode the decoded image
+    assert_eq!(encode_arithmetic_scan(&decoded, &jf).unwrap(), scan_data);
+}
@@ -269,6 +269,7 @@ impl HuffTree {
 #[derive(Debug)]
 pub struct JPegHeader {
     pub q_tables: [[u16; 64]; 4],     // quantization tables 4 x 64
+    pub qt_set: [u8; 4],              // 1 if quantization table is set
     h_codes: [[HuffCodes; 4]; 2],     // huffman codes (access via get_huff_xx_codes)
     h_trees: [[HuffTree; 4]; 2],      // huffman decoding trees (access via get_huff_xx_tree)
     pub ht_set: [[u8; 4]; 2],         // 1 if huffman table is set
@@ -276,8 +277,16 @@ pub struct JPegHeader {
     pub cmpc: usize,                  // component count
     pub img_width: i32,               // width of image
     pub img_height: i32,              // height of image
+    pub precision: u8,                // bits per sample, 8 or 12
+
+    pub(crate) jpeg_type: JPegType,
+    pub arithmetic: bool, // entropy coded with arithmetic coding instead of huffman coding
+
+    // arithmetic coding conditioning (from DAC segment, accessed via the same table index as the huffman tables)
+    pub arith_dc_l: [u8; 4], // DC lower bound of the small difference category
+    pub arith_dc_u: [u8; 4], // DC upper bound of the small difference category
+    pub arith_ac_k: [u8; 4], // AC coefficient index that separates the low and high magnitude contexts
 
-    pub jpeg_type: JPegType,
     pub sfhm: i32, // max horizontal sample factor
     pub sfvm: i32, // max verical sample factor
     pub mcuv: i32, // mcus per line
@@ -305,6 +314,7 @@ impl JPegHeader {
     pub fn new() -> Self {
         return JPegHeader {
             q_tables: [[0; 64]; 4],
+            qt_set: [0; 4],
             h_codes: [[HuffCodes::default(); 4]; 2],
             h_trees: [[HuffTree::default(); 4]; 2],
             ht_set: [[0; 4]; 2],
@@ -317,7 +327,12 @@ impl JPegHeader {
             cmpc: 0,
             img_width: 0,
             img_height: 0,
+            precision: 8,
             jpeg_type: JPegType::Unknown,
+            arithmetic: false,
+            arith_dc_l: [0; 4],
+            arith_dc_u: [1; 4],
+            arith_ac_k: [5; 4],
             sfhm: 0,
             sfvm: 0,
             mcuv: 0,
@@ -349,6 +364,29 @@ impl JPegHeader {
         &self.h_trees[1][usize::from(self.cmp_info[cmp].huff_ac)]
     }
 
+    /// Reads the header of a JPEG file (starting with the SOI marker) up to the first scan
+    /// without decoding any coefficients. This is useful to inspect the structure of an image,
+    /// such as the component count, sampling factors and quantization table assignments,
+    /// before deciding whether to recompress it.
+    #[allow(dead_code)]
+    pub fn from_reader<R: Read>(
+        reader: &mut R,
+        enabled_features: &EnabledFeatures,
+    ) -> Result<Self> {
+        let mut startheader = [0u8; 2];
+        reader.read_exact(&mut startheader).context(here!())?;
+        if startheader[0] != 0xFF || startheader[1] != jpeg_code::SOI {
+            return err_exit_code(ExitCode::UnsupportedJpeg, "header invalid");
+        }
+
+        let mut header = JPegHeader::new();
+        if !header.parse(reader, enabled_features).context(here!())? {
+            return err_exit_code(ExitCode::UnsupportedJpeg, "JPeg does not contain scans");
+        }
+
+        Ok(header)
+    }
+
     /// Parses header for imageinfo
     pub fn parse<R: Read>(
         &mut self,
@@ -380,6 +418,19 @@ impl JPegHeader {
         }
 
         for cmp in 0..self.cmpc {
+            // otherwise the component would silently be dequantized with a table of zeros
+            let q_table_index = usize::from(self.cmp_info[cmp].q_table_index);
+            if self.qt_set.get(q_table_index).copied().unwrap_or(0) == 0 {
+                return err_exit_code(
+                    ExitCode::UnsupportedJpeg,
+                    format!(
+                        "component {0} references quantization table {1} which is not defined",
+                        cmp, q_table_index
+                    )
+                    .as_str(),
+                );
+            }
+
             if (self.cmp_info[cmp].sfv == 0)
                 || (self.cmp_info[cmp].sfh == 0)
                 || (self.q_tables[usize::from(self.cmp_info[cmp].q_table_index)][0] == 0)
@@ -553,6 +604,7 @@ impl JPegHeader {
                     }
 
                     hpos+=1;
+                    self.qt_set[rval] = 1;
                     if lval == 0
                     {
                         ensure_space(segment,hpos, 64).context(here!())?;
@@ -607,6 +659,41 @@ impl JPegHeader {
 
             }
 
+            jpeg_code::DAC => // DAC segment
+            {
+                // define arithmetic coding conditioning
+                while hpos < len
+                {
+                    ensure_space(segment,hpos, 2).context(here!())?;
+
+                    let lval = lbits(segment[hpos], 4);
+                    let rval = usize::from(rbits(segment[hpos], 4));
+                    if lval >= 2 || rval >= 4
+                    {
+                        return err_exit_code(ExitCode::UnsupportedJpeg,"DAC has invalid index");
+                    }
+
+                    let cs = segment[hpos + 1];
+                    if lval == 0
+                    {
+                        // DC conditioning, lower and upper bound of the small difference category
+                        self.arith_dc_l[rval] = rbits(cs, 4);
+                        self.arith_dc_u[rval] = lbits(cs, 4);
+                        if self.arith_dc_l[rval] > self.arith_dc_u[rval]
+                        {
+                            return err_exit_code(ExitCode::UnsupportedJpeg,"DAC has invalid DC conditioning");
+                        }
+                    }
+                    else
+                    {
+                        // AC conditioning
+                        self.arith_ac_k[rval] = cs;
+                    }
+
+                    hpos += 2;
+                }
+            }
+
             jpeg_code::DRI =>
             {  // DRI segment
                 // define restart interval
@@ -683,7 +770,9 @@ impl JPegHeader {
 
             jpeg_code::SOF0| // SOF0 segment, coding process: baseline DCT
             jpeg_code::SOF1| // SOF1 segment, coding process: extended sequential DCT
-            jpeg_code::SOF2 =>  // SOF2 segment, coding process: progressive DCT
+            jpeg_code::SOF2| // SOF2 segment, coding process: progressive DCT
+            jpeg_code::SOF9| // SOF9 segment, coding process: arithmetic extended sequential DCT
+            jpeg_code::SOF10 =>  // SOF10 segment, coding process: arithmetic progressive DCT
             {
                 if self.jpeg_type != JPegType::Unknown
                 {
@@ -691,7 +780,9 @@ impl JPegHeader {
                 }
 
                 // set JPEG coding type
-                if btype == jpeg_code::SOF2
+                self.arithmetic = btype == jpeg_code::SOF9 || btype == jpeg_code::SOF10;
+
+                if btype == jpeg_code::SOF2 || btype == jpeg_code::SOF10
                 {
                     self.jpeg_type = JPegType::Progressive;
                 }
@@ -702,12 +793,17 @@ impl JPegHeader {
 
                 ensure_space(segment,hpos, 6).context(here!())?;
 
-                // check data precision, only 8 bit is allowed
+                // check data precision, 8 and 12 bit are allowed. The model codes coefficients
+                // of up to 11 bits, which covers every 8 bit image, but 12 bit images with fine
+                // quantization can have larger ones and fail with CoefficientOutOfRange. The
+                // predictions don't depend on the precision: the level shift of the samples is
+                // added to the neighbor edges and subtracted again in the DC prediction.
                 let lval = segment[hpos];
-                if lval != 8
+                if lval != 8 && lval != 12
                 {
                     return err_exit_code(ExitCode::UnsupportedJpeg, format!("{0} bit data precision is not supported", lval).as_str());
                 }
+                self.precision = lval;
 
                 // image size, height & component count
                 self.img_height = i32::from(b_short(segment[hpos + 1], segment[hpos + 2]));
@@ -741,6 +837,20 @@ impl JPegHeader {
                     self.cmp_info[cmp].sfv = lbits(segment[hpos + 1], 4) as i32;
                     self.cmp_info[cmp].sfh = rbits(segment[hpos + 1], 4) as i32;
 
+                    // the JPEG standard only allows sampling factors from 1 to 4, and Lepton only
+                    // supports 1 and 2. Within that range any combination works, including chroma
+                    // sampled at a higher rate than luma, since the block rows of every component
+                    // are derived from the same MCU rows.
+                    if self.cmp_info[cmp].sfv == 0 || self.cmp_info[cmp].sfh == 0
+                    {
+                        return err_exit_code(ExitCode::UnsupportedJpeg, format!("component {0} has a zero sampling factor", cmp).as_str());
+                    }
+
+                    if self.cmp_info[cmp].sfv > 4 || self.cmp_info[cmp].sfh > 4
+                    {
+                        return err_exit_code(ExitCode::SamplingBeyondFourUnsupported, format!("component {0} has sampling factors {1}x{2}, max 4 is allowed", cmp, self.cmp_info[cmp].sfv, self.cmp_info[cmp].sfh).as_str());
+                    }
+
                     if self.cmp_info[cmp].sfv > 2 || self.cmp_info[cmp].sfh > 2
                     {
                         return err_exit_code(ExitCode::SamplingBeyondTwoUnsupported, "Sampling type beyond to not supported");
@@ -758,65 +868,12 @@ impl JPegHeader {
 
             }
 
-            0xC3 => // SOF3 segment
-                {
-                    // coding process: lossless sequential
-                    return err_exit_code(ExitCode::UnsupportedJpeg,"sof3 marker found, image is coded lossless");
-                }
-
-            0xC5 => // SOF5 segment
-                {
-                    // coding process: differential sequential DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg,"sof5 marker found, image is coded diff. sequential");
-                }
-
-            0xC6 => // SOF6 segment
-                {
-                    // coding process: differential progressive DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg,"sof6 marker found, image is coded diff. progressive");
-                }
-
-            0xC7 => // SOF7 segment
-                {
-                    // coding process: differential lossless
-                    return err_exit_code(ExitCode::UnsupportedJpeg,"sof7 marker found, image is coded diff. lossless");
-                }
-
-            0xC9 => // SOF9 segment
-                {
-                    // coding process: arithmetic extended sequential DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof9 marker found, image is coded arithm. sequential");
-                }
-
-            0xCA => // SOF10 segment
-                {
-                    // coding process: arithmetic extended sequential DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof10 marker found, image is coded arithm. progressive");
-                }
-
-            0xCB => // SOF11 segment
-                {
-                    // coding process: arithmetic extended sequential DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof11 marker found, image is coded arithm. lossless");
-                }
-
-            0xCD => // SOF13 segment
-                {
-                    // coding process: arithmetic differntial sequential DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof13 marker found, image is coded arithm. diff. sequential");
-                }
-
-            0xCE => // SOF14 segment
-                {
-                    // coding process: arithmetic differential progressive DCT
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof14 marker found, image is coded arithm. diff. progressive");
-                }
-
-            0xCF => // SOF15 segment
-                {
-                    // coding process: arithmetic differntial lossless
-                    return err_exit_code(ExitCode::UnsupportedJpeg, "sof15 marker found, image is coded arithm. diff. lossless");
-                }
+            0xC3 | 0xC5..=0xC7 | 0xCB | 0xCD..=0xCF => // SOF3, SOF5-7, SOF11, SOF13-15 segments
+            {
+                return err_exit_code(
+                    ExitCode::UnsupportedCompression,
+                    format!("SOF{0} marker found, image is coded {1}", btype - jpeg_code::SOF0, unsupported_coding_process(btype)).as_str());
+            }
 
             0xE0| // APP0 segment
             0xE1| // APP1 segment
@@ -873,6 +930,21 @@ impl JPegHeader {
     }
 }
 
+/// describes the coding process of the SOF markers that we cannot recompress
+fn unsupported_coding_process(marker: u8) -> &'static str {
+    match marker {
+        0xC3 => "lossless sequential",
+        0xC5 => "differential sequential DCT",
+        0xC6 => "differential progressive DCT",
+        0xC7 => "differential lossless",
+        0xCB => "lossless sequential with arithmetic coding",
+        0xCD => "differential sequential DCT with arithmetic coding",
+        0xCE => "differential progressive DCT with arithmetic coding",
+        0xCF => "differential lossless with arithmetic coding",
+        _ => "with an unknown coding process",
+    }
+}
+
 fn ensure_space(segment: &[u8], hpos: usize, amount: usize) -> Result<()> {
     if hpos + amount > segment.len() {
         return err_exit_code(ExitCode::UnsupportedJpeg, "SOF too small");
@@ -977,3 +1049,75 @@ pub fn generate_huff_table_from_distribution(freq: &[usize; 256]) -> HuffCodes {
 
     retval
 }
+
+// the header probe should give the sampling factors and block counts without decoding the scan
+#[test]
+fn parse_header_sampling_factors() {
+    use crate::structs::synthetic_jpeg::synthetic_jpeg_header;
+
+    let read_header = |sampling: &[u8]| {
+        let file = synthetic_jpeg_header(sampling, 120, 136);
+        JPegHeader::from_reader(
+            &mut &file[..],
+            &EnabledFeatures::compat_lepton_vector_read(),
+        )
+    };
+
+    // 4:2:0
+    let header = read_header(&[0x22, 0x11, 0x11]).unwrap();
+    assert_eq!(header.cmpc, 3);
+    assert_eq!((header.cmp_info[0].sfv, header.cmp_info[0].sfh), (2, 2));
+    assert_eq!((header.cmp_info[1].sfv, header.cmp_info[1].sfh), (1, 1));
+    assert_eq!((header.cmp_info[2].sfv, header.cmp_info[2].sfh), (1, 1));
+    assert_eq!(header.cmp_info[0].bch, 2 * header.cmp_info[1].bch);
+    assert_eq!(header.cmp_info[0].bcv, 2 * header.cmp_info[1].bcv);
+
+    // 4:4:4
+    let header = read_header(&[0x11, 0x11, 0x11]).unwrap();
+    assert_eq!(header.cmpc, 3);
+    for cmp in 0..3 {
+        assert_eq!((header.cmp_info[cmp].sfv, header.cmp_info[cmp].sfh), (1, 1));
+        assert_eq!(header.cmp_info[cmp].bch, header.cmp_info[0].bch);
+        assert_eq!(header.cmp_info[cmp].bcv, header.cmp_info[0].bcv);
+    }
+    assert!(header.cmp_info[0].bch > 0 && header.cmp_info[0].bcv > 0);
+}
+
+// sampling factors outside of the legal JPEG range should be rejected with a specific error
+#[test]
+fn parse_header_illegal_sampling_factors() {
+    use crate::lepton_error::LeptonError;
+
+    let exit_code_for = |sampling: u8| {
+        let sof = [
+            0xFF,
+            jpeg_code::SOI,
+            0xFF,
+            jpeg_code::SOF0,
+            0,
+            11,
+            8,
+            0,
+            8,
+            0,
+            8,
+            1,
+            1,
+            sampling,
+            0,
+        ];
+
+        JPegHeader::from_reader(&mut &sof[..], &EnabledFeatures::compat_lepton_vector_read())
+            .err()
+            .unwrap()
+            .root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code
+    };
+
+    assert_eq!(exit_code_for(0x51), ExitCode::SamplingBeyondFourUnsupported);
+    assert_eq!(exit_code_for(0x15), ExitCode::SamplingBeyondFourUnsupported);
+    assert_eq!(exit_code_for(0x01), ExitCode::UnsupportedJpeg);
+    assert_eq!(exit_code_for(0x41), ExitCode::SamplingBeyondTwoUnsupported);
+}
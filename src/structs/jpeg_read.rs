@@ -88,7 +88,9 @@ pub fn read_scan<R: Read>(
             let mut last_dc = [0i16; 4];
 
             while sta == JPegDecodeStatus::DecodeInProgress {
-                let current_block = image_data[state.get_cmp()].get_block_mut(state.get_dpos());
+                let current_block = image_data[state.get_cmp()]
+                    .get_block_mut(state.get_dpos())
+                    .context(here!())?;
 
                 // first time through, collect the handoffs although for progressive images the offsets
                 // won't mean much, but we do need to divide the scan into sections
@@ -211,7 +213,9 @@ pub fn read_progressive_scan<R: Read>(
             jf.verify_huffman_table(true, false).context(here!())?;
 
             while sta == JPegDecodeStatus::DecodeInProgress {
-                let current_block = image_data[state.get_cmp()].get_block_mut(state.get_dpos());
+                let current_block = image_data[state.get_cmp()]
+                    .get_block_mut(state.get_dpos())
+                    .context(here!())?;
 
                 // ---> progressive DC encoding <---
 
@@ -256,7 +260,9 @@ pub fn read_progressive_scan<R: Read>(
                 let mut block = [0; 64];
 
                 while sta == JPegDecodeStatus::DecodeInProgress {
-                    let current_block = image_data[state.get_cmp()].get_block_mut(state.get_dpos());
+                    let current_block = image_data[state.get_cmp()]
+                        .get_block_mut(state.get_dpos())
+                        .context(here!())?;
 
                     if state.eobrun == 0 {
                         // only need to do something if we are not in a zero-block run
@@ -298,7 +304,9 @@ pub fn read_progressive_scan<R: Read>(
                 let mut block = [0; 64];
 
                 while sta == JPegDecodeStatus::DecodeInProgress {
-                    let current_block = image_data[state.get_cmp()].get_block_mut(state.get_dpos());
+                    let current_block = image_data[state.get_cmp()]
+                        .get_block_mut(state.get_dpos())
+                        .context(here!())?;
 
                     for bpos in jf.cs_from..jf.cs_to + 1 {
                         block[usize::from(bpos)] =
@@ -424,7 +432,9 @@ fn decode_baseline_rst<R: Read>(
             block_tr.set_transposed_from_zigzag(bpos, block[bpos]);
         }
 
-        image_data[state.get_cmp()].set_block_data(state.get_dpos(), &block_tr);
+        image_data[state.get_cmp()]
+            .set_block_data(state.get_dpos(), &block_tr)
+            .context(here!())?;
 
         // see if here is a good position to do a handoff (has to be aligned between MCU rows since we can't split any finer)
         let old_mcu = state.get_mcu();
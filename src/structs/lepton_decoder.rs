@@ -9,27 +9,57 @@ use anyhow::{Context, Result};
 use bytemuck::cast_mut;
 use wide::i32x8;
 
-use default_boxed::DefaultBoxed;
-
+use std::cell::RefCell;
 use std::cmp;
 use std::io::Read;
+use std::time::Instant;
 
-use crate::consts::UNZIGZAG_49_TR;
-use crate::enabled_features::EnabledFeatures;
-use crate::helpers::{err_exit_code, here, u16_bit_length};
-use crate::lepton_error::ExitCode;
+use crate::consts::{RASTER_TO_ZIGZAG, UNZIGZAG_49_TR};
+use crate::enabled_features::{EnabledFeatures, ModelVersion};
+use crate::helpers::{err_exit_code, err_exit_code_at, here, u16_bit_length};
+use crate::lepton_error::{add_error_location, ErrorLocation, ExitCode};
 
-use crate::metrics::Metrics;
+use crate::metrics::{take_phase_times, DecodePhase, Metrics, PhaseTimer};
 use crate::structs::{
-    block_based_image::AlignedBlock, block_based_image::BlockBasedImage, model::Model,
-    model::ModelPerColor, neighbor_summary::NeighborSummary, probability_tables::ProbabilityTables,
+    block_based_image::AlignedBlock, block_based_image::BlockBasedImage,
+    block_based_image::EMPTY_BLOCK, model::Model, model::ModelPerColor,
+    neighbor_summary::seed_neighbor_summary_cache, neighbor_summary::NeighborSummary,
+    neighbor_summary::NEIGHBOR_DATA_EMPTY, probability_tables::ProbabilityTables,
     probability_tables_set::ProbabilityTablesSet, quantization_tables::QuantizationTables,
     row_spec::RowSpec, truncate_components::*, vpx_bool_reader::VPXBoolReader,
 };
 
 use super::block_context::{BlockContext, NeighborData};
 
-// reads stream from reader and populates image_data with the decoded data
+/// The rows decoded by `lepton_decode_row_range` and whether it also returns the content hash.
+///
+/// The range covers the rows with min_y <= luma_y < max_y, exactly like the encoder, and the
+/// last thread of a full file decodes everything that is left. Each range starts with a fresh
+/// model, so only the compressed size depends on how an image is split into ranges. The decoded
+/// coefficients are identical regardless of the number of threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowRangeOptions {
+    pub min_y: i32,
+    pub max_y: i32,
+    pub is_last_thread: bool,
+    pub full_file_compression: bool,
+    /// also return the combined hash of the decoded coefficients
+    pub compute_hash: bool,
+}
+
+impl RowRangeOptions {
+    /// the luma_y where decoding stops, the last thread decodes everything that is left
+    fn end_y(&self) -> i32 {
+        if self.is_last_thread && self.full_file_compression {
+            i32::MAX
+        } else {
+            self.max_y
+        }
+    }
+}
+
+// reads stream from reader and populates image_data with the decoded data,
+// optionally returning the combined hash of the decoded coefficients.
 
 #[inline(never)] // don't inline so that the profiler can get proper data
 pub fn lepton_decode_row_range<R: Read>(
@@ -38,271 +68,628 @@ pub fn lepton_decode_row_range<R: Read>(
     trunc: &TruncateComponents,
     image_data: &mut [BlockBasedImage],
     reader: &mut R,
-    min_y: i32,
-    max_y: i32,
-    is_last_thread: bool,
-    full_file_compression: bool,
+    options: RowRangeOptions,
     features: &EnabledFeatures,
-) -> Result<Metrics> {
-    let component_size_in_blocks = trunc.get_component_sizes_in_blocks();
-    let max_coded_heights = trunc.get_max_coded_heights();
+) -> Result<(Metrics, Option<u64>)> {
+    let start = Instant::now();
+    let blocks_before = stored_block_count(image_data);
 
-    let mut is_top_row = Vec::new();
-    let mut neighbor_summary_cache = Vec::new();
+    let mut session =
+        DecodeSession::new(pts, qt, trunc, image_data, reader, options.min_y, features)
+            .context(here!())?;
 
-    // Init helper structures
-    for i in 0..image_data.len() {
-        is_top_row.push(true);
+    session.decode_until(options.end_y()).context(here!())?;
 
-        let num_non_zeros_length = (image_data[i].get_block_width() << 1) as usize;
+    Ok(finish_row_range(
+        session,
+        start,
+        blocks_before,
+        options.compute_hash,
+    ))
+}
 
-        let mut num_non_zero_list = Vec::new();
-        num_non_zero_list.resize(num_non_zeros_length, NeighborSummary::default());
+/// Same as `lepton_decode_row_range`, but reads the stream from an `AsyncRead`. Before each
+/// row enough data for the widest row is fetched, so the reads never block the executor.
+/// The decoding itself is synchronous and identical to `lepton_decode_row_range`.
+#[cfg(feature = "tokio")]
+pub async fn lepton_decode_row_range_async<R: tokio::io::AsyncRead + Unpin>(
+    pts: &ProbabilityTablesSet,
+    qt: &[QuantizationTables],
+    trunc: &TruncateComponents,
+    image_data: &mut [BlockBasedImage],
+    reader: &mut R,
+    options: RowRangeOptions,
+    features: &EnabledFeatures,
+) -> Result<(Metrics, Option<u64>)> {
+    use super::async_vpx_bool_reader::{AsyncVPXBoolReader, MAX_BYTES_PER_BLOCK};
 
-        neighbor_summary_cache.push(num_non_zero_list);
-    }
+    let start = Instant::now();
+    let blocks_before = stored_block_count(image_data);
+
+    let max_block_width = image_data
+        .iter()
+        .map(|x| x.get_block_width() as usize)
+        .max()
+        .unwrap_or(0);
+
+    // the bool reader keeps up to 4 bytes of the stream in its value
+    let bytes_per_row = max_block_width * MAX_BYTES_PER_BLOCK + 4;
+
+    let mut async_reader = AsyncVPXBoolReader::new(reader);
+    async_reader
+        .vpx_reader_fill(bytes_per_row)
+        .await
+        .context(here!())?;
 
-    let mut model = Model::default_boxed();
-    let mut bool_reader = VPXBoolReader::new(reader)?;
+    let mut session = DecodeSession::new(
+        pts,
+        qt,
+        trunc,
+        image_data,
+        async_reader,
+        options.min_y,
+        features,
+    )
+    .context(here!())?;
 
-    let mut decode_index = 0;
+    let max_y = options.end_y();
 
     loop {
-        let cur_row = RowSpec::get_row_spec_from_index(
-            decode_index,
-            &image_data[..],
-            trunc.mcu_count_vertical,
-            &max_coded_heights,
-        );
-        decode_index += 1;
+        session
+            .bool_reader
+            .get_upstream_mut()
+            .vpx_reader_fill(bytes_per_row)
+            .await
+            .context(here!())?;
 
-        if cur_row.done {
+        if !session.decode_next_row(max_y).context(here!())? {
             break;
         }
+    }
 
-        if cur_row.luma_y >= max_y && !(is_last_thread && full_file_compression) {
-            break;
-        }
+    Ok(finish_row_range(
+        session,
+        start,
+        blocks_before,
+        options.compute_hash,
+    ))
+}
 
-        if cur_row.skip {
-            continue;
-        }
+/// collects the metrics and optionally the content hash after decoding a row range
+fn finish_row_range<R: Read>(
+    mut session: DecodeSession<R>,
+    start: Instant,
+    blocks_before: usize,
+    compute_hash: bool,
+) -> (Metrics, Option<u64>) {
+    let mut metrics = session.drain_stats();
+    #[cfg(feature = "branch_visits")]
+    metrics.record_branch_visits(&session.model.branch_visits());
+    #[cfg(feature = "branch_visits")]
+    metrics.record_branch_renormalizations(&session.model.branch_renormalizations());
+    metrics.record_phase_times(take_phase_times());
+    metrics.record_blocks_processed(
+        (stored_block_count(session.get_image_data()) - blocks_before) as u64,
+        start.elapsed(),
+    );
 
-        if cur_row.luma_y < min_y {
-            continue;
-        }
+    let hash = if compute_hash {
+        Some(BlockBasedImage::combined_content_hash(
+            session.get_image_data(),
+        ))
+    } else {
+        None
+    };
 
-        decode_row_wrapper(
-            &mut model,
-            &mut bool_reader,
-            pts,
-            &mut image_data[cur_row.component],
-            &qt[cur_row.component],
-            &mut neighbor_summary_cache[cur_row.component],
-            &mut is_top_row[..],
-            &component_size_in_blocks[..],
-            cur_row.component,
-            cur_row.curr_y,
-            features,
-        )
-        .context(here!())?;
-    }
-    Ok(bool_reader.drain_stats())
+    session.recycle();
+
+    (metrics, hash)
 }
 
-#[inline(never)] // don't inline so that the profiler can get proper data
-fn decode_row_wrapper<R: Read>(
-    model: &mut Model,
-    bool_reader: &mut VPXBoolReader<R>,
+fn stored_block_count(image_data: &[BlockBasedImage]) -> usize {
+    image_data.iter().map(|x| x.get_stored_block_count()).sum()
+}
+
+/// Decodes a stream that may have been cut short, for example by an interrupted transfer.
+/// Returns the image data together with the position where decoding stopped if the
+/// stream turned out to be truncated. All rows up to that position are intact.
+pub fn lepton_decode_row_range_truncated<R: Read>(
     pts: &ProbabilityTablesSet,
-    image_data: &mut BlockBasedImage,
-    qt: &QuantizationTables,
-    neighbor_summary_cache: &mut Vec<NeighborSummary>,
-    is_top_row: &mut [bool],
-    component_size_in_blocks: &[i32],
-    component: usize,
-    curr_y: i32,
+    qt: &[QuantizationTables],
+    trunc: &TruncateComponents,
+    mut image_data: Vec<BlockBasedImage>,
+    reader: &mut R,
     features: &EnabledFeatures,
-) -> Result<()> {
-    let mut context = image_data.off_y(curr_y);
+) -> Result<(Vec<BlockBasedImage>, Option<TruncationInfo>)> {
+    let truncation = {
+        let mut session = DecodeSession::new(pts, qt, trunc, &mut image_data, reader, 0, features)
+            .context(here!())?;
 
-    let block_width = image_data.get_block_width();
-    if is_top_row[component] {
-        is_top_row[component] = false;
-        decode_row(
-            model,
-            bool_reader,
-            &qt,
-            &pts.corner[component],
-            &pts.top[component],
-            &pts.top[component],
-            image_data,
-            &mut context,
-            neighbor_summary_cache,
-            component_size_in_blocks[component],
-            features,
-        )
-        .context(here!())?;
-    } else if block_width > 1 {
-        let _bt = component;
-        decode_row(
-            model,
-            bool_reader,
-            &qt,
-            &pts.mid_left[component],
-            &pts.middle[component],
-            &pts.mid_right[component],
+        session.set_detect_truncation(true);
+        session.decode_until(i32::MAX).context(here!())?;
+        session.get_truncation()
+    };
+
+    Ok((image_data, truncation))
+}
+
+/// The allocations of a finished session that the next session on the same thread can reuse
+struct DecodeWorkspace {
+    model: Box<Model>,
+    neighbor_summary_cache: Vec<Vec<NeighborSummary>>,
+}
+
+thread_local! {
+    // None unless the thread opted in with `enable_workspace_reuse`, so that ordinary
+    // threads don't keep the memory of their last decode alive
+    static WORKSPACES: RefCell<Option<Vec<DecodeWorkspace>>> = const { RefCell::new(None) };
+}
+
+/// Makes the decode sessions that finish on the current thread keep their model and neighbor
+/// caches for the next session instead of freeing them. Meant for the long lived worker threads
+/// of a pool that decodes one file after another.
+pub fn enable_workspace_reuse() {
+    WORKSPACES.with(|w| {
+        w.borrow_mut().get_or_insert_with(Vec::new);
+    });
+}
+
+fn take_workspace() -> Option<DecodeWorkspace> {
+    WORKSPACES.with(|w| w.borrow_mut().as_mut().and_then(|v| v.pop()))
+}
+
+fn return_workspace(workspace: DecodeWorkspace) {
+    WORKSPACES.with(|w| {
+        if let Some(v) = w.borrow_mut().as_mut() {
+            v.push(workspace);
+        }
+    });
+}
+
+/// Describes the last row that was fully decoded from a truncated stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TruncationInfo {
+    /// luma_y of the last fully decoded row, -1 if no row could be decoded
+    pub luma_y: i32,
+    /// component of the last fully decoded row
+    pub component: usize,
+    /// number of rows that were fully decoded
+    pub rows_decoded: u32,
+}
+
+/// Called with the component, the dpos and the final coefficients of every block as soon as
+/// it has been decoded, in the order of the stream
+pub type BlockVisitor<'a> = dyn FnMut(usize, i32, &AlignedBlock) + 'a;
+
+/// Decoding state for a range of rows that can be resumed. The model, the arithmetic decoder
+/// and the neighbor caches are kept between calls to `decode_until`, so decoding an image in
+/// several steps gives exactly the same result as decoding it in one go.
+pub struct DecodeSession<'a, R> {
+    pts: &'a ProbabilityTablesSet,
+    qt: &'a [QuantizationTables],
+    trunc: &'a TruncateComponents,
+    image_data: &'a mut [BlockBasedImage],
+    features: &'a EnabledFeatures,
+    model: Box<Model>,
+    bool_reader: VPXBoolReader<R>,
+    neighbor_summary_cache: Vec<Vec<NeighborSummary>>,
+    is_top_row: Vec<bool>,
+    component_size_in_blocks: Vec<i32>,
+    max_coded_heights: Vec<u32>,
+    decode_index: u32,
+    min_y: i32,
+    done: bool,
+    detect_truncation: bool,
+    last_decoded: TruncationInfo,
+    truncation: Option<TruncationInfo>,
+    block_visitor: Option<&'a mut BlockVisitor<'a>>,
+}
+
+impl<'a, R: Read> DecodeSession<'a, R> {
+    /// Creates a new session. Rows above `min_y` are skipped since they are not
+    /// part of the stream read by `reader`.
+    pub fn new(
+        pts: &'a ProbabilityTablesSet,
+        qt: &'a [QuantizationTables],
+        trunc: &'a TruncateComponents,
+        image_data: &'a mut [BlockBasedImage],
+        reader: R,
+        min_y: i32,
+        features: &'a EnabledFeatures,
+    ) -> Result<Self> {
+        let num_components = image_data.len();
+
+        let (model, mut neighbor_summary_cache) = match take_workspace() {
+            Some(mut workspace) => {
+                workspace.model.reset(num_components);
+                (workspace.model, workspace.neighbor_summary_cache)
+            }
+            None => (Model::new_for_components(num_components), Vec::new()),
+        };
+
+        let mut is_top_row = Vec::new();
+        neighbor_summary_cache.resize_with(num_components, Vec::new);
+
+        // drop the time of blocks decoded on this thread outside of a row range
+        take_phase_times();
+
+        // Init helper structures
+        for i in 0..num_components {
+            is_top_row.push(true);
+
+            let num_non_zeros_length = (image_data[i].get_block_width() << 1) as usize;
+
+            let num_non_zero_list = &mut neighbor_summary_cache[i];
+            num_non_zero_list.clear();
+            num_non_zero_list.resize(num_non_zeros_length, NeighborSummary::default());
+        }
+
+        Ok(DecodeSession {
+            pts,
+            qt,
+            trunc,
             image_data,
-            &mut context,
-            neighbor_summary_cache,
-            component_size_in_blocks[component],
             features,
-        )
-        .context(here!())?;
-    } else {
-        assert!(block_width == 1, "block_width == 1");
-        decode_row(
             model,
-            bool_reader,
-            &qt,
-            &pts.width_one[component],
-            &pts.width_one[component],
-            &pts.width_one[component],
-            image_data,
-            &mut context,
+            bool_reader: VPXBoolReader::new(reader)?,
             neighbor_summary_cache,
-            component_size_in_blocks[component],
-            features,
-        )
-        .context(here!())?;
+            is_top_row,
+            component_size_in_blocks: trunc.get_component_sizes_in_blocks(),
+            max_coded_heights: trunc.get_max_coded_heights(),
+            decode_index: 0,
+            min_y,
+            done: false,
+            detect_truncation: false,
+            last_decoded: TruncationInfo {
+                luma_y: -1,
+                component: 0,
+                rows_decoded: 0,
+            },
+            truncation: None,
+            block_visitor: None,
+        })
     }
 
-    Ok(())
-}
+    /// Hands the model and the neighbor caches to the next session on this thread
+    /// if the thread reuses them, otherwise they are freed.
+    fn recycle(self) {
+        return_workspace(DecodeWorkspace {
+            model: self.model,
+            neighbor_summary_cache: self.neighbor_summary_cache,
+        });
+    }
 
-fn decode_row<R: Read>(
-    model: &mut Model,
-    bool_reader: &mut VPXBoolReader<R>,
-    qt: &QuantizationTables,
-    left_model: &ProbabilityTables,
-    middle_model: &ProbabilityTables,
-    right_model: &ProbabilityTables,
-    image_data: &mut BlockBasedImage,
-    block_context: &mut BlockContext,
-    neighbor_summary_cache: &mut [NeighborSummary],
-    component_size_in_blocks: i32,
-    features: &EnabledFeatures,
-) -> Result<()> {
-    let block_width = image_data.get_block_width();
-    if block_width > 0 {
-        parse_token::<R, false>(
-            model,
-            bool_reader,
-            image_data,
-            block_context,
-            neighbor_summary_cache,
-            qt,
-            left_model,
-            features,
-        )
-        .context(here!())?;
-        let offset = block_context.next();
+    /// Decodes all rows with luma_y below `max_y` that have not been decoded yet.
+    /// The row that reached `max_y` is not consumed, so the next call continues from it.
+    pub fn decode_until(&mut self, max_y: i32) -> Result<()> {
+        while self.decode_next_row(max_y).context(here!())? {}
+
+        Ok(())
+    }
+
+    /// Decodes the rows with luma_y below `max_y` as a continuation of the rows above `min_y`
+    /// instead of as a new band. `seed` has the summaries of the row above the first decoded row
+    /// of every component, which is what the neighbor cache would hold after decoding the rows
+    /// above. The first row then uses the same probability tables as any other row, and the
+    /// blocks above it come from the image data (or are empty if it doesn't have them). The
+    /// stream has to be encoded with the same seed by `lepton_encode_row_range_with_seed`.
+    pub fn decode_row_range_with_seed(
+        &mut self,
+        max_y: i32,
+        seed: &[Vec<NeighborSummary>],
+    ) -> Result<()> {
+        if self.last_decoded.rows_decoded != 0 {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                "neighbor summaries can only be seeded before the first row is decoded",
+            );
+        }
 
-        if offset >= component_size_in_blocks {
-            return Ok(()); // no sure if this is an error
+        if seed.len() != self.image_data.len() {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                "neighbor summary seed must have one row per component",
+            );
         }
+
+        for (i, component_seed) in seed.iter().enumerate() {
+            seed_neighbor_summary_cache(&mut self.neighbor_summary_cache[i], component_seed)
+                .context(here!())?;
+            self.is_top_row[i] = false;
+        }
+
+        self.decode_until(max_y).context(here!())
     }
 
-    for _jpeg_x in 1..block_width - 1 {
-        if middle_model.is_all_present() {
-            parse_token::<R, true>(
-                model,
-                bool_reader,
-                image_data,
-                block_context,
-                neighbor_summary_cache,
-                qt,
-                middle_model,
-                features,
-            )
-            .context(here!())?;
-        } else {
-            parse_token::<R, false>(
-                model,
-                bool_reader,
-                image_data,
-                block_context,
-                neighbor_summary_cache,
-                qt,
-                middle_model,
-                features,
-            )
-            .context(here!())?;
+    /// Decodes the next row of the stream if its luma_y is below `max_y`. Returns false
+    /// without consuming anything if all rows are done or the next row reached `max_y`.
+    fn decode_next_row(&mut self, max_y: i32) -> Result<bool> {
+        loop {
+            if self.done {
+                return Ok(false);
+            }
+
+            let cur_row = RowSpec::get_row_spec_from_index(
+                self.decode_index,
+                &self.image_data[..],
+                self.trunc.mcu_count_vertical,
+                &self.max_coded_heights,
+            );
+
+            if cur_row.done {
+                self.done = true;
+                return Ok(false);
+            }
+
+            if cur_row.luma_y >= max_y {
+                return Ok(false);
+            }
+
+            self.decode_index += 1;
+
+            if cur_row.skip {
+                continue;
+            }
+
+            if cur_row.luma_y < self.min_y {
+                continue;
+            }
+
+            let result = self
+                .decode_row_wrapper(cur_row.component, cur_row.curr_y)
+                .map_err(|e| {
+                    add_error_location(
+                        e,
+                        ErrorLocation {
+                            component: Some(cur_row.component),
+                            luma_y: Some(cur_row.luma_y),
+                            block_y: Some(cur_row.curr_y),
+                            ..Default::default()
+                        },
+                    )
+                });
+
+            if self.detect_truncation && self.bool_reader.is_past_eof() {
+                // the row that ran into the end of the stream is incomplete (and may have
+                // failed because of the made up data), so stop at the previous one
+                self.truncation = Some(self.last_decoded);
+                self.done = true;
+                return Ok(false);
+            }
+
+            result.context(here!())?;
+
+            self.last_decoded = TruncationInfo {
+                luma_y: cur_row.luma_y,
+                component: cur_row.component,
+                rows_decoded: self.last_decoded.rows_decoded + 1,
+            };
+
+            return Ok(true);
         }
+    }
+
+    /// Calls `visitor` for every block decoded from now on, which saves a second pass over the
+    /// image for code that only needs to look at each block once
+    pub fn set_block_visitor(&mut self, visitor: &'a mut BlockVisitor<'a>) {
+        self.block_visitor = Some(visitor);
+    }
 
-        let offset = block_context.next();
+    /// If set, decoding stops at the first row that runs into the end of the stream
+    /// instead of decoding the rest of the image from made up data.
+    pub fn set_detect_truncation(&mut self, detect_truncation: bool) {
+        self.detect_truncation = detect_truncation;
+    }
 
-        if offset >= component_size_in_blocks {
-            return Ok(()); // no sure if this is an error
+    /// returns where decoding stopped if truncation detection was enabled and the stream was truncated
+    pub fn get_truncation(&self) -> Option<TruncationInfo> {
+        self.truncation
+    }
+
+    /// true if all rows of the image have been decoded
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// returns the image data decoded so far
+    pub fn get_image_data(&self) -> &[BlockBasedImage] {
+        self.image_data
+    }
+
+    /// Decodes just enough of the stream to cover the region of `w_blocks` x `h_blocks` luma
+    /// blocks starting at (`x_blocks`, `y_blocks`), and returns a copy of the region for every
+    /// component. Decoding stops after the last MCU row touching the region, but all rows above
+    /// it still have to be decoded since the model depends on them. Chroma components get the
+    /// blocks covering the same area of the image, rounded outwards to whole blocks.
+    ///
+    /// The region is clipped to the image, and the full image data can be dropped once
+    /// the session is no longer needed.
+    pub fn decode_region(
+        &mut self,
+        x_blocks: i32,
+        y_blocks: i32,
+        w_blocks: i32,
+        h_blocks: i32,
+    ) -> Result<Vec<BlockBasedImage>> {
+        if x_blocks < 0 || y_blocks < 0 || w_blocks < 0 || h_blocks < 0 {
+            return err_exit_code(ExitCode::SyntaxError, "region must not be negative");
         }
+
+        let luma_width = self.image_data[0].get_block_width();
+        let luma_height = self.image_data[0].get_original_height();
+
+        let x0 = x_blocks.min(luma_width);
+        let y0 = y_blocks.min(luma_height);
+        let x1 = x_blocks.saturating_add(w_blocks).min(luma_width);
+        let y1 = y_blocks.saturating_add(h_blocks).min(luma_height);
+
+        // chroma rows of an MCU row are decoded before its luma rows and share its luma_y,
+        // so this also covers the chroma blocks of the last MCU row in the region
+        self.decode_until(y1).context(here!())?;
+
+        let mut region = Vec::with_capacity(self.image_data.len());
+        for image in self.image_data.iter() {
+            let width = image.get_block_width();
+            let height = image.get_original_height();
+
+            // scale the luma coordinates to this component, rounding outwards
+            let cx0 = x0 * width / luma_width;
+            let cy0 = y0 * height / luma_height;
+            let cx1 = (x1 * width + luma_width - 1) / luma_width;
+            let cy1 = (y1 * height + luma_height - 1) / luma_height;
+
+            region.push(
+                image
+                    .crop(cx0, cy0, cx1 - cx0, cy1 - cy0)
+                    .context(here!())?,
+            );
+        }
+
+        Ok(region)
     }
 
-    if block_width > 1 {
-        if right_model.is_all_present() {
-            parse_token::<R, true>(
-                model,
-                bool_reader,
-                image_data,
-                block_context,
-                neighbor_summary_cache,
-                qt,
-                right_model,
-                features,
+    pub fn drain_stats(&mut self) -> Metrics {
+        self.bool_reader.drain_stats()
+    }
+}
+
+impl<'a, R: Read> DecodeSession<'a, R> {
+    #[inline(never)] // don't inline so that the profiler can get proper data
+    fn decode_row_wrapper(&mut self, component: usize, curr_y: i32) -> Result<()> {
+        let pts = self.pts;
+        let mut context = self.image_data[component].off_y(curr_y);
+
+        let block_width = self.image_data[component].get_block_width();
+        if self.is_top_row[component] {
+            self.is_top_row[component] = false;
+            self.decode_row(
+                component,
+                &mut context,
+                &pts.corner[component],
+                &pts.top[component],
+                &pts.top[component],
+            )
+            .context(here!())?;
+        } else if block_width > 1 {
+            self.decode_row(
+                component,
+                &mut context,
+                &pts.mid_left[component],
+                &pts.middle[component],
+                &pts.mid_right[component],
             )
             .context(here!())?;
         } else {
-            parse_token::<R, false>(
-                model,
-                bool_reader,
-                image_data,
-                block_context,
-                neighbor_summary_cache,
-                qt,
-                right_model,
-                features,
+            assert!(block_width == 1, "block_width == 1");
+            self.decode_row(
+                component,
+                &mut context,
+                &pts.width_one[component],
+                &pts.width_one[component],
+                &pts.width_one[component],
             )
             .context(here!())?;
         }
 
-        block_context.next();
+        Ok(())
     }
-    Ok(())
-}
 
-#[inline(never)] // don't inline so that the profiler can get proper data
-fn parse_token<R: Read, const ALL_PRESENT: bool>(
-    model: &mut Model,
-    bool_reader: &mut VPXBoolReader<R>,
-    image_data: &mut BlockBasedImage,
-    context: &mut BlockContext,
-    neighbor_summary_cache: &mut [NeighborSummary],
-    qt: &QuantizationTables,
-    pt: &ProbabilityTables,
-    features: &EnabledFeatures,
-) -> Result<()> {
-    debug_assert!(pt.is_all_present() == ALL_PRESENT);
+    fn decode_row(
+        &mut self,
+        component: usize,
+        block_context: &mut BlockContext,
+        left_model: &ProbabilityTables,
+        middle_model: &ProbabilityTables,
+        right_model: &ProbabilityTables,
+    ) -> Result<()> {
+        let block_width = self.image_data[component].get_block_width();
+        let component_size_in_blocks = self.component_size_in_blocks[component];
+        if block_width > 0 {
+            self.parse_token::<false>(component, block_context, left_model)
+                .context(here!())?;
+            let offset = block_context.advance();
 
-    let neighbors =
-        context.get_neighbor_data::<ALL_PRESENT>(image_data, neighbor_summary_cache, pt);
+            if offset >= component_size_in_blocks {
+                return Ok(()); // no sure if this is an error
+            }
+        }
 
-    let (output, ns) =
-        read_coefficient_block::<ALL_PRESENT, R>(pt, &neighbors, model, bool_reader, qt, features)?;
+        for _jpeg_x in 1..block_width - 1 {
+            if middle_model.is_all_present() {
+                self.parse_token::<true>(component, block_context, middle_model)
+                    .context(here!())?;
+            } else {
+                self.parse_token::<false>(component, block_context, middle_model)
+                    .context(here!())?;
+            }
 
-    context.set_neighbor_summary_here(neighbor_summary_cache, ns);
+            let offset = block_context.advance();
 
-    image_data.append_block(output);
+            if offset >= component_size_in_blocks {
+                return Ok(()); // no sure if this is an error
+            }
+        }
 
-    Ok(())
+        if block_width > 1 {
+            if right_model.is_all_present() {
+                self.parse_token::<true>(component, block_context, right_model)
+                    .context(here!())?;
+            } else {
+                self.parse_token::<false>(component, block_context, right_model)
+                    .context(here!())?;
+            }
+
+            block_context.advance();
+        }
+        Ok(())
+    }
+
+    #[inline(never)] // don't inline so that the profiler can get proper data
+    fn parse_token<const ALL_PRESENT: bool>(
+        &mut self,
+        component: usize,
+        context: &mut BlockContext,
+        pt: &ProbabilityTables,
+    ) -> Result<()> {
+        debug_assert!(pt.is_all_present() == ALL_PRESENT);
+
+        let image_data = &mut self.image_data[component];
+        let neighbor_summary_cache = &mut self.neighbor_summary_cache[component];
+
+        let neighbors =
+            context.get_neighbor_data::<ALL_PRESENT>(image_data, neighbor_summary_cache, pt);
+
+        let (output, ns) = read_coefficient_block::<ALL_PRESENT, R>(
+            pt,
+            &neighbors,
+            &mut self.model,
+            &mut self.bool_reader,
+            &self.qt[component],
+            self.features,
+        )
+        .map_err(|e| {
+            let block_width = image_data.get_block_width();
+            add_error_location(
+                e,
+                ErrorLocation {
+                    block_x: Some(context.get_here_index() % block_width),
+                    block_y: Some(context.get_here_index() / block_width),
+                    ..Default::default()
+                },
+            )
+        })?;
+
+        context.set_neighbor_summary_here(neighbor_summary_cache, ns);
+
+        image_data.append_block(output).context(here!())?;
+
+        if let Some(visitor) = &mut self.block_visitor {
+            let dpos = context.get_here_index();
+            visitor(pt.get_component(), dpos, image_data.get_block(dpos));
+        }
+
+        Ok(())
+    }
 }
 
 /// Reads the 8x8 coefficient block from the bit reader, taking into account the neighboring
@@ -317,9 +704,41 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
     bool_reader: &mut VPXBoolReader<R>,
     qt: &QuantizationTables,
     features: &EnabledFeatures,
+) -> Result<(AlignedBlock, NeighborSummary)> {
+    let _timer = PhaseTimer::start(DecodePhase::ReadCoefficientBlock);
+
+    // the longest coefficient of the model version is a constant for the coding functions,
+    // so that V1 files keep the 16 bit arithmetic
+    match features.model_version {
+        ModelVersion::V1 => read_coefficients::<
+            ALL_PRESENT,
+            { ModelVersion::V1.max_coefficient_bits() },
+            R,
+        >(pt, neighbor_data, model, bool_reader, qt, features),
+        ModelVersion::V1WideCoefficients => {
+            read_coefficients::<
+                ALL_PRESENT,
+                { ModelVersion::V1WideCoefficients.max_coefficient_bits() },
+                R,
+            >(pt, neighbor_data, model, bool_reader, qt, features)
+        }
+    }
+}
+
+fn read_coefficients<const ALL_PRESENT: bool, const MAX_BITS: usize, R: Read>(
+    pt: &ProbabilityTables,
+    neighbor_data: &NeighborData,
+    model: &mut Model,
+    bool_reader: &mut VPXBoolReader<R>,
+    qt: &QuantizationTables,
+    features: &EnabledFeatures,
 ) -> Result<(AlignedBlock, NeighborSummary)> {
     let model_per_color = model.get_per_color(pt);
 
+    // attribute the bits of this block to its component in the compression stats
+    bool_reader.set_stats_color_index(pt.get_component());
+    bool_reader.set_stats_zigzag_index(None);
+
     // First we read the 49 inner coefficients
 
     // calculate the predictor context bin based on the neighbors
@@ -334,9 +753,21 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
 
     if num_non_zeros_7x7 > 49 {
         // most likely a stream or model synchronization error
-        return err_exit_code(ExitCode::StreamInconsistent, "numNonzeros7x7 > 49");
+        return err_exit_code_at(
+            ExitCode::StreamInconsistent,
+            "numNonzeros7x7 > 49",
+            ErrorLocation {
+                component: Some(pt.get_component()),
+                ..Default::default()
+            },
+        );
     }
 
+    // The AC coefficients themselves need no range check: their length is unary coded up to
+    // MAX_BITS, so even a corrupt stream can't produce a magnitude above 2047 (or 32767 for
+    // the wide model versions), which is what the context calculations for the following
+    // blocks rely on.
+
     let mut output = AlignedBlock::default();
     let mut raster = [i32x8::ZERO; 8];
     let raster_col: &mut [i32; 64] = cast_mut(&mut raster);
@@ -349,11 +780,13 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
     let mut num_non_zeros_7x7_remaining = num_non_zeros_7x7 as usize;
 
     if num_non_zeros_7x7_remaining > 0 {
-        let best_priors = pt.calc_coefficient_context_7x7_aavg_block::<ALL_PRESENT>(
-            neighbor_data.left,
-            neighbor_data.above,
-            neighbor_data.above_left,
-        );
+        let best_priors = pt
+            .calc_coefficient_context_7x7_aavg_block::<ALL_PRESENT, MAX_BITS>(
+                neighbor_data.left,
+                neighbor_data.above,
+                neighbor_data.above_left,
+            )
+            .context(here!())?;
 
         // calculate the bin we are using for the number of non-zeros
         let mut num_non_zeros_bin =
@@ -363,8 +796,12 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
         for (zig49, &coord_tr) in UNZIGZAG_49_TR.iter().enumerate() {
             let best_prior_bit_length = u16_bit_length(best_priors[coord_tr as usize]);
 
+            bool_reader.set_stats_zigzag_index(Some(usize::from(zigzag_from_transposed(
+                usize::from(coord_tr),
+            ))));
+
             let coef = model_per_color
-                .read_coef(
+                .read_coef::<MAX_BITS, R>(
                     bool_reader,
                     zig49,
                     num_non_zeros_bin,
@@ -400,16 +837,36 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
     }
 
     if num_non_zeros_7x7_remaining > 0 {
-        return err_exit_code(
+        // ran past the last coefficient of the 7x7 block
+        return err_exit_code_at(
             ExitCode::StreamInconsistent,
             "not enough nonzeros in 7x7 block",
+            ErrorLocation {
+                component: Some(pt.get_component()),
+                zigzag_index: Some(zigzag_from_transposed(usize::from(UNZIGZAG_49_TR[48]))),
+                ..Default::default()
+            },
+        );
+    }
+
+    // the coefficients that were actually set have to add up to the decoded count, otherwise
+    // the model is out of sync with the stream
+    #[cfg(debug_assertions)]
+    {
+        let num_set = (8..64)
+            .filter(|&i| i & 7 != 0 && output.get_coefficient(i) != 0)
+            .count();
+        debug_assert_eq!(
+            num_set,
+            usize::from(num_non_zeros_7x7),
+            "decoded 7x7 coefficients don't match the non-zero count"
         );
     }
 
     // step 2, read the edge coefficients
     // Here we produce the first part of edge DCT coefficients predictions for neighborhood blocks
     // and build transposed raster of dequantized DCT coefficients with 0 in DC
-    let (horiz_pred, vert_pred) = decode_edge::<R, ALL_PRESENT>(
+    let (horiz_pred, vert_pred) = decode_edge::<R, ALL_PRESENT, MAX_BITS>(
         neighbor_data,
         model_per_color,
         bool_reader,
@@ -424,26 +881,42 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
 
     // step 3, read the DC coefficient (0,0 of the block)
     let q0 = qt.get_quantization_table()[0] as i32;
-    let predicted_dc = pt.adv_predict_dc_pix::<ALL_PRESENT>(&raster, q0, &neighbor_data, features);
+    let predicted_dc = {
+        let _timer = PhaseTimer::start(DecodePhase::PredictDC);
+        pt.adv_predict_dc_pix::<ALL_PRESENT>(&raster, q0, &neighbor_data, features)
+    };
+
+    bool_reader.set_stats_zigzag_index(Some(0));
 
     let coef = model
-        .read_dc(
+        .read_dc::<MAX_BITS, R>(
             bool_reader,
             pt.get_color_index(),
             predicted_dc.uncertainty,
             predicted_dc.uncertainty2,
+            features.dc_uncertainty_bins,
         )
         .context(here!())?;
-    output.set_dc(ProbabilityTables::adv_predict_or_unpredict_dc(
+    let dc = ProbabilityTables::adv_predict_or_unpredict_dc(
         coef,
         true,
         predicted_dc.predicted_dc,
-    ) as i16);
+        MAX_BITS,
+    );
+
+    // For analysis we can keep the residual as stored in the stream. The prediction still has
+    // to be calculated since the DC model context depends on it, and the neighbor summary
+    // needs the real DC so that the following blocks decode correctly.
+    output.set_dc(if features.decode_dc_residuals {
+        coef
+    } else {
+        dc as i16
+    });
 
     // neighbor summary is used as a predictor for the next block
     let neighbor_summary = NeighborSummary::new(
         &predicted_dc.advanced_predict_dc_pixels_sans_dc,
-        output.get_dc() as i32 * q0,
+        dc * q0,
         num_non_zeros_7x7,
         horiz_pred,
         vert_pred,
@@ -453,8 +926,76 @@ pub fn read_coefficient_block<const ALL_PRESENT: bool, R: Read>(
     Ok((output, neighbor_summary))
 }
 
-//#[inline(never)] // don't inline so that the profiler can get proper data
-fn decode_edge<R: Read, const ALL_PRESENT: bool>(
+/// The already decoded neighbors of a block, in the transposed order used during coding
+#[derive(Clone, Copy)]
+pub struct BlockNeighbors<'a> {
+    pub left: &'a AlignedBlock,
+    pub above: &'a AlignedBlock,
+    pub above_left: &'a AlignedBlock,
+}
+
+/// Convenience wrapper around `read_coefficient_block` that takes the neighboring blocks
+/// directly and builds the `NeighborData` and the neighbor summaries from them. Neighbors
+/// that are not present according to `pt` are ignored.
+pub fn decode_block_standalone<R: Read>(
+    neighbors: BlockNeighbors,
+    pt: &ProbabilityTables,
+    model: &mut Model,
+    bool_reader: &mut VPXBoolReader<R>,
+    qt: &QuantizationTables,
+    features: &EnabledFeatures,
+) -> Result<(AlignedBlock, NeighborSummary)> {
+    let BlockNeighbors {
+        left,
+        above,
+        above_left,
+    } = neighbors;
+
+    let left_summary = NeighborSummary::from_block(left, qt, features);
+    let above_summary = NeighborSummary::from_block(above, qt, features);
+
+    let neighbor_data = NeighborData {
+        above_left: if pt.is_all_present() {
+            above_left
+        } else {
+            &EMPTY_BLOCK
+        },
+        above: if pt.is_above_present() {
+            above
+        } else {
+            &EMPTY_BLOCK
+        },
+        left: if pt.is_left_present() {
+            left
+        } else {
+            &EMPTY_BLOCK
+        },
+        neighbor_context_above: if pt.is_above_present() {
+            &above_summary
+        } else {
+            &NEIGHBOR_DATA_EMPTY
+        },
+        neighbor_context_left: if pt.is_left_present() {
+            &left_summary
+        } else {
+            &NEIGHBOR_DATA_EMPTY
+        },
+    };
+
+    if pt.is_all_present() {
+        read_coefficient_block::<true, R>(pt, &neighbor_data, model, bool_reader, qt, features)
+    } else {
+        read_coefficient_block::<false, R>(pt, &neighbor_data, model, bool_reader, qt, features)
+    }
+}
+
+/// converts a coefficient index in the transposed order (x * 8 + y) to the JPEG zigzag order
+fn zigzag_from_transposed(coord_tr: usize) -> u8 {
+    RASTER_TO_ZIGZAG[(coord_tr & 7) * 8 + (coord_tr >> 3)]
+}
+
+#[inline(never)] // don't inline so that the profiler can get proper data
+fn decode_edge<R: Read, const ALL_PRESENT: bool, const MAX_BITS: usize>(
     neighbor_data: &NeighborData,
     model_per_color: &mut ModelPerColor,
     bool_reader: &mut VPXBoolReader<R>,
@@ -466,13 +1007,15 @@ fn decode_edge<R: Read, const ALL_PRESENT: bool>(
     eob_x: u8,
     eob_y: u8,
 ) -> Result<(i32x8, i32x8)> {
+    let _timer = PhaseTimer::start(DecodePhase::DecodeEdge);
+
     let num_non_zeros_bin = (num_non_zeros_7x7 + 3) / 7;
 
     // get predictors for edge coefficients of the current block
     let (curr_horiz_pred, curr_vert_pred) =
         ProbabilityTables::predict_current_edges(neighbor_data, raster);
 
-    decode_one_edge::<R, ALL_PRESENT, true>(
+    decode_one_edge::<R, ALL_PRESENT, MAX_BITS, true>(
         model_per_color,
         bool_reader,
         &curr_horiz_pred.to_array(),
@@ -483,7 +1026,7 @@ fn decode_edge<R: Read, const ALL_PRESENT: bool>(
         eob_x,
         cast_mut(raster),
     )?;
-    decode_one_edge::<R, ALL_PRESENT, false>(
+    decode_one_edge::<R, ALL_PRESENT, MAX_BITS, false>(
         model_per_color,
         bool_reader,
         &curr_vert_pred.to_array(),
@@ -501,7 +1044,12 @@ fn decode_edge<R: Read, const ALL_PRESENT: bool>(
     Ok((next_horiz_pred, next_vert_pred))
 }
 
-fn decode_one_edge<R: Read, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
+fn decode_one_edge<
+    R: Read,
+    const ALL_PRESENT: bool,
+    const MAX_BITS: usize,
+    const HORIZONTAL: bool,
+>(
     model_per_color: &mut ModelPerColor,
     bool_reader: &mut VPXBoolReader<R>,
     pred: &[i32; 8],
@@ -512,6 +1060,8 @@ fn decode_one_edge<R: Read, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
     est_eob: u8,
     raster: &mut [i32; 64],
 ) -> Result<()> {
+    bool_reader.set_stats_zigzag_index(None);
+
     let mut num_non_zeros_edge = model_per_color
         .read_non_zero_edge_count::<R, HORIZONTAL>(bool_reader, est_eob, num_non_zeros_bin)
         .context(here!())?;
@@ -528,6 +1078,7 @@ fn decode_one_edge<R: Read, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
     }
 
     let mut coord_tr = delta;
+    let mut noise_coefficients = 0;
 
     for _lane in 0..7 {
         if num_non_zeros_edge == 0 {
@@ -537,12 +1088,15 @@ fn decode_one_edge<R: Read, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
         let best_prior =
             pt.calc_coefficient_context8_lak::<ALL_PRESENT, HORIZONTAL>(qt, coord_tr, pred);
 
-        let coef = model_per_color.read_edge_coefficient(
+        bool_reader.set_stats_zigzag_index(Some(usize::from(zigzag_from_transposed(coord_tr))));
+
+        let coef = model_per_color.read_edge_coefficient::<MAX_BITS, R>(
             bool_reader,
             qt,
             zig15offset,
             num_non_zeros_edge,
             best_prior,
+            &mut noise_coefficients,
         )?;
 
         if coef != 0 {
@@ -556,9 +1110,1377 @@ fn decode_one_edge<R: Read, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
         zig15offset += 1;
     }
 
+    bool_reader.record_edge_noise_coefficients(pt.get_component(), noise_coefficients);
+
     if num_non_zeros_edge != 0 {
-        return err_exit_code(ExitCode::StreamInconsistent, "StreamInconsistent");
+        // ran past the last coefficient of the edge
+        return err_exit_code_at(
+            ExitCode::StreamInconsistent,
+            "StreamInconsistent",
+            ErrorLocation {
+                component: Some(pt.get_component()),
+                zigzag_index: Some(zigzag_from_transposed(coord_tr - delta)),
+                ..Default::default()
+            },
+        );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+use crate::structs::lepton_format::LeptonHeader;
+
+// a 4:2:0 image with partial MCUs at the right and bottom edges
+#[cfg(test)]
+fn color_jpeg() -> Vec<u8> {
+    crate::structs::synthetic_jpeg::synthetic_jpeg(&[0x22, 0x11, 0x11], 120, 136)
+}
+
+#[cfg(test)]
+fn grayscale_jpeg() -> Vec<u8> {
+    crate::structs::synthetic_jpeg::synthetic_jpeg(&[0x11], 64, 56)
+}
+
+/// reads a JPEG and encodes it as a single lepton stream
+#[cfg(test)]
+fn read_and_encode(jpeg: &[u8]) -> (LeptonHeader, Vec<QuantizationTables>, Vec<u8>) {
+    use crate::structs::lepton_encoder::lepton_encode_row_range;
+    use crate::structs::lepton_format::read_jpeg;
+    use std::io::Cursor;
+
+    let features = EnabledFeatures::compat_lepton_vector_write();
+
+    let (lh, image_data) = read_jpeg(&mut Cursor::new(jpeg), &features, 1, |_| {}).unwrap();
+
+    let pts = ProbabilityTablesSet::new();
+    let mut qt = Vec::new();
+    for i in 0..lh.jpeg_header.cmpc {
+        qt.push(QuantizationTables::new(&lh.jpeg_header, i));
+    }
+
+    let mut encoded = Vec::new();
+    lepton_encode_row_range(
+        &pts,
+        &qt,
+        &image_data,
+        &mut encoded,
+        0,
+        &lh.truncate_components,
+        0,
+        i32::MAX,
+        true,
+        true,
+        &features,
+    )
+    .unwrap();
+
+    (lh, qt, encoded)
+}
+
+#[cfg(test)]
+fn new_image_data(lh: &LeptonHeader) -> Vec<BlockBasedImage> {
+    let mut image_data = Vec::new();
+    for i in 0..lh.jpeg_header.cmpc {
+        image_data.push(
+            BlockBasedImage::new(
+                &lh.jpeg_header,
+                i,
+                0,
+                lh.jpeg_header.cmp_info[0].bcv,
+                &EnabledFeatures::compat_lepton_vector_read(),
+            )
+            .unwrap(),
+        );
+    }
+    image_data
+}
+
+#[cfg(test)]
+fn encode_and_decode_in_steps(jpeg: &[u8], steps: &[i32]) -> Vec<BlockBasedImage> {
+    let (lh, qt, encoded) = read_and_encode(jpeg);
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let mut output = new_image_data(&lh);
+
+    let mut session = DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut output,
+        &encoded[..],
+        0,
+        &features,
+    )
+    .unwrap();
+
+    for &max_y in steps {
+        assert!(!session.is_done());
+        session.decode_until(max_y).unwrap();
+    }
+    session.decode_until(i32::MAX).unwrap();
+    assert!(session.is_done());
+
+    output
+}
+
+// decoding in several steps should give exactly the same result as a single full decode
+#[test]
+fn decode_session_resume() {
+    let full = encode_and_decode_in_steps(&color_jpeg(), &[]);
+    let half = full[0].get_original_height() / 2;
+    let steps = encode_and_decode_in_steps(&color_jpeg(), &[half, half, half + 1]);
+
+    assert_eq!(full.len(), steps.len());
+    for (a, b) in full.iter().zip(steps.iter()) {
+        let blocks = a.get_block_width() * a.get_original_height();
+        for dpos in 0..blocks {
+            assert_eq!(a.get_block(dpos).get_block(), b.get_block(dpos).get_block());
+        }
+    }
+}
+
+// the visitor sees every block exactly once, in order within each component, and with the same
+// coefficients that end up in the image
+#[test]
+fn decode_block_visitor() {
+    let (lh, qt, encoded) = read_and_encode(&color_jpeg());
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let mut output = new_image_data(&lh);
+
+    let mut visited = Vec::new();
+    let mut visitor = |component: usize, dpos: i32, block: &AlignedBlock| {
+        visited.push((component, dpos, block.clone()));
+    };
+
+    {
+        let mut session = DecodeSession::new(
+            &pts,
+            &qt,
+            &lh.truncate_components,
+            &mut output,
+            &encoded[..],
+            0,
+            &features,
+        )
+        .unwrap();
+
+        session.set_block_visitor(&mut visitor);
+        session.decode_until(i32::MAX).unwrap();
+    }
+
+    let mut next_dpos = vec![0; output.len()];
+    for (component, dpos, block) in visited {
+        assert_eq!(dpos, next_dpos[component]);
+        next_dpos[component] += 1;
+
+        assert_eq!(&block, output[component].get_block(dpos));
+    }
+
+    for (image, count) in output.iter().zip(next_dpos) {
+        assert_eq!(count, image.get_block_width() * image.get_original_height());
+    }
+}
+
+// images that take their buffers from a pool decode the same way, and the buffers given back
+// after the first decode are used again for the second one instead of allocating new ones
+#[test]
+fn decode_with_block_buffer_pool() {
+    use crate::structs::block_based_image::BlockBufferPool;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockPool {
+        free: Mutex<Vec<Vec<AlignedBlock>>>,
+        allocated: Mutex<Vec<*const AlignedBlock>>,
+        reused: Mutex<Vec<*const AlignedBlock>>,
+    }
+
+    impl BlockBufferPool for MockPool {
+        fn take(&self, capacity: usize) -> Vec<AlignedBlock> {
+            match self.free.lock().unwrap().pop() {
+                Some(buffer) => {
+                    self.reused.lock().unwrap().push(buffer.as_ptr());
+                    buffer
+                }
+                None => {
+                    let buffer = Vec::with_capacity(capacity);
+                    self.allocated.lock().unwrap().push(buffer.as_ptr());
+                    buffer
+                }
+            }
+        }
+
+        fn recycle(&self, buffer: Vec<AlignedBlock>) {
+            self.free.lock().unwrap().push(buffer);
+        }
+    }
+
+    let (lh, qt, encoded) = read_and_encode(&color_jpeg());
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let expected = encode_and_decode_in_steps(&color_jpeg(), &[]);
+
+    let pool = MockPool::default();
+
+    for _ in 0..2 {
+        let mut output: Vec<_> = (0..lh.jpeg_header.cmpc)
+            .map(|i| {
+                BlockBasedImage::new_in(
+                    &lh.jpeg_header,
+                    i,
+                    0,
+                    lh.jpeg_header.cmp_info[0].bcv,
+                    &features,
+                    &pool,
+                )
+                .unwrap()
+            })
+            .collect();
+
+        lepton_decode_row_range(
+            &pts,
+            &qt,
+            &lh.truncate_components,
+            &mut output,
+            &mut &encoded[..],
+            RowRangeOptions {
+                min_y: 0,
+                max_y: i32::MAX,
+                is_last_thread: true,
+                full_file_compression: true,
+                compute_hash: false,
+            },
+            &features,
+        )
+        .unwrap();
+
+        assert_eq!(
+            BlockBasedImage::combined_content_hash(&output),
+            BlockBasedImage::combined_content_hash(&expected)
+        );
+
+        for image in output {
+            image.recycle(&pool);
+        }
+    }
+
+    let mut allocated = pool.allocated.into_inner().unwrap();
+    let mut reused = pool.reused.into_inner().unwrap();
+    assert_eq!(allocated.len(), lh.jpeg_header.cmpc);
+
+    allocated.sort();
+    reused.sort();
+    assert_eq!(allocated, reused);
+}
+
+// the content hash is used as a golden value, so it must never change
+#[test]
+fn decode_content_hash() {
+    let (lh, qt, encoded) = read_and_encode(&color_jpeg());
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let mut output = new_image_data(&lh);
+
+    let (_, hash) = lepton_decode_row_range(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut output,
+        &mut &encoded[..],
+        RowRangeOptions {
+            min_y: 0,
+            max_y: i32::MAX,
+            is_last_thread: true,
+            full_file_compression: true,
+            compute_hash: true,
+        },
+        &features,
+    )
+    .unwrap();
+
+    assert_eq!(hash, Some(0x8b654422f0f81ae0));
+}
+
+// truncated streams should recover more rows the more data is available, and the
+// recovered rows should match the full decode
+#[test]
+fn decode_truncated_stream() {
+    let (lh, qt, encoded) = read_and_encode(&color_jpeg());
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+
+    let (full, truncation) = lepton_decode_row_range_truncated(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        new_image_data(&lh),
+        &mut &encoded[..],
+        &features,
+    )
+    .unwrap();
+    assert_eq!(truncation, None);
+
+    let mut last_rows_decoded = 0;
+    for fraction in 1..8 {
+        let len = encoded.len() * fraction / 8;
+
+        let (partial, truncation) = lepton_decode_row_range_truncated(
+            &pts,
+            &qt,
+            &lh.truncate_components,
+            new_image_data(&lh),
+            &mut &encoded[..len],
+            &features,
+        )
+        .unwrap();
+
+        let truncation = truncation.unwrap();
+        assert!(truncation.rows_decoded >= last_rows_decoded);
+        last_rows_decoded = truncation.rows_decoded;
+
+        // all rows of the luma component up to the last decoded one should be intact
+        // (chroma rows of an MCU row are decoded before the luma rows)
+        let luma_rows = if truncation.component == 0 {
+            truncation.luma_y + 1
+        } else {
+            truncation.luma_y
+        };
+        let luma_blocks = full[0].get_block_width() * luma_rows;
+        for dpos in 0..luma_blocks {
+            assert_eq!(
+                full[0].get_block(dpos).get_block(),
+                partial[0].get_block(dpos).get_block()
+            );
+        }
+    }
+
+    assert!(last_rows_decoded > 0);
+}
+
+// the standalone decoder should read back the blocks written by the encoder and produce the
+// same neighbor summaries, both for the top-left corner and for a block with all neighbors
+#[test]
+fn decode_block_standalone_roundtrip() {
+    use crate::structs::lepton_encoder::write_coefficient_block;
+    use crate::structs::vpx_bool_writer::VPXBoolWriter;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([3u8; 32]);
+    let mut random_block = || {
+        let mut block = AlignedBlock::default();
+        for i in 0..64 {
+            if rng.gen_range(0..3) == 0 {
+                block.set_coefficient(i, rng.gen_range(-50..=50));
+            }
+        }
+        block
+    };
+
+    let left = random_block();
+    let above = random_block();
+    let above_left = random_block();
+    let here = random_block();
+
+    let qt = QuantizationTables::new_from_table(&[2; 64]);
+    let features = EnabledFeatures::compat_lepton_vector_read();
+
+    let corner = ProbabilityTables::new(0, false, false);
+    let middle = ProbabilityTables::new(0, true, true);
+
+    let left_summary = NeighborSummary::from_block(&left, &qt, &features);
+    let above_summary = NeighborSummary::from_block(&above, &qt, &features);
+
+    let mut buffer = Vec::new();
+    let mut write_model = Model::default_boxed();
+    let mut bool_writer = VPXBoolWriter::new(&mut buffer).unwrap();
+
+    let corner_summary = write_coefficient_block::<false, _>(
+        &corner,
+        &NeighborData {
+            above: &EMPTY_BLOCK,
+            left: &EMPTY_BLOCK,
+            above_left: &EMPTY_BLOCK,
+            neighbor_context_above: &NEIGHBOR_DATA_EMPTY,
+            neighbor_context_left: &NEIGHBOR_DATA_EMPTY,
+        },
+        &here,
+        &mut write_model,
+        &mut bool_writer,
+        &qt,
+        &features,
+    )
+    .unwrap();
+
+    let middle_summary = write_coefficient_block::<true, _>(
+        &middle,
+        &NeighborData {
+            above: &above,
+            left: &left,
+            above_left: &above_left,
+            neighbor_context_above: &above_summary,
+            neighbor_context_left: &left_summary,
+        },
+        &here,
+        &mut write_model,
+        &mut bool_writer,
+        &qt,
+        &features,
+    )
+    .unwrap();
+
+    bool_writer.finish().unwrap();
+
+    let mut read_model = Model::default_boxed();
+    let mut bool_reader = VPXBoolReader::new(&buffer[..]).unwrap();
+
+    let (block, summary) = decode_block_standalone(
+        BlockNeighbors {
+            left: &EMPTY_BLOCK,
+            above: &EMPTY_BLOCK,
+            above_left: &EMPTY_BLOCK,
+        },
+        &corner,
+        &mut read_model,
+        &mut bool_reader,
+        &qt,
+        &features,
+    )
+    .unwrap();
+
+    assert_eq!(block.get_block(), here.get_block());
+    assert_eq!(summary, corner_summary);
+    assert_eq!(summary, NeighborSummary::from_block(&block, &qt, &features));
+
+    let (block, summary) = decode_block_standalone(
+        BlockNeighbors {
+            left: &left,
+            above: &above,
+            above_left: &above_left,
+        },
+        &middle,
+        &mut read_model,
+        &mut bool_reader,
+        &qt,
+        &features,
+    )
+    .unwrap();
+
+    assert_eq!(block.get_block(), here.get_block());
+    assert_eq!(summary, middle_summary);
+}
+
+// a consistent stream has to pass the debug check that the number of set 7x7 coefficients
+// matches the decoded number of non-zeros, for every possible count in the 7x7 block
+#[test]
+fn decode_non_zero_count_matches_set_coefficients() {
+    use crate::structs::lepton_encoder::write_coefficient_block;
+    use crate::structs::vpx_bool_writer::VPXBoolWriter;
+
+    let qt = QuantizationTables::new_from_table(&[1; 64]);
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let corner = ProbabilityTables::new(0, false, false);
+
+    let blocks: Vec<AlignedBlock> = (0..=49)
+        .map(|num_non_zeros| {
+            let mut block = AlignedBlock::default();
+            for (i, &coord_tr) in UNZIGZAG_49_TR.iter().take(num_non_zeros).enumerate() {
+                block.set_coefficient(usize::from(coord_tr), i as i16 + 1);
+            }
+            block
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut write_model = Model::default_boxed();
+    let mut bool_writer = VPXBoolWriter::new(&mut buffer).unwrap();
+
+    for block in &blocks {
+        write_coefficient_block::<false, _>(
+            &corner,
+            &NeighborData {
+                above: &EMPTY_BLOCK,
+                left: &EMPTY_BLOCK,
+                above_left: &EMPTY_BLOCK,
+                neighbor_context_above: &NEIGHBOR_DATA_EMPTY,
+                neighbor_context_left: &NEIGHBOR_DATA_EMPTY,
+            },
+            block,
+            &mut write_model,
+            &mut bool_writer,
+            &qt,
+            &features,
+        )
+        .unwrap();
+    }
+
+    bool_writer.finish().unwrap();
+
+    let mut read_model = Model::default_boxed();
+    let mut bool_reader = VPXBoolReader::new(&buffer[..]).unwrap();
+
+    for block in &blocks {
+        let (decoded, _) = decode_block_standalone(
+            BlockNeighbors {
+                left: &EMPTY_BLOCK,
+                above: &EMPTY_BLOCK,
+                above_left: &EMPTY_BLOCK,
+            },
+            &corner,
+            &mut read_model,
+            &mut bool_reader,
+            &qt,
+            &features,
+        )
+        .unwrap();
+
+        assert_eq!(decoded.get_block(), block.get_block());
+    }
+}
+
+// grayscale images only allocate the luma model, which should decode exactly
+// the same as the full model
+#[test]
+fn decode_grayscale_reduced_model() {
+    let (lh, qt, encoded) = read_and_encode(&grayscale_jpeg());
+    assert_eq!(lh.jpeg_header.cmpc, 1);
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+
+    let mut reduced = new_image_data(&lh);
+    let mut session = DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut reduced,
+        &encoded[..],
+        0,
+        &features,
+    )
+    .unwrap();
+    assert_eq!(session.model.get_num_per_color(), 1);
+    session.decode_until(i32::MAX).unwrap();
+
+    let mut full = new_image_data(&lh);
+    let mut session = DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut full,
+        &encoded[..],
+        0,
+        &features,
+    )
+    .unwrap();
+    session.model = Model::default_boxed();
+    assert!(session.model.get_num_per_color() > 1);
+    session.decode_until(i32::MAX).unwrap();
+
+    assert_eq!(
+        BlockBasedImage::combined_content_hash(&reduced),
+        BlockBasedImage::combined_content_hash(&full)
+    );
+}
+
+// with decode_dc_residuals the stored DC should be exactly the residual written to the
+// stream, while the rest of the block and the neighbor summary stay the same
+#[test]
+fn decode_dc_residuals_only() {
+    use crate::structs::lepton_encoder::write_coefficient_block;
+    use crate::structs::vpx_bool_writer::VPXBoolWriter;
+
+    let mut left = AlignedBlock::default();
+    let mut above = AlignedBlock::default();
+    let mut here = AlignedBlock::default();
+    for i in 0..64 {
+        left.set_coefficient(i, (i as i16 % 7) - 3);
+        above.set_coefficient(i, (i as i16 % 5) - 2);
+        here.set_coefficient(i, (i as i16 % 3) - 1);
+    }
+    left.set_dc(40);
+    above.set_dc(-25);
+    here.set_dc(17);
+
+    let qt = QuantizationTables::new_from_table(&[4; 64]);
+    let write_features = EnabledFeatures::compat_lepton_vector_write();
+    let features = EnabledFeatures {
+        decode_dc_residuals: true,
+        ..EnabledFeatures::compat_lepton_vector_read()
+    };
+
+    let pt = ProbabilityTables::new(0, true, true);
+    let left_summary = NeighborSummary::from_block(&left, &qt, &write_features);
+    let above_summary = NeighborSummary::from_block(&above, &qt, &write_features);
+    let neighbor_data = NeighborData {
+        above: &above,
+        left: &left,
+        above_left: &EMPTY_BLOCK,
+        neighbor_context_above: &above_summary,
+        neighbor_context_left: &left_summary,
+    };
+
+    let mut buffer = Vec::new();
+    let mut write_model = Model::default_boxed();
+    let mut bool_writer = VPXBoolWriter::new(&mut buffer).unwrap();
+    let here_summary = write_coefficient_block::<true, _>(
+        &pt,
+        &neighbor_data,
+        &here,
+        &mut write_model,
+        &mut bool_writer,
+        &qt,
+        &write_features,
+    )
+    .unwrap();
+    bool_writer.finish().unwrap();
+
+    // calculate the residual the encoder wrote for the DC
+    let mut raster = [i32x8::ZERO; 8];
+    let raster_col: &mut [i32; 64] = bytemuck::cast_mut(&mut raster);
+    for (i, r) in raster_col.iter_mut().enumerate().skip(1) {
+        *r = i32::from(here.get_coefficient(i))
+            * i32::from(qt.get_quantization_table_transposed()[i]);
+    }
+    let q0 = qt.get_quantization_table()[0] as i32;
+    let predicted = pt.adv_predict_dc_pix::<true>(&raster, q0, &neighbor_data, &write_features);
+    let residual = ProbabilityTables::adv_predict_or_unpredict_dc(
+        here.get_dc(),
+        false,
+        predicted.predicted_dc,
+        write_features.model_version.max_coefficient_bits(),
+    );
+    assert!(residual != i32::from(here.get_dc()));
+
+    let mut read_model = Model::default_boxed();
+    let mut bool_reader = VPXBoolReader::new(&buffer[..]).unwrap();
+    let (block, summary) = decode_block_standalone(
+        BlockNeighbors {
+            left: &left,
+            above: &above,
+            above_left: &EMPTY_BLOCK,
+        },
+        &pt,
+        &mut read_model,
+        &mut bool_reader,
+        &qt,
+        &features,
+    )
+    .unwrap();
+
+    assert_eq!(i32::from(block.get_dc()), residual);
+    assert_eq!(block.get_block()[1..], here.get_block()[1..]);
+    assert_eq!(summary, here_summary);
+
+    // the encoder refuses to run in analysis mode
+    let lh = LeptonHeader::new();
+    assert!(crate::structs::lepton_encoder::lepton_encode_row_range(
+        &ProbabilityTablesSet::new(),
+        &[qt],
+        &[],
+        &mut Vec::new(),
+        0,
+        &lh.truncate_components,
+        0,
+        i32::MAX,
+        true,
+        true,
+        &features,
+    )
+    .is_err());
+}
+
+// edge coefficients with a small quantizer have a noise threshold, so large values should
+// be counted against their component, while the decoded block stays the same
+#[test]
+fn decode_edge_counts_noise_coefficients() {
+    use crate::structs::lepton_encoder::write_coefficient_block;
+    use crate::structs::vpx_bool_writer::VPXBoolWriter;
+
+    let qt = QuantizationTables::new_from_table(&[1; 64]);
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pt = ProbabilityTables::new(1, false, false);
+
+    // smooth block without any edge coefficients
+    let mut smooth = AlignedBlock::default();
+    smooth.set_coefficient(0, 100);
+    smooth.set_coefficient(9, 3);
+
+    // high frequency detail along both edges
+    let mut detailed = AlignedBlock::default();
+    let mut expected = 0;
+    for i in 1..8 {
+        for (coord_tr, zig15offset, coef) in [(i * 8, i - 1, 300), (i, i + 6, -150)] {
+            detailed.set_coefficient(coord_tr, coef);
+            if qt.get_min_noise_threshold(zig15offset) > 0 {
+                expected += 1;
+            }
+        }
+    }
+    assert!(expected > 0);
+
+    let neighbor_data = NeighborData {
+        above: &EMPTY_BLOCK,
+        left: &EMPTY_BLOCK,
+        above_left: &EMPTY_BLOCK,
+        neighbor_context_above: &NEIGHBOR_DATA_EMPTY,
+        neighbor_context_left: &NEIGHBOR_DATA_EMPTY,
+    };
+
+    let mut buffer = Vec::new();
+    let mut write_model = Model::default_boxed();
+    let mut bool_writer = VPXBoolWriter::new(&mut buffer).unwrap();
+    for block in [&smooth, &detailed] {
+        write_coefficient_block::<false, _>(
+            &pt,
+            &neighbor_data,
+            block,
+            &mut write_model,
+            &mut bool_writer,
+            &qt,
+            &features,
+        )
+        .unwrap();
+    }
+    bool_writer.finish().unwrap();
+
+    let mut read_model = Model::default_boxed();
+    let mut bool_reader = VPXBoolReader::new(&buffer[..]).unwrap();
+
+    let (block, _) = read_coefficient_block::<false, _>(
+        &pt,
+        &neighbor_data,
+        &mut read_model,
+        &mut bool_reader,
+        &qt,
+        &features,
+    )
+    .unwrap();
+    assert_eq!(block.get_block(), smooth.get_block());
+    assert_eq!(bool_reader.drain_stats().edge_noise_coefficients(), [0; 3]);
+
+    let (block, _) = read_coefficient_block::<false, _>(
+        &pt,
+        &neighbor_data,
+        &mut read_model,
+        &mut bool_reader,
+        &qt,
+        &features,
+    )
+    .unwrap();
+    assert_eq!(block.get_block(), detailed.get_block());
+    assert_eq!(
+        bool_reader.drain_stats().edge_noise_coefficients(),
+        [0, expected, 0]
+    );
+}
+
+// extreme coefficients with a huge quantizer overflow the edge predictors, which should
+// wrap the same way in the encoder and decoder (and not panic in debug builds)
+#[test]
+fn decode_block_extreme_coefficients_roundtrip() {
+    use crate::structs::lepton_encoder::write_coefficient_block;
+    use crate::structs::vpx_bool_writer::VPXBoolWriter;
+
+    let mut extreme = AlignedBlock::default();
+    for i in 1..64 {
+        extreme.set_coefficient(i, if i % 2 == 0 { 2047 } else { -2047 });
+    }
+
+    let mut flipped = AlignedBlock::default();
+    for i in 0..64 {
+        flipped.set_coefficient(i, -extreme.get_coefficient(i));
+    }
+
+    let qt = QuantizationTables::new_from_table(&[65535; 64]);
+    let features = EnabledFeatures::compat_lepton_vector_read();
+
+    let corner = ProbabilityTables::new(0, false, false);
+    let middle = ProbabilityTables::new(0, true, true);
+
+    let mut buffer = Vec::new();
+    let mut write_model = Model::default_boxed();
+    let mut bool_writer = VPXBoolWriter::new(&mut buffer).unwrap();
+
+    for (pt, neighbor) in [
+        (&corner, &EMPTY_BLOCK),
+        (&middle, &extreme),
+        (&middle, &flipped),
+    ] {
+        let summary = NeighborSummary::from_block(neighbor, &qt, &features);
+        let neighbor_data = NeighborData {
+            above: neighbor,
+            left: neighbor,
+            above_left: neighbor,
+            neighbor_context_above: &summary,
+            neighbor_context_left: &summary,
+        };
+
+        for here in [&extreme, &flipped] {
+            if pt.is_all_present() {
+                write_coefficient_block::<true, _>(
+                    pt,
+                    &neighbor_data,
+                    here,
+                    &mut write_model,
+                    &mut bool_writer,
+                    &qt,
+                    &features,
+                )
+                .unwrap();
+            } else {
+                write_coefficient_block::<false, _>(
+                    pt,
+                    &neighbor_data,
+                    here,
+                    &mut write_model,
+                    &mut bool_writer,
+                    &qt,
+                    &features,
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    bool_writer.finish().unwrap();
+
+    let mut read_model = Model::default_boxed();
+    let mut bool_reader = VPXBoolReader::new(&buffer[..]).unwrap();
+
+    for (pt, neighbor) in [
+        (&corner, &EMPTY_BLOCK),
+        (&middle, &extreme),
+        (&middle, &flipped),
+    ] {
+        for here in [&extreme, &flipped] {
+            let (block, _) = decode_block_standalone(
+                BlockNeighbors {
+                    left: neighbor,
+                    above: neighbor,
+                    above_left: neighbor,
+                },
+                pt,
+                &mut read_model,
+                &mut bool_reader,
+                &qt,
+                &features,
+            )
+            .unwrap();
+
+            assert_eq!(block.get_block(), here.get_block());
+        }
+    }
+}
+
+// splitting an image into a different number of row ranges changes the compressed
+// stream, but the merged decoded image has to be identical
+#[test]
+fn decode_thread_count_invariant() {
+    use crate::structs::lepton_encoder::lepton_encode_row_range;
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::io::Cursor;
+
+    let write_features = EnabledFeatures::compat_lepton_vector_write();
+    let read_features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+
+    // 4:2:0 and 4:2:2
+    for sampling in [[0x22, 0x11, 0x11], [0x21, 0x11, 0x11]] {
+        let file = synthetic_jpeg(&sampling, 120, 136);
+
+        let (lh, image_data) =
+            read_jpeg(&mut Cursor::new(file), &write_features, 1, |_| {}).unwrap();
+
+        let mut qt = Vec::new();
+        for i in 0..lh.jpeg_header.cmpc {
+            qt.push(QuantizationTables::new(&lh.jpeg_header, i));
+        }
+
+        // ranges have to start at the beginning of an MCU row
+        let mcu_rows = lh.truncate_components.mcu_count_vertical;
+        let luma_rows_per_mcu = lh.jpeg_header.cmp_info[0].bcv / mcu_rows;
+
+        let mut hashes = Vec::new();
+        for num_ranges in [1, 2, 4, 8] {
+            let boundaries: Vec<i32> = (0..=num_ranges)
+                .map(|i| mcu_rows * i / num_ranges * luma_rows_per_mcu)
+                .collect();
+
+            let mut range_images = Vec::new();
+            for r in 0..num_ranges as usize {
+                let is_last_thread = r == num_ranges as usize - 1;
+                let (min_y, max_y) = (boundaries[r], boundaries[r + 1]);
+
+                let mut encoded = Vec::new();
+                lepton_encode_row_range(
+                    &pts,
+                    &qt,
+                    &image_data,
+                    &mut encoded,
+                    r as i32,
+                    &lh.truncate_components,
+                    min_y,
+                    max_y,
+                    is_last_thread,
+                    true,
+                    &write_features,
+                )
+                .unwrap();
+
+                let mut output = Vec::new();
+                for i in 0..lh.jpeg_header.cmpc {
+                    output.push(
+                        BlockBasedImage::new(
+                            &lh.jpeg_header,
+                            i,
+                            min_y,
+                            if is_last_thread {
+                                lh.jpeg_header.cmp_info[0].bcv
+                            } else {
+                                max_y
+                            },
+                            &read_features,
+                        )
+                        .unwrap(),
+                    );
+                }
+
+                lepton_decode_row_range(
+                    &pts,
+                    &qt,
+                    &lh.truncate_components,
+                    &mut output,
+                    &mut &encoded[..],
+                    RowRangeOptions {
+                        min_y,
+                        max_y,
+                        is_last_thread,
+                        full_file_compression: true,
+                        compute_hash: false,
+                    },
+                    &read_features,
+                )
+                .unwrap();
+
+                range_images.push(output);
+            }
+
+            let merged: Vec<BlockBasedImage> = (0..lh.jpeg_header.cmpc)
+                .map(|i| BlockBasedImage::merge(&mut range_images, i).unwrap())
+                .collect();
+
+            hashes.push(BlockBasedImage::combined_content_hash(&merged));
+        }
+
+        assert!(
+            hashes.iter().all(|h| *h == hashes[0]),
+            "{0:x?}: {1:x?}",
+            sampling,
+            hashes
+        );
+        assert_eq!(
+            hashes[0],
+            BlockBasedImage::combined_content_hash(&image_data),
+            "{0:x?}",
+            sampling
+        );
+    }
+}
+
+// corrupt streams should either fail with StreamInconsistent or decode AC coefficients
+// in the range the model can handle, never panic when used as neighbors
+#[test]
+fn decode_corrupt_stream_coefficient_range() {
+    use crate::lepton_error::LeptonError;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([10u8; 32]);
+
+    let qt = QuantizationTables::new_from_table(&[1; 64]);
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let middle = ProbabilityTables::new(0, true, true);
+
+    // all ones maximizes the unary coded lengths, the others are random garbage
+    let mut streams = vec![vec![0xFFu8; 4096]];
+    for _ in 0..500 {
+        let mut stream = vec![0u8; 4096];
+        rng.fill(&mut stream[..]);
+        streams.push(stream);
+    }
+
+    for stream in streams {
+        let mut model = Model::default_boxed();
+        let mut bool_reader = VPXBoolReader::new(&stream[..]).unwrap();
+
+        // use the previously decoded block as all neighbors so that any out of range
+        // values would end up in the context calculations
+        let mut neighbor = AlignedBlock::default();
+        for _ in 0..64 {
+            match decode_block_standalone(
+                BlockNeighbors {
+                    left: &neighbor,
+                    above: &neighbor,
+                    above_left: &neighbor,
+                },
+                &middle,
+                &mut model,
+                &mut bool_reader,
+                &qt,
+                &features,
+            ) {
+                Ok((block, _)) => {
+                    for i in 1..64 {
+                        assert!(block.get_coefficient(i).unsigned_abs() <= 2047);
+                    }
+                    neighbor = block;
+                }
+                Err(e) => {
+                    assert_eq!(
+                        e.root_cause()
+                            .downcast_ref::<LeptonError>()
+                            .unwrap()
+                            .exit_code,
+                        ExitCode::StreamInconsistent
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// decoding a region should stop early and give the same blocks as the corresponding
+// part of a full decode, for all components
+#[test]
+fn decode_region_matches_full_decode() {
+    let (lh, qt, encoded) = read_and_encode(&color_jpeg());
+    let full = encode_and_decode_in_steps(&color_jpeg(), &[]);
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let mut output = new_image_data(&lh);
+
+    let mut session = DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut output,
+        &encoded[..],
+        0,
+        &features,
+    )
+    .unwrap();
+
+    let luma_width = full[0].get_block_width();
+    let luma_height = full[0].get_original_height();
+    let (x, y) = (luma_width / 4 + 1, luma_height / 4 + 1);
+    let (w, h) = (luma_width / 2, luma_height / 4);
+
+    let region = session.decode_region(x, y, w, h).unwrap();
+    assert!(!session.is_done());
+
+    assert_eq!(region.len(), full.len());
+    for (r, f) in region.iter().zip(full.iter()) {
+        let width = f.get_block_width();
+        let height = f.get_original_height();
+        let cx = x * width / luma_width;
+        let cy = y * height / luma_height;
+
+        // the region has to cover the same area of the image in every component
+        assert!(r.get_block_width() * luma_width >= w * width);
+        assert!(r.get_original_height() * luma_height >= h * height);
+
+        for by in 0..r.get_original_height() {
+            for bx in 0..r.get_block_width() {
+                assert_eq!(
+                    r.get_block(by * r.get_block_width() + bx).get_block(),
+                    f.get_block((cy + by) * width + cx + bx).get_block()
+                );
+            }
+        }
+    }
+}
+
+// a band that continues from the summaries of the row above it instead of starting with the
+// top row tables decodes to the same rows as a full decode
+#[test]
+fn decode_row_range_with_seed_matches_full_decode() {
+    use crate::structs::lepton_encoder::lepton_encode_row_range_with_seed;
+    use crate::structs::lepton_format::read_jpeg;
+    use std::io::Cursor;
+
+    let (lh, qt, encoded) = read_and_encode(&grayscale_jpeg());
+    let full = encode_and_decode_in_steps(&grayscale_jpeg(), &[]);
+    assert_eq!(full.len(), 1);
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+
+    let width = full[0].get_block_width();
+    let height = full[0].get_original_height();
+    let band_y = height / 2;
+
+    let seed = vec![(0..width)
+        .map(|x| {
+            NeighborSummary::from_block(
+                full[0].get_block((band_y - 1) * width + x),
+                &qt[0],
+                &features,
+            )
+        })
+        .collect::<Vec<_>>()];
+
+    let write_features = EnabledFeatures::compat_lepton_vector_write();
+    let (_, image_data) = read_jpeg(
+        &mut Cursor::new(grayscale_jpeg()),
+        &write_features,
+        1,
+        |_| {},
+    )
+    .unwrap();
+
+    let mut band = Vec::new();
+    lepton_encode_row_range_with_seed(
+        &pts,
+        &qt,
+        &image_data,
+        &mut band,
+        &lh.truncate_components,
+        band_y,
+        i32::MAX,
+        true,
+        true,
+        &seed,
+        &write_features,
+    )
+    .unwrap();
+
+    // the rows above the band come from the full stream, since the first row of the band
+    // looks at the blocks above it
+    let mut output = new_image_data(&lh);
+    DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut output,
+        &encoded[..],
+        0,
+        &features,
+    )
+    .unwrap()
+    .decode_until(band_y)
+    .unwrap();
+
+    let mut session = DecodeSession::new(
+        &pts,
+        &qt,
+        &lh.truncate_components,
+        &mut output,
+        &band[..],
+        band_y,
+        &features,
+    )
+    .unwrap();
+    session.decode_row_range_with_seed(i32::MAX, &seed).unwrap();
+    assert!(session.is_done());
+
+    // seeding only works for a session that hasn't decoded anything yet
+    assert!(session.decode_row_range_with_seed(i32::MAX, &seed).is_err());
+
+    for dpos in band_y * width..height * width {
+        assert_eq!(
+            output[0].get_block(dpos).get_block(),
+            full[0].get_block(dpos).get_block()
+        );
+    }
+}
+
+// a corrupt stream should fail with the location of the block that could not be decoded,
+// which has to be the first row that fails when decoding row by row
+#[test]
+fn decode_corrupt_stream_error_location() {
+    use crate::lepton_error::LeptonError;
+
+    let (lh, qt, mut encoded) = read_and_encode(&color_jpeg());
+
+    let start = encoded.len() / 2;
+    for b in &mut encoded[start..start + 64] {
+        *b = 0xFF;
+    }
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+
+    let decode = |max_y: i32| {
+        let mut output = new_image_data(&lh);
+        let mut session = DecodeSession::new(
+            &pts,
+            &qt,
+            &lh.truncate_components,
+            &mut output,
+            &encoded[..],
+            0,
+            &features,
+        )
+        .unwrap();
+        session.decode_until(max_y)
+    };
+
+    let e = decode(i32::MAX).unwrap_err();
+    let lepton_error = e.root_cause().downcast_ref::<LeptonError>().unwrap();
+    assert_eq!(lepton_error.exit_code, ExitCode::StreamInconsistent);
+
+    let location = lepton_error.location;
+    let component = location.component.unwrap();
+    let luma_y = location.luma_y.unwrap();
+    let block_x = location.block_x.unwrap();
+    let block_y = location.block_y.unwrap();
+    assert!(component < lh.jpeg_header.cmpc);
+    assert!(block_x < lh.jpeg_header.cmp_info[component].bch);
+    assert!(block_y < lh.jpeg_header.cmp_info[component].bcv);
+    assert!(lepton_error
+        .to_string()
+        .contains(&format!("luma_y {0}", luma_y)));
+
+    // everything above the failing row decodes fine
+    decode(luma_y).unwrap();
+    decode(luma_y + 1).unwrap_err();
+}
+
+// decoding through an AsyncRead that only delivers a few bytes at a time has to give
+// exactly the same blocks and hash as the synchronous decode
+#[cfg(feature = "tokio")]
+#[test]
+fn decode_async_matches_sync() {
+    use super::async_vpx_bool_reader::MAX_BYTES_PER_BLOCK;
+
+    // a narrow image, so that the stream is long compared to what is fetched for a row
+    let jpeg = crate::structs::synthetic_jpeg::synthetic_jpeg(&[0x11], 8, 8192);
+    let (lh, qt, encoded) = read_and_encode(&jpeg);
+    let full = encode_and_decode_in_steps(&jpeg, &[]);
+
+    // the stream has to be longer than what is fetched for a row, so that it is refilled
+    assert!(encoded.len() > 2 * full[0].get_block_width() as usize * MAX_BYTES_PER_BLOCK);
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+    let pts = ProbabilityTablesSet::new();
+    let mut output = new_image_data(&lh);
+
+    let mut reader = tokio::io::BufReader::with_capacity(64, &encoded[..]);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let (metrics, hash) = runtime
+        .block_on(lepton_decode_row_range_async(
+            &pts,
+            &qt,
+            &lh.truncate_components,
+            &mut output,
+            &mut reader,
+            RowRangeOptions {
+                min_y: 0,
+                max_y: i32::MAX,
+                is_last_thread: true,
+                full_file_compression: true,
+                compute_hash: true,
+            },
+            &features,
+        ))
+        .unwrap();
+
+    assert_eq!(hash, Some(BlockBasedImage::combined_content_hash(&full)));
+    assert_eq!(metrics.blocks_processed(), stored_block_count(&full) as u64);
+
+    for (a, b) in full.iter().zip(output.iter()) {
+        let blocks = a.get_block_width() * a.get_original_height();
+        for dpos in 0..blocks {
+            assert_eq!(a.get_block(dpos).get_block(), b.get_block(dpos).get_block());
+        }
+    }
+}
+
+// a header without any blocks (for example of a zero height thumbnail) gives empty
+// components that are coded and decoded without any error
+#[test]
+fn decode_empty_components() {
+    use crate::structs::jpeg_header::JPegHeader;
+    use crate::structs::lepton_encoder::lepton_encode_row_range;
+
+    let mut jpeg_header = JPegHeader::new();
+    jpeg_header.cmpc = 3;
+    jpeg_header.img_width = 16;
+    for cmp in 0..3 {
+        jpeg_header.cmp_info[cmp].sfv = 1;
+        jpeg_header.cmp_info[cmp].sfh = 1;
+        jpeg_header.cmp_info[cmp].bch = 2;
+        jpeg_header.cmp_info[cmp].bcv = 0;
+        jpeg_header.cmp_info[cmp].bc = 0;
+    }
+
+    let mut trunc = TruncateComponents::new();
+    trunc.init(&jpeg_header);
+
+    let features = EnabledFeatures::compat_lepton_vector_write();
+    let pts = ProbabilityTablesSet::new();
+    let qt: Vec<_> = (0..3)
+        .map(|_| QuantizationTables::new_from_table(&[1; 64]))
+        .collect();
+
+    let new_empty_image_data = || {
+        (0..3)
+            .map(|cmp| BlockBasedImage::new(&jpeg_header, cmp, 0, 0, &features).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let image_data = new_empty_image_data();
+
+    let mut encoded = Vec::new();
+    lepton_encode_row_range(
+        &pts,
+        &qt,
+        &image_data,
+        &mut encoded,
+        0,
+        &trunc,
+        0,
+        i32::MAX,
+        true,
+        true,
+        &features,
+    )
+    .unwrap();
+
+    let mut output = new_empty_image_data();
+    let (metrics, _) = lepton_decode_row_range(
+        &pts,
+        &qt,
+        &trunc,
+        &mut output,
+        &mut &encoded[..],
+        RowRangeOptions {
+            min_y: 0,
+            max_y: i32::MAX,
+            is_last_thread: true,
+            full_file_compression: true,
+            compute_hash: false,
+        },
+        &features,
+    )
+    .unwrap();
+
+    assert_eq!(metrics.blocks_processed(), 0);
+    for image in output.iter() {
+        assert_eq!(image.get_stored_block_count(), 0);
+        assert_eq!(image.get_block(0).get_block(), EMPTY_BLOCK.get_block());
+    }
+}
@@ -10,9 +10,10 @@ use wide::i32x8;
 
 use std::cmp;
 use std::io::Write;
+use std::time::Instant;
 
 use crate::consts::UNZIGZAG_49_TR;
-use crate::enabled_features::EnabledFeatures;
+use crate::enabled_features::{EnabledFeatures, ModelVersion};
 use crate::helpers::*;
 use crate::lepton_error::ExitCode;
 
@@ -20,15 +21,22 @@ use crate::metrics::Metrics;
 use crate::structs::{
     block_based_image::AlignedBlock, block_based_image::BlockBasedImage,
     block_context::BlockContext, model::Model, model::ModelPerColor,
-    neighbor_summary::NeighborSummary, probability_tables::ProbabilityTables,
-    probability_tables_set::ProbabilityTablesSet, quantization_tables::QuantizationTables,
-    row_spec::RowSpec, truncate_components::*, vpx_bool_writer::VPXBoolWriter,
+    neighbor_summary::seed_neighbor_summary_cache, neighbor_summary::NeighborSummary,
+    probability_tables::ProbabilityTables, probability_tables_set::ProbabilityTablesSet,
+    quantization_tables::QuantizationTables, row_spec::RowSpec, truncate_components::*,
+    vpx_bool_writer::VPXBoolWriter,
 };
 
-use default_boxed::DefaultBoxed;
-
 use super::block_context::NeighborData;
 
+// encodes the rows with min_y <= luma_y < max_y into writer as an independent VPX stream,
+// the last thread of a full file encodes everything that is left.
+//
+// Each range starts with a fresh model, an empty neighbor summary cache and the top row
+// probability tables, exactly like lepton_decode_row_range does, so the ranges can be
+// encoded in parallel. run_lepton_encoder_threads multiplexes the output of range i as
+// thread i, and the decoder uses the thread handoffs stored in the header to find
+// the ranges again.
 #[inline(never)] // don't inline so that the profiler can get proper data
 pub fn lepton_encode_row_range<W: Write>(
     pts: &ProbabilityTablesSet,
@@ -43,7 +51,77 @@ pub fn lepton_encode_row_range<W: Write>(
     full_file_compression: bool,
     features: &EnabledFeatures,
 ) -> Result<Metrics> {
-    let mut model = Model::default_boxed();
+    encode_row_range(
+        pts,
+        quantization_tables,
+        image_data,
+        writer,
+        colldata,
+        min_y,
+        max_y,
+        is_last_thread,
+        full_file_compression,
+        None,
+        features,
+    )
+}
+
+/// Same as `lepton_encode_row_range`, but continues from the rows above `min_y` instead of
+/// starting a new band. `seed` has the summaries of the row above the first coded row for every
+/// component, see `DecodeSession::decode_row_range_with_seed` which decodes the result.
+#[allow(dead_code)]
+pub fn lepton_encode_row_range_with_seed<W: Write>(
+    pts: &ProbabilityTablesSet,
+    quantization_tables: &[QuantizationTables],
+    image_data: &[BlockBasedImage],
+    writer: &mut W,
+    colldata: &TruncateComponents,
+    min_y: i32,
+    max_y: i32,
+    is_last_thread: bool,
+    full_file_compression: bool,
+    seed: &[Vec<NeighborSummary>],
+    features: &EnabledFeatures,
+) -> Result<Metrics> {
+    encode_row_range(
+        pts,
+        quantization_tables,
+        image_data,
+        writer,
+        colldata,
+        min_y,
+        max_y,
+        is_last_thread,
+        full_file_compression,
+        Some(seed),
+        features,
+    )
+}
+
+fn encode_row_range<W: Write>(
+    pts: &ProbabilityTablesSet,
+    quantization_tables: &[QuantizationTables],
+    image_data: &[BlockBasedImage],
+    writer: &mut W,
+    colldata: &TruncateComponents,
+    min_y: i32,
+    max_y: i32,
+    is_last_thread: bool,
+    full_file_compression: bool,
+    seed: Option<&[Vec<NeighborSummary>]>,
+    features: &EnabledFeatures,
+) -> Result<Metrics> {
+    if features.decode_dc_residuals {
+        return err_exit_code(
+            ExitCode::GeneralFailure,
+            "decode_dc_residuals is for analysis only and cannot be used for encoding",
+        );
+    }
+
+    let start = Instant::now();
+    let mut blocks_processed = 0;
+
+    let mut model = Model::new_for_components(image_data.len());
     let mut bool_writer = VPXBoolWriter::new(writer)?;
 
     let mut is_top_row = Vec::new();
@@ -61,6 +139,21 @@ pub fn lepton_encode_row_range<W: Write>(
         neighbor_summary_cache.push(neighbor_summary_component);
     }
 
+    if let Some(seed) = seed {
+        if seed.len() != image_data.len() {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                "neighbor summary seed must have one row per component",
+            );
+        }
+
+        for i in 0..image_data.len() {
+            seed_neighbor_summary_cache(&mut neighbor_summary_cache[i], &seed[i])
+                .context(here!())?;
+            is_top_row[i] = false;
+        }
+    }
+
     let component_size_in_blocks = colldata.get_component_sizes_in_blocks();
     let max_coded_heights = colldata.get_max_coded_heights();
 
@@ -151,6 +244,9 @@ pub fn lepton_encode_row_range<W: Write>(
             )
             .context(here!())?;
         }
+
+        // the context has moved past the last block that was coded in this row
+        blocks_processed += block_context.get_here_index() - cur_row.curr_y * block_width;
     }
 
     if is_last_thread && full_file_compression {
@@ -174,7 +270,10 @@ pub fn lepton_encode_row_range<W: Write>(
 
     bool_writer.finish().context(here!())?;
 
-    Ok(bool_writer.drain_stats())
+    let mut metrics = bool_writer.drain_stats();
+    metrics.record_blocks_processed(blocks_processed as u64, start.elapsed());
+
+    Ok(metrics)
 }
 
 #[inline(never)] // don't inline so that the profiler can get proper data
@@ -205,7 +304,7 @@ fn process_row<W: Write>(
             features,
         )
         .context(here!())?;
-        let offset = state.next();
+        let offset = state.advance();
 
         if offset >= component_size_in_block {
             return Ok(());
@@ -240,7 +339,7 @@ fn process_row<W: Write>(
             .context(here!())?;
         }
 
-        let offset = state.next();
+        let offset = state.advance();
 
         if offset >= component_size_in_block {
             return Ok(());
@@ -274,7 +373,7 @@ fn process_row<W: Write>(
             .context(here!())?;
         }
 
-        state.next();
+        state.advance();
     }
     Ok(())
 }
@@ -332,9 +431,51 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
     bool_writer: &mut VPXBoolWriter<W>,
     qt: &QuantizationTables,
     features: &EnabledFeatures,
+) -> Result<NeighborSummary> {
+    // the longest coefficient of the model version is a constant for the coding functions,
+    // so that V1 files keep the 16 bit arithmetic
+    match features.model_version {
+        ModelVersion::V1 => {
+            write_coefficients::<ALL_PRESENT, { ModelVersion::V1.max_coefficient_bits() }, W>(
+                pt,
+                neighbors_data,
+                here_tr,
+                model,
+                bool_writer,
+                qt,
+                features,
+            )
+        }
+        ModelVersion::V1WideCoefficients => write_coefficients::<
+            ALL_PRESENT,
+            { ModelVersion::V1WideCoefficients.max_coefficient_bits() },
+            W,
+        >(
+            pt,
+            neighbors_data,
+            here_tr,
+            model,
+            bool_writer,
+            qt,
+            features,
+        ),
+    }
+}
+
+fn write_coefficients<const ALL_PRESENT: bool, const MAX_BITS: usize, W: Write>(
+    pt: &ProbabilityTables,
+    neighbors_data: &NeighborData,
+    here_tr: &AlignedBlock,
+    model: &mut Model,
+    bool_writer: &mut VPXBoolWriter<W>,
+    qt: &QuantizationTables,
+    features: &EnabledFeatures,
 ) -> Result<NeighborSummary> {
     let model_per_color = model.get_per_color(pt);
 
+    // attribute the bits of this block to its component in the compression stats
+    bool_writer.set_stats_color_index(pt.get_component());
+
     // First we encode the 49 inner coefficients
 
     // calculate the predictor context bin based on the neighbors
@@ -361,11 +502,13 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
     let mut num_non_zeros_7x7_remaining = num_non_zeros_7x7 as usize;
 
     if num_non_zeros_7x7_remaining > 0 {
-        let best_priors = pt.calc_coefficient_context_7x7_aavg_block::<ALL_PRESENT>(
-            neighbors_data.left,
-            neighbors_data.above,
-            neighbors_data.above_left,
-        );
+        let best_priors = pt
+            .calc_coefficient_context_7x7_aavg_block::<ALL_PRESENT, MAX_BITS>(
+                neighbors_data.left,
+                neighbors_data.above,
+                neighbors_data.above_left,
+            )
+            .context(here!())?;
         // calculate the bin we are using for the number of non-zeros
         let mut num_non_zeros_remaining_bin =
             ProbabilityTables::num_non_zeros_to_bin_7x7(num_non_zeros_7x7_remaining);
@@ -377,7 +520,7 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
             let coef = here_tr.get_coefficient(coord_tr as usize);
 
             model_per_color
-                .write_coef(
+                .write_coef::<MAX_BITS, W>(
                     bool_writer,
                     coef,
                     zig49,
@@ -412,7 +555,7 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
     // Next step is the edge coefficients.
     // Here we produce the first part of edge DCT coefficients predictions for neighborhood blocks
     // and transposed raster of dequantized DCT coefficients with 0 in DC
-    let (raster, horiz_pred, vert_pred) = encode_edge::<W, ALL_PRESENT>(
+    let (raster, horiz_pred, vert_pred) = encode_edge::<W, ALL_PRESENT, MAX_BITS>(
         neighbors_data,
         &here_tr,
         model_per_color,
@@ -434,25 +577,26 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
         here_tr.get_dc(),
         false,
         predicted_val.predicted_dc,
+        MAX_BITS,
     );
 
-    if here_tr.get_dc() as i32
-        != ProbabilityTables::adv_predict_or_unpredict_dc(
-            avg_predicted_dc as i16,
-            true,
-            predicted_val.predicted_dc,
-        )
-    {
+    // only DC values inside the prediction window can be recovered by the decoder
+    if !ProbabilityTables::dc_prediction_roundtrips(
+        here_tr.get_dc(),
+        predicted_val.predicted_dc,
+        MAX_BITS,
+    ) {
         return err_exit_code(ExitCode::CoefficientOutOfRange, "BlockDC mismatch");
     }
 
     model
-        .write_dc(
+        .write_dc::<MAX_BITS, W>(
             bool_writer,
             pt.get_color_index(),
             avg_predicted_dc as i16,
             predicted_val.uncertainty,
             predicted_val.uncertainty2,
+            features.dc_uncertainty_bins,
         )
         .context(here!())?;
 
@@ -470,7 +614,7 @@ pub fn write_coefficient_block<const ALL_PRESENT: bool, W: Write>(
 }
 
 #[inline(never)] // don't inline so that the profiler can get proper data
-fn encode_edge<W: Write, const ALL_PRESENT: bool>(
+fn encode_edge<W: Write, const ALL_PRESENT: bool, const MAX_BITS: usize>(
     neighbors_data: &NeighborData,
     here_tr: &AlignedBlock,
     model_per_color: &mut ModelPerColor,
@@ -496,7 +640,7 @@ fn encode_edge<W: Write, const ALL_PRESENT: bool>(
 
     let num_non_zeros_bin = (num_non_zeros_7x7 + 3) / 7;
 
-    encode_one_edge::<W, ALL_PRESENT, true>(
+    encode_one_edge::<W, ALL_PRESENT, MAX_BITS, true>(
         here_tr,
         model_per_color,
         bool_writer,
@@ -508,7 +652,7 @@ fn encode_edge<W: Write, const ALL_PRESENT: bool>(
     )
     .context(here!())?;
 
-    encode_one_edge::<W, ALL_PRESENT, false>(
+    encode_one_edge::<W, ALL_PRESENT, MAX_BITS, false>(
         here_tr,
         model_per_color,
         bool_writer,
@@ -534,7 +678,12 @@ fn count_non_zero(v: i16) -> u8 {
     }
 }
 
-fn encode_one_edge<W: Write, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
+fn encode_one_edge<
+    W: Write,
+    const ALL_PRESENT: bool,
+    const MAX_BITS: usize,
+    const HORIZONTAL: bool,
+>(
     block: &AlignedBlock,
     model_per_color: &mut ModelPerColor,
     bool_writer: &mut VPXBoolWriter<W>,
@@ -597,7 +746,7 @@ fn encode_one_edge<W: Write, const ALL_PRESENT: bool, const HORIZONTAL: bool>(
         let coef = block.get_coefficient(coord_tr);
 
         model_per_color
-            .write_edge_coefficient(
+            .write_edge_coefficient::<MAX_BITS, W>(
                 bool_writer,
                 qt,
                 coef,
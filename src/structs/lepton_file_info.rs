@@ -0,0 +1,170 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::Read;
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+
+use crate::consts::{
+    JPegType, LEPTON_HEADER_FLAG_16BIT_ADV_PREDICT, LEPTON_HEADER_FLAG_16BIT_DC_ESTIMATE,
+    LEPTON_VERSION, LEPTON_VERSION_CRC32_TRAILER,
+};
+use crate::enabled_features::{EnabledFeatures, ModelVersion};
+use crate::helpers::here;
+
+use super::lepton_format::LeptonHeader;
+
+/// The kind of JPEG that is stored in a Lepton file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeptonJpegType {
+    /// sequential JPEG with a single scan per component
+    Baseline,
+    /// progressive JPEG whose coefficients are spread over several scans
+    Progressive,
+}
+
+/// Geometry and quantization of one color component of the stored JPEG
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeptonComponentInfo {
+    /// id of the component in the JPEG frame header
+    pub id: u8,
+
+    /// horizontal sampling factor
+    pub horizontal_sampling: u32,
+
+    /// vertical sampling factor
+    pub vertical_sampling: u32,
+
+    /// number of 8x8 blocks per row, including the padding to a whole MCU
+    pub block_width: u32,
+
+    /// number of rows of 8x8 blocks, including the padding to a whole MCU
+    pub block_height: u32,
+
+    /// quantization table of the component in zigzag order, as stored in the JPEG
+    pub quantization_table: [u16; 64],
+}
+
+/// Description of a Lepton file that is read from its header only, without decoding any
+/// coefficients, so it is cheap enough to run over a whole archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeptonFileInfo {
+    /// version byte of the container
+    pub version: u8,
+
+    pub jpeg_type: LeptonJpegType,
+
+    /// whether the JPEG is entropy coded with arithmetic instead of huffman coding
+    pub arithmetic_coding: bool,
+
+    /// width of the image in pixels
+    pub width: u32,
+
+    /// height of the image in pixels
+    pub height: u32,
+
+    /// bits per sample, 8 or 12
+    pub precision: u8,
+
+    pub components: Vec<LeptonComponentInfo>,
+
+    /// the rows of luma blocks that are coded by each thread segment. The segments are
+    /// independent and can be decoded in parallel.
+    pub thread_segments: Vec<Range<u32>>,
+
+    /// the DC estimate math the file was encoded with, or None if the header doesn't record
+    /// it, which is the case for files written by the c++ lepton released by Dropbox
+    pub use_16bit_dc_estimate: Option<bool>,
+
+    /// the edge prediction math the file was encoded with, or None if the header doesn't
+    /// record it
+    pub use_16bit_adv_predict: Option<bool>,
+
+    /// the version of the model the file was encoded with, or None if the header doesn't
+    /// record it, in which case it is `ModelVersion::V1`
+    pub model_version: Option<ModelVersion>,
+
+    /// whether a CRC32 of the original JPEG is stored in front of the file size at the end
+    pub has_crc32_trailer: bool,
+
+    /// whether the file was encoded with `row_seekable` and has an index of its MCU rows
+    pub row_seekable: bool,
+
+    /// size in bytes of the original JPEG
+    pub jpeg_file_size: u32,
+
+    /// number of bytes after the EOI marker of the JPEG, such as a thumbnail or padding, that
+    /// are stored verbatim. None if the JPEG was truncated and has no EOI marker.
+    pub trailing_data_size: Option<u32>,
+}
+
+impl LeptonFileInfo {
+    fn from_header(lh: &LeptonHeader) -> Self {
+        let jh = &lh.jpeg_header;
+
+        let components = jh.cmp_info[..jh.cmpc]
+            .iter()
+            .map(|ci| LeptonComponentInfo {
+                id: ci.jid,
+                horizontal_sampling: ci.sfh as u32,
+                vertical_sampling: ci.sfv as u32,
+                block_width: ci.bch as u32,
+                block_height: ci.bcv as u32,
+                quantization_table: jh.q_tables[usize::from(ci.q_table_index)],
+            })
+            .collect();
+
+        LeptonFileInfo {
+            version: if lh.has_crc32_trailer {
+                LEPTON_VERSION_CRC32_TRAILER
+            } else {
+                LEPTON_VERSION
+            },
+            jpeg_type: if jh.jpeg_type == JPegType::Progressive {
+                LeptonJpegType::Progressive
+            } else {
+                LeptonJpegType::Baseline
+            },
+            arithmetic_coding: jh.arithmetic,
+            width: jh.img_width as u32,
+            height: jh.img_height as u32,
+            precision: jh.precision,
+            components,
+            thread_segments: lh
+                .thread_handoff
+                .iter()
+                .map(|th| th.luma_y_start as u32..th.luma_y_end as u32)
+                .collect(),
+            use_16bit_dc_estimate: lh
+                .stream_flags
+                .map(|f| (f & LEPTON_HEADER_FLAG_16BIT_DC_ESTIMATE) != 0),
+            use_16bit_adv_predict: lh
+                .stream_flags
+                .map(|f| (f & LEPTON_HEADER_FLAG_16BIT_ADV_PREDICT) != 0),
+            model_version: lh.model_version,
+            has_crc32_trailer: lh.has_crc32_trailer,
+            row_seekable: !lh.row_index.is_empty(),
+            jpeg_file_size: lh.plain_text_size,
+            trailing_data_size: lh.trailing_data_size().map(|size| size as u32),
+        }
+    }
+}
+
+/// Reads the header of the Lepton file at the current position of the reader. Only the header
+/// is consumed, the coefficient streams that follow it are not read.
+#[allow(dead_code)]
+pub fn read_lepton_file_info<R: Read>(reader: &mut R) -> Result<LeptonFileInfo> {
+    let mut lh = LeptonHeader::new();
+
+    // the flags in the header are reported as they are rather than checked
+    let mut enabled_features = EnabledFeatures::compat_lepton_vector_read();
+
+    lh.read_lepton_header(reader, &mut enabled_features)
+        .context(here!())?;
+
+    Ok(LeptonFileInfo::from_header(&lh))
+}
@@ -11,29 +11,33 @@ use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::time::Instant;
 
 use anyhow::{Context, Result};
+use rayon::prelude::*;
 
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
-use flate2::Compression;
+use flate2::{Compression, CrcReader, CrcWriter};
 
 use crate::consts::*;
-use crate::enabled_features::EnabledFeatures;
+use crate::enabled_features::{EnabledFeatures, ModelVersion};
 use crate::helpers::*;
 use crate::jpeg_code;
-use crate::lepton_error::ExitCode;
+use crate::lepton_error::{ErrorLocation, ExitCode, LeptonError};
 use crate::metrics::{CpuTimeMeasure, Metrics};
 use crate::structs::bit_writer::BitWriter;
 use crate::structs::block_based_image::BlockBasedImage;
+use crate::structs::counting_writer::CountingWriter;
 use crate::structs::jpeg_header::JPegHeader;
 use crate::structs::jpeg_write::jpeg_write_row_range;
-use crate::structs::lepton_decoder::lepton_decode_row_range;
+use crate::structs::lepton_decoder::{lepton_decode_row_range, RowRangeOptions};
 use crate::structs::lepton_encoder::lepton_encode_row_range;
-use crate::structs::multiplexer::{multiplex_read, multiplex_write};
+use crate::structs::multiplexer::{multiplex_read, multiplex_read_range, multiplex_write};
 use crate::structs::probability_tables_set::ProbabilityTablesSet;
 use crate::structs::quantization_tables::QuantizationTables;
+use crate::structs::tap_reader::TapReader;
 use crate::structs::thread_handoff::ThreadHandoff;
 use crate::structs::truncate_components::TruncateComponents;
 
+use super::jpeg_arithmetic::{jpeg_write_arithmetic_scan, read_arithmetic_scan};
 use super::jpeg_read::{read_progressive_scan, read_scan};
 use super::jpeg_write::jpeg_write_entire_scan;
 
@@ -44,6 +48,8 @@ pub fn decode_lepton_wrapper<R: Read + Seek, W: Write>(
     num_threads: usize,
     enabled_features: &EnabledFeatures,
 ) -> Result<Metrics> {
+    enabled_features.validate().context(here!())?;
+
     // figure out how long the input is
     let orig_pos = reader.stream_position()?;
     let size = reader.seek(SeekFrom::End(0))?;
@@ -59,14 +65,44 @@ pub fn decode_lepton_wrapper<R: Read + Seek, W: Write>(
     lh.read_lepton_header(&mut reader_minus_trailer, &mut features_mut)
         .context(here!())?;
 
-    let metrics = lh
-        .recode_jpeg(
-            writer,
-            &mut reader_minus_trailer,
-            num_threads,
-            &features_mut,
-        )
-        .context(here!())?;
+    let metrics;
+    let mut crc = None;
+
+    if lh.has_crc32_trailer {
+        // the CRC32 sits in front of the file size
+        let limit = reader_minus_trailer.limit();
+        if limit < 4 {
+            return err_exit_code(ExitCode::BadLeptonFile, "CRC32 trailer missing");
+        }
+        reader_minus_trailer.set_limit(limit - 4);
+
+        let mut crc_writer = CrcWriter::new(writer);
+        metrics = lh
+            .recode_jpeg(
+                &mut crc_writer,
+                &mut reader_minus_trailer,
+                num_threads,
+                &features_mut,
+            )
+            .context(here!())?;
+
+        crc = Some(crc_writer.crc().sum());
+    } else {
+        metrics = lh
+            .recode_jpeg(
+                writer,
+                &mut reader_minus_trailer,
+                num_threads,
+                &features_mut,
+            )
+            .context(here!())?;
+    }
+
+    let expected_crc = if crc.is_some() {
+        Some(reader.read_u32::<LittleEndian>()?)
+    } else {
+        None
+    };
 
     let expected_size = reader.read_u32::<LittleEndian>()?;
     if expected_size != size as u32 {
@@ -80,6 +116,18 @@ pub fn decode_lepton_wrapper<R: Read + Seek, W: Write>(
         );
     }
 
+    if expected_crc != crc {
+        return err_exit_code(
+            ExitCode::ChecksumMismatch,
+            format!(
+                "ERROR mismatch expected_crc = {0:08x}, actual_crc = {1:08x}",
+                expected_crc.unwrap_or_default(),
+                crc.unwrap_or_default()
+            )
+            .as_str(),
+        );
+    }
+
     return Ok(metrics);
 }
 
@@ -90,20 +138,85 @@ pub fn encode_lepton_wrapper<R: Read + Seek, W: Write + Seek>(
     max_threads: usize,
     enabled_features: &EnabledFeatures,
 ) -> Result<Metrics> {
-    let (lp, image_data) = read_jpeg(reader, enabled_features, max_threads, |_jh| {})?;
+    enabled_features.validate().context(here!())?;
+
+    let orig_pos = reader.stream_position()?;
+
+    if let Some(threshold) = enabled_features.lossy_ac_threshold {
+        // the cleaned up JPEG is encoded losslessly, so the decoder doesn't need to know
+        let features = EnabledFeatures {
+            lossy_ac_threshold: None,
+            ..*enabled_features
+        };
+
+        return match lossy_ac_cleanup(reader, threshold, max_threads, enabled_features)
+            .context(here!())?
+        {
+            Some(cleaned) => {
+                encode_lepton_wrapper(&mut Cursor::new(cleaned), writer, max_threads, &features)
+            }
+            None => {
+                reader.seek(SeekFrom::Start(orig_pos))?;
+                encode_lepton_wrapper(reader, writer, max_threads, &features)
+            }
+        };
+    }
+
+    let (mut lp, image_data) = read_jpeg(reader, enabled_features, max_threads, |_jh| {})?;
+
+    // 12 bit images are written with the wide version of the model
+    let enabled_features = &EnabledFeatures {
+        model_version: enabled_features
+            .model_version
+            .for_precision(lp.jpeg_header.precision),
+        ..*enabled_features
+    };
+
+    let metrics = if enabled_features.row_seekable {
+        // the sizes of the rows go into the header, so everything has to be encoded
+        // before the header can be written
+        let (streams, metrics) =
+            encode_row_seekable_streams(&mut lp, &image_data[..], enabled_features)
+                .context(here!())?;
+
+        lp.write_lepton_header(writer, enabled_features)
+            .context(here!())?;
 
-    lp.write_lepton_header(writer, enabled_features)
+        multiplex_write(writer, streams.len(), |thread_writer, thread_id| {
+            thread_writer
+                .write_all(&streams[thread_id])
+                .context(here!())
+        })
         .context(here!())?;
 
-    let metrics = run_lepton_encoder_threads(
-        &lp.jpeg_header,
-        &lp.truncate_components,
-        writer,
-        &lp.thread_handoff[..],
-        &image_data[..],
-        enabled_features,
-    )
-    .context(here!())?;
+        metrics
+    } else {
+        lp.write_lepton_header(writer, enabled_features)
+            .context(here!())?;
+
+        run_lepton_encoder_threads(
+            &lp.jpeg_header,
+            &lp.truncate_components,
+            writer,
+            &lp.thread_handoff[..],
+            &image_data[..],
+            enabled_features,
+        )
+        .context(here!())?
+    };
+
+    if enabled_features.write_crc32_trailer {
+        // go over the JPEG a second time rather than slowing down the parsing
+        let end_pos = reader.stream_position()?;
+        reader.seek(SeekFrom::Start(orig_pos))?;
+
+        let mut crc_reader = CrcReader::new(reader.take(end_pos - orig_pos));
+        std::io::copy(&mut crc_reader, &mut std::io::sink()).context(here!())?;
+
+        writer
+            .write_u32::<LittleEndian>(crc_reader.crc().sum())
+            .context(here!())?;
+    }
 
     let final_file_size = writer.stream_position()? + 4;
 
@@ -114,6 +227,117 @@ pub fn encode_lepton_wrapper<R: Read + Seek, W: Write + Seek>(
     Ok(metrics)
 }
 
+/// Checks if the lepton file at the current position of the reader has the header written by the
+/// original c++ lepton released by Dropbox. The container is the same, but the 12 bytes that hold
+/// our "MS" marker, the uncompressed header size and the math flags contain the git revision of
+/// the encoder instead, so the file doesn't tell which math it was encoded with. The position of
+/// the reader is restored.
+#[allow(dead_code)]
+pub fn is_legacy_dropbox_header<R: Read + Seek>(reader: &mut R) -> Result<bool> {
+    let start = reader.stream_position()?;
+
+    let mut header = [0u8; LEPTON_FILE_HEADER.len() + 1 + 21];
+    reader.read_exact(&mut header).context(here!())?;
+    reader.seek(SeekFrom::Start(start))?;
+
+    if !buffer_prefix_matches_marker(header, LEPTON_FILE_HEADER) {
+        return err_exit_code(ExitCode::BadLeptonFile, "header doesn't match");
+    }
+
+    // the git revision starts after the magic, the version, the file type and the thread count
+    let revision = &header[LEPTON_FILE_HEADER.len() + 6..];
+    Ok(revision[0] != b'M' || revision[1] != b'S')
+}
+
+/// reads a lepton file written by either the Dropbox c++ lepton or this library and writes it out
+/// as a jpeg, without the caller having to know which math the encoder used.
+///
+/// The Dropbox release was built with SIMD and emulates its 16 bit overflow, but scalar builds of
+/// it were in use as well. Files with the legacy header are decoded with the SIMD math first, and
+/// if the stream turns out to be inconsistent with it, again with the scalar math. The bool
+/// decoder needs no special handling, it keeps more bits of the stream than the 16 bit `value` of
+/// the c++ version but decodes exactly the same bits.
+#[allow(dead_code)]
+pub fn decode_lepton_dropbox_wrapper<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    num_threads: usize,
+) -> Result<Metrics> {
+    let vector_features = EnabledFeatures::compat_lepton_vector_read();
+
+    if !is_legacy_dropbox_header(reader).context(here!())? {
+        // the flags in our header select the math
+        return decode_lepton_wrapper(reader, writer, num_threads, &vector_features);
+    }
+
+    let start = reader.stream_position()?;
+
+    // buffer the output so that nothing is written by a failed attempt
+    let mut output = Vec::new();
+    let metrics = match decode_lepton_wrapper(reader, &mut output, num_threads, &vector_features) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            let inconsistent = matches!(
+                e.root_cause().downcast_ref::<LeptonError>(),
+                Some(x) if x.exit_code == ExitCode::StreamInconsistent
+            );
+            if !inconsistent {
+                return Err(e);
+            }
+
+            warn!("stream inconsistent with SIMD math, retrying with scalar math");
+
+            reader.seek(SeekFrom::Start(start))?;
+            output.clear();
+
+            decode_lepton_wrapper(
+                reader,
+                &mut output,
+                num_threads,
+                &EnabledFeatures::compat_lepton_scalar_read(),
+            )
+            .context(here!())?
+        }
+    };
+
+    writer.write_all(&output).context(here!())?;
+
+    Ok(metrics)
+}
+
+/// Runs the complete encode of a jpeg, but only counts the bytes of the lepton file instead of
+/// writing them, and returns the count together with the metrics. The count is exactly the size
+/// that `encode_lepton_wrapper` would produce with the same settings, including the header and
+/// the trailer, which the per model compression stats don't cover.
+#[allow(dead_code)]
+pub fn encode_lepton_wrapper_size<R: Read + Seek>(
+    reader: &mut R,
+    max_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<(u64, Metrics)> {
+    let mut writer = CountingWriter::new();
+
+    let metrics = encode_lepton_wrapper(reader, &mut writer, max_threads, enabled_features)
+        .context(here!())?;
+
+    Ok((writer.stream_len(), metrics))
+}
+
+/// reads a jpeg and writes it out as a lepton file, passing the bytes of the jpeg to the
+/// tap callback in order as they are consumed. The lepton output is the same as without the tap.
+#[allow(dead_code)]
+pub fn encode_lepton_wrapper_with_tap<R: Read + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    max_threads: usize,
+    enabled_features: &EnabledFeatures,
+    tap: &mut dyn FnMut(&[u8]),
+) -> Result<Metrics> {
+    let mut tap_reader = TapReader::new(reader, tap).context(here!())?;
+
+    encode_lepton_wrapper(&mut tap_reader, writer, max_threads, enabled_features)
+}
+
 /// Encodes JPEG as compressed Lepton format, verifies roundtrip in buffer. Requires everything to be buffered
 /// since we need to pass through the data multiple times
 pub fn encode_lepton_wrapper_verify(
@@ -121,6 +345,28 @@ pub fn encode_lepton_wrapper_verify(
     max_threads: usize,
     enabled_features: &EnabledFeatures,
 ) -> Result<(Vec<u8>, Metrics)> {
+    if let Some(threshold) = enabled_features.lossy_ac_threshold {
+        // verify against the cleaned up JPEG, which is what the file decodes to
+        let features = EnabledFeatures {
+            lossy_ac_threshold: None,
+            ..*enabled_features
+        };
+
+        let cleaned = lossy_ac_cleanup(
+            &mut Cursor::new(input_data),
+            threshold,
+            max_threads,
+            enabled_features,
+        )
+        .context(here!())?;
+
+        return encode_lepton_wrapper_verify(
+            cleaned.as_deref().unwrap_or(input_data),
+            max_threads,
+            &features,
+        );
+    }
+
     let mut output_data = Vec::with_capacity(input_data.len());
 
     info!("compressing to Lepton format");
@@ -150,26 +396,116 @@ pub fn encode_lepton_wrapper_verify(
             .context(here!())?,
     );
 
-    if input_data.len() != verify_buffer.len() {
-        return err_exit_code(
+    compare_decoded_jpeg(input_data, &verify_buffer).context(here!())?;
+
+    Ok((output_data, metrics))
+}
+
+/// decodes a lepton file and checks that the result is identical to the original jpeg
+#[allow(dead_code)]
+pub fn verify_lepton_wrapper(
+    lepton_data: &[u8],
+    jpeg_data: &[u8],
+    num_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<Metrics> {
+    let mut decoded = Vec::with_capacity(jpeg_data.len());
+
+    let metrics = decode_lepton_wrapper(
+        &mut Cursor::new(lepton_data),
+        &mut decoded,
+        num_threads,
+        enabled_features,
+    )
+    .context(here!())?;
+
+    compare_decoded_jpeg(jpeg_data, &decoded).context(here!())?;
+
+    Ok(metrics)
+}
+
+/// compares a decoded jpeg byte for byte with the original, and reports the offset of the
+/// first byte that differs in the location of the error
+fn compare_decoded_jpeg(original: &[u8], decoded: &[u8]) -> Result<()> {
+    let first_difference = match original.iter().zip(decoded).position(|(a, b)| a != b) {
+        Some(offset) => offset,
+        None if original.len() != decoded.len() => cmp::min(original.len(), decoded.len()),
+        None => return Ok(()),
+    };
+
+    let location = ErrorLocation {
+        jpeg_offset: Some(first_difference as u64),
+        ..Default::default()
+    };
+
+    if original.len() != decoded.len() {
+        return err_exit_code_at(
             ExitCode::VerificationLengthMismatch,
             format!(
                 "ERROR mismatch input_len = {0}, decoded_len = {1}",
-                input_data.len(),
-                verify_buffer.len()
+                original.len(),
+                decoded.len()
             )
             .as_str(),
+            location,
         );
     }
 
-    if input_data[..] != verify_buffer[..] {
-        return err_exit_code(
-            ExitCode::VerificationContentMismatch,
-            "ERROR mismatching data (but same size)",
-        );
+    err_exit_code_at(
+        ExitCode::VerificationContentMismatch,
+        "ERROR mismatching data (but same size)",
+        location,
+    )
+}
+
+/// Zeros the AC coefficients of the JPEG whose magnitude is below the threshold, and writes
+/// the JPEG again from the remaining coefficients with the same tables and framing. Returns None
+/// if there was nothing to zero, in which case the original can be used as it is.
+fn lossy_ac_cleanup<R: Read + Seek>(
+    reader: &mut R,
+    threshold: i16,
+    max_threads: usize,
+    enabled_features: &EnabledFeatures,
+) -> Result<Option<Vec<u8>>> {
+    // the scan is written again as a single segment, since the handoffs between the threads
+    // hold the partial bytes of the original scan, which don't fit the new coefficients
+    let (lp, mut image_data) = read_jpeg(reader, enabled_features, 1, |_jh| {}).context(here!())?;
+
+    let mut zeroed = 0;
+    for image in image_data.iter_mut() {
+        zeroed += image.zero_small_ac(threshold);
     }
 
-    Ok((output_data, metrics))
+    if zeroed == 0 {
+        return Ok(None);
+    }
+
+    let mut cleaned = Vec::new();
+    lp.reconstruct_jpeg(&image_data, &mut cleaned)
+        .context(here!())?;
+
+    // the huffman tables were built for the original coefficients, so they might not have
+    // codes for the new runs of zeros, which would give a JPEG that reads back differently
+    let reread = read_jpeg(
+        &mut Cursor::new(&cleaned),
+        enabled_features,
+        max_threads,
+        |_jh| {},
+    );
+
+    match reread {
+        Ok((_, reread_data))
+            if BlockBasedImage::combined_content_hash(&reread_data)
+                == BlockBasedImage::combined_content_hash(&image_data) =>
+        {
+            Ok(Some(cleaned))
+        }
+        _ => err_exit_code(
+            ExitCode::UnsupportedJpeg,
+            "the huffman tables can't code the coefficients left by lossy_ac_threshold",
+        )
+        .context(here!()),
+    }
 }
 
 /// reads JPEG and returns corresponding header and image vector. This encapsulate all
@@ -216,17 +552,31 @@ pub fn read_jpeg<R: Read + Seek>(
     let mut image_data = Vec::<BlockBasedImage>::new();
     for i in 0..lp.jpeg_header.cmpc {
         // constructor takes height in proportion to the component[0]
-        image_data.push(BlockBasedImage::new(
-            &lp.jpeg_header,
-            i,
-            0,
-            lp.jpeg_header.cmp_info[0].bcv,
-        ));
+        image_data.push(
+            BlockBasedImage::new(
+                &lp.jpeg_header,
+                i,
+                0,
+                lp.jpeg_header.cmp_info[0].bcv,
+                enabled_features,
+            )
+            .context(here!())?,
+        );
     }
 
     let mut thread_handoff = Vec::<ThreadHandoff>::new();
     let start_scan = reader.stream_position()? as i32;
-    read_scan(&mut lp, reader, &mut thread_handoff, &mut image_data[..]).context(here!())?;
+    if lp.jpeg_header.arithmetic {
+        read_arithmetic_scan(
+            &mut lp,
+            reader,
+            Some(&mut thread_handoff),
+            &mut image_data[..],
+        )
+        .context(here!())?;
+    } else {
+        read_scan(&mut lp, reader, &mut thread_handoff, &mut image_data[..]).context(here!())?;
+    }
     lp.scnc += 1;
 
     let mut end_scan = reader.stream_position()? as i32;
@@ -254,7 +604,18 @@ pub fn read_jpeg<R: Read + Seek>(
         );
     }
 
-    if lp.jpeg_header.jpeg_type == JPegType::Sequential {
+    // a baseline image whose first scan doesn't contain all the components is coded as one
+    // non-interleaved scan per component (or group of components), which are read one after
+    // the other like the scans of a progressive image
+    let non_interleaved = lp.jpeg_header.jpeg_type == JPegType::Sequential
+        && !lp.jpeg_header.arithmetic
+        && lp.jpeg_header.cs_cmpc < lp.jpeg_header.cmpc
+        && !lp.early_eof_encountered;
+
+    if lp.jpeg_header.jpeg_type == JPegType::Sequential
+        && !lp.jpeg_header.arithmetic
+        && !non_interleaved
+    {
         if lp.early_eof_encountered {
             lp.truncate_components
                 .set_truncation_bounds(&lp.jpeg_header, lp.max_dpos);
@@ -276,7 +637,12 @@ pub fn read_jpeg<R: Read + Seek>(
         // rest of data is garbage data if it is a sequential jpeg (including EOI marker)
         reader.read_to_end(&mut lp.garbage_data).context(here!())?;
     } else {
-        assert!(lp.jpeg_header.jpeg_type == JPegType::Progressive);
+        // arithmetic coded images may have multiple scans even if they are sequential
+        assert!(
+            lp.jpeg_header.jpeg_type == JPegType::Progressive
+                || lp.jpeg_header.arithmetic
+                || non_interleaved
+        );
 
         if lp.early_eof_encountered {
             return err_exit_code(
@@ -286,11 +652,25 @@ pub fn read_jpeg<R: Read + Seek>(
             .context(here!());
         }
 
+        // the decoder builds its model from the tables defined before the first scan, so a
+        // table that is redefined between the scans must not be used for encoding either.
+        // The redefinition itself stays in the raw header and is written back as it was.
+        let first_scan_q_tables = lp.jpeg_header.q_tables;
+
         // for progressive images, loop around reading headers and decoding until we a complete image_data
         while prepare_to_decode_next_scan(&mut lp, reader, enabled_features).context(here!())? {
             callback(&lp.jpeg_header);
 
-            read_progressive_scan(&mut lp, reader, &mut image_data[..]).context(here!())?;
+            if lp.jpeg_header.arithmetic {
+                read_arithmetic_scan(&mut lp, reader, None, &mut image_data[..])
+                    .context(here!())?;
+            } else if lp.jpeg_header.jpeg_type == JPegType::Sequential {
+                // the rows are only split into threads by the first scan
+                read_scan(&mut lp, reader, &mut Vec::new(), &mut image_data[..])
+                    .context(here!())?;
+            } else {
+                read_progressive_scan(&mut lp, reader, &mut image_data[..]).context(here!())?;
+            }
             lp.scnc += 1;
 
             if lp.early_eof_encountered {
@@ -304,6 +684,8 @@ pub fn read_jpeg<R: Read + Seek>(
 
         end_scan = reader.stream_position()? as i32;
 
+        lp.jpeg_header.q_tables = first_scan_q_tables;
+
         // since prepare_to_decode_next_scan consumes the EOI,
         // we need to add it to the beginning of the garbage data (if there is any)
         lp.garbage_data = Vec::from(EOI);
@@ -322,6 +704,32 @@ pub fn read_jpeg<R: Read + Seek>(
     Ok((lp, image_data))
 }
 
+/// creates the quantization tables of the components, checking that they can be used for
+/// the predictions
+fn new_quantization_tables(
+    jpeg_header: &JPegHeader,
+    num_components: usize,
+) -> Result<Vec<QuantizationTables>> {
+    let mut quantization_tables = Vec::new();
+    for i in 0..num_components {
+        let qtables = QuantizationTables::new(jpeg_header, i);
+
+        // check to see if quantitization table was properly initialized
+        // (table contains divisors for coefficients so it never should have a zero)
+        for i in [0, 1, 2, 3, 4, 5, 6, 7, 8, 16, 24, 32, 40, 48, 56] {
+            if qtables.get_quantization_table()[i] == 0 {
+                return err_exit_code(
+                    ExitCode::UnsupportedJpeg,
+                    "Quantization table contains zero",
+                );
+            }
+        }
+        quantization_tables.push(qtables);
+    }
+
+    Ok(quantization_tables)
+}
+
 fn run_lepton_decoder_threads<R: Read, P: Send>(
     lh: &LeptonHeader,
     reader: &mut R,
@@ -336,22 +744,7 @@ fn run_lepton_decoder_threads<R: Read, P: Send>(
     let wall_time = Instant::now();
 
     let pts = ProbabilityTablesSet::new();
-    let mut qt = Vec::new();
-    for i in 0..lh.jpeg_header.cmpc {
-        let qtables = QuantizationTables::new(&lh.jpeg_header, i);
-
-        // check to see if quantitization table was properly initialized
-        // (table contains divisors for coefficients so it never should have a zero)
-        for i in [0, 1, 2, 3, 4, 5, 6, 7, 8, 16, 24, 32, 40, 48, 56] {
-            if qtables.get_quantization_table()[i] == 0 {
-                return err_exit_code(
-                    ExitCode::UnsupportedJpeg,
-                    "Quantization table contains zero",
-                );
-            }
-        }
-        qt.push(qtables);
-    }
+    let qt = new_quantization_tables(&lh.jpeg_header, lh.jpeg_header.cmpc).context(here!())?;
 
     let pts_ref = &pts;
     let q_ref = &qt[..];
@@ -364,36 +757,78 @@ fn run_lepton_decoder_threads<R: Read, P: Send>(
 
             let mut image_data = Vec::new();
             for i in 0..lh.jpeg_header.cmpc {
-                image_data.push(BlockBasedImage::new(
-                    &lh.jpeg_header,
-                    i,
-                    lh.thread_handoff[thread_id].luma_y_start,
-                    if thread_id == lh.thread_handoff.len() - 1 {
-                        // if this is the last thread, then the image should extend all the way to the bottom
-                        lh.jpeg_header.cmp_info[0].bcv
-                    } else {
-                        lh.thread_handoff[thread_id].luma_y_end
-                    },
-                ));
+                image_data.push(
+                    BlockBasedImage::new(
+                        &lh.jpeg_header,
+                        i,
+                        lh.thread_handoff[thread_id].luma_y_start,
+                        if thread_id == lh.thread_handoff.len() - 1 {
+                            // if this is the last thread, then the image should extend all the way to the bottom
+                            lh.jpeg_header.cmp_info[0].bcv
+                        } else {
+                            lh.thread_handoff[thread_id].luma_y_end
+                        },
+                        features,
+                    )
+                    .context(here!())?,
+                );
             }
 
             let mut metrics = Metrics::default();
 
-            metrics.merge_from(
-                lepton_decode_row_range(
+            if lh.row_index.is_empty() {
+                let (decode_metrics, _) = lepton_decode_row_range(
                     pts_ref,
                     q_ref,
                     &lh.truncate_components,
                     &mut image_data,
                     reader,
-                    lh.thread_handoff[thread_id].luma_y_start,
-                    lh.thread_handoff[thread_id].luma_y_end,
-                    thread_id == lh.thread_handoff.len() - 1,
-                    true,
+                    RowRangeOptions {
+                        min_y: lh.thread_handoff[thread_id].luma_y_start,
+                        max_y: lh.thread_handoff[thread_id].luma_y_end,
+                        is_last_thread: thread_id == lh.thread_handoff.len() - 1,
+                        full_file_compression: true,
+                        compute_hash: false,
+                    },
                     features,
                 )
-                .context(here!())?,
-            );
+                .context(here!())?;
+
+                metrics.merge_from(decode_metrics);
+            } else {
+                // every MCU row is a separate stream that starts with a fresh model
+                let row_height = lh.get_mcu_row_height();
+                let row_sizes = &lh.row_index[thread_id];
+
+                for (i, &row_size) in row_sizes.iter().enumerate() {
+                    let min_y = lh.thread_handoff[thread_id].luma_y_start + i as i32 * row_height;
+
+                    let mut row_reader = reader.take(u64::from(row_size));
+
+                    let (decode_metrics, _) = lepton_decode_row_range(
+                        pts_ref,
+                        q_ref,
+                        &lh.truncate_components,
+                        &mut image_data,
+                        &mut row_reader,
+                        RowRangeOptions {
+                            min_y,
+                            max_y: min_y + row_height,
+                            is_last_thread: thread_id == lh.thread_handoff.len() - 1
+                                && i == row_sizes.len() - 1,
+                            full_file_compression: true,
+                            compute_hash: false,
+                        },
+                        features,
+                    )
+                    .context(here!())?;
+
+                    // skip whatever the bool reader didn't need of the row
+                    std::io::copy(&mut row_reader, &mut std::io::sink()).context(here!())?;
+
+                    metrics.merge_from(decode_metrics);
+                }
+            }
 
             let process_result = process(&lh.thread_handoff[thread_id], image_data, lh)?;
 
@@ -440,22 +875,8 @@ fn run_lepton_encoder_threads<W: Write + Seek>(
 
     // Prepare quantization tables
     let pts = ProbabilityTablesSet::new();
-    let mut quantization_tables = Vec::new();
-    for i in 0..image_data.len() {
-        let qtables = QuantizationTables::new(jpeg_header, i);
-
-        // check to see if quantitization table was properly initialized
-        // (table contains divisors for coefficients so it never should have a zero)
-        for i in [0, 1, 2, 3, 4, 5, 6, 7, 8, 16, 24, 32, 40, 48, 56] {
-            if qtables.get_quantization_table()[i] == 0 {
-                return err_exit_code(
-                    ExitCode::UnsupportedJpeg,
-                    "Quantization table contains zero",
-                );
-            }
-        }
-        quantization_tables.push(qtables);
-    }
+    let quantization_tables =
+        new_quantization_tables(jpeg_header, image_data.len()).context(here!())?;
 
     let pts_ref = &pts;
     let q_ref = &quantization_tables[..];
@@ -499,9 +920,92 @@ fn run_lepton_encoder_threads<W: Write + Seek>(
     Ok(merged_metrics)
 }
 
+/// Encodes the rows of every thread like `run_lepton_encoder_threads`, except that each MCU
+/// row is encoded as a separate stream with a fresh model, exactly as if it was the range of
+/// a thread of its own. Returns the concatenated rows of every thread and stores the size of
+/// each row in the row index of the header.
+fn encode_row_seekable_streams(
+    lp: &mut LeptonHeader,
+    image_data: &[BlockBasedImage],
+    features: &EnabledFeatures,
+) -> Result<(Vec<Vec<u8>>, Metrics)> {
+    let pts = ProbabilityTablesSet::new();
+    let quantization_tables =
+        new_quantization_tables(&lp.jpeg_header, image_data.len()).context(here!())?;
+
+    let row_height = lp.get_mcu_row_height();
+    let luma_height = lp.jpeg_header.cmp_info[0].bcv;
+    let num_threads = lp.thread_handoff.len();
+
+    let lp_ref = &*lp;
+    let mut thread_results = lp
+        .thread_handoff
+        .par_iter()
+        .enumerate()
+        .map(|(thread_id, thread_handoff)| -> Result<_> {
+            let cpu_time = CpuTimeMeasure::new();
+
+            let is_last_thread = thread_id == num_threads - 1;
+            let luma_y_end = if is_last_thread {
+                luma_height
+            } else {
+                thread_handoff.luma_y_end
+            };
+
+            let mut stream = Vec::new();
+            let mut row_sizes = Vec::new();
+            let mut metrics = Metrics::default();
+
+            let mut min_y = thread_handoff.luma_y_start;
+            while min_y < luma_y_end {
+                let row_start = stream.len();
+
+                metrics.merge_from(
+                    lepton_encode_row_range(
+                        &pts,
+                        &quantization_tables,
+                        image_data,
+                        &mut stream,
+                        thread_id as i32,
+                        &lp_ref.truncate_components,
+                        min_y,
+                        min_y + row_height,
+                        is_last_thread && min_y + row_height >= luma_y_end,
+                        true,
+                        features,
+                    )
+                    .context(here!())?,
+                );
+
+                row_sizes.push(u32::try_from(stream.len() - row_start)?);
+                min_y += row_height;
+            }
+
+            metrics.record_cpu_worker_time(cpu_time.elapsed());
+
+            Ok((stream, row_sizes, metrics))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut streams = Vec::with_capacity(num_threads);
+    let mut merged_metrics = Metrics::default();
+    lp.row_index.clear();
+
+    for (stream, row_sizes, metrics) in thread_results.drain(..) {
+        streams.push(stream);
+        lp.row_index.push(row_sizes);
+        merged_metrics.merge_from(metrics);
+    }
+
+    Ok((streams, merged_metrics))
+}
+
 #[derive(Debug)]
 pub struct LeptonHeader {
-    /// raw jpeg header to be written back to the file when it is recreated
+    /// raw jpeg header to be written back to the file when it is recreated. The segments are
+    /// stored verbatim, so the huffman and quantization tables are reproduced byte for byte,
+    /// including how they are grouped into DHT and DQT segments, and standard tables are never
+    /// regenerated.
     pub raw_jpeg_header: Vec<u8>,
 
     /// how far we have read into the raw header, since the header is divided
@@ -556,6 +1060,21 @@ pub struct LeptonHeader {
 
     /// on decompression, uncompressed lepton header size
     pub uncompressed_lepton_header_size: u32,
+
+    /// on decompression, the flags recording the math the file was encoded with, None if the
+    /// header doesn't have them
+    pub stream_flags: Option<u8>,
+
+    /// on decompression, the version of the model the file was encoded with, None if the
+    /// header doesn't have it
+    pub model_version: Option<ModelVersion>,
+
+    /// on decompression, whether a CRC32 of the JPEG precedes the file size at the end of the file
+    pub has_crc32_trailer: bool,
+
+    /// size in bytes of every MCU row in the stream of each thread, empty unless the file was
+    /// encoded with `row_seekable`
+    pub row_index: Vec<Vec<u32>>,
 }
 
 impl LeptonHeader {
@@ -580,7 +1099,59 @@ impl LeptonHeader {
             jpeg_file_size: 0,
             plain_text_size: 0,
             uncompressed_lepton_header_size: 0,
+            stream_flags: None,
+            model_version: None,
+            has_crc32_trailer: false,
+            row_index: Vec::new(),
+        };
+    }
+
+    /// number of bytes after the EOI marker of the jpeg, such as a thumbnail or padding, which
+    /// are kept verbatim with the garbage data. None if the jpeg was truncated before its EOI.
+    pub fn trailing_data_size(&self) -> Option<usize> {
+        if self.early_eof_encountered {
+            return None;
+        }
+
+        Some(
+            self.garbage_data
+                .strip_prefix(&EOI)
+                .unwrap_or(&self.garbage_data)
+                .len(),
+        )
+    }
+
+    /// Writes the JPEG file for the header and coefficients returned by `read_jpeg`, recreating
+    /// the original file bit for bit: the coefficients are huffman coded again into the scans
+    /// in their original order, and the header segments, restart markers, padding and any data
+    /// after the image are put back where they were. This is the inverse of `read_jpeg`
+    /// without going through the Lepton entropy coding.
+    pub fn reconstruct_jpeg<W: Write>(
+        &self,
+        image_data: &[BlockBasedImage],
+        writer: &mut W,
+    ) -> Result<()> {
+        // the image was already checked against the limits when it was read
+        let mut features = EnabledFeatures {
+            max_blocks_per_component: u64::MAX,
+            max_total_pixels: u64::MAX,
+            ..EnabledFeatures::compat_lepton_vector_read()
         };
+
+        // the header is in the state after reading the whole JPEG, so take it through the
+        // Lepton header to get it into the state the decoder expects for writing the scans
+        let mut serialized_header = Vec::new();
+        self.write_lepton_header(&mut Cursor::new(&mut serialized_header), &features)
+            .context(here!())?;
+
+        let mut decode_lh = LeptonHeader::new();
+        decode_lh
+            .read_lepton_header(&mut Cursor::new(&serialized_header), &mut features)
+            .context(here!())?;
+
+        decode_lh
+            .recode_jpeg_from_image(writer, image_data, &features)
+            .context(here!())
     }
 
     fn recode_jpeg<R: Read, W: Write>(
@@ -590,6 +1161,68 @@ impl LeptonHeader {
         num_threads: usize,
         enabled_features: &EnabledFeatures,
     ) -> Result<Metrics, anyhow::Error> {
+        self.write_jpeg_framing(writer, |lh, writer| {
+            if lh.has_multiple_scans() {
+                lh.recode_progressive_jpeg(reader, writer, num_threads, enabled_features)
+                    .context(here!())
+            } else {
+                lh.recode_baseline_jpeg(
+                    reader,
+                    writer,
+                    lh.baseline_size_limit(),
+                    num_threads,
+                    enabled_features,
+                )
+                .context(here!())
+            }
+        })
+    }
+
+    /// Writes the JPEG from coefficients that are already in memory instead of decoding them
+    /// from a Lepton stream. The header has to be in the state after `read_lepton_header`.
+    fn recode_jpeg_from_image<W: Write>(
+        &mut self,
+        writer: &mut W,
+        image_data: &[BlockBasedImage],
+        enabled_features: &EnabledFeatures,
+    ) -> Result<()> {
+        self.write_jpeg_framing(writer, |lh, writer| {
+            if lh.has_multiple_scans() {
+                lh.write_progressive_scans(writer, image_data, enabled_features)
+                    .context(here!())
+            } else {
+                let mut results = Vec::with_capacity(lh.thread_handoff.len());
+                for thread_handoff in &lh.thread_handoff {
+                    results.push(
+                        Self::write_baseline_row_range(thread_handoff, image_data, lh)
+                            .context(here!())?,
+                    );
+                }
+
+                lh.write_baseline_segments(writer, results, lh.baseline_size_limit())
+                    .context(here!())
+            }
+        })
+    }
+
+    /// True if the scans have to be written from the coefficients of the whole image, which
+    /// is the case for progressive and arithmetic coded images, and for baseline images with
+    /// non-interleaved scans. The headers of the scans after the first one are then part of
+    /// the raw header, while a baseline image with a single scan has all of its raw header
+    /// in front of the scan.
+    fn has_multiple_scans(&self) -> bool {
+        self.jpeg_header.jpeg_type == JPegType::Progressive
+            || self.jpeg_header.arithmetic
+            || self.raw_jpeg_header_read_index < self.raw_jpeg_header.len()
+    }
+
+    /// writes everything around the scans: the SOI, the raw header, and after the scans
+    /// written by `write_scans` the trailing header data and the garbage at the end of the file
+    fn write_jpeg_framing<W: Write, T>(
+        &mut self,
+        writer: &mut W,
+        write_scans: impl FnOnce(&mut Self, &mut W) -> Result<T>,
+    ) -> Result<T> {
         writer.write_all(&SOI)?;
 
         // write the raw header as far as we've decoded it
@@ -597,22 +1230,7 @@ impl LeptonHeader {
             .write_all(&self.raw_jpeg_header[0..self.raw_jpeg_header_read_index])
             .context(here!())?;
 
-        let metrics = if self.jpeg_header.jpeg_type == JPegType::Progressive {
-            self.recode_progressive_jpeg(reader, writer, num_threads, enabled_features)
-                .context(here!())?
-        } else {
-            self.recode_baseline_jpeg(
-                reader,
-                writer,
-                self.plain_text_size as u64
-                    - self.garbage_data.len() as u64
-                    - self.raw_jpeg_header_read_index as u64
-                    - SOI.len() as u64,
-                num_threads,
-                enabled_features,
-            )
-            .context(here!())?
-        };
+        let result = write_scans(self, writer).context(here!())?;
 
         // Blit any trailing header data.
         // Run this logic even if early_eof_encountered to be compatible with C++ version.
@@ -621,7 +1239,16 @@ impl LeptonHeader {
             .context(here!())?;
 
         writer.write_all(&self.garbage_data).context(here!())?;
-        Ok(metrics)
+        Ok(result)
+    }
+
+    /// size of the scan data of a baseline image, which the restart markers added
+    /// for restart errors can't exceed
+    fn baseline_size_limit(&self) -> u64 {
+        self.plain_text_size as u64
+            - self.garbage_data.len() as u64
+            - self.raw_jpeg_header_read_index as u64
+            - SOI.len() as u64
     }
 
     /// decodes the entire image and merges the results into a single set of BlockBaseImage per component
@@ -649,12 +1276,171 @@ impl LeptonHeader {
 
         let num_components = results[0].len();
         for i in 0..num_components {
-            merged.push(BlockBasedImage::merge(&mut results, i));
+            merged.push(BlockBasedImage::merge(&mut results, i).context(here!())?);
         }
 
         Ok((merged, metrics))
     }
 
+    /// Decodes the entire image into horizontal bands of `band_height` luma block rows, so that
+    /// tiles can be cached and served per band without merging the image first. Returns the
+    /// images of every component for each band. Like the images decoded by each thread, the
+    /// images of a band keep their position in the full image, so merging the bands gives the
+    /// same image as `decode_as_single_image`. `band_height` has to be a multiple of the MCU
+    /// row height so that every component is split at whole rows.
+    #[allow(dead_code)]
+    pub fn decode_banded<R: Read>(
+        &mut self,
+        reader: &mut R,
+        band_height: i32,
+        num_threads: usize,
+        features: &EnabledFeatures,
+    ) -> Result<(Vec<Vec<BlockBasedImage>>, Metrics)> {
+        let mcu_row_height = self.get_mcu_row_height();
+        if band_height <= 0 || band_height % mcu_row_height != 0 {
+            return err_exit_code(
+                ExitCode::SyntaxError,
+                format!(
+                    "band height {0} is not a multiple of the MCU row height {1}",
+                    band_height, mcu_row_height
+                )
+                .as_str(),
+            );
+        }
+
+        let (metrics, results) = run_lepton_decoder_threads(
+            self,
+            reader,
+            num_threads,
+            features,
+            |_thread_handoff, image_data, _lh| {
+                return Ok(image_data);
+            },
+        )
+        .context(here!())?;
+
+        // regroup the images of the threads by component
+        let num_components = results[0].len();
+        let mut per_component: Vec<Vec<BlockBasedImage>> =
+            (0..num_components).map(|_| Vec::new()).collect();
+        for thread_images in results {
+            for (i, image) in thread_images.into_iter().enumerate() {
+                per_component[i].push(image);
+            }
+        }
+
+        let mut bands: Vec<Vec<BlockBasedImage>> = Vec::new();
+        for (i, images) in per_component.into_iter().enumerate() {
+            let component_rows_per_mcu = self.jpeg_header.cmp_info[i]
+                .bcv
+                .checked_div(self.jpeg_header.mcuv)
+                .unwrap_or(0)
+                .max(1);
+
+            let component_bands = BlockBasedImage::split_into_bands(
+                images,
+                band_height / mcu_row_height * component_rows_per_mcu,
+            )
+            .context(here!())?;
+
+            bands.resize_with(component_bands.len(), Vec::new);
+            for (band, image) in bands.iter_mut().zip(component_bands) {
+                band.push(image);
+            }
+        }
+
+        Ok((bands, metrics))
+    }
+
+    /// number of luma block rows in an MCU row
+    fn get_mcu_row_height(&self) -> i32 {
+        self.jpeg_header.cmp_info[0]
+            .bcv
+            .checked_div(self.jpeg_header.mcuv)
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    /// Decodes a single MCU row of a file that was encoded with `row_seekable`, without
+    /// decoding any of the rows before it. The reader has to be positioned right after the
+    /// header that was read by `read_lepton_header`, and only the parts of the file that
+    /// belong to the row are read. Returns the blocks of the row for every component.
+    #[allow(dead_code)]
+    pub fn decode_mcu_row<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        mcu_row: i32,
+        features: &EnabledFeatures,
+    ) -> Result<Vec<BlockBasedImage>> {
+        if self.row_index.is_empty() {
+            return err_exit_code(ExitCode::SyntaxError, "file is not row seekable");
+        }
+
+        let row_height = self.get_mcu_row_height();
+        let min_y = mcu_row.saturating_mul(row_height);
+
+        // find the thread whose stream contains the row
+        let thread_id = match self
+            .thread_handoff
+            .iter()
+            .zip(self.row_index.iter())
+            .position(|(th, rows)| {
+                let luma_y_end = i32::try_from(rows.len())
+                    .ok()
+                    .and_then(|num_rows| num_rows.checked_mul(row_height))
+                    .and_then(|height| height.checked_add(th.luma_y_start));
+
+                luma_y_end.is_some_and(|luma_y_end| min_y >= th.luma_y_start && min_y < luma_y_end)
+            }) {
+            Some(thread_id) => thread_id,
+            None => {
+                return err_exit_code(
+                    ExitCode::SyntaxError,
+                    format!("MCU row {0} is not in the file", mcu_row).as_str(),
+                );
+            }
+        };
+
+        let row_sizes = &self.row_index[thread_id];
+        let row = ((min_y - self.thread_handoff[thread_id].luma_y_start) / row_height) as usize;
+        let offset = row_sizes[..row].iter().map(|&x| u64::from(x)).sum();
+
+        let stream = multiplex_read_range(reader, thread_id, offset, row_sizes[row] as usize)
+            .context(here!())?;
+
+        let pts = ProbabilityTablesSet::new();
+        let qt =
+            new_quantization_tables(&self.jpeg_header, self.jpeg_header.cmpc).context(here!())?;
+
+        let mut image_data = Vec::new();
+        for i in 0..self.jpeg_header.cmpc {
+            image_data.push(
+                BlockBasedImage::new(&self.jpeg_header, i, min_y, min_y + row_height, features)
+                    .context(here!())?,
+            );
+        }
+
+        lepton_decode_row_range(
+            &pts,
+            &qt,
+            &self.truncate_components,
+            &mut image_data,
+            &mut Cursor::new(stream),
+            RowRangeOptions {
+                min_y,
+                max_y: min_y + row_height,
+                is_last_thread: thread_id == self.thread_handoff.len() - 1
+                    && row == row_sizes.len() - 1,
+                full_file_compression: true,
+                compute_hash: false,
+            },
+            features,
+        )
+        .context(here!())?;
+
+        Ok(image_data)
+    }
+
     /// parses and advances to the next header segment out of raw_jpeg_header into the jpeg header
     pub fn advance_next_header_segment(
         &mut self,
@@ -673,7 +1459,8 @@ impl LeptonHeader {
         Ok(result)
     }
 
-    /// progressive decoder, requires that the entire lepton file is processed first
+    /// progressive decoder, requires that the entire lepton file is processed first. Also used
+    /// for arithmetic coded images, since they can't be split into independent sections.
     fn recode_progressive_jpeg<R: Read, W: Write>(
         &mut self,
         reader: &mut R,
@@ -686,9 +1473,26 @@ impl LeptonHeader {
             .decode_as_single_image(reader, num_threads, enabled_features)
             .context(here!())?;
 
+        self.write_progressive_scans(writer, &merged, enabled_features)
+            .context(here!())?;
+
+        Ok(metrics)
+    }
+
+    /// writes all the scans of a progressive or arithmetic coded image from the complete coefficients
+    fn write_progressive_scans<W: Write>(
+        &mut self,
+        writer: &mut W,
+        merged: &[BlockBasedImage],
+        enabled_features: &EnabledFeatures,
+    ) -> Result<()> {
         loop {
             // code another scan
-            jpeg_write_entire_scan(writer, &merged[..], self).context(here!())?;
+            if self.jpeg_header.arithmetic {
+                jpeg_write_arithmetic_scan(writer, merged, &self.jpeg_header).context(here!())?;
+            } else {
+                jpeg_write_entire_scan(writer, merged, self).context(here!())?;
+            }
 
             // read the next headers (DHT, etc) while mirroring it back to the writer
             let old_pos = self.raw_jpeg_header_read_index;
@@ -708,7 +1512,7 @@ impl LeptonHeader {
             self.scnc += 1;
         }
 
-        Ok(metrics)
+        Ok(())
     }
 
     // baseline decoder can run the jpeg encoder inside the worker thread vs progressive encoding which needs to get the entire set of coefficients first
@@ -728,45 +1532,69 @@ impl LeptonHeader {
             num_threads,
             enabled_features,
             |thread_handoff, image_data, lh| {
-                let mut result_buffer = Vec::with_capacity(thread_handoff.segment_size as usize);
-                let mut cursor = Cursor::new(&mut result_buffer);
+                Self::write_baseline_row_range(thread_handoff, &image_data, lh)
+            },
+        )?;
+
+        self.write_baseline_segments(writer, results, size_limit)
+            .context(here!())?;
 
-                let mut huffw = BitWriter::new();
+        Ok(metrics)
+    }
 
-                let _start_size = cursor.position();
+    /// writes the scan data of the rows of a single thread of a baseline image
+    fn write_baseline_row_range(
+        thread_handoff: &ThreadHandoff,
+        image_data: &[BlockBasedImage],
+        lh: &LeptonHeader,
+    ) -> Result<Vec<u8>> {
+        let mut result_buffer = Vec::with_capacity(thread_handoff.segment_size as usize);
+        let mut cursor = Cursor::new(&mut result_buffer);
 
-                let max_coded_heights = lh.truncate_components.get_max_coded_heights();
+        let mut huffw = BitWriter::new();
 
-                jpeg_write_row_range(
-                    &mut cursor,
-                    &image_data,
-                    lh.truncate_components.mcu_count_vertical,
-                    &thread_handoff,
-                    &max_coded_heights[..],
-                    &mut huffw,
-                    lh,
-                )
-                .context(here!())?;
+        let _start_size = cursor.position();
 
-                #[cfg(detailed_tracing)]
-                info!("ystart = {0}, segment_size = {1}, amount = {2}, offset = {3}, ob = {4}, nb = {5}", 
-                combined_thread_handoff.luma_y_start,
-                combined_thread_handoff.segment_size,
-                cursor.position() - _start_size,
-                combined_thread_handoff.segment_offset_in_file,
-                combined_thread_handoff.overhang_byte,
-                combined_thread_handoff.num_overhang_bits
-            );
+        let max_coded_heights = lh.truncate_components.get_max_coded_heights();
 
-                if result_buffer.len() > thread_handoff.segment_size as usize {
-                    warn!("warning: truncating segment");
-                    result_buffer.resize(thread_handoff.segment_size as usize, 0);
-                }
+        jpeg_write_row_range(
+            &mut cursor,
+            image_data,
+            lh.truncate_components.mcu_count_vertical,
+            &thread_handoff,
+            &max_coded_heights[..],
+            &mut huffw,
+            lh,
+        )
+        .context(here!())?;
 
-                return Ok(result_buffer);
-            },
-        )?;
+        #[cfg(detailed_tracing)]
+        info!(
+            "ystart = {0}, segment_size = {1}, amount = {2}, offset = {3}, ob = {4}, nb = {5}",
+            thread_handoff.luma_y_start,
+            thread_handoff.segment_size,
+            cursor.position() - _start_size,
+            thread_handoff.segment_offset_in_file,
+            thread_handoff.overhang_byte,
+            thread_handoff.num_overhang_bits
+        );
+
+        if result_buffer.len() > thread_handoff.segment_size as usize {
+            warn!("warning: truncating segment");
+            result_buffer.resize(thread_handoff.segment_size as usize, 0);
+        }
+
+        Ok(result_buffer)
+    }
 
+    /// writes the scan data of all the threads of a baseline image, followed by any restart
+    /// markers that have to be injected for restart errors
+    fn write_baseline_segments<W: Write>(
+        &self,
+        writer: &mut W,
+        results: Vec<Vec<u8>>,
+        size_limit: u64,
+    ) -> Result<()> {
         let mut amount_written: u64 = 0;
 
         // write all the buffers that we collected
@@ -803,7 +1631,7 @@ impl LeptonHeader {
             }
         }
 
-        Ok(metrics)
+        Ok(())
     }
 
     /// reads the start of the lepton file and parses the compressed header. Returns the raw JPEG header contents.
@@ -823,12 +1651,13 @@ impl LeptonHeader {
         // Complicated logic of version compatibility should be verified by the caller.
         // Currently just matching the version version.
         let version = reader.read_u8().context(here!())?;
-        if version != LEPTON_VERSION {
+        if version != LEPTON_VERSION && version != LEPTON_VERSION_CRC32_TRAILER {
             return err_exit_code(
                 ExitCode::VersionUnsupported,
                 format!("incompatible file with version {0}", version).as_str(),
             );
         }
+        self.has_crc32_trailer = version == LEPTON_VERSION_CRC32_TRAILER;
 
         let mut header = [0 as u8; 21];
         reader.read_exact(&mut header).context(here!())?;
@@ -853,11 +1682,49 @@ impl LeptonHeader {
             c.set_position(7);
             self.uncompressed_lepton_header_size = c.read_u32::<LittleEndian>()?;
 
-            // read the flag bits to know how we should decode this file
+            // read the flag bits to know how we should decode this file. If they are
+            // missing, we stick with whatever the caller passed in (legacy behavior).
             let flags = c.read_u8()?;
-            if (flags & 0x80) != 0 {
-                enabled_features.use_16bit_dc_estimate = (flags & 0x01) != 0;
-                enabled_features.use_16bit_adv_predict = (flags & 0x02) != 0;
+            if (flags & LEPTON_HEADER_FLAGS_PRESENT) != 0 {
+                self.stream_flags = Some(flags);
+
+                let use_16bit_dc_estimate = (flags & LEPTON_HEADER_FLAG_16BIT_DC_ESTIMATE) != 0;
+                let use_16bit_adv_predict = (flags & LEPTON_HEADER_FLAG_16BIT_ADV_PREDICT) != 0;
+
+                if enabled_features.require_matching_stream_flags
+                    && (use_16bit_dc_estimate != enabled_features.use_16bit_dc_estimate
+                        || use_16bit_adv_predict != enabled_features.use_16bit_adv_predict)
+                {
+                    return err_exit_code(
+                        ExitCode::VersionUnsupported,
+                        format!(
+                            "file was encoded with use_16bit_dc_estimate={0} use_16bit_adv_predict={1} which conflicts with the requested features",
+                            use_16bit_dc_estimate, use_16bit_adv_predict
+                        )
+                        .as_str(),
+                    );
+                }
+
+                enabled_features.use_16bit_dc_estimate = use_16bit_dc_estimate;
+                enabled_features.use_16bit_adv_predict = use_16bit_adv_predict;
+            }
+
+            // the model version follows the flags, files written before it was stored have a
+            // zero there and are decoded with the version the caller passed in
+            let model_version = c.read_u8()?;
+            if model_version != 0 {
+                let Some(model_version) = ModelVersion::from_header_byte(model_version) else {
+                    return err_exit_code(
+                        ExitCode::VersionUnsupported,
+                        format!(
+                            "file was encoded with unknown model version {0}",
+                            model_version
+                        )
+                        .as_str(),
+                    );
+                };
+
+                self.model_version = Some(model_version);
             }
         }
 
@@ -899,6 +1766,27 @@ impl LeptonHeader {
             self.raw_jpeg_header_read_index = header_data_cursor.position() as usize;
         }
 
+        // the version the encoder would have written depends on the precision of the image
+        if let Some(model_version) = self.model_version {
+            if enabled_features.require_matching_stream_flags
+                && model_version
+                    != enabled_features
+                        .model_version
+                        .for_precision(self.jpeg_header.precision)
+            {
+                return err_exit_code(
+                    ExitCode::VersionUnsupported,
+                    format!(
+                        "file was encoded with model version {0:?} which conflicts with the requested features",
+                        model_version
+                    )
+                    .as_str(),
+                );
+            }
+
+            enabled_features.model_version = model_version;
+        }
+
         self.truncate_components.init(&self.jpeg_header);
 
         if self.early_eof_encountered {
@@ -908,10 +1796,38 @@ impl LeptonHeader {
 
         let num_threads = self.thread_handoff.len();
 
+        if !self.row_index.is_empty() && self.row_index.len() != num_threads {
+            return err_exit_code(
+                ExitCode::BadLeptonFile,
+                "row index doesn't match the number of threads",
+            );
+        }
+
         // luma_y_end of the last thread is not serialized/deserialized, fill it here
         self.thread_handoff[num_threads - 1].luma_y_end =
             self.truncate_components.get_block_height(0);
 
+        // the row index has to have a size for every MCU row that the encoder wrote for each
+        // thread, since decode_mcu_row finds the row in the stream of a thread by its number
+        if !self.row_index.is_empty() {
+            let row_height = self.get_mcu_row_height() as u32;
+
+            for (thread_handoff, row_sizes) in self.thread_handoff.iter().zip(&self.row_index) {
+                let num_rows = thread_handoff
+                    .luma_y_end
+                    .checked_sub(thread_handoff.luma_y_start)
+                    .and_then(|height| u32::try_from(height).ok())
+                    .map(|height| (height + row_height - 1) / row_height);
+
+                if num_rows != Some(row_sizes.len() as u32) {
+                    return err_exit_code(
+                        ExitCode::BadLeptonFile,
+                        "row index doesn't match the MCU rows of the threads",
+                    );
+                }
+            }
+        }
+
         // if the last segment was too big to fit with the garbage data taken into account, shorten it
         // (a bit of broken logic in the encoder, but can't change it without breaking the file format)
         if self.early_eof_encountered {
@@ -1037,6 +1953,25 @@ impl LeptonHeader {
                 self.max_dpos[2] = header_reader.read_i32::<LittleEndian>()?;
                 self.max_dpos[3] = header_reader.read_i32::<LittleEndian>()?;
                 self.early_eof_encountered = true;
+            } else if buffer_prefix_matches_marker(
+                current_lepton_marker,
+                LEPTON_HEADER_ROW_INDEX_MARKER,
+            ) {
+                // RSI marker
+                // read the sizes of the MCU rows in the stream of each thread
+                let num_threads = header_reader.read_u8()?;
+                for _i in 0..num_threads {
+                    let num_rows = header_reader.read_u32::<LittleEndian>()?;
+                    if num_rows > MAX_FILE_SIZE_BYTES as u32 {
+                        return err_exit_code(ExitCode::BadLeptonFile, "Too many rows in index");
+                    }
+
+                    let mut row_sizes = Vec::new();
+                    for _j in 0..num_rows {
+                        row_sizes.push(header_reader.read_u32::<LittleEndian>()?);
+                    }
+                    self.row_index.push(row_sizes);
+                }
             } else {
                 return err_exit_code(ExitCode::BadLeptonFile, "unknown data found");
             }
@@ -1069,6 +2004,7 @@ impl LeptonHeader {
             self.write_lepton_jpeg_restart_errors_if_needed(&mut mrw)?;
             self.write_lepton_early_eof_truncation_data_if_needed(&mut mrw)?;
             self.write_lepton_jpeg_garbage_if_needed(&mut mrw, false)?;
+            self.write_lepton_row_index_if_needed(&mut mrw)?;
         }
 
         let mut compressed_header = Vec::<u8>::new(); // we collect a zlib compressed version of the header here
@@ -1081,7 +2017,11 @@ impl LeptonHeader {
         }
 
         writer.write_all(&LEPTON_FILE_HEADER)?;
-        writer.write_u8(LEPTON_VERSION)?;
+        writer.write_u8(if enabled_features.write_crc32_trailer {
+            LEPTON_VERSION_CRC32_TRAILER
+        } else {
+            LEPTON_VERSION
+        })?;
 
         if self.jpeg_header.jpeg_type == JPegType::Progressive {
             writer.write_all(&LEPTON_HEADER_PROGRESSIVE_JPEG_TYPE)?;
@@ -1100,18 +2040,22 @@ impl LeptonHeader {
 
         // write the flags that were used to encode this file
         writer.write_u8(
-            0x80 | if enabled_features.use_16bit_dc_estimate {
-                1
-            } else {
-                0
-            } | if enabled_features.use_16bit_adv_predict {
-                2
-            } else {
-                0
-            },
+            LEPTON_HEADER_FLAGS_PRESENT
+                | if enabled_features.use_16bit_dc_estimate {
+                    LEPTON_HEADER_FLAG_16BIT_DC_ESTIMATE
+                } else {
+                    0
+                }
+                | if enabled_features.use_16bit_adv_predict {
+                    LEPTON_HEADER_FLAG_16BIT_ADV_PREDICT
+                } else {
+                    0
+                },
         )?;
 
-        writer.write_all(&[0; 5])?;
+        writer.write_u8(enabled_features.model_version.to_header_byte())?;
+
+        writer.write_all(&[0; 4])?;
 
         writer.write_u32::<LittleEndian>(self.jpeg_file_size)?;
         writer.write_u32::<LittleEndian>(compressed_header.len() as u32)?;
@@ -1184,6 +2128,25 @@ impl LeptonHeader {
         Ok(())
     }
 
+    fn write_lepton_row_index_if_needed<W: Write>(&self, mrw: &mut W) -> Result<()> {
+        if !self.row_index.is_empty() {
+            // marker: "RSI" + [number of threads] + [number of rows and their sizes per thread]
+            mrw.write_all(&LEPTON_HEADER_ROW_INDEX_MARKER)?;
+
+            mrw.write_u8(self.row_index.len() as u8)?;
+
+            for row_sizes in &self.row_index {
+                mrw.write_u32::<LittleEndian>(row_sizes.len() as u32)?;
+
+                for &size in row_sizes {
+                    mrw.write_u32::<LittleEndian>(size)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn write_lepton_early_eof_truncation_data_if_needed<W: Write>(
         &self,
         mrw: &mut W,
@@ -1315,6 +2278,9 @@ fn split_row_handoffs_to_threads(
     return selected_splits;
 }
 
+// Small images are not worth splitting since each thread adds the cost of a fresh model
+// to the compressed size, so images with less than SMALL_FILE_BYTES_PER_ENCDOING_THREAD
+// bytes of scan data per thread are encoded with fewer threads (down to a single one).
 fn get_number_of_threads_for_encoding(
     num_rows: usize,
     framebuffer_byte_size: usize,
@@ -1407,6 +2373,15 @@ fn set_segment_size_in_row_thread_handoffs(
     }
 }
 
+#[cfg(test)]
+use crate::structs::synthetic_jpeg::synthetic_jpeg;
+
+// a 4:2:0 image whose scan is large enough to be split between 4 threads
+#[cfg(test)]
+fn multithreaded_jpeg() -> Vec<u8> {
+    synthetic_jpeg(&[0x22, 0x11, 0x11], 1280, 2048)
+}
+
 // test serializing and deserializing header
 #[test]
 fn parse_and_write_header() {
@@ -1456,3 +2431,284 @@ fn parse_and_write_header() {
         .read_lepton_header(&mut other_reader, &mut enabled_features)
         .unwrap();
 }
+
+// every component should be dequantized with the table it references, here three distinct
+// tables for three components, and a reference to an undefined table should be rejected
+#[test]
+fn quantization_table_per_component() {
+    use crate::lepton_error::LeptonError;
+
+    let file = synthetic_jpeg(&[0x11, 0x11, 0x11], 24, 16);
+
+    // find the SOF0 segment, the image has table 0 for luma and table 1 for both chroma components
+    let mut sof = 2;
+    while file[sof + 1] != jpeg_code::SOF0 {
+        sof += 2 + usize::from(file[sof + 2]) * 256 + usize::from(file[sof + 3]);
+    }
+    let cr_table_index = sof + 10 + 2 * 3 + 2;
+    assert_eq!(file[cr_table_index], 1);
+
+    // insert a third table in front of the SOF and use it for Cr
+    let mut table2 = [0u8; 64];
+    for (i, q) in table2.iter_mut().enumerate() {
+        *q = 100 + i as u8;
+    }
+
+    let with_table = |index: u8| {
+        let mut jpeg = file[..sof].to_vec();
+        jpeg.extend_from_slice(&[0xFF, jpeg_code::DQT, 0, 67, 2]);
+        jpeg.extend_from_slice(&table2);
+        jpeg.extend_from_slice(&file[sof..]);
+        jpeg[cr_table_index + 69] = index;
+        jpeg
+    };
+
+    let jpeg = with_table(2);
+    let features = EnabledFeatures::compat_lepton_vector_write();
+    let (lh, _) = read_jpeg(&mut Cursor::new(&jpeg), &features, 1, |_| {}).unwrap();
+
+    for (cmp, table) in [(0, 0), (1, 1), (2, 2)] {
+        assert_eq!(
+            usize::from(lh.jpeg_header.cmp_info[cmp].q_table_index),
+            table
+        );
+        let qt = QuantizationTables::new(&lh.jpeg_header, cmp);
+        for i in 0..64 {
+            assert_eq!(
+                qt.get_quantization_table()[i],
+                lh.jpeg_header.q_tables[table][usize::from(RASTER_TO_ZIGZAG[i])]
+            );
+        }
+    }
+    assert_eq!(
+        QuantizationTables::new(&lh.jpeg_header, 2).get_quantization_table()[0],
+        100
+    );
+
+    let mut lepton = Vec::new();
+    encode_lepton_wrapper(
+        &mut Cursor::new(&jpeg),
+        &mut Cursor::new(&mut lepton),
+        1,
+        &features,
+    )
+    .unwrap();
+
+    let mut output = Vec::new();
+    decode_lepton_wrapper(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        1,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert!(output[..] == jpeg[..]);
+
+    // table 3 is never defined
+    let e = read_jpeg(&mut Cursor::new(&with_table(3)), &features, 1, |_| {})
+        .err()
+        .unwrap();
+    let e = e.root_cause().downcast_ref::<LeptonError>().unwrap();
+    assert_eq!(e.exit_code, ExitCode::UnsupportedJpeg);
+    assert!(e.message.contains("quantization table 3"));
+}
+
+// a row seekable file has to round trip, and a row from the middle of the image has to
+// decode on its own to the same blocks as the full image
+#[test]
+fn row_seekable_decode_single_row() {
+    let file = multithreaded_jpeg();
+
+    let features = EnabledFeatures {
+        row_seekable: true,
+        ..EnabledFeatures::compat_lepton_vector_write()
+    };
+
+    let (lepton, _) = encode_lepton_wrapper_verify(&file, 4, &features).unwrap();
+
+    let (default_lepton, _) =
+        encode_lepton_wrapper_verify(&file, 4, &EnabledFeatures::compat_lepton_vector_write())
+            .unwrap();
+    assert!(lepton.len() > default_lepton.len());
+
+    let (_, image_data) = read_jpeg(&mut Cursor::new(&file), &features, 4, |_| {}).unwrap();
+
+    let mut reader = Cursor::new(&lepton[..]);
+    let mut lh = LeptonHeader::new();
+    let mut read_features = EnabledFeatures::compat_lepton_vector_read();
+    lh.read_lepton_header(&mut reader, &mut read_features)
+        .unwrap();
+    assert_eq!(lh.thread_handoff.len(), 4);
+
+    let mcu_row = lh.jpeg_header.mcuv / 2;
+    let row = lh
+        .decode_mcu_row(&mut reader, mcu_row, &read_features)
+        .unwrap();
+
+    for (c, (row_image, full_image)) in row.iter().zip(image_data.iter()).enumerate() {
+        let width = full_image.get_block_width();
+        let rows_per_mcu = full_image.get_original_height() / lh.jpeg_header.mcuv;
+
+        for dpos in mcu_row * rows_per_mcu * width..(mcu_row + 1) * rows_per_mcu * width {
+            assert_eq!(
+                row_image.get_block(dpos).get_block(),
+                full_image.get_block(dpos).get_block(),
+                "component {c} dpos {dpos}"
+            );
+        }
+    }
+}
+
+// the header of a row seekable file is rejected if the row index of a thread doesn't have
+// the size of each of its MCU rows, and rows outside of the image are not found
+#[test]
+fn row_index_matches_mcu_rows() {
+    let features = EnabledFeatures {
+        row_seekable: true,
+        ..EnabledFeatures::compat_lepton_vector_write()
+    };
+    let (lepton, _) =
+        encode_lepton_wrapper_verify(&synthetic_jpeg(&[0x11], 64, 56), 1, &features).unwrap();
+
+    let mut lh = LeptonHeader::new();
+    let mut read_features = EnabledFeatures::compat_lepton_vector_read();
+    lh.read_lepton_header(&mut Cursor::new(&lepton), &mut read_features)
+        .unwrap();
+    assert_eq!(lh.row_index[0].len(), 7);
+
+    for mcu_row in [-1, 7, i32::MAX] {
+        let e = lh
+            .decode_mcu_row(&mut Cursor::new(&lepton), mcu_row, &read_features)
+            .err()
+            .unwrap();
+        let e = e.root_cause().downcast_ref::<LeptonError>().unwrap();
+        assert_eq!(e.exit_code, ExitCode::SyntaxError, "row {mcu_row}");
+    }
+
+    lh.row_index[0].pop();
+    let mut header = Vec::new();
+    lh.write_lepton_header(&mut Cursor::new(&mut header), &read_features)
+        .unwrap();
+
+    let e = LeptonHeader::new()
+        .read_lepton_header(&mut Cursor::new(&header), &mut read_features)
+        .err()
+        .unwrap();
+    let e = e.root_cause().downcast_ref::<LeptonError>().unwrap();
+    assert_eq!(e.exit_code, ExitCode::BadLeptonFile);
+}
+
+// merging the bands has to give the same image as decoding it in one piece, also when the bands
+// don't line up with the rows decoded by each thread
+#[test]
+fn decode_banded_matches_single_image() {
+    let file = multithreaded_jpeg();
+
+    let (lepton, _) =
+        encode_lepton_wrapper_verify(&file, 4, &EnabledFeatures::compat_lepton_vector_write())
+            .unwrap();
+
+    let read_header = || {
+        // the last 4 bytes are the size of the JPEG
+        let mut reader = Cursor::new(&lepton[..lepton.len() - 4]);
+        let mut lh = LeptonHeader::new();
+        let mut features = EnabledFeatures::compat_lepton_vector_read();
+        lh.read_lepton_header(&mut reader, &mut features).unwrap();
+        (lh, reader, features)
+    };
+
+    let (mut lh, mut reader, features) = read_header();
+    let (full, _) = lh
+        .decode_as_single_image(&mut reader, 4, &features)
+        .unwrap();
+
+    let (mut lh, mut reader, features) = read_header();
+    let mcu_row_height = lh.get_mcu_row_height();
+    assert_eq!(lh.thread_handoff.len(), 4);
+
+    // not a multiple of the MCU row height
+    assert!(lh
+        .decode_banded(&mut reader, mcu_row_height + 1, 4, &features)
+        .is_err());
+
+    let (mut lh, mut reader, features) = read_header();
+    let band_height = 7 * mcu_row_height;
+    let (mut bands, _) = lh
+        .decode_banded(&mut reader, band_height, 4, &features)
+        .unwrap();
+
+    let luma_height = full[0].get_original_height();
+    assert_eq!(
+        bands.len() as i32,
+        (luma_height + band_height - 1) / band_height
+    );
+
+    assert!(bands.iter().all(|b| b.len() == full.len()));
+
+    for (i, full_image) in full.iter().enumerate() {
+        let merged = BlockBasedImage::merge(&mut bands, i).unwrap();
+        assert_eq!(merged.content_hash(), full_image.content_hash());
+    }
+}
+
+// a threshold of zero doesn't change anything, while a larger one zeros exactly the small AC
+// coefficients and the file decodes to the JPEG written from what is left
+#[test]
+fn lossy_ac_threshold_zeros_small_coefficients() {
+    let file = multithreaded_jpeg();
+
+    let lossless = EnabledFeatures::compat_lepton_vector_write();
+    let (lepton, _) = encode_lepton_wrapper_verify(&file, 4, &lossless).unwrap();
+
+    let (lepton_zero, _) = encode_lepton_wrapper_verify(
+        &file,
+        4,
+        &EnabledFeatures {
+            lossy_ac_threshold: Some(0),
+            ..lossless
+        },
+    )
+    .unwrap();
+    assert_eq!(lepton_zero, lepton);
+
+    let (lepton_lossy, _) = encode_lepton_wrapper_verify(
+        &file,
+        4,
+        &EnabledFeatures {
+            lossy_ac_threshold: Some(2),
+            ..lossless
+        },
+    )
+    .unwrap();
+    assert!(lepton_lossy.len() < lepton.len());
+
+    let mut decoded = Vec::new();
+    decode_lepton_wrapper(
+        &mut Cursor::new(&lepton_lossy),
+        &mut decoded,
+        4,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let count = |jpeg: &[u8]| {
+        let (_, images) = read_jpeg(&mut Cursor::new(jpeg), &lossless, 4, |_jh| {}).unwrap();
+        let mut nonzero = 0;
+        let mut ones = 0;
+        for image in images.iter() {
+            for dpos in 0..image.get_stored_block_count() as i32 {
+                for &c in image.get_block(dpos).get_block()[1..].iter() {
+                    nonzero += usize::from(c != 0);
+                    ones += usize::from(c.abs() == 1);
+                }
+            }
+        }
+        (nonzero, ones)
+    };
+
+    let (original_nonzero, original_ones) = count(&file);
+    let (cleaned_nonzero, cleaned_ones) = count(&decoded);
+    assert!(original_ones > 0);
+    assert_eq!(cleaned_ones, 0);
+    assert_eq!(cleaned_nonzero, original_nonzero - original_ones);
+}
@@ -8,29 +8,71 @@
 // badly/maliciously formatted images, we want this extra level of safety.
 #![forbid(unsafe_code)]
 
+// Only the modules without a cfg attribute are part of the no_std core (see lib.rs).
+
+#[cfg(feature = "std")]
+mod arithmetic_reader;
+#[cfg(feature = "std")]
+mod arithmetic_writer;
+#[cfg(feature = "tokio")]
+mod async_vpx_bool_reader;
+#[cfg(feature = "std")]
+pub(crate) mod batch_decoder;
+#[cfg(feature = "std")]
 mod bit_reader;
+#[cfg(feature = "std")]
 mod bit_writer;
-mod block_based_image;
-mod block_context;
+pub mod block_based_image;
+#[cfg(feature = "std")]
+pub(crate) mod block_context;
 mod branch;
 mod component_info;
+#[cfg(feature = "std")]
+mod counting_writer;
 mod idct;
+#[cfg(feature = "std")]
+pub(crate) mod io_error_tracker;
+#[cfg(feature = "std")]
+mod jpeg_arithmetic;
+#[cfg(feature = "std")]
 mod jpeg_header;
+#[cfg(feature = "std")]
 mod jpeg_position_state;
+#[cfg(feature = "std")]
 mod jpeg_read;
+#[cfg(feature = "std")]
 mod jpeg_write;
-mod lepton_decoder;
+#[cfg(feature = "std")]
+pub(crate) mod lepton_decoder;
+#[cfg(feature = "std")]
 mod lepton_encoder;
+#[cfg(feature = "std")]
+pub(crate) mod lepton_file_info;
+#[cfg(feature = "std")]
 pub mod lepton_format;
-mod model;
+#[cfg(feature = "std")]
+pub(crate) mod model;
+#[cfg(feature = "std")]
 mod multiplexer;
-mod neighbor_summary;
-mod probability_tables;
-mod probability_tables_set;
-mod quantization_tables;
+#[cfg(feature = "std")]
+pub(crate) mod neighbor_summary;
+mod power_of_two;
+#[cfg(feature = "std")]
+pub(crate) mod probability_tables;
+#[cfg(feature = "std")]
+pub(crate) mod probability_tables_set;
+pub(crate) mod quantization_tables;
+#[cfg(feature = "std")]
 mod row_spec;
 mod simple_hash;
+#[cfg(test)]
+mod synthetic_jpeg;
+#[cfg(feature = "std")]
+mod tap_reader;
+#[cfg(feature = "std")]
 mod thread_handoff;
-mod truncate_components;
-mod vpx_bool_reader;
+#[cfg(feature = "std")]
+pub(crate) mod truncate_components;
+pub(crate) mod vpx_bool_reader;
+#[cfg(feature = "std")]
 mod vpx_bool_writer;
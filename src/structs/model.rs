@@ -9,6 +9,9 @@ use std::cmp;
 use std::io::{Read, Write};
 
 use crate::consts::*;
+use crate::enabled_features::{
+    DcUncertaintyBins, ModelVersion, DC_UNCERTAINTY2_BINS, DC_UNCERTAINTY_BINS,
+};
 use crate::helpers::{calc_sign_index, err_exit_code, here, u16_bit_length, u32_bit_length};
 use crate::lepton_error::ExitCode;
 use crate::metrics::{ModelComponent, ModelSubComponent};
@@ -26,6 +29,10 @@ const NUMERIC_LENGTH_MAX: usize = 12;
 pub const MAX_EXPONENT: usize = 11; // range from 0 to 1023 requires 11 bins to describe
 const COEF_BITS: usize = MAX_EXPONENT - 1; // the MSB of the value is always 1
 
+// the wide model versions code longer coefficients with the same branches plus bypass bits
+const _: () = assert!(ModelVersion::V1.max_coefficient_bits() == MAX_EXPONENT);
+const _: () = assert!(ModelVersion::V1WideCoefficients.max_coefficient_bits() < 16);
+
 const NON_ZERO_7X7_COUNT_BITS: usize = 49_usize.ilog2() as usize + 1;
 const NON_ZERO_EDGE_COUNT_BITS: usize = 7_usize.ilog2() as usize + 1;
 // 0th bin corresponds to 0 non-zeros and therefore is not used for encoding/decoding.
@@ -38,14 +45,159 @@ const RESIDUAL_THRESHOLD_COUNTS_D1: usize = 1 << (1 + RESIDUAL_NOISE_FLOOR);
 const RESIDUAL_THRESHOLD_COUNTS_D2: usize = 1 + RESIDUAL_NOISE_FLOOR;
 const RESIDUAL_THRESHOLD_COUNTS_D3: usize = 1 << RESIDUAL_NOISE_FLOOR;
 
-#[derive(DefaultBoxed)]
+/// magic and version of the serialized model, the version has to be incremented
+/// whenever the layout or meaning of the branches changes
+const MODEL_SNAPSHOT_MAGIC: [u8; 4] = *b"LMDL";
+const MODEL_SNAPSHOT_VERSION: u8 = 1;
+
+/// magic, version, number of per color models and number of branches
+const MODEL_SNAPSHOT_HEADER_SIZE: usize = 4 + 1 + 1 + 4;
+
+/// Visits all the branches of an exponent/residual counts structure. These are macros so
+/// the same walking order is used for both shared and mutable access.
+macro_rules! walk_counts {
+    ($x:expr, $iter:ident, $walker:expr) => {
+        for w in $x.exponent_counts.$iter() {
+            for q in w.$iter() {
+                $walker(q);
+            }
+        }
+
+        for w in $x.residual_noise_counts.$iter() {
+            $walker(w);
+        }
+    };
+}
+
+/// Visits all the branches of a per color model
+macro_rules! walk_model_per_color {
+    ($x:expr, $iter:ident, $walker:expr) => {
+        for y in $x.num_non_zeros_counts7x7.$iter() {
+            for z in y.$iter() {
+                $walker(z);
+            }
+        }
+
+        for y in $x.counts.$iter() {
+            for z in y.$iter() {
+                walk_counts!(z, $iter, $walker);
+            }
+        }
+
+        for y in $x.num_non_zeros_counts1x8.$iter() {
+            for z in y.$iter() {
+                for w in z.$iter() {
+                    $walker(w);
+                }
+            }
+        }
+
+        for y in $x.num_non_zeros_counts8x1.$iter() {
+            for z in y.$iter() {
+                for w in z.$iter() {
+                    $walker(w);
+                }
+            }
+        }
+
+        for y in $x.counts_x.$iter() {
+            for z in y.$iter() {
+                walk_counts!(z, $iter, $walker);
+            }
+        }
+
+        for y in $x.residual_threshold_counts.$iter() {
+            for z in y.$iter() {
+                for w in z.$iter() {
+                    $walker(w);
+                }
+            }
+        }
+
+        for y in $x.sign_counts.$iter() {
+            for z in y.$iter() {
+                $walker(z);
+            }
+        }
+    };
+}
+
 pub struct Model {
-    per_color: [ModelPerColor; BLOCK_TYPES],
+    // the luma and chroma models in a single allocation, or only the luma model for grayscale
+    // images
+    per_color: Box<[ModelPerColor]>,
 
-    counts_dc: [CountsDC; NUMERIC_LENGTH_MAX],
+    counts_dc: Box<[CountsDC; NUMERIC_LENGTH_MAX]>,
 }
 
 impl Model {
+    /// Creates a model for an image with the given number of components. Grayscale images
+    /// never use the chroma model, so it is only allocated if there is more than one component.
+    pub fn new_for_components(num_components: usize) -> Box<Self> {
+        Box::new(Model {
+            per_color: Self::new_per_color(num_components),
+            counts_dc: CountsDC::default_boxed_array::<NUMERIC_LENGTH_MAX>(),
+        })
+    }
+
+    fn new_per_color(num_components: usize) -> Box<[ModelPerColor]> {
+        if num_components > 1 {
+            ModelPerColor::default_boxed_array::<BLOCK_TYPES>()
+        } else {
+            ModelPerColor::default_boxed_array::<1>()
+        }
+    }
+
+    /// Puts the model back into the state returned by `new_for_components`, keeping the
+    /// allocations if the number of per color models doesn't change.
+    pub fn reset(&mut self, num_components: usize) {
+        let num_block_types = if num_components > 1 { BLOCK_TYPES } else { 1 };
+
+        if self.per_color.len() != num_block_types {
+            self.per_color = Self::new_per_color(num_components);
+        }
+
+        self.walk_all_mut(|b| *b = Branch::default());
+    }
+
+    /// Creates a model with all the per color models allocated
+    #[cfg(test)]
+    pub fn default_boxed() -> Box<Self> {
+        Self::new_for_components(COLOR_CHANNEL_NUM_BLOCK_TYPES)
+    }
+
+    /// number of per color models that were allocated
+    #[allow(dead_code)]
+    pub fn get_num_per_color(&self) -> usize {
+        self.per_color.len()
+    }
+
+    /// Number of bytes used by the model including its heap allocations, which is
+    /// useful to estimate the memory needed by each concurrent encode or decode.
+    #[allow(dead_code)]
+    pub fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.per_color.len() * std::mem::size_of::<ModelPerColor>()
+            + std::mem::size_of::<[CountsDC; NUMERIC_LENGTH_MAX]>()
+    }
+
+    /// Number of bits coded with each branch of the model, in the order of the serialized model
+    #[cfg(feature = "branch_visits")]
+    pub fn branch_visits(&self) -> Vec<u64> {
+        let mut visits = Vec::new();
+        self.walk_all(|b| visits.push(u64::from(b.get_visits())));
+        visits
+    }
+
+    /// Number of times the counts of each branch of the model overflowed, in the order of the
+    /// serialized model
+    #[cfg(feature = "branch_visits")]
+    pub fn branch_renormalizations(&self) -> Vec<u64> {
+        let mut renormalizations = Vec::new();
+        self.walk_all(|b| renormalizations.push(u64::from(b.get_renormalizations())));
+        renormalizations
+    }
+
     /// Walks through the model and applies the walker function to each branch
     /// This is used by testing to randomize the model so we can detect
     /// any mismatches in the way that updates are handled.
@@ -57,70 +209,105 @@ impl Model {
     #[cfg(test)]
     pub fn walk(&mut self, mut walker: impl FnMut(&mut Branch)) {
         for x in self.per_color.iter_mut() {
-            for y in x.num_non_zeros_counts7x7.iter_mut() {
-                for z in y.iter_mut() {
-                    walker(z);
-                }
-            }
+            walk_model_per_color!(x, iter_mut, walker);
+        }
+    }
 
-            for y in x.counts.iter_mut() {
-                for z in y.iter_mut() {
-                    for w in z.exponent_counts.iter_mut() {
-                        for q in w.iter_mut() {
-                            walker(q);
-                        }
-                    }
+    /// Walks through every branch of the model including the DC counts, in the
+    /// fixed order used by the serialized form of the model.
+    fn walk_all(&self, mut walker: impl FnMut(&Branch)) {
+        for x in self.per_color.iter() {
+            walk_model_per_color!(x, iter, walker);
+        }
 
-                    for w in z.residual_noise_counts.iter_mut() {
-                        walker(w);
-                    }
-                }
-            }
+        for x in self.counts_dc.iter() {
+            walk_counts!(x, iter, walker);
+        }
+    }
 
-            for y in x.num_non_zeros_counts1x8.iter_mut() {
-                for z in y.iter_mut() {
-                    for w in z.iter_mut() {
-                        walker(w);
-                    }
-                }
-            }
+    /// mutable version of `walk_all`, the order of the branches must stay the same
+    fn walk_all_mut(&mut self, mut walker: impl FnMut(&mut Branch)) {
+        for x in self.per_color.iter_mut() {
+            walk_model_per_color!(x, iter_mut, walker);
+        }
 
-            for y in x.num_non_zeros_counts8x1.iter_mut() {
-                for z in y.iter_mut() {
-                    for w in z.iter_mut() {
-                        walker(w);
-                    }
-                }
-            }
+        for x in self.counts_dc.iter_mut() {
+            walk_counts!(x, iter_mut, walker);
+        }
+    }
 
-            for y in x.counts_x.iter_mut() {
-                for z in y.iter_mut() {
-                    for w in z.exponent_counts.iter_mut() {
-                        for q in w.iter_mut() {
-                            walker(q);
-                        }
-                    }
+    /// Writes the counts of every branch of the model in little endian order, preceded by
+    /// a header describing the layout, so that a model trained on a set of images can be
+    /// stored and used as the starting point for other images.
+    #[allow(dead_code)]
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut num_branches: u32 = 0;
+        self.walk_all(|_| num_branches += 1);
 
-                    for w in z.residual_noise_counts.iter_mut() {
-                        walker(w);
-                    }
-                }
-            }
+        let mut data = Vec::with_capacity(MODEL_SNAPSHOT_HEADER_SIZE + num_branches as usize * 2);
+        data.extend_from_slice(&MODEL_SNAPSHOT_MAGIC);
+        data.push(MODEL_SNAPSHOT_VERSION);
+        data.push(self.per_color.len() as u8);
+        data.extend_from_slice(&num_branches.to_le_bytes());
 
-            for y in x.residual_threshold_counts.iter_mut() {
-                for z in y.iter_mut() {
-                    for w in z.iter_mut() {
-                        walker(w);
-                    }
-                }
-            }
+        self.walk_all(|x| data.extend_from_slice(&x.get_count().to_le_bytes()));
 
-            for y in x.sign_counts.iter_mut() {
-                for z in y.iter_mut() {
-                    walker(z);
-                }
-            }
+        writer.write_all(&data).context(here!())?;
+        Ok(())
+    }
+
+    /// Reads a model written by `serialize`. Fails if the snapshot was written
+    /// by a different version of the model with a different layout.
+    #[allow(dead_code)]
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Box<Model>> {
+        let mut header = [0u8; MODEL_SNAPSHOT_HEADER_SIZE];
+        reader.read_exact(&mut header).context(here!())?;
+
+        if header[0..4] != MODEL_SNAPSHOT_MAGIC || header[4] != MODEL_SNAPSHOT_VERSION {
+            return err_exit_code(
+                ExitCode::VersionUnsupported,
+                "model snapshot has an unsupported version",
+            );
+        }
+
+        let num_per_color = usize::from(header[5]);
+        if num_per_color == 0 || num_per_color > BLOCK_TYPES {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "model snapshot has an invalid number of color models",
+            );
+        }
+
+        let mut model = Model::new_for_components(num_per_color);
+
+        let mut num_branches: u32 = 0;
+        model.walk_all(|_| num_branches += 1);
+
+        if u32::from_le_bytes(header[6..10].try_into().unwrap()) != num_branches {
+            return err_exit_code(
+                ExitCode::VersionUnsupported,
+                "model snapshot layout does not match the model",
+            );
+        }
+
+        let mut data = vec![0u8; num_branches as usize * 2];
+        reader.read_exact(&mut data).context(here!())?;
+
+        // both counts of a branch are never less than one
+        if data.chunks_exact(2).any(|c| c[0] == 0 || c[1] == 0) {
+            return err_exit_code(
+                ExitCode::StreamInconsistent,
+                "model snapshot contains invalid branch counts",
+            );
         }
+
+        let mut counts = data.chunks_exact(2);
+        model.walk_all_mut(|x| {
+            let c = counts.next().unwrap();
+            x.set_count(u16::from_le_bytes([c[0], c[1]]));
+        });
+
+        Ok(model)
     }
 
     /// calculates a checksum of the model so we can compare two models for equality
@@ -176,15 +363,18 @@ struct CountsEdge {
     residual_noise_counts: [Branch; 3],
 }
 
+// the DC counts are selected by the bins from EnabledFeatures::dc_uncertainty_bins
+const _: () = assert!(NUMERIC_LENGTH_MAX == DC_UNCERTAINTY_BINS);
+
 #[derive(DefaultBoxed)]
 struct CountsDC {
-    exponent_counts: [[Branch; MAX_EXPONENT]; 17],
+    exponent_counts: [[Branch; MAX_EXPONENT]; DC_UNCERTAINTY2_BINS],
     residual_noise_counts: [Branch; COEF_BITS],
 }
 
 impl ModelPerColor {
     #[inline(never)]
-    pub fn read_coef<R: Read>(
+    pub fn read_coef<const MAX_BITS: usize, R: Read>(
         &mut self,
         bool_reader: &mut VPXBoolReader<R>,
         zig49: usize,
@@ -194,7 +384,7 @@ impl ModelPerColor {
         let (exp, sign, bits) =
             self.get_coef_branches(num_non_zeros_bin, zig49, best_prior_bit_len);
 
-        return Model::read_length_sign_coef(
+        return Model::read_length_sign_coef::<MAX_EXPONENT, COEF_BITS, MAX_BITS, R>(
             bool_reader,
             exp,
             sign,
@@ -206,7 +396,7 @@ impl ModelPerColor {
     }
 
     #[inline(never)]
-    pub fn write_coef<W: Write>(
+    pub fn write_coef<const MAX_BITS: usize, W: Write>(
         &mut self,
         bool_writer: &mut VPXBoolWriter<W>,
         coef: i16,
@@ -217,7 +407,7 @@ impl ModelPerColor {
         let (exp, sign, bits) =
             self.get_coef_branches(num_non_zeros_bin, zig49, best_prior_bit_len);
 
-        return Model::write_length_sign_coef(
+        return Model::write_length_sign_coef::<MAX_EXPONENT, COEF_BITS, MAX_BITS, W>(
             bool_writer,
             coef,
             exp,
@@ -250,11 +440,10 @@ impl ModelPerColor {
             num_non_zeros_bin
         );
         assert!(zig49 < 49, "zig49 {0} too high", num_non_zeros_bin);
-        assert!(
-            best_prior_bit_len < NUMERIC_LENGTH_MAX,
-            "best_prior_bit_len {0} too high",
-            best_prior_bit_len
-        );
+
+        // the priors of V1 are at most MAX_EXPONENT bits long, the longer ones of the wide
+        // model versions share the last bin
+        let best_prior_bit_len = cmp::min(best_prior_bit_len, NUMERIC_LENGTH_MAX - 1);
 
         let exp = &mut self.counts[num_non_zeros_bin][zig49].exponent_counts[best_prior_bit_len];
         let sign = &mut self.sign_counts[0][0];
@@ -327,13 +516,14 @@ impl ModelPerColor {
             .context(here!())? as u8);
     }
 
-    pub fn read_edge_coefficient<R: Read>(
+    pub fn read_edge_coefficient<const MAX_BITS: usize, R: Read>(
         &mut self,
         bool_reader: &mut VPXBoolReader<R>,
         qt: &QuantizationTables,
         zig15offset: usize,
         num_non_zeros_edge: u8,
         best_prior: i32,
+        noise_coefficients: &mut u32,
     ) -> Result<i16> {
         let num_non_zeros_edge_bin = usize::from(num_non_zeros_edge) - 1;
 
@@ -355,12 +545,23 @@ impl ModelPerColor {
         let length_branches = &mut self.counts_x[num_non_zeros_edge_bin][zig15offset]
             .exponent_counts[best_prior_bit_len];
 
-        let length = bool_reader
+        let mut length = bool_reader
             .get_unary_encoded(
                 length_branches,
                 ModelComponent::Edge(ModelSubComponent::Exp),
             )
-            .context(here!())? as i32;
+            .context(here!())?;
+
+        if length == MAX_EXPONENT {
+            length = Model::read_length_escape(
+                bool_reader,
+                length,
+                MAX_BITS,
+                ModelComponent::Edge(ModelSubComponent::Exp),
+            )?;
+        }
+
+        let length = length as i32;
 
         let mut coef = 0;
         if length != 0 {
@@ -405,6 +606,8 @@ impl ModelPerColor {
                 }
 
                 if i >= 0 {
+                    *noise_coefficients += 1;
+
                     let res_prob = &mut self.counts_x[num_non_zeros_edge_bin][zig15offset]
                         .residual_noise_counts;
 
@@ -424,7 +627,7 @@ impl ModelPerColor {
         Ok(coef)
     }
 
-    pub fn write_edge_coefficient<W: Write>(
+    pub fn write_edge_coefficient<const MAX_BITS: usize, W: Write>(
         &mut self,
         bool_writer: &mut VPXBoolWriter<W>,
         qt: &QuantizationTables,
@@ -456,16 +659,26 @@ impl ModelPerColor {
         let exp_array = &mut self.counts_x[num_non_zeros_edge_bin][zig15offset].exponent_counts
             [best_prior_bit_len];
 
-        if length > MAX_EXPONENT {
+        if length > MAX_BITS {
             return err_exit_code(ExitCode::CoefficientOutOfRange, "CoefficientOutOfRange");
         }
 
         bool_writer.put_unary_encoded(
-            length,
+            cmp::min(length, MAX_EXPONENT),
             exp_array,
             ModelComponent::Edge(ModelSubComponent::Exp),
         )?;
 
+        if length >= MAX_EXPONENT {
+            Model::write_length_escape(
+                bool_writer,
+                length,
+                MAX_EXPONENT,
+                MAX_BITS,
+                ModelComponent::Edge(ModelSubComponent::Exp),
+            )?;
+        }
+
         if coef != 0 {
             // best_prior in the initial Lepton implementation is stored as i32,
             // but the sign here is taken from its truncated i16 value
@@ -568,16 +781,17 @@ impl Model {
         &mut self.per_color[pt.get_color_index()]
     }
 
-    pub fn read_dc<R: Read>(
+    pub fn read_dc<const MAX_BITS: usize, R: Read>(
         &mut self,
         bool_reader: &mut VPXBoolReader<R>,
         color_index: usize,
         uncertainty: i16,
         uncertainty2: i16,
+        bins: &dyn DcUncertaintyBins,
     ) -> Result<i16> {
-        let (exp, sign, bits) = self.get_dc_branches(uncertainty, uncertainty2, color_index);
+        let (exp, sign, bits) = self.get_dc_branches(uncertainty, uncertainty2, color_index, bins);
 
-        return Model::read_length_sign_coef(
+        return Model::read_length_sign_coef::<MAX_EXPONENT, COEF_BITS, MAX_BITS, R>(
             bool_reader,
             exp,
             sign,
@@ -589,17 +803,18 @@ impl Model {
         .context(here!());
     }
 
-    pub fn write_dc<W: Write>(
+    pub fn write_dc<const MAX_BITS: usize, W: Write>(
         &mut self,
         bool_writer: &mut VPXBoolWriter<W>,
         color_index: usize,
         coef: i16,
         uncertainty: i16,
         uncertainty2: i16,
+        bins: &dyn DcUncertaintyBins,
     ) -> Result<()> {
-        let (exp, sign, bits) = self.get_dc_branches(uncertainty, uncertainty2, color_index);
+        let (exp, sign, bits) = self.get_dc_branches(uncertainty, uncertainty2, color_index, bins);
 
-        return Model::write_length_sign_coef(
+        return Model::write_length_sign_coef::<MAX_EXPONENT, COEF_BITS, MAX_BITS, W>(
             bool_writer,
             coef,
             exp,
@@ -617,26 +832,26 @@ impl Model {
         uncertainty: i16,
         uncertainty2: i16,
         color_index: usize,
+        bins: &dyn DcUncertaintyBins,
     ) -> (
         &mut [Branch; MAX_EXPONENT],
         &mut Branch,
         &mut [Branch; COEF_BITS],
     ) {
-        let len_abs_mxm = u16_bit_length(uncertainty.unsigned_abs());
-        let len_abs_offset_to_closest_edge = u16_bit_length(uncertainty2.unsigned_abs());
-        let len_abs_mxm_clamp = cmp::min(len_abs_mxm as usize, self.counts_dc.len() - 1);
+        let (uncertainty_bin, uncertainty2_bin) = bins.get_bins(uncertainty, uncertainty2);
+        let uncertainty_bin = cmp::min(uncertainty_bin, DC_UNCERTAINTY_BINS - 1);
+        let uncertainty2_bin = cmp::min(uncertainty2_bin, DC_UNCERTAINTY2_BINS - 1);
 
-        let exp = &mut self.counts_dc[len_abs_mxm_clamp].exponent_counts
-            [len_abs_offset_to_closest_edge as usize];
+        let exp = &mut self.counts_dc[uncertainty_bin].exponent_counts[uncertainty2_bin];
         let sign =
             &mut self.per_color[color_index].sign_counts[0][calc_sign_index(uncertainty2) + 1]; // +1 to separate from sign_counts[0][0]
-        let bits = &mut self.counts_dc[len_abs_mxm_clamp].residual_noise_counts;
+        let bits = &mut self.counts_dc[uncertainty_bin].residual_noise_counts;
 
         (exp, sign, bits)
     }
 
     #[inline(always)]
-    fn read_length_sign_coef<const A: usize, const B: usize, R: Read>(
+    fn read_length_sign_coef<const A: usize, const B: usize, const MAX_BITS: usize, R: Read>(
         bool_reader: &mut VPXBoolReader<R>,
         magnitude_branches: &mut [Branch; A],
         sign_branch: &mut Branch,
@@ -653,12 +868,19 @@ impl Model {
             mag_cmp
         );
 
-        let length = bool_reader.get_unary_encoded(magnitude_branches, mag_cmp)?;
+        let mut length = bool_reader.get_unary_encoded(magnitude_branches, mag_cmp)?;
+        if length == A {
+            length = Model::read_length_escape(bool_reader, length, MAX_BITS, mag_cmp)?;
+        }
 
         let mut coef: i16 = 0;
         if length != 0 {
             let neg = !bool_reader.get(sign_branch, sign_cmp)?;
-            if length > 1 {
+            if length > B + 1 {
+                // only the wide model versions have more bits than branches
+                coef = (bool_reader.get_bypass_bits(length - 1 - B, bits_cmp)? << B) as i16;
+                coef |= bool_reader.get_n_bits(B, bits_branch, bits_cmp)? as i16;
+            } else if length > 1 {
                 coef = bool_reader.get_n_bits(length - 1, bits_branch, bits_cmp)? as i16;
             }
 
@@ -672,7 +894,7 @@ impl Model {
         return Ok(coef);
     }
 
-    fn write_length_sign_coef<const A: usize, const B: usize, W: Write>(
+    fn write_length_sign_coef<const A: usize, const B: usize, const MAX_BITS: usize, W: Write>(
         bool_writer: &mut VPXBoolWriter<W>,
         coef: i16,
         magnitude_branches: &mut [Branch; A],
@@ -693,14 +915,22 @@ impl Model {
         let abs_coef = coef.unsigned_abs();
         let coef_bit_len = u16_bit_length(abs_coef);
 
-        if coef_bit_len > A as u8 {
+        if usize::from(coef_bit_len) > MAX_BITS {
             return err_exit_code(
                 ExitCode::CoefficientOutOfRange,
                 "coefficient > MAX_EXPONENT",
             );
         }
 
-        bool_writer.put_unary_encoded(coef_bit_len as usize, magnitude_branches, mag_cmp)?;
+        bool_writer.put_unary_encoded(
+            cmp::min(usize::from(coef_bit_len), A),
+            magnitude_branches,
+            mag_cmp,
+        )?;
+        if usize::from(coef_bit_len) >= A {
+            Model::write_length_escape(bool_writer, coef_bit_len.into(), A, MAX_BITS, mag_cmp)?;
+        }
+
         if coef != 0 {
             bool_writer.put(coef > 0, sign_branch, sign_cmp)?;
         }
@@ -715,14 +945,236 @@ impl Model {
                 "Beyond Biggest bit must be zero"
             );
 
-            bool_writer.put_n_bits(
-                abs_coef as usize,
-                coef_bit_len as usize - 1,
-                bits_branch,
-                bits_cmp,
-            )?;
+            let mut num_bits = coef_bit_len as usize - 1;
+            if num_bits > B {
+                // only the wide model versions have more bits than branches
+                bool_writer.put_bypass_bits(usize::from(abs_coef) >> B, num_bits - B, bits_cmp)?;
+                num_bits = B;
+            }
+
+            bool_writer.put_n_bits(abs_coef as usize, num_bits, bits_branch, bits_cmp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Continues a coefficient length that used up all the branches of its unary code. The
+    /// wide model versions add a bypass bit for every further bit up to `max_bits`, which
+    /// is where V1 (with `max_bits` equal to the number of branches) already stops.
+    #[cold]
+    fn read_length_escape<R: Read>(
+        bool_reader: &mut VPXBoolReader<R>,
+        mut length: usize,
+        max_bits: usize,
+        cmp: ModelComponent,
+    ) -> std::io::Result<usize> {
+        while length < max_bits && bool_reader.get_bypass_bits(1, cmp)? != 0 {
+            length += 1;
+        }
+
+        Ok(length)
+    }
+
+    /// Writes what `read_length_escape` reads for a length of at least `num_branches`.
+    #[cold]
+    fn write_length_escape<W: Write>(
+        bool_writer: &mut VPXBoolWriter<W>,
+        length: usize,
+        num_branches: usize,
+        max_bits: usize,
+        cmp: ModelComponent,
+    ) -> Result<()> {
+        for i in num_branches..max_bits {
+            let cur_bit = length != i;
+            bool_writer.put_bypass_bits(usize::from(cur_bit), 1, cmp)?;
+            if !cur_bit {
+                break;
+            }
         }
 
         Ok(())
     }
 }
+
+// a randomized model has to come back with exactly the same branches and
+// probabilities after a roundtrip through the serialized form
+#[test]
+fn serialize_roundtrip() {
+    use crate::lepton_error::LeptonError;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([5u8; 32]);
+
+    let mut model = Model::default_boxed();
+    model.walk_all_mut(|x| x.set_count(rng.gen_range(0x101..=0xffff) | 0x101));
+
+    let mut data = Vec::new();
+    model.serialize(&mut data).unwrap();
+
+    let restored = Model::deserialize(&mut &data[..]).unwrap();
+    assert_eq!(restored.get_num_per_color(), model.get_num_per_color());
+
+    let mut probabilities = Vec::new();
+    model.walk_all(|x| probabilities.push((x.get_count(), x.get_probability())));
+
+    let mut restored_probabilities = Vec::new();
+    restored.walk_all(|x| restored_probabilities.push((x.get_count(), x.get_probability())));
+
+    assert_eq!(probabilities, restored_probabilities);
+
+    // grayscale models only have the luma model and must keep that layout
+    let mut gray_data = Vec::new();
+    Model::new_for_components(1)
+        .serialize(&mut gray_data)
+        .unwrap();
+    assert_eq!(
+        Model::deserialize(&mut &gray_data[..])
+            .unwrap()
+            .get_num_per_color(),
+        1
+    );
+
+    let exit_code_for = |data: &[u8]| {
+        Model::deserialize(&mut &data[..])
+            .err()
+            .unwrap()
+            .root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code
+    };
+
+    // mismatched version and layout are rejected
+    let mut bad_version = data.clone();
+    bad_version[4] += 1;
+    assert_eq!(exit_code_for(&bad_version), ExitCode::VersionUnsupported);
+
+    let mut bad_layout = data.clone();
+    bad_layout[6] ^= 1;
+    assert_eq!(exit_code_for(&bad_layout), ExitCode::VersionUnsupported);
+
+    let mut bad_counts = data.clone();
+    bad_counts[MODEL_SNAPSHOT_HEADER_SIZE] = 0;
+    assert_eq!(exit_code_for(&bad_counts), ExitCode::StreamInconsistent);
+}
+
+// the reported memory footprint has to account for every allocation of the model
+// and of the probability tables used for a color image
+#[test]
+fn memory_footprint_color_image() {
+    use super::probability_tables_set::ProbabilityTablesSet;
+    use std::mem::size_of;
+
+    let model = Model::new_for_components(3);
+    assert_eq!(
+        model.memory_footprint(),
+        size_of::<Model>()
+            + BLOCK_TYPES * size_of::<ModelPerColor>()
+            + NUMERIC_LENGTH_MAX * size_of::<CountsDC>()
+    );
+
+    // grayscale images only allocate the luma model
+    let gray_model = Model::new_for_components(1);
+    assert_eq!(
+        model.memory_footprint() - gray_model.memory_footprint(),
+        (BLOCK_TYPES - 1) * size_of::<ModelPerColor>()
+    );
+
+    let pts = ProbabilityTablesSet::new();
+    assert_eq!(
+        pts.memory_footprint(),
+        6 * COLOR_CHANNEL_NUM_BLOCK_TYPES * size_of::<ProbabilityTables>()
+    );
+}
+
+// a model that was used and then reset has to be indistinguishable from a new one
+#[test]
+fn reset_matches_new_model() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([6u8; 32]);
+
+    for (from, to) in [(3, 3), (3, 1), (1, 3)] {
+        let mut model = Model::new_for_components(from);
+        model.walk_all_mut(|x| x.set_count(rng.gen_range(0x101..=0xffff) | 0x101));
+        model.reset(to);
+
+        let mut expected = Vec::new();
+        Model::new_for_components(to)
+            .serialize(&mut expected)
+            .unwrap();
+
+        let mut actual = Vec::new();
+        model.serialize(&mut actual).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+}
+
+// coefficients of up to 15 bits have to survive the escape of the wide model, while the
+// short ones are coded exactly like in the V1 model and the long ones are rejected by it
+#[test]
+fn wide_coefficients_roundtrip() {
+    use crate::lepton_error::LeptonError;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([8u8; 32]);
+
+    let values: Vec<(i16, usize, usize)> = (0..4096)
+        .map(|_| {
+            let bits = rng.gen_range(0..=15);
+            let magnitude = if bits == 0 {
+                0
+            } else {
+                rng.gen_range((1i32 << (bits - 1))..(1i32 << bits)) as i16
+            };
+            let coef = if rng.gen() { magnitude } else { -magnitude };
+            (coef, rng.gen_range(0..49), rng.gen_range(0..=12))
+        })
+        .collect();
+
+    fn write<const MAX_BITS: usize>(values: &[(i16, usize, usize)]) -> Result<Vec<u8>> {
+        let mut model = ModelPerColor::default_boxed();
+        let mut buffer = Vec::new();
+        let mut writer = VPXBoolWriter::new(&mut buffer)?;
+        for &(coef, zig49, best_prior_bit_len) in values {
+            model.write_coef::<MAX_BITS, _>(&mut writer, coef, zig49, 3, best_prior_bit_len)?;
+        }
+        writer.finish()?;
+        Ok(buffer)
+    }
+
+    const WIDE_BITS: usize = ModelVersion::V1WideCoefficients.max_coefficient_bits();
+    let buffer = write::<WIDE_BITS>(&values).unwrap();
+
+    let mut model = ModelPerColor::default_boxed();
+    let mut reader = VPXBoolReader::new(&buffer[..]).unwrap();
+    for &(coef, zig49, best_prior_bit_len) in &values {
+        assert_eq!(
+            model
+                .read_coef::<WIDE_BITS, _>(&mut reader, zig49, 3, best_prior_bit_len)
+                .unwrap(),
+            coef
+        );
+    }
+
+    let short: Vec<_> = values
+        .iter()
+        .copied()
+        .filter(|&(coef, _, _)| coef.unsigned_abs() < 1 << (MAX_EXPONENT - 1))
+        .collect();
+    assert_eq!(
+        write::<WIDE_BITS>(&short).unwrap(),
+        write::<MAX_EXPONENT>(&short).unwrap()
+    );
+
+    let error = write::<MAX_EXPONENT>(&[(1 << MAX_EXPONENT, 0, 0)]).unwrap_err();
+    assert_eq!(
+        error
+            .root_cause()
+            .downcast_ref::<LeptonError>()
+            .unwrap()
+            .exit_code,
+        ExitCode::CoefficientOutOfRange
+    );
+}
@@ -9,7 +9,7 @@ use anyhow::{Context, Result};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use std::{
     cmp,
-    io::{Cursor, Read, Write},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
     mem::swap,
     sync::mpsc::{channel, Receiver, SendError, Sender},
 };
@@ -278,14 +278,10 @@ where
 
         // now that the channels are waiting for input, read the stream and send all the buffers to their respective readers
         loop {
-            let mut thread_marker_a = [0; 1];
-            if reader.read(&mut thread_marker_a)? == 0 {
-                break;
-            }
-
-            let thread_marker = thread_marker_a[0];
-
-            let thread_id = (thread_marker & 0xf) as u8;
+            let (thread_id, data_length) = match read_block_header(reader).context(here!())? {
+                Some(header) => header,
+                None => break,
+            };
 
             if thread_id >= channel_to_sender.len() as u8 {
                 return err_exit_code(
@@ -294,18 +290,6 @@ where
                 );
             }
 
-            let data_length = if thread_marker < 16 {
-                let b0 = reader.read_u8().context(here!())?;
-                let b1 = reader.read_u8().context(here!())?;
-
-                ((b1 as usize) << 8) + b0 as usize + 1
-            } else {
-                // This format is used by Lepton C++ to write encoded chunks with length of 4096, 16384 or 65536 bytes
-                let flags = (thread_marker >> 4) & 3;
-
-                1024 << (2 * flags)
-            };
-
             //info!("offset {0} len {1}", reader.stream_position()?-2, data_length);
 
             let mut buffer = vec![0; data_length as usize];
@@ -359,6 +343,83 @@ where
     Ok(result)
 }
 
+/// Reads the header of the next block of the multiplexed stream. Returns the thread_id and
+/// the length of the data that follows, or None at the end of the stream.
+fn read_block_header<READ: Read>(reader: &mut READ) -> Result<Option<(u8, usize)>> {
+    let mut thread_marker_a = [0; 1];
+    if reader.read(&mut thread_marker_a)? == 0 {
+        return Ok(None);
+    }
+
+    let thread_marker = thread_marker_a[0];
+
+    let thread_id = (thread_marker & 0xf) as u8;
+
+    let data_length = if thread_marker < 16 {
+        let b0 = reader.read_u8().context(here!())?;
+        let b1 = reader.read_u8().context(here!())?;
+
+        ((b1 as usize) << 8) + b0 as usize + 1
+    } else {
+        // This format is used by Lepton C++ to write encoded chunks with length of 4096, 16384 or 65536 bytes
+        let flags = (thread_marker >> 4) & 3;
+
+        1024 << (2 * flags)
+    };
+
+    Ok(Some((thread_id, data_length)))
+}
+
+/// Reads `length` bytes starting at `offset` of the stream of a single thread, seeking past
+/// the blocks of all the other threads. This allows fetching part of one stream without
+/// demultiplexing the whole file.
+pub fn multiplex_read_range<READ: Read + Seek>(
+    reader: &mut READ,
+    thread_id: usize,
+    offset: u64,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(length);
+
+    // position within the stream of the thread
+    let mut stream_position = 0u64;
+
+    while result.len() < length {
+        let (block_thread_id, data_length) = match read_block_header(reader).context(here!())? {
+            Some(header) => header,
+            None => {
+                return err_exit_code(
+                    ExitCode::BadLeptonFile,
+                    format!("stream of thread {0} ended early", thread_id).as_str(),
+                );
+            }
+        };
+
+        let block_start = stream_position;
+        let block_end = block_start + data_length as u64;
+        let wanted_start = offset + result.len() as u64;
+
+        if usize::from(block_thread_id) == thread_id && block_end > wanted_start {
+            let mut buffer = vec![0; data_length];
+            reader.read_exact(&mut buffer).context(here!())?;
+
+            let start = (wanted_start - block_start) as usize;
+            let end = cmp::min(data_length, start + length - result.len());
+            result.extend_from_slice(&buffer[start..end]);
+        } else {
+            reader
+                .seek(SeekFrom::Current(data_length as i64))
+                .context(here!())?;
+        }
+
+        if usize::from(block_thread_id) == thread_id {
+            stream_position = block_end;
+        }
+    }
+
+    Ok(result)
+}
+
 /// simple end to end test that write the thread id and reads it back
 #[test]
 fn test_multiplex_end_to_end() {
@@ -6,11 +6,18 @@
 
 use std::num::Wrapping;
 
+use anyhow::Result;
+use bytemuck::cast_mut;
 use wide::{i16x8, i32x8};
 
 use super::block_based_image::AlignedBlock;
+use super::idct::run_idct;
+use super::probability_tables::ProbabilityTables;
+use super::quantization_tables::QuantizationTables;
 use crate::consts::X_IDCT_SCALE;
 use crate::enabled_features::EnabledFeatures;
+use crate::helpers::err_exit_code;
+use crate::lepton_error::ExitCode;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct NeighborSummary {
@@ -23,6 +30,10 @@ pub struct NeighborSummary {
     num_non_zeros: u8,
 }
 
+/// size of the serialized form of `NeighborSummary`: two edges of 8 pixels (i16),
+/// two edges of 8 coefficients (i32) and the number of non-zeros
+pub const NEIGHBOR_SUMMARY_SERIALIZED_SIZE: usize = 2 * 8 * 2 + 2 * 8 * 4 + 1;
+
 pub static NEIGHBOR_DATA_EMPTY: NeighborSummary = NeighborSummary {
     edge_pixels_h: i16x8::ZERO,
     edge_pixels_v: i16x8::ZERO,
@@ -55,6 +66,35 @@ impl NeighborSummary {
         }
     }
 
+    /// Calculates the summary of an already decoded block (in the transposed order used
+    /// during coding). This gives the same result as the summary produced while the
+    /// block is coded, since it only depends on the block itself.
+    pub fn from_block(
+        block: &AlignedBlock,
+        qt: &QuantizationTables,
+        features: &EnabledFeatures,
+    ) -> Self {
+        // dequantized coefficients without the DC
+        let mut raster = [i32x8::ZERO; 8];
+        let raster_col: &mut [i32; 64] = cast_mut(&mut raster);
+        for (i, r) in raster_col.iter_mut().enumerate().skip(1) {
+            *r = i32::from(block.get_coefficient(i))
+                * i32::from(qt.get_quantization_table_transposed()[i]);
+        }
+
+        let (horiz_pred, vert_pred) = ProbabilityTables::predict_next_edges(&raster);
+        let q0 = qt.get_quantization_table()[0] as i32;
+
+        NeighborSummary::new(
+            &run_idct(&raster),
+            block.get_dc() as i32 * q0,
+            block.get_count_of_non_zeros_7x7(),
+            horiz_pred,
+            vert_pred,
+            features,
+        )
+    }
+
     pub fn get_num_non_zeros(&self) -> u8 {
         self.num_non_zeros
     }
@@ -106,6 +146,68 @@ impl NeighborSummary {
         return self.edge_coefs_h;
     }
 
+    /// Serializes the summary in a compact little endian form. Used for debugging
+    /// to snapshot the neighbor summary cache at thread boundaries and compare them.
+    #[allow(dead_code)]
+    pub fn to_bytes(self) -> [u8; NEIGHBOR_SUMMARY_SERIALIZED_SIZE] {
+        let mut retval = [0u8; NEIGHBOR_SUMMARY_SERIALIZED_SIZE];
+        let mut pos = 0;
+
+        for v in self
+            .edge_pixels_h
+            .to_array()
+            .iter()
+            .chain(self.edge_pixels_v.to_array().iter())
+        {
+            retval[pos..pos + 2].copy_from_slice(&v.to_le_bytes());
+            pos += 2;
+        }
+
+        for v in self
+            .edge_coefs_h
+            .to_array()
+            .iter()
+            .chain(self.edge_coefs_v.to_array().iter())
+        {
+            retval[pos..pos + 4].copy_from_slice(&v.to_le_bytes());
+            pos += 4;
+        }
+
+        retval[pos] = self.num_non_zeros;
+
+        retval
+    }
+
+    /// Deserializes a summary written by `to_bytes`
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8; NEIGHBOR_SUMMARY_SERIALIZED_SIZE]) -> Self {
+        let read_i16x8 = |offset: usize| {
+            let mut v = [0i16; 8];
+            for (i, x) in v.iter_mut().enumerate() {
+                let p = offset + i * 2;
+                *x = i16::from_le_bytes([bytes[p], bytes[p + 1]]);
+            }
+            i16x8::from(v)
+        };
+
+        let read_i32x8 = |offset: usize| {
+            let mut v = [0i32; 8];
+            for (i, x) in v.iter_mut().enumerate() {
+                let p = offset + i * 4;
+                *x = i32::from_le_bytes([bytes[p], bytes[p + 1], bytes[p + 2], bytes[p + 3]]);
+            }
+            i32x8::from(v)
+        };
+
+        NeighborSummary {
+            edge_pixels_h: read_i16x8(0),
+            edge_pixels_v: read_i16x8(16),
+            edge_coefs_h: read_i32x8(32),
+            edge_coefs_v: read_i32x8(64),
+            num_non_zeros: bytes[96],
+        }
+    }
+
     // used for debugging
     #[allow(dead_code)]
     pub fn checksum(&self) -> u32 {
@@ -116,3 +218,52 @@ impl NeighborSummary {
         return sum.0;
     }
 }
+
+/// Fills the neighbor summary cache of a component so that the first row coded with it sees
+/// `seed` as the summaries of the row above, as if that row had just been coded. Both halves
+/// of the cache are filled since the half that is read as the row above depends on the parity
+/// of the row, and the other half is overwritten before it is read.
+pub fn seed_neighbor_summary_cache(
+    neighbor_summary_cache: &mut [NeighborSummary],
+    seed: &[NeighborSummary],
+) -> Result<()> {
+    if neighbor_summary_cache.len() != seed.len() * 2 {
+        return err_exit_code(
+            ExitCode::SyntaxError,
+            "neighbor summary seed must have one summary per block of the row",
+        );
+    }
+
+    let (above, here) = neighbor_summary_cache.split_at_mut(seed.len());
+    above.copy_from_slice(seed);
+    here.copy_from_slice(seed);
+
+    Ok(())
+}
+
+// serializing and deserializing a populated summary should give back the same summary
+#[test]
+fn roundtrip_serialization() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([4u8; 32]);
+
+    let mut block = AlignedBlock::default();
+    for i in 0..64 {
+        block.set_coefficient(i, rng.gen_range(-100..=100));
+    }
+
+    let summary = NeighborSummary::from_block(
+        &block,
+        &QuantizationTables::new_from_table(&[3; 64]),
+        &EnabledFeatures::compat_lepton_vector_read(),
+    );
+    assert!(summary != NEIGHBOR_DATA_EMPTY);
+
+    let bytes = summary.to_bytes();
+    assert_eq!(NeighborSummary::from_bytes(&bytes), summary);
+    assert_eq!(
+        bytes[NEIGHBOR_SUMMARY_SERIALIZED_SIZE - 1],
+        summary.num_non_zeros
+    );
+}
@@ -0,0 +1,17 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+/// Compile time check that `A` is a power of two, which the sizes of the branch grids read and
+/// written as binary trees have to be. Using `IsPowerOfTwo::<A>::OK` in a function fails to
+/// compile for any other `A` the function is instantiated with, so there is no runtime check.
+///
+/// This file doesn't depend on the rest of the crate, so that the compile-fail test can
+/// include it.
+pub(crate) struct IsPowerOfTwo<const A: usize>;
+
+impl<const A: usize> IsPowerOfTwo<A> {
+    pub(crate) const OK: () = assert!(A.is_power_of_two(), "the grid size must be a power of two");
+}
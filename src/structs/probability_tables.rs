@@ -4,18 +4,52 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+use anyhow::Result;
+
 use crate::consts::*;
 use crate::enabled_features;
+#[cfg(debug_assertions)]
+use crate::helpers::{err_exit_code, u16_bit_length};
+#[cfg(debug_assertions)]
+use crate::lepton_error::ExitCode;
 use crate::structs::idct::*;
 use crate::structs::model::*;
 use crate::structs::quantization_tables::*;
 
-use super::block_based_image::AlignedBlock;
+use super::block_based_image::{AlignedBlock, EMPTY_BLOCK};
 use super::block_context::NeighborData;
+use super::neighbor_summary::{NeighborSummary, NEIGHBOR_DATA_EMPTY};
 
+use bytemuck::{cast, cast_ref};
 use wide::i16x8;
 use wide::i32x8;
 
+/// Bin of the model for the number of non-zero coefficients in the 7x7 part of a block, from
+/// the number of non-zeros in the 7x7 part of the blocks above and to the left. The counts of
+/// missing neighbors are ignored. This is the context that the codec uses, so it can be used to
+/// reproduce it without the rest of the codec.
+///
+/// The counts can't be larger than 49, the number of coefficients in the 7x7 part of a block,
+/// larger counts may panic.
+#[inline(always)]
+pub fn nonzeros_7x7_context_bin(
+    above_count: u8,
+    left_count: u8,
+    above_present: bool,
+    left_present: bool,
+) -> u8 {
+    debug_assert!(above_count <= 49 && left_count <= 49);
+
+    let num_non_zeros_context = match (above_present, left_present) {
+        (true, true) => (usize::from(above_count) + usize::from(left_count) + 2) / 4,
+        (true, false) => (usize::from(above_count) + 1) / 2,
+        (false, true) => (usize::from(left_count) + 1) / 2,
+        (false, false) => 0,
+    };
+
+    NON_ZERO_TO_BIN[num_non_zeros_context]
+}
+
 pub struct ProbabilityTables {
     left_present: bool,
     above_present: bool,
@@ -30,6 +64,21 @@ pub struct PredictDCResult {
     pub advanced_predict_dc_pixels_sans_dc: AlignedBlock,
 }
 
+impl PredictDCResult {
+    /// Returns the IDCT of the complete block (scaled by X_IDCT_SCALE and centered around zero
+    /// like `run_idct`) given the DC of the block and its quantization factor. The DC adds the
+    /// same offset to every pixel and passes through the integer IDCT without any rounding, so
+    /// adding it to the pixels without DC is exact and saves running the IDCT a second time.
+    #[allow(dead_code)]
+    pub fn reconstruct_pixels(&self, dc: i16, q0: i32) -> AlignedBlock {
+        // the IDCT truncates its output to 16 bits, so wrapping here gives the same result
+        let offset = i16x8::splat((i32::from(dc) * q0) as i16);
+        let rows: &[i16x8; 8] = cast_ref(self.advanced_predict_dc_pixels_sans_dc.get_block());
+
+        AlignedBlock::new(cast(rows.map(|row| row + offset)))
+    }
+}
+
 impl ProbabilityTables {
     pub fn new(kcolor: usize, in_left_present: bool, in_above_present: bool) -> ProbabilityTables {
         return ProbabilityTables {
@@ -51,12 +100,20 @@ impl ProbabilityTables {
         self.above_present
     }
 
+    /// Subtracts (or adds back if `recover_original` is set) the prediction from the DC and
+    /// wraps the result once by 2049 towards the window `[-1024, 1024]`, or for coefficients of
+    /// up to `max_bits` bits by `2^max_bits + 1` towards `[-2^(max_bits-1), 2^(max_bits-1)]`.
+    /// The round trip gives back every DC inside the window for any prediction. DC values
+    /// outside the window (which baseline JPEGs don't produce) only survive if the prediction
+    /// happens to be far enough away, so the encoder checks every block (see
+    /// `dc_prediction_roundtrips`).
     pub fn adv_predict_or_unpredict_dc(
         saved_dc: i16,
         recover_original: bool,
         predicted_val: i32,
+        max_bits: usize,
     ) -> i32 {
-        let max_value = 1 << (MAX_EXPONENT - 1);
+        let max_value = 1 << (max_bits - 1);
         let min_value = -max_value;
         let adjustment_factor = (2 * max_value) + 1;
         let mut retval = predicted_val;
@@ -73,10 +130,23 @@ impl ProbabilityTables {
         return retval;
     }
 
+    /// true if the DC can be recovered from the residual that is stored for the given prediction
+    pub fn dc_prediction_roundtrips(saved_dc: i16, predicted_val: i32, max_bits: usize) -> bool {
+        let residual = Self::adv_predict_or_unpredict_dc(saved_dc, false, predicted_val, max_bits);
+
+        i32::from(saved_dc)
+            == Self::adv_predict_or_unpredict_dc(residual as i16, true, predicted_val, max_bits)
+    }
+
     pub fn get_color_index(&self) -> usize {
         return if self.color == 0 { 0 } else { 1 };
     }
 
+    /// index of the image component (Y, Cb, Cr) these tables are used for
+    pub fn get_component(&self) -> usize {
+        self.color
+    }
+
     pub fn num_non_zeros_to_bin_7x7(num_non_zeros: usize) -> usize {
         return usize::from(NON_ZERO_TO_BIN_7X7[num_non_zeros]);
     }
@@ -85,55 +155,79 @@ impl ProbabilityTables {
         &self,
         neighbor_data: &NeighborData,
     ) -> u8 {
-        let mut num_non_zeros_above = 0;
-        let mut num_non_zeros_left = 0;
-        if ALL_PRESENT || self.above_present {
-            num_non_zeros_above = neighbor_data.neighbor_context_above.get_num_non_zeros();
-        }
-
-        if ALL_PRESENT || self.left_present {
-            num_non_zeros_left = neighbor_data.neighbor_context_left.get_num_non_zeros();
-        }
+        let above_present = ALL_PRESENT || self.above_present;
+        let left_present = ALL_PRESENT || self.left_present;
 
-        let num_non_zeros_context;
-        if (!ALL_PRESENT) && self.above_present && !self.left_present {
-            num_non_zeros_context = (num_non_zeros_above + 1) / 2;
-        } else if (!ALL_PRESENT) && self.left_present && !self.above_present {
-            num_non_zeros_context = (num_non_zeros_left + 1) / 2;
-        } else if ALL_PRESENT || (self.left_present && self.above_present) {
-            num_non_zeros_context = (num_non_zeros_above + num_non_zeros_left + 2) / 4;
-        } else {
-            num_non_zeros_context = 0;
-        }
-
-        return NON_ZERO_TO_BIN[usize::from(num_non_zeros_context)];
+        nonzeros_7x7_context_bin(
+            if above_present {
+                neighbor_data.neighbor_context_above.get_num_non_zeros()
+            } else {
+                0
+            },
+            if left_present {
+                neighbor_data.neighbor_context_left.get_num_non_zeros()
+            } else {
+                0
+            },
+            above_present,
+            left_present,
+        )
     }
 
     // calculates the average of the prior values from their corresponding value in the left, above and above/left block
     // the C++ version does one coefficient at a time, but if we do it all at the same time, the compiler vectorizes everything
     #[inline(never)]
-    pub fn calc_coefficient_context_7x7_aavg_block<const ALL_PRESENT: bool>(
+    pub fn calc_coefficient_context_7x7_aavg_block<
+        const ALL_PRESENT: bool,
+        const MAX_BITS: usize,
+    >(
         &self,
         left: &AlignedBlock,
         above: &AlignedBlock,
         above_left: &AlignedBlock,
-    ) -> [u16; 64] {
+    ) -> Result<[u16; 64]> {
         let mut best_prior = [0; 64];
 
         if ALL_PRESENT {
-            // compiler does a pretty amazing job with SSE/AVX2 here
-            for i in 8..64 {
-                // approximate average of 3 without a divide with double the weight for left/top vs diagonal
-                //
-                // No need to go to 32 bits since max exponent is 11, ie 2047, so
-                // (2047 + 2047) * 13 + 2047 * 6 = 65504 which still fits in 16 bits.
-                // In addition, if we ever returned anything higher that 2047, it would
-                // assert in the array lookup in the model.
-                best_prior[i] = ((left.get_coefficient(i).unsigned_abs()
-                    + above.get_coefficient(i).unsigned_abs())
-                    * 13
-                    + 6 * above_left.get_coefficient(i).unsigned_abs())
-                    >> 5;
+            // The coefficients come from the model, which can't code more than MAX_BITS
+            // bits, so this only catches bugs and blocks that were filled some other way.
+            #[cfg(debug_assertions)]
+            for block in [left, above, above_left] {
+                if (8..64).any(|i| {
+                    usize::from(u16_bit_length(block.get_coefficient(i).unsigned_abs())) > MAX_BITS
+                }) {
+                    return err_exit_code(
+                        ExitCode::StreamInconsistent,
+                        "neighbor coefficient exceeds the bits of the model",
+                    );
+                }
+            }
+
+            if MAX_BITS <= MAX_EXPONENT {
+                // compiler does a pretty amazing job with SSE/AVX2 here
+                for i in 8..64 {
+                    // approximate average of 3 without a divide with double the weight for left/top vs diagonal
+                    //
+                    // No need to go to 32 bits since max exponent is 11, ie 2047, so
+                    // (2047 + 2047) * 13 + 2047 * 6 = 65504 which still fits in 16 bits.
+                    // In addition, if we ever returned anything higher that 2047, it would
+                    // assert in the array lookup in the model.
+                    best_prior[i] = ((left.get_coefficient(i).unsigned_abs()
+                        + above.get_coefficient(i).unsigned_abs())
+                        * 13
+                        + 6 * above_left.get_coefficient(i).unsigned_abs())
+                        >> 5;
+                }
+            } else {
+                // The 15 bit coefficients of the wide model versions need the sum in 32 bits.
+                // The weights add up to 32, so the average fits 16 bits again.
+                for i in 8..64 {
+                    best_prior[i] = (((u32::from(left.get_coefficient(i).unsigned_abs())
+                        + u32::from(above.get_coefficient(i).unsigned_abs()))
+                        * 13
+                        + 6 * u32::from(above_left.get_coefficient(i).unsigned_abs()))
+                        >> 5) as u16;
+                }
             }
         } else {
             // handle edge case :) where we are on the top or left edge
@@ -149,7 +243,7 @@ impl ProbabilityTables {
             }
         }
 
-        best_prior
+        Ok(best_prior)
     }
 
     // Predictor calculations in `compute_lak` are made using partial IDCT along only one dimension
@@ -162,6 +256,11 @@ impl ProbabilityTables {
     // Instead of non-continuous memory accesses to blocks we can use dequantized raster DCT coefficients
     // needed for DC prediction and apply horizontal SIMD instructions for direction along the raster order.
 
+    // Extreme coefficients with large quantizers can overflow the predictor arithmetic. This is
+    // fine since it is only a predictor, as long as the encoder and decoder overflow the same way
+    // on every platform and build: all `i32x8` operations of `wide` wrap (also in debug builds and
+    // in the scalar fallbacks), and the remaining scalar arithmetic uses explicit wrapping operations.
+
     // Produce current block predictors for edge DCT coefficients
     #[inline(always)]
     pub fn predict_current_edges(
@@ -179,7 +278,7 @@ impl ProbabilityTables {
         let mut vert_pred: i32x8 = neighbors_data.neighbor_context_left.get_vertical_coef();
 
         for col in 1..8 {
-            // some extreme coefficents can cause overflows, but since this is just predictors, no need to panic
+            // wrapping on overflow, see above
             vert_pred -= raster[col] * ICOS_BASED_8192_SCALED[col];
             horiz_pred[col] = horiz_pred[col].wrapping_sub((raster[col] * mult).reduce_add());
         }
@@ -194,6 +293,7 @@ impl ProbabilityTables {
 
         let mut horiz_pred: [i32; 8] = [0; 8];
         let mut vert_pred = ICOS_BASED_8192_SCALED_PM[0] * raster[0];
+        // only vector arithmetic, which wraps on overflow (see above)
         for col in 1..8 {
             // produce predictions for edge DCT coefficientss for the block below
             horiz_pred[col] = (mult * raster[col]).reduce_add();
@@ -239,6 +339,11 @@ impl ProbabilityTables {
 
         // helper functions to avoid code duplication that calculate the left and above prediction values
 
+        // Both versions divide the delta by 2 rounding towards zero and the rest of the
+        // calculation wraps the same way in 16 and 32 bits, so they only give different
+        // predictions if a1 - a2 itself overflows 16 bits. That can only happen for blocks
+        // with extreme coefficients, but it changes the wire format, so the flag is stored
+        // in the Lepton header and has to match between encoder and decoder.
         let calc_pred = |init_pred: i16x8, a1: i16x8, a2: i16x8| {
             if enabled_features.use_16bit_adv_predict {
                 let pixel_delta = a1 - a2;
@@ -329,4 +434,543 @@ impl ProbabilityTables {
             advanced_predict_dc_pixels_sans_dc: pixels_sans_dc,
         };
     }
+
+    /// Predicts the DC of a block from its dequantized raster coefficients (without the DC)
+    /// and the summaries of the left and above neighbors, if present. This gives the same
+    /// prediction as `adv_predict_dc_pix`, but resolves the presence of the neighbors at runtime
+    /// so it can be used to evaluate the predictor on its own.
+    #[allow(dead_code)]
+    pub fn predict_dc(
+        raster_cols: &[i32x8; 8],
+        q0: i32,
+        left: Option<&NeighborSummary>,
+        above: Option<&NeighborSummary>,
+        enabled_features: &enabled_features::EnabledFeatures,
+    ) -> i32 {
+        let pt = ProbabilityTables::new(0, left.is_some(), above.is_some());
+
+        // the DC prediction only looks at the summaries of the neighbors, not their blocks
+        let neighbor_data = NeighborData {
+            above: &EMPTY_BLOCK,
+            left: &EMPTY_BLOCK,
+            above_left: &EMPTY_BLOCK,
+            neighbor_context_above: above.unwrap_or(&NEIGHBOR_DATA_EMPTY),
+            neighbor_context_left: left.unwrap_or(&NEIGHBOR_DATA_EMPTY),
+        };
+
+        let result = if pt.is_all_present() {
+            pt.adv_predict_dc_pix::<true>(raster_cols, q0, &neighbor_data, enabled_features)
+        } else {
+            pt.adv_predict_dc_pix::<false>(raster_cols, q0, &neighbor_data, enabled_features)
+        };
+
+        result.predicted_dc
+    }
+}
+
+// the context bin depends only on the counts of the neighbors that are present
+#[test]
+fn nonzeros_7x7_context_bin_presence() {
+    // (above + left + 2) / 4 = 8
+    assert_eq!(nonzeros_7x7_context_bin(10, 20, true, true), 5);
+    // (above + 1) / 2 = 5
+    assert_eq!(nonzeros_7x7_context_bin(10, 20, true, false), 4);
+    // (left + 1) / 2 = 10
+    assert_eq!(nonzeros_7x7_context_bin(10, 20, false, true), 6);
+    assert_eq!(nonzeros_7x7_context_bin(10, 20, false, false), 0);
+
+    // full neighbors give the last bin in every case that looks at them
+    assert_eq!(nonzeros_7x7_context_bin(49, 49, true, true), 8);
+    assert_eq!(nonzeros_7x7_context_bin(49, 0, true, false), 8);
+    assert_eq!(nonzeros_7x7_context_bin(0, 49, false, true), 8);
+    assert_eq!(nonzeros_7x7_context_bin(0, 0, true, true), 0);
+}
+
+// the runtime dispatch of predict_dc should give the same result as the generic
+// version for all combinations of present neighbors
+#[test]
+fn predict_dc_matches_generic() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([6u8; 32]);
+    let mut random_block = || {
+        let mut block = AlignedBlock::default();
+        for i in 0..64 {
+            if rng.gen_range(0..3) == 0 {
+                block.set_coefficient(i, rng.gen_range(-100..=100));
+            }
+        }
+        block
+    };
+
+    let qt = QuantizationTables::new_from_table(&[5; 64]);
+    let q0 = i32::from(qt.get_quantization_table()[0]);
+
+    for features in [
+        enabled_features::EnabledFeatures::compat_lepton_vector_read(),
+        enabled_features::EnabledFeatures::compat_lepton_scalar_read(),
+    ] {
+        let left = NeighborSummary::from_block(&random_block(), &qt, &features);
+        let above = NeighborSummary::from_block(&random_block(), &qt, &features);
+
+        let here = random_block();
+        let mut raster = [i32x8::ZERO; 8];
+        let raster_col: &mut [i32; 64] = bytemuck::cast_mut(&mut raster);
+        for (i, r) in raster_col.iter_mut().enumerate().skip(1) {
+            *r = i32::from(here.get_coefficient(i))
+                * i32::from(qt.get_quantization_table_transposed()[i]);
+        }
+
+        for (left_present, above_present) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let pt = ProbabilityTables::new(0, left_present, above_present);
+            let neighbor_data = NeighborData {
+                above: &EMPTY_BLOCK,
+                left: &EMPTY_BLOCK,
+                above_left: &EMPTY_BLOCK,
+                neighbor_context_above: &above,
+                neighbor_context_left: &left,
+            };
+
+            let expected = if pt.is_all_present() {
+                pt.adv_predict_dc_pix::<true>(&raster, q0, &neighbor_data, &features)
+            } else {
+                pt.adv_predict_dc_pix::<false>(&raster, q0, &neighbor_data, &features)
+            };
+
+            let predicted = ProbabilityTables::predict_dc(
+                &raster,
+                q0,
+                if left_present { Some(&left) } else { None },
+                if above_present { Some(&above) } else { None },
+                &features,
+            );
+
+            assert_eq!(predicted, expected.predicted_dc);
+            if left_present || above_present {
+                assert!(predicted != 0);
+            }
+        }
+    }
+}
+
+// the 16 and 32 bit versions of the DC prediction have to give identical results as long as
+// the pixel deltas fit in 16 bits, and only diverge for blocks where they don't
+#[test]
+fn adv_predict_16bit_matches_32bit() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([12u8; 32]);
+
+    let features_16bit = enabled_features::EnabledFeatures::compat_lepton_vector_read();
+    let features_32bit = enabled_features::EnabledFeatures {
+        use_16bit_adv_predict: false,
+        ..features_16bit
+    };
+
+    let mut num_same = 0;
+    let mut num_diverged = 0;
+
+    for _ in 0..2000 {
+        let max_coef = if rng.gen_range(0..4) == 0 { 2047 } else { 100 };
+
+        let mut table = [0u16; 64];
+        for q in table.iter_mut() {
+            *q = rng.gen_range(1..=if max_coef > 100 { 255 } else { 16 });
+        }
+        let qt = QuantizationTables::new_from_table(&table);
+        let q0 = i32::from(qt.get_quantization_table()[0]);
+
+        let mut random_block = || {
+            let mut block = AlignedBlock::default();
+            for i in 0..64 {
+                if rng.gen_range(0..3) == 0 {
+                    block.set_coefficient(i, rng.gen_range(-max_coef..=max_coef));
+                }
+            }
+            block
+        };
+
+        // use the same neighbors for both versions so only the prediction itself is compared
+        let left = NeighborSummary::from_block(&random_block(), &qt, &features_16bit);
+        let above = NeighborSummary::from_block(&random_block(), &qt, &features_16bit);
+
+        let here = random_block();
+        let mut raster = [i32x8::ZERO; 8];
+        let raster_col: &mut [i32; 64] = bytemuck::cast_mut(&mut raster);
+        for (i, r) in raster_col.iter_mut().enumerate().skip(1) {
+            *r = i32::from(here.get_coefficient(i))
+                * i32::from(qt.get_quantization_table_transposed()[i]);
+        }
+
+        // check whether the deltas between the edge pixels used by the prediction overflow
+        let pixels = run_idct(&raster);
+        let delta_overflows = (0..8).any(|i| {
+            let delta_left =
+                i32::from(pixels.get_block()[i * 8]) - i32::from(pixels.get_block()[i * 8 + 1]);
+            let delta_above =
+                i32::from(pixels.get_block()[i]) - i32::from(pixels.get_block()[i + 8]);
+            i16::try_from(delta_left).is_err() || i16::try_from(delta_above).is_err()
+        });
+
+        let pt = ProbabilityTables::new(0, true, true);
+        let neighbor_data = NeighborData {
+            above: &EMPTY_BLOCK,
+            left: &EMPTY_BLOCK,
+            above_left: &EMPTY_BLOCK,
+            neighbor_context_above: &above,
+            neighbor_context_left: &left,
+        };
+
+        let r16 = pt.adv_predict_dc_pix::<true>(&raster, q0, &neighbor_data, &features_16bit);
+        let r32 = pt.adv_predict_dc_pix::<true>(&raster, q0, &neighbor_data, &features_32bit);
+
+        let same = r16.predicted_dc == r32.predicted_dc
+            && r16.uncertainty == r32.uncertainty
+            && r16.uncertainty2 == r32.uncertainty2
+            && r16.advanced_predict_dc_pixels_sans_dc.get_block()
+                == r32.advanced_predict_dc_pixels_sans_dc.get_block();
+
+        if delta_overflows {
+            if !same {
+                num_diverged += 1;
+            }
+        } else {
+            assert!(same, "16 and 32 bit predictions differ without overflow");
+            num_same += 1;
+        }
+    }
+
+    // make sure both cases were actually covered, the flag really changes the predictions
+    assert!(num_same > 0);
+    assert!(num_diverged > 0);
+}
+
+// with the neighbor data built from explicit blocks, the edge predictions of a block
+// without any coefficients of its own come straight from the neighbor summaries
+#[test]
+fn predict_current_edges_from_explicit_neighbors() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([13u8; 32]);
+    let mut random_block = || {
+        let mut block = AlignedBlock::default();
+        for i in 0..64 {
+            if rng.gen_range(0..3) == 0 {
+                block.set_coefficient(i, rng.gen_range(-100..=100));
+            }
+        }
+        block
+    };
+
+    let features = enabled_features::EnabledFeatures::compat_lepton_vector_read();
+    let qt = QuantizationTables::new_from_table(&[3; 64]);
+
+    let above = random_block();
+    let left = random_block();
+    let above_left = random_block();
+    let above_summary = NeighborSummary::from_block(&above, &qt, &features);
+    let left_summary = NeighborSummary::from_block(&left, &qt, &features);
+
+    let neighbor_data =
+        NeighborData::new(&above, &left, &above_left, &above_summary, &left_summary);
+    assert_eq!(neighbor_data.above_left.get_block(), above_left.get_block());
+
+    let (horiz_pred, vert_pred) =
+        ProbabilityTables::predict_current_edges(&neighbor_data, &[i32x8::ZERO; 8]);
+    assert_eq!(horiz_pred, above_summary.get_horizontal_coef());
+    assert_eq!(vert_pred, left_summary.get_vertical_coef());
+
+    // the coefficients of the block itself are subtracted from the neighbor predictions
+    let mut raster = [i32x8::ZERO; 8];
+    raster[1] = i32x8::splat(1);
+    let (horiz_pred, vert_pred) = ProbabilityTables::predict_current_edges(&neighbor_data, &raster);
+    assert_ne!(horiz_pred, above_summary.get_horizontal_coef());
+    assert_ne!(vert_pred, left_summary.get_vertical_coef());
+}
+
+/// Scalar version of `predict_current_edges` that spells out the math: the edge predictions
+/// from the neighbor summaries minus the contribution of the coefficients of the block itself,
+/// where `raster[col][row]` is the dequantized coefficient of horizontal frequency `col` and
+/// vertical frequency `row`. The DC column and row don't contribute. All arithmetic wraps.
+#[cfg(test)]
+fn predict_current_edges_scalar(
+    above_horizontal: [i32; 8],
+    left_vertical: [i32; 8],
+    raster: &[[i32; 8]; 8],
+) -> ([i32; 8], [i32; 8]) {
+    let mut horiz_pred = above_horizontal;
+    let mut vert_pred = left_vertical;
+
+    for col in 1..8 {
+        for row in 0..8 {
+            // the bottom edge of the block above is predicted from each column
+            horiz_pred[col] = horiz_pred[col]
+                .wrapping_sub(raster[col][row].wrapping_mul(ICOS_BASED_8192_SCALED[row]));
+
+            // and the right edge of the block to the left from each row
+            vert_pred[row] = vert_pred[row]
+                .wrapping_sub(raster[col][row].wrapping_mul(ICOS_BASED_8192_SCALED[col]));
+        }
+    }
+
+    (horiz_pred, vert_pred)
+}
+
+// the SIMD edge prediction has to agree with the scalar reference for any neighbor summaries and
+// coefficients, including values large enough to overflow
+#[test]
+fn predict_current_edges_matches_scalar() {
+    use super::neighbor_summary::NEIGHBOR_SUMMARY_SERIALIZED_SIZE;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([14u8; 32]);
+
+    for i in 0..10000 {
+        let mut random_summary = || {
+            let mut bytes = [0u8; NEIGHBOR_SUMMARY_SERIALIZED_SIZE];
+            rng.fill(&mut bytes[..]);
+            NeighborSummary::from_bytes(&bytes)
+        };
+        let above_summary = random_summary();
+        let left_summary = random_summary();
+
+        let neighbor_data = NeighborData::new(
+            &EMPTY_BLOCK,
+            &EMPTY_BLOCK,
+            &EMPTY_BLOCK,
+            &above_summary,
+            &left_summary,
+        );
+
+        // alternate between realistic dequantized coefficients and the full range
+        let mut raster = [[0i32; 8]; 8];
+        for c in raster.iter_mut().flatten() {
+            *c = match i % 3 {
+                0 => rng.gen_range(-2047..=2047) * rng.gen_range(1..=255),
+                1 => rng.gen(),
+                _ => *[i32::MIN, i32::MAX, 0, -1]
+                    .get(rng.gen_range(0..4))
+                    .unwrap(),
+            };
+        }
+
+        let (horiz_pred, vert_pred) =
+            ProbabilityTables::predict_current_edges(&neighbor_data, &raster.map(i32x8::from));
+        let (expected_horiz, expected_vert) = predict_current_edges_scalar(
+            above_summary.get_horizontal_coef().to_array(),
+            left_summary.get_vertical_coef().to_array(),
+            &raster,
+        );
+
+        assert_eq!(horiz_pred.to_array(), expected_horiz, "iteration {i}");
+        assert_eq!(vert_pred.to_array(), expected_vert, "iteration {i}");
+    }
+}
+
+// adding the DC to the pixels of the prediction should give exactly the same result as
+// running the IDCT on the complete block, including DC values at the edge of the range
+#[test]
+fn reconstruct_pixels_matches_idct() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([11u8; 32]);
+    let features = enabled_features::EnabledFeatures::compat_lepton_vector_read();
+    let pt = ProbabilityTables::new(0, false, false);
+    let neighbor_data = NeighborData {
+        above: &EMPTY_BLOCK,
+        left: &EMPTY_BLOCK,
+        above_left: &EMPTY_BLOCK,
+        neighbor_context_above: &NEIGHBOR_DATA_EMPTY,
+        neighbor_context_left: &NEIGHBOR_DATA_EMPTY,
+    };
+
+    for _ in 0..1000 {
+        let mut table = [0u16; 64];
+        for q in table.iter_mut() {
+            *q = rng.gen_range(1..=16);
+        }
+        let qt = QuantizationTables::new_from_table(&table);
+        let q0 = i32::from(qt.get_quantization_table()[0]);
+
+        let mut coefficients = [0i32; 64];
+        for (i, c) in coefficients.iter_mut().enumerate().skip(1) {
+            if rng.gen_range(0..3) == 0 {
+                *c = rng.gen_range(-100..=100)
+                    * i32::from(qt.get_quantization_table_transposed()[i]);
+            }
+        }
+
+        let result =
+            pt.adv_predict_dc_pix::<false>(&cast(coefficients), q0, &neighbor_data, &features);
+
+        let dc = match rng.gen_range(0..4) {
+            0 => -1024,
+            1 => 1024,
+            _ => rng.gen_range(-1024..=1024),
+        };
+        coefficients[0] = i32::from(dc) * q0;
+
+        assert_eq!(
+            result.reconstruct_pixels(dc, q0).get_block(),
+            run_idct(&cast(coefficients)).get_block()
+        );
+    }
+}
+
+// predicting and unpredicting the DC has to be the identity for every DC inside the window,
+// including the boundaries, for any prediction the neighbors can produce. DC values outside
+// the window don't round trip with predictions inside the window, which is why the encoder
+// has to check.
+#[test]
+fn dc_prediction_invertible() {
+    use crate::enabled_features::ModelVersion;
+
+    let window = 1 << (MAX_EXPONENT - 1);
+
+    let check = |saved_dc: i16, predicted_val: i32| {
+        let roundtrips =
+            ProbabilityTables::dc_prediction_roundtrips(saved_dc, predicted_val, MAX_EXPONENT);
+        let dc_in_window = i32::from(saved_dc).abs() <= window;
+        let prediction_in_window = predicted_val.abs() <= window;
+
+        assert!(
+            if dc_in_window {
+                roundtrips
+            } else {
+                !prediction_in_window || !roundtrips
+            },
+            "saved_dc {0} predicted_val {1}",
+            saved_dc,
+            predicted_val
+        );
+
+        if dc_in_window && prediction_in_window {
+            let residual = ProbabilityTables::adv_predict_or_unpredict_dc(
+                saved_dc,
+                false,
+                predicted_val,
+                MAX_EXPONENT,
+            );
+            assert!(residual.abs() <= window);
+        }
+    };
+
+    // all wrap cases with predictions up to two windows away
+    for saved_dc in -1100..=1100 {
+        for predicted_val in -4200..=4200 {
+            check(saved_dc, predicted_val);
+        }
+    }
+
+    // the prediction is an average of 16 bit pixel values, so it stays within +/- 32768
+    use rand::Rng;
+    let mut rng = crate::helpers::get_rand_from_seed([3u8; 32]);
+    for _ in 0..1000000 {
+        check(
+            rng.gen_range(-window as i16..=window as i16),
+            rng.gen_range(-32768..=32768),
+        );
+    }
+
+    // the window of the wide model versions covers the DC of 12 bit images, and the residual
+    // still fits the longest coefficient
+    let max_bits = ModelVersion::V1WideCoefficients.max_coefficient_bits();
+    let wide_window = 1 << (max_bits - 1);
+    for _ in 0..1000000 {
+        let saved_dc = rng.gen_range(-wide_window as i16..=wide_window as i16);
+        let predicted_val = rng.gen_range(-wide_window..=wide_window);
+
+        assert!(ProbabilityTables::dc_prediction_roundtrips(
+            saved_dc,
+            predicted_val,
+            max_bits
+        ));
+        let residual = ProbabilityTables::adv_predict_or_unpredict_dc(
+            saved_dc,
+            false,
+            predicted_val,
+            max_bits,
+        );
+        assert!(residual.abs() <= wide_window);
+    }
+}
+
+// the neighbors can only have coefficients that the model can code, anything longer is reported
+// instead of silently giving priors that the model never saw
+#[cfg(debug_assertions)]
+#[test]
+fn aavg_rejects_out_of_range_coefficients() {
+    use crate::enabled_features::ModelVersion;
+    use crate::lepton_error::LeptonError;
+
+    let pt = ProbabilityTables::new(0, true, true);
+
+    let mut max_block = AlignedBlock::default();
+    for i in 0..64 {
+        max_block.set_coefficient(i, if i % 2 == 0 { 2047 } else { -2047 });
+    }
+
+    let best_prior = pt
+        .calc_coefficient_context_7x7_aavg_block::<true, MAX_EXPONENT>(
+            &max_block, &max_block, &max_block,
+        )
+        .unwrap();
+    assert_eq!(best_prior[8], 65504 >> 5);
+
+    // the 32 bit sum of the wide model versions gives the same priors in the range of V1
+    const WIDE_BITS: usize = ModelVersion::V1WideCoefficients.max_coefficient_bits();
+    assert_eq!(
+        pt.calc_coefficient_context_7x7_aavg_block::<true, WIDE_BITS>(
+            &max_block, &max_block, &max_block,
+        )
+        .unwrap(),
+        best_prior
+    );
+
+    let mut over_range = max_block.clone();
+    over_range.set_coefficient(9, -2048);
+
+    for (left, above, above_left) in [
+        (&over_range, &max_block, &max_block),
+        (&max_block, &over_range, &max_block),
+        (&max_block, &max_block, &over_range),
+    ] {
+        let e = pt
+            .calc_coefficient_context_7x7_aavg_block::<true, MAX_EXPONENT>(left, above, above_left)
+            .unwrap_err();
+        assert_eq!(
+            e.root_cause()
+                .downcast_ref::<LeptonError>()
+                .unwrap()
+                .exit_code,
+            ExitCode::StreamInconsistent
+        );
+    }
+
+    // the wide model versions code up to 15 bits, where the weighted sum needs 32 bits
+
+    let mut wide_block = AlignedBlock::default();
+    for i in 0..64 {
+        wide_block.set_coefficient(i, if i % 2 == 0 { 32767 } else { -32767 });
+    }
+
+    let best_prior = pt
+        .calc_coefficient_context_7x7_aavg_block::<true, WIDE_BITS>(
+            &wide_block,
+            &wide_block,
+            &wide_block,
+        )
+        .unwrap();
+    assert!(best_prior[8..].iter().all(|&p| p == 32767));
+
+    wide_block.set_coefficient(9, i16::MIN);
+    assert!(pt
+        .calc_coefficient_context_7x7_aavg_block::<true, WIDE_BITS>(
+            &max_block,
+            &max_block,
+            &wide_block,
+        )
+        .is_err());
 }
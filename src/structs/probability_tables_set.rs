@@ -28,6 +28,12 @@ fn make_probability_tables_tuple(
     ];
 }
 
+impl Default for ProbabilityTablesSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProbabilityTablesSet {
     pub fn new() -> Self {
         return ProbabilityTablesSet {
@@ -39,4 +45,11 @@ impl ProbabilityTablesSet {
             width_one: make_probability_tables_tuple(false, true),
         };
     }
+
+    /// Number of bytes used by the set. All the tables are stored inline, so this is
+    /// just the static size of the structure.
+    #[allow(dead_code)]
+    pub fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
 }
@@ -4,9 +4,13 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+use alloc::vec::Vec;
+
 use crate::consts::*;
 use crate::helpers::*;
+use crate::lepton_error::{ExitCode, Result};
 
+#[cfg(feature = "std")]
 use super::jpeg_header::JPegHeader;
 
 pub struct QuantizationTables {
@@ -20,6 +24,7 @@ pub struct QuantizationTables {
 }
 
 impl QuantizationTables {
+    #[cfg(feature = "std")]
     pub fn new(jpeg_header: &JPegHeader, component: usize) -> Self {
         Self::new_from_table(
             &jpeg_header.q_tables[usize::from(jpeg_header.cmp_info[component].q_table_index)],
@@ -62,6 +67,43 @@ impl QuantizationTables {
         retval
     }
 
+    /// Parses the payload of a DQT marker segment, i.e. everything after the two length bytes,
+    /// and returns the tables with their ids in the order they appear. A segment can hold
+    /// several tables, each with 8 or 16 bit precision and the values in zigzag order.
+    #[allow(dead_code)]
+    pub fn parse_dqt(segment: &[u8]) -> Result<Vec<(u8, QuantizationTables)>> {
+        let mut tables = Vec::new();
+        let mut hpos = 0;
+
+        while hpos < segment.len() {
+            let precision = lbits(segment[hpos], 4);
+            let table_id = rbits(segment[hpos], 4);
+            if precision >= 2 || table_id >= 4 {
+                return err_exit_code(ExitCode::UnsupportedJpeg, "DQT has invalid index");
+            }
+            hpos += 1;
+
+            let value_size = usize::from(precision) + 1;
+            if segment.len() - hpos < 64 * value_size {
+                return err_exit_code(ExitCode::UnsupportedJpeg, "size mismatch in dqt marker");
+            }
+
+            let mut table = [0u16; 64];
+            for (i, q) in table.iter_mut().enumerate() {
+                *q = if precision == 0 {
+                    u16::from(segment[hpos + i])
+                } else {
+                    b_short(segment[hpos + (2 * i)], segment[hpos + (2 * i) + 1])
+                };
+            }
+            hpos += 64 * value_size;
+
+            tables.push((table_id, QuantizationTables::new_from_table(&table)));
+        }
+
+        Ok(tables)
+    }
+
     pub fn get_quantization_table(&self) -> &[u16; 64] {
         &self.quantization_table
     }
@@ -74,3 +116,52 @@ impl QuantizationTables {
         self.min_noise_threshold[coef]
     }
 }
+
+// a segment with the 8 bit luminance table of the JPEG standard followed by a 16 bit
+// chrominance table, both stored in zigzag order
+#[test]
+fn parse_dqt_tables() {
+    const LUMA: [u8; 64] = [
+        16, 11, 12, 14, 12, 10, 16, 14, 13, 14, 18, 17, 16, 19, 24, 40, 26, 24, 22, 22, 24, 49, 35,
+        37, 29, 40, 58, 51, 61, 60, 57, 51, 56, 55, 64, 72, 92, 78, 64, 68, 87, 69, 55, 56, 80,
+        109, 81, 87, 95, 98, 103, 104, 103, 62, 77, 113, 121, 112, 100, 120, 92, 101, 103, 99,
+    ];
+
+    let mut segment = vec![0x00];
+    segment.extend_from_slice(&LUMA);
+    segment.push(0x11);
+    for i in 0..64u16 {
+        segment.extend_from_slice(&(300 + i).to_be_bytes());
+    }
+
+    let tables = QuantizationTables::parse_dqt(&segment).unwrap();
+    assert_eq!(tables.len(), 2);
+
+    let (luma_id, luma) = &tables[0];
+    assert_eq!(*luma_id, 0);
+    for coord in 0..64 {
+        assert_eq!(
+            luma.get_quantization_table()[coord],
+            u16::from(LUMA[usize::from(RASTER_TO_ZIGZAG[coord])])
+        );
+    }
+    // first row in raster order
+    assert_eq!(
+        luma.get_quantization_table()[..8],
+        [16, 11, 10, 16, 24, 40, 51, 61]
+    );
+
+    let (chroma_id, chroma) = &tables[1];
+    assert_eq!(*chroma_id, 1);
+    for coord in 0..64 {
+        assert_eq!(
+            chroma.get_quantization_table()[coord],
+            300 + u16::from(RASTER_TO_ZIGZAG[coord])
+        );
+    }
+
+    // truncated tables, unknown precision and out of range ids are rejected
+    assert!(QuantizationTables::parse_dqt(&segment[..segment.len() - 1]).is_err());
+    assert!(QuantizationTables::parse_dqt(&[0x20; 129]).is_err());
+    assert!(QuantizationTables::parse_dqt(&[0x04; 65]).is_err());
+}
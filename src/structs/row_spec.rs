@@ -7,6 +7,7 @@
 use crate::consts::COLOR_CHANNEL_NUM_BLOCK_TYPES;
 
 use super::block_based_image::BlockBasedImage;
+use super::jpeg_header::JPegHeader;
 
 pub struct RowSpec {
     pub min_row_luma_y: i32,
@@ -40,10 +41,25 @@ impl RowSpec {
 
         for i in 0..num_cmp {
             heights.push(image_data[i].get_original_height() as u32);
-            component_multiple.push(heights[i] / mcuv as u32);
+            component_multiple.push(heights[i].checked_div(mcuv as u32).unwrap_or(0));
             mcu_multiple += component_multiple[i];
         }
 
+        if mcuv <= 0 || mcu_multiple == 0 {
+            // there are no rows at all if the image doesn't have any blocks
+            return RowSpec {
+                skip: true,
+                done: true,
+                mcu_row_index: 0,
+                component: num_cmp,
+                min_row_luma_y: 0,
+                next_row_luma_y: 0,
+                luma_y: 0,
+                curr_y: 0,
+                last_row_to_complete_mcu: false,
+            };
+        }
+
         let mcu_row = decode_index / mcu_multiple;
         let min_row_luma_y = (mcu_row * component_multiple[0]) as i32;
         let mut retval = RowSpec {
@@ -102,3 +118,134 @@ impl RowSpec {
         return retval;
     }
 }
+
+/// Rows in the order that the Lepton coder visits them, leaving out the rows that are skipped.
+/// Each component is coded row by row, and the rows of the components are interleaved by MCU
+/// row the same way as the blocks of an interleaved JPEG scan.
+#[allow(dead_code)]
+pub fn get_row_specs(
+    image_data: &[BlockBasedImage],
+    mcuv: i32,
+    max_coded_heights: &[u32],
+) -> Vec<RowSpec> {
+    let mut rows = Vec::new();
+
+    for decode_index in 0.. {
+        let row =
+            RowSpec::get_row_spec_from_index(decode_index, image_data, mcuv, max_coded_heights);
+        if row.done {
+            break;
+        }
+
+        if !row.skip {
+            rows.push(row);
+        }
+    }
+
+    rows
+}
+
+/// Blocks as `(component, dpos)` in the order that an interleaved JPEG scan stores them,
+/// following the JPEG rules rather than `JpegPositionState`: MCU by MCU, and within the MCU
+/// the blocks of each component of the scan row by row.
+#[allow(dead_code)]
+pub fn get_mcu_interleaved_order(jpeg_header: &JPegHeader) -> Vec<(usize, i32)> {
+    let mut order = Vec::new();
+
+    for mcu in 0..jpeg_header.mcuc {
+        let mcu_x = mcu % jpeg_header.mcuh;
+        let mcu_y = mcu / jpeg_header.mcuh;
+
+        for &cmp in &jpeg_header.cs_cmp[..jpeg_header.cs_cmpc] {
+            // like in c++ lepton, sfv holds the horizontal and sfh the vertical sampling factor
+            let ci = &jpeg_header.cmp_info[cmp];
+            let (horizontal, vertical) = (ci.sfv, ci.sfh);
+
+            for y in 0..vertical {
+                for x in 0..horizontal {
+                    order.push((
+                        cmp,
+                        (mcu_y * vertical + y) * ci.bch + mcu_x * horizontal + x,
+                    ));
+                }
+            }
+        }
+    }
+
+    order
+}
+
+// for subsampled images, the blocks that the JPEG reader visits MCU by MCU have to be the
+// ones that the rows of the Lepton coder cover for the same MCU row
+#[test]
+fn row_specs_match_mcu_interleaving() {
+    use crate::enabled_features::EnabledFeatures;
+    use crate::structs::jpeg_position_state::JpegPositionState;
+    use crate::structs::lepton_format::read_jpeg;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg;
+    use std::collections::BTreeSet;
+    use std::io::Cursor;
+
+    // 4:2:0, 4:2:2 and 4:4:0, with partial MCUs at the right and bottom edges
+    for sampling in [[0x22, 0x11, 0x11], [0x21, 0x11, 0x11], [0x12, 0x11, 0x11]] {
+        let data = synthetic_jpeg(&sampling, 72, 40);
+        let (lh, image_data) = read_jpeg(
+            &mut Cursor::new(data),
+            &EnabledFeatures::compat_lepton_vector_write(),
+            1,
+            |_| {},
+        )
+        .unwrap();
+        let jh = &lh.jpeg_header;
+
+        let mcu_order = get_mcu_interleaved_order(jh);
+
+        // the order used by the JPEG reader and writer
+        let mut state = JpegPositionState::new(jh, 0);
+        for (i, &expected) in mcu_order.iter().enumerate() {
+            assert_eq!(
+                (state.get_cmp(), state.get_dpos()),
+                expected,
+                "{sampling:x?} {i}"
+            );
+            state.next_mcu_pos(jh);
+        }
+
+        let max_coded_heights = lh.truncate_components.get_max_coded_heights();
+        let rows = get_row_specs(&image_data, jh.mcuv, &max_coded_heights);
+
+        let mut next_y = vec![0; image_data.len()];
+        let mut mcu_order = mcu_order.iter().peekable();
+
+        for mcu_row in 0..jh.mcuv {
+            let mut from_rows = BTreeSet::new();
+            for row in rows.iter().filter(|r| r.mcu_row_index == mcu_row) {
+                // each component is coded top to bottom without gaps
+                assert_eq!(row.curr_y, next_y[row.component], "{sampling:x?}");
+                next_y[row.component] += 1;
+
+                let block_width = image_data[row.component].get_block_width();
+                for x in 0..block_width {
+                    from_rows.insert((row.component, row.curr_y * block_width + x));
+                }
+            }
+
+            let mut from_mcus = BTreeSet::new();
+            for _ in 0..jh.mcuh {
+                for _ in jh.cs_cmp[..jh.cs_cmpc]
+                    .iter()
+                    .flat_map(|&c| 0..jh.cmp_info[c].mbs)
+                {
+                    from_mcus.insert(*mcu_order.next().unwrap());
+                }
+            }
+
+            assert_eq!(from_rows, from_mcus, "{sampling:x?} mcu row {mcu_row}");
+        }
+
+        assert!(mcu_order.peek().is_none());
+        for (c, image) in image_data.iter().enumerate() {
+            assert_eq!(next_y[c], image.get_original_height(), "{sampling:x?}");
+        }
+    }
+}
@@ -6,7 +6,7 @@
 
 #![allow(dead_code)]
 
-use std::num::Wrapping;
+use core::num::Wrapping;
 
 /// used for debugging when there are divergences between encoder and decoder
 pub struct SimpleHash {
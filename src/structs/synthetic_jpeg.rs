@@ -0,0 +1,248 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Small baseline JPEGs that are generated in memory, so that the unit tests don't depend on
+//! the image files of the end to end tests.
+
+use rand::Rng;
+
+use crate::helpers::get_rand_from_seed;
+
+// DC categories 0 to 3 with the canonical codes 00, 01, 100 and 101
+const DC_SYMBOLS: [u8; 4] = [0x00, 0x01, 0x02, 0x03];
+const DC_CODES: [(u16, u8); 4] = [(0b00, 2), (0b01, 2), (0b100, 3), (0b101, 3)];
+
+// the random blocks only use EOB, (0,1), (0,2), (1,1), (0,3) and (2,1), with the canonical codes
+// 00, 01, 100, 101, 1100 and 1101
+const AC_USED_SYMBOLS: [u8; 6] = [0x00, 0x01, 0x02, 0x11, 0x03, 0x21];
+const AC_USED_CODES: [(u16, u8); 6] = [
+    (0b00, 2),
+    (0b01, 2),
+    (0b100, 3),
+    (0b101, 3),
+    (0b1100, 4),
+    (0b1101, 4),
+];
+
+// the table also has 9 bit codes for every other run with sizes 1 to 3 and for ZRL, so that the
+// blocks can still be written after code like lossy_ac_cleanup made the zero runs longer
+fn ac_symbols() -> Vec<u8> {
+    let mut symbols = AC_USED_SYMBOLS.to_vec();
+    for run in 0..16u8 {
+        for size in 1..4u8 {
+            let symbol = (run << 4) | size;
+            if !symbols.contains(&symbol) {
+                symbols.push(symbol);
+            }
+        }
+    }
+    symbols.push(0xF0);
+    symbols
+}
+
+fn put_bits(bits: &mut Vec<bool>, code: u16, length: u8) {
+    for i in (0..length).rev() {
+        bits.push((code >> i) & 1 != 0);
+    }
+}
+
+// a random non-zero value of the given size
+fn random_value(rng: &mut impl Rng, size: u8) -> i16 {
+    let magnitude = rng.gen_range((1i16 << (size - 1))..(1i16 << size));
+    if rng.gen() {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+// writes the value in the one's complement form of the JPEG
+fn put_value(bits: &mut Vec<bool>, value: i16, size: u8) {
+    let code = if value > 0 {
+        value
+    } else {
+        value + (1 << size) - 1
+    };
+    put_bits(bits, code as u16, size);
+}
+
+/// The segments up to and including the SOS of `synthetic_jpeg`, which is all that the code
+/// that only parses the header needs. The first component uses quantization table 0 and the
+/// others table 1, like the files of most cameras.
+pub fn synthetic_jpeg_header(sampling: &[u8], width: u16, height: u16) -> Vec<u8> {
+    let ncomp = sampling.len() as u8;
+
+    let mut jpeg = vec![0xFF, 0xD8];
+
+    jpeg.extend_from_slice(&[0xFF, 0xDB, 0, 132]);
+    for table in 0..2u8 {
+        jpeg.push(table);
+        jpeg.extend((0..64u8).map(|i| 1 + table + i / 16));
+    }
+
+    jpeg.extend_from_slice(&[0xFF, 0xC0, 0, 8 + 3 * ncomp, 8]);
+    jpeg.extend_from_slice(&height.to_be_bytes());
+    jpeg.extend_from_slice(&width.to_be_bytes());
+    jpeg.push(ncomp);
+    for (i, &s) in sampling.iter().enumerate() {
+        jpeg.extend_from_slice(&[i as u8 + 1, s, u8::from(i > 0)]);
+    }
+
+    let ac_symbols = ac_symbols();
+    let ac_long_codes = (ac_symbols.len() - AC_USED_SYMBOLS.len()) as u8;
+
+    jpeg.extend_from_slice(&[0xFF, 0xC4, 0, 2 + 17 + 4 + 17 + ac_symbols.len() as u8]);
+    jpeg.extend_from_slice(&[0x00, 0, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    jpeg.extend_from_slice(&DC_SYMBOLS);
+    jpeg.extend_from_slice(&[
+        0x10,
+        0,
+        2,
+        2,
+        2,
+        0,
+        0,
+        0,
+        0,
+        ac_long_codes,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]);
+    jpeg.extend_from_slice(&ac_symbols);
+
+    jpeg.extend_from_slice(&[0xFF, 0xDA, 0, 6 + 2 * ncomp, ncomp]);
+    for i in 0..ncomp {
+        jpeg.extend_from_slice(&[i + 1, 0]);
+    }
+    jpeg.extend_from_slice(&[0, 63, 0]);
+
+    jpeg
+}
+
+/// Builds a baseline JPEG of `width` x `height` pixels with the given sampling factors (high
+/// nibble horizontal, low nibble vertical) per component. The blocks have random DC differences
+/// of up to 7 and random AC coefficients of up to 7 with short zero runs in between, so that
+/// every block is different but the images stay small.
+pub fn synthetic_jpeg(sampling: &[u8], width: u16, height: u16) -> Vec<u8> {
+    let mut rng = get_rand_from_seed([7u8; 32]);
+
+    let mut jpeg = synthetic_jpeg_header(sampling, width, height);
+
+    let mut bits = Vec::new();
+
+    // a single component is coded block by block, several components MCU by MCU
+    let blocks_per_mcu: Vec<u16> = if sampling.len() == 1 {
+        vec![1]
+    } else {
+        sampling
+            .iter()
+            .map(|s| u16::from(s >> 4) * u16::from(s & 15))
+            .collect()
+    };
+    let (hmax, vmax) = if sampling.len() == 1 {
+        (1, 1)
+    } else {
+        (
+            sampling.iter().map(|s| u16::from(s >> 4)).max().unwrap(),
+            sampling.iter().map(|s| u16::from(s & 15)).max().unwrap(),
+        )
+    };
+    let mcus = usize::from((width + 8 * hmax - 1) / (8 * hmax))
+        * usize::from((height + 8 * vmax - 1) / (8 * vmax));
+
+    // the DC differences turn around before the DC of large images can leave the valid range
+    let mut dc = vec![0i16; blocks_per_mcu.len()];
+
+    for _ in 0..mcus {
+        for (c, &blocks) in blocks_per_mcu.iter().enumerate() {
+            for _ in 0..blocks {
+                let category = rng.gen_range(0..4);
+                let (code, length) = DC_CODES[category];
+                put_bits(&mut bits, code, length);
+                if category > 0 {
+                    let mut diff = random_value(&mut rng, category as u8);
+                    if (dc[c] + diff).abs() > 64 {
+                        diff = -diff;
+                    }
+                    dc[c] += diff;
+                    put_value(&mut bits, diff, category as u8);
+                }
+
+                let mut k = 1;
+                for _ in 0..rng.gen_range(0..16) {
+                    let symbol = rng.gen_range(1..AC_USED_SYMBOLS.len());
+                    let run = usize::from(AC_USED_SYMBOLS[symbol] >> 4);
+                    if k + run > 63 {
+                        break;
+                    }
+
+                    let (code, length) = AC_USED_CODES[symbol];
+                    put_bits(&mut bits, code, length);
+                    let size = AC_USED_SYMBOLS[symbol] & 15;
+                    let value = random_value(&mut rng, size);
+                    put_value(&mut bits, value, size);
+
+                    k += run + 1;
+                    if k > 63 {
+                        break;
+                    }
+                }
+
+                if k <= 63 {
+                    let (code, length) = AC_USED_CODES[0];
+                    put_bits(&mut bits, code, length);
+                }
+            }
+        }
+    }
+
+    // pad with ones and stuff the 0xFF bytes
+    while bits.len() % 8 != 0 {
+        bits.push(true);
+    }
+    for byte in bits.chunks(8) {
+        let b = byte
+            .iter()
+            .fold(0u8, |acc, &bit| (acc << 1) | u8::from(bit));
+        jpeg.push(b);
+        if b == 0xFF {
+            jpeg.push(0);
+        }
+    }
+
+    jpeg.extend_from_slice(&[0xFF, 0xD9]);
+    jpeg
+}
+
+// the generated files have to be read and written back by the JPEG code exactly
+#[test]
+fn synthetic_jpeg_roundtrip() {
+    use crate::enabled_features::EnabledFeatures;
+    use crate::structs::lepton_format::read_jpeg;
+    use std::io::Cursor;
+
+    for sampling in [&[0x11][..], &[0x22, 0x11, 0x11], &[0x21, 0x11, 0x11]] {
+        let jpeg = synthetic_jpeg(sampling, 40, 24);
+
+        let (lh, image_data) = read_jpeg(
+            &mut Cursor::new(&jpeg),
+            &EnabledFeatures::compat_lepton_vector_write(),
+            1,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(image_data.len(), sampling.len());
+
+        let mut output = Vec::new();
+        lh.reconstruct_jpeg(&image_data, &mut output).unwrap();
+        assert!(output == jpeg, "{sampling:x?}");
+    }
+}
@@ -0,0 +1,66 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::{Read, Seek, SeekFrom};
+
+/// Reader that passes every byte of the underlying stream to a callback the first time it is read.
+///
+/// The JPEG parser seeks backwards to reread a few bytes, so the reader remembers how far the
+/// stream has been passed to the callback and only passes on the bytes beyond that. Seeking
+/// forward past that point reads the skipped bytes, so the callback always sees the stream in
+/// order and exactly once.
+#[allow(dead_code)]
+pub struct TapReader<'a, R> {
+    inner: R,
+    position: u64,
+    tapped_to: u64,
+    tap: &'a mut dyn FnMut(&[u8]),
+}
+
+#[allow(dead_code)]
+impl<'a, R: Read + Seek> TapReader<'a, R> {
+    pub fn new(mut inner: R, tap: &'a mut dyn FnMut(&[u8])) -> std::io::Result<Self> {
+        let position = inner.stream_position()?;
+
+        Ok(TapReader {
+            inner,
+            position,
+            tapped_to: position,
+            tap,
+        })
+    }
+}
+
+impl<R: Read> Read for TapReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+
+        let end = self.position + bytes_read as u64;
+        if end > self.tapped_to {
+            (self.tap)(&buf[(self.tapped_to - self.position) as usize..bytes_read]);
+            self.tapped_to = end;
+        }
+        self.position = end;
+
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read + Seek> Seek for TapReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = self.inner.seek(pos)?;
+
+        if new_position > self.tapped_to {
+            // read the bytes that would be skipped so the callback doesn't miss them
+            let skipped = new_position - self.tapped_to;
+            self.position = self.inner.seek(SeekFrom::Start(self.tapped_to))?;
+            std::io::copy(&mut self.by_ref().take(skipped), &mut std::io::sink())?;
+        }
+
+        self.position = self.inner.seek(SeekFrom::Start(new_position))?;
+        Ok(self.position)
+    }
+}
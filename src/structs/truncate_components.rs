@@ -28,6 +28,12 @@ pub struct TruncateComponents {
     pub mcu_count_vertical: i32,
 }
 
+impl Default for TruncateComponents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TruncateComponents {
     pub fn new() -> Self {
         return TruncateComponents {
@@ -71,6 +77,17 @@ impl TruncateComponents {
         }
     }
 
+    /// returns the number of block rows and the number of blocks of a component that are
+    /// coded after truncation. The row count is rounded up to whole MCUs, so it may include
+    /// blocks beyond the block count.
+    #[allow(dead_code)]
+    pub fn get_component_truncation(&self, cmp: usize) -> (i32, i32) {
+        (
+            self.trunc_info[cmp].trunc_bcv,
+            self.trunc_info[cmp].trunc_bc,
+        )
+    }
+
     pub fn get_block_height(&self, cmp: usize) -> i32 {
         return self.trunc_info[cmp].trunc_bcv;
     }
@@ -117,3 +134,56 @@ impl TruncateComponents {
         return retval;
     }
 }
+
+// check the truncation geometry of a 960x1280 4:2:0 image, both for the full image
+// and for an image cut off in the middle of a luma block row
+#[test]
+fn truncation_geometry() {
+    use crate::enabled_features::EnabledFeatures;
+    use crate::structs::synthetic_jpeg::synthetic_jpeg_header;
+
+    let file = synthetic_jpeg_header(&[0x22, 0x11, 0x11], 960, 1280);
+    let jpeg_header = JPegHeader::from_reader(
+        &mut &file[..],
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let mut truncate_components = TruncateComponents::new();
+    truncate_components.init(&jpeg_header);
+
+    assert_eq!(truncate_components.components_count, 3);
+    assert_eq!(truncate_components.mcu_count_horizontal, 960 / 16);
+    assert_eq!(truncate_components.mcu_count_vertical, 1280 / 16);
+
+    truncate_components
+        .set_truncation_bounds(&jpeg_header, [120 * 160 - 1, 60 * 80 - 1, 60 * 80 - 1, 0]);
+    assert_eq!(
+        truncate_components.get_component_truncation(0),
+        (160, 120 * 160)
+    );
+    assert_eq!(
+        truncate_components.get_component_truncation(1),
+        (80, 60 * 80)
+    );
+    assert_eq!(
+        truncate_components.get_component_truncation(2),
+        (80, 60 * 80)
+    );
+
+    // luma stops 10 blocks into row 51, so its rows are rounded up to the 2 rows of an MCU
+    truncate_components
+        .set_truncation_bounds(&jpeg_header, [120 * 51 + 9, 60 * 25 - 1, 60 * 25 - 1, 0]);
+    assert_eq!(
+        truncate_components.get_component_truncation(0),
+        (52, 120 * 51 + 10)
+    );
+    assert_eq!(
+        truncate_components.get_component_truncation(1),
+        (25, 60 * 25)
+    );
+    assert_eq!(
+        truncate_components.get_max_coded_heights(),
+        vec![52, 25, 25]
+    );
+}
@@ -22,11 +22,41 @@ Neither the name of Google nor the names of its contributors may be used to endo
 THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS “AS IS” AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 */
 
-use std::io::{Read, Result};
-
 use crate::metrics::{Metrics, ModelComponent};
 
-use super::{branch::Branch, simple_hash::SimpleHash};
+use super::{branch::Branch, power_of_two::IsPowerOfTwo, simple_hash::SimpleHash};
+
+/// The only thing the reader needs from its input, so that it doesn't depend on `std::io`.
+/// With std this is implemented for every `Read`, without std for byte slices.
+pub trait ByteSource {
+    type Error;
+
+    /// reads up to `buf.len()` bytes, returning 0 at the end of the stream
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> ByteSource for R {
+    type Error = std::io::Error;
+
+    #[inline(always)]
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.read(buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSource for &[u8] {
+    type Error = core::convert::Infallible;
+
+    #[inline(always)]
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = core::cmp::min(buf.len(), self.len());
+        buf[..n].copy_from_slice(&self[..n]);
+        *self = &self[n..];
+        Ok(n)
+    }
+}
 
 const BITS_IN_BYTE: i32 = 8;
 const BITS_IN_VALUE: i32 = 32;
@@ -38,21 +68,38 @@ pub struct VPXBoolReader<R> {
     count: i32,
     upstream_reader: R,
     model_statistics: Metrics,
+    #[cfg(feature = "compression_stats")]
+    stats_color_index: usize,
+    #[cfg(feature = "compression_stats")]
+    stats_zigzag_index: Option<usize>,
+    past_eof: bool,
+    bytes_consumed: u64,
     pub hash: SimpleHash,
 }
 
-impl<R: Read> VPXBoolReader<R> {
-    pub fn new(reader: R) -> Result<Self> {
+impl<R: ByteSource> VPXBoolReader<R> {
+    pub fn new(reader: R) -> Result<Self, R::Error> {
         let mut r = VPXBoolReader {
             upstream_reader: reader,
             value: 0,
             count: -8,
             range: 255 << BITS_IN_VALUE_MINUS_LAST_BYTE,
             model_statistics: Metrics::default(),
+            #[cfg(feature = "compression_stats")]
+            stats_color_index: 0,
+            #[cfg(feature = "compression_stats")]
+            stats_zigzag_index: None,
+            past_eof: false,
+            bytes_consumed: 0,
             hash: SimpleHash::new(),
         };
 
-        Self::vpx_reader_fill(&mut r.value, &mut r.count, &mut r.upstream_reader)?;
+        Self::vpx_reader_fill(
+            &mut r.value,
+            &mut r.count,
+            &mut r.bytes_consumed,
+            &mut r.upstream_reader,
+        )?;
 
         let mut dummy_branch = Branch::new();
         r.get(&mut dummy_branch, ModelComponent::Dummy)?; // marker bit
@@ -64,14 +111,65 @@ impl<R: Read> VPXBoolReader<R> {
         self.model_statistics.drain()
     }
 
+    /// see `Metrics::record_edge_noise_coefficients`
+    #[inline(always)]
+    pub fn record_edge_noise_coefficients(&mut self, component: usize, count: u32) {
+        if count != 0 {
+            self.model_statistics
+                .record_edge_noise_coefficients(component, i64::from(count));
+        }
+    }
+
+    /// gives access to the source of the bytes, for example to refill an async source
+    #[cfg(feature = "tokio")]
+    pub fn get_upstream_mut(&mut self) -> &mut R {
+        &mut self.upstream_reader
+    }
+
+    /// true if the decoder had to read beyond the end of the stream, which usually
+    /// means that the stream was truncated and the data decoded from that point is garbage
+    pub fn is_past_eof(&self) -> bool {
+        self.past_eof
+    }
+
+    /// Number of bytes read from the upstream reader so far. This includes the up to 4 bytes
+    /// that are already buffered in `value` but haven't been needed by the bits decoded yet.
+    /// The writer appends 4 bytes of padding after the last bit (plus a zero byte if the last
+    /// byte could be mistaken for a marker), so after decoding the last bit of a stream this is
+    /// between the stream length minus 5 and the stream length. The exact end of a stream has
+    /// to come from the framing around it, like the lengths of the multiplexer blocks.
+    #[allow(dead_code)]
+    pub fn bytes_consumed(&self) -> u64 {
+        self.bytes_consumed
+    }
+
+    /// sets the color plane that the following bits are attributed to in the compression stats
+    #[inline(always)]
+    pub fn set_stats_color_index(&mut self, _color_index: usize) {
+        #[cfg(feature = "compression_stats")]
+        {
+            self.stats_color_index = _color_index;
+        }
+    }
+
+    /// sets the zigzag index of the coefficient that the following bits are attributed to in
+    /// the compression stats, None for bits that don't belong to a single coefficient
+    #[inline(always)]
+    pub fn set_stats_zigzag_index(&mut self, _zigzag_index: Option<usize>) {
+        #[cfg(feature = "compression_stats")]
+        {
+            self.stats_zigzag_index = _zigzag_index;
+        }
+    }
+
     #[inline(never)]
     pub fn get_grid<const A: usize>(
         &mut self,
         branches: &mut [Branch; A],
         cmp: ModelComponent,
-    ) -> Result<usize> {
-        // check if A is a power of 2
-        assert!((A & (A - 1)) == 0);
+    ) -> Result<usize, R::Error> {
+        // fails to compile if A is not a power of 2
+        let () = IsPowerOfTwo::<A>::OK;
 
         let mut decoded_so_far = 1;
 
@@ -92,7 +190,7 @@ impl<R: Read> VPXBoolReader<R> {
         &mut self,
         branches: &mut [Branch; A],
         cmp: ModelComponent,
-    ) -> Result<usize> {
+    ) -> Result<usize, R::Error> {
         let mut value = 0;
 
         while value != A {
@@ -113,7 +211,7 @@ impl<R: Read> VPXBoolReader<R> {
         n: usize,
         branches: &mut [Branch; A],
         cmp: ModelComponent,
-    ) -> Result<usize> {
+    ) -> Result<usize, R::Error> {
         assert!(n <= branches.len());
 
         let mut coef = 0;
@@ -146,20 +244,110 @@ impl<R: Read> VPXBoolReader<R> {
     // and it allows to reduce number of operations to compute `split` - previously `big_split` -
     // and to update `range` and `shift`).
     #[inline(always)]
-    pub fn get(&mut self, branch: &mut Branch, _cmp: ModelComponent) -> Result<bool> {
+    pub fn get(&mut self, branch: &mut Branch, cmp: ModelComponent) -> Result<bool, R::Error> {
+        let bit = self.get_with_probability(branch.get_probability() as u32, cmp)?;
+
+        branch.record_and_update_bit(bit);
+
+        #[cfg(feature = "detailed_tracing")]
+        {
+            self.hash.hash(branch.get_u64());
+            self.hash.hash(self.value);
+            self.hash.hash(self.count);
+            self.hash.hash(self.range);
+
+            //if hash == 0x88f9c945
+            {
+                let hash = self.hash.get();
+
+                print!("({0}:{1:x})", bit as u8, hash);
+                if hash % 8 == 0 {
+                    println!();
+                }
+            }
+        }
+
+        Ok(bit)
+    }
+
+    /// Returns the bit that `get` would decode next with `branch`, without consuming it or
+    /// updating the branch, so that a caller can try several branches before deciding which
+    /// one to use. The window may be refilled from the stream, which doesn't change any of
+    /// the bits that are decoded afterwards.
+    #[allow(dead_code)]
+    pub fn peek_bit(&mut self, branch: &Branch) -> Result<bool, R::Error> {
+        if self.count < 0 {
+            Self::vpx_reader_fill(
+                &mut self.value,
+                &mut self.count,
+                &mut self.bytes_consumed,
+                &mut self.upstream_reader,
+            )?;
+
+            if self.count < 0 {
+                self.past_eof = true;
+            }
+        }
+
+        let split = Self::split(self.range, branch.get_probability() as u32);
+
+        Ok(self.value >= split)
+    }
+
+    /// Consumes the next bit with `branch` and updates the branch, exactly like `get`. After
+    /// `peek_bit` with the same branch this returns the peeked bit.
+    #[allow(dead_code)]
+    pub fn commit_bit(
+        &mut self,
+        branch: &mut Branch,
+        cmp: ModelComponent,
+    ) -> Result<bool, R::Error> {
+        self.get(branch, cmp)
+    }
+
+    /// Reads `n` bits (most significant first) with a fixed probability of 128, which gives
+    /// the same result as reading each bit with a new neutral branch, without any branch
+    /// to update. Intended for bits that are effectively random.
+    pub fn get_bypass_bits(&mut self, n: usize, cmp: ModelComponent) -> Result<usize, R::Error> {
+        assert!(n <= usize::BITS as usize);
+
+        let mut value = 0;
+        for _i in 0..n {
+            value = (value << 1) | self.get_with_probability(128, cmp)? as usize;
+        }
+
+        Ok(value)
+    }
+
+    /// decodes a single bit with the given probability of it being false, see the
+    /// description of the scheme above
+    #[inline(always)]
+    fn get_with_probability(
+        &mut self,
+        probability: u32,
+        _cmp: ModelComponent,
+    ) -> Result<bool, R::Error> {
         let mut tmp_value = self.value;
         let mut tmp_range = self.range;
         let mut tmp_count = self.count;
 
         if tmp_count < 0 {
-            Self::vpx_reader_fill(&mut tmp_value, &mut tmp_count, &mut self.upstream_reader)?;
+            Self::vpx_reader_fill(
+                &mut tmp_value,
+                &mut tmp_count,
+                &mut self.bytes_consumed,
+                &mut self.upstream_reader,
+            )?;
+
+            // our encoder flushes enough padding after the last real bit that the window
+            // can always be filled, so if it can't, the stream was most likely cut short.
+            // Note that other encoders may trim trailing zeros, so this is only a hint.
+            if tmp_count < 0 {
+                self.past_eof = true;
+            }
         }
 
-        let probability = branch.get_probability() as u32;
-
-        let split = ((((tmp_range - (1 << BITS_IN_VALUE_MINUS_LAST_BYTE)) >> 8) * probability)
-            & (0xFF << BITS_IN_VALUE_MINUS_LAST_BYTE))
-            + (1 << BITS_IN_VALUE_MINUS_LAST_BYTE);
+        let split = Self::split(tmp_range, probability);
 
         // So optimizer understands that 0 should never happen and uses a cold jump
         // if we don't have LZCNT on x86 CPUs (older BSR instruction requires check for zero).
@@ -173,8 +361,6 @@ impl<R: Read> VPXBoolReader<R> {
 
         let bit = tmp_value >= split;
 
-        branch.record_and_update_bit(bit);
-
         if bit {
             tmp_range -= split;
             tmp_value -= split;
@@ -182,6 +368,12 @@ impl<R: Read> VPXBoolReader<R> {
             tmp_range = split;
         }
 
+        // split is a multiple of the lowest bit of the 8 MSBs, at least that bit and at most
+        // range minus that bit, so neither part of the range can be empty whatever the stream
+        // contains. The stream only decides which part is taken. This module doesn't use any
+        // unsafe code, so a broken invariant would give a wrong shift rather than UB.
+        debug_assert!(tmp_range >= 1 << BITS_IN_VALUE_MINUS_LAST_BYTE);
+
         let shift = tmp_range.leading_zeros() as i32;
 
         self.value = tmp_value << shift;
@@ -190,29 +382,44 @@ impl<R: Read> VPXBoolReader<R> {
 
         #[cfg(feature = "compression_stats")]
         {
-            self.model_statistics
-                .record_compression_stats(_cmp, 1, i64::from(shift));
+            self.model_statistics.record_compression_stats(
+                _cmp,
+                self.stats_color_index,
+                self.stats_zigzag_index,
+                1,
+                i64::from(shift),
+            );
         }
 
-        #[cfg(feature = "detailed_tracing")]
-        {
-            self.hash.hash(branch.get_u64());
-            self.hash.hash(self.value);
-            self.hash.hash(self.count);
-            self.hash.hash(self.range);
+        return Ok(bit);
+    }
 
-            //if hash == 0x88f9c945
-            {
-                let hash = self.hash.get();
+    /// calculates `split` with the formula selected by the `classic_split` feature
+    #[inline(always)]
+    fn split(tmp_range: u32, probability: u32) -> u32 {
+        #[cfg(not(feature = "classic_split"))]
+        return Self::calc_split(tmp_range, probability);
+        #[cfg(feature = "classic_split")]
+        return Self::calc_split_classic(tmp_range, probability);
+    }
 
-                print!("({0}:{1:x})", bit as u8, hash);
-                if hash % 8 == 0 {
-                    println!();
-                }
-            }
-        }
+    /// calculates `split` directly on the 8 MSBs of `range`, see the description of `get`
+    #[allow(dead_code)]
+    #[inline(always)]
+    fn calc_split(tmp_range: u32, probability: u32) -> u32 {
+        ((((tmp_range - (1 << BITS_IN_VALUE_MINUS_LAST_BYTE)) >> 8) * probability)
+            & (0xFF << BITS_IN_VALUE_MINUS_LAST_BYTE))
+            + (1 << BITS_IN_VALUE_MINUS_LAST_BYTE)
+    }
 
-        return Ok(bit);
+    /// calculates `split` with the original 8-bit formula of the VP8 scheme and shifts it into
+    /// the 8 MSBs. Gives exactly the same result as `calc_split`, selected with the
+    /// `classic_split` feature for comparison and benchmarking.
+    #[allow(dead_code)]
+    #[inline(always)]
+    fn calc_split_classic(tmp_range: u32, probability: u32) -> u32 {
+        let range = tmp_range >> BITS_IN_VALUE_MINUS_LAST_BYTE;
+        (1 + (((range - 1) * probability) >> BITS_IN_BYTE)) << BITS_IN_VALUE_MINUS_LAST_BYTE
     }
 
     #[cold]
@@ -220,14 +427,15 @@ impl<R: Read> VPXBoolReader<R> {
     fn vpx_reader_fill(
         tmp_value: &mut u32,
         tmp_count: &mut i32,
+        bytes_consumed: &mut u64,
         upstream_reader: &mut R,
-    ) -> Result<()> {
+    ) -> Result<(), R::Error> {
         let mut shift = BITS_IN_VALUE_MINUS_LAST_BYTE - (*tmp_count + BITS_IN_BYTE);
 
         while shift >= 0 {
             // BufReader is already pretty efficient handling small reads, so optimization doesn't help that much
             let mut v = [0u8; 1];
-            let bytes_read = upstream_reader.read(&mut v)?;
+            let bytes_read = upstream_reader.read_bytes(&mut v)?;
             if bytes_read == 0 {
                 break;
             }
@@ -235,8 +443,185 @@ impl<R: Read> VPXBoolReader<R> {
             *tmp_value |= (v[0] as u32) << shift;
             shift -= BITS_IN_BYTE;
             *tmp_count += BITS_IN_BYTE;
+            *bytes_consumed += 1;
         }
 
         return Ok(());
     }
 }
+
+// both split formulas have to give identical results for every range and probability,
+// otherwise the decoded bits would depend on the selected feature
+#[test]
+fn split_formulas_match() {
+    for range in 128u32..=255 {
+        for probability in 1u32..=255 {
+            let tmp_range = range << BITS_IN_VALUE_MINUS_LAST_BYTE;
+            let split = VPXBoolReader::<&[u8]>::calc_split(tmp_range, probability);
+
+            assert_eq!(
+                split,
+                VPXBoolReader::<&[u8]>::calc_split_classic(tmp_range, probability),
+                "range {0} probability {1}",
+                range,
+                probability
+            );
+            assert!(split > 0 && split < tmp_range);
+        }
+    }
+}
+
+// bypass bits should decode exactly like bits read with a fresh neutral branch each time
+#[test]
+fn bypass_bits_match_neutral_branch() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([7u8; 32]);
+
+    for _ in 0..16 {
+        let mut stream = vec![0u8; 256];
+        rng.fill(&mut stream[..]);
+
+        let mut bypass_reader = VPXBoolReader::new(&stream[..]).unwrap();
+        let mut branch_reader = VPXBoolReader::new(&stream[..]).unwrap();
+
+        for _ in 0..200 {
+            let n = rng.gen_range(0..=12);
+
+            let mut expected = 0;
+            for _ in 0..n {
+                let mut neutral = Branch::new();
+                expected = (expected << 1)
+                    | branch_reader
+                        .get(&mut neutral, ModelComponent::Dummy)
+                        .unwrap() as usize;
+            }
+
+            assert_eq!(
+                bypass_reader
+                    .get_bypass_bits(n, ModelComponent::Dummy)
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+}
+
+// after decoding every bit of a stream, the reader has consumed all of it except for at most
+// the documented tail of padding
+#[test]
+fn bytes_consumed_matches_stream_length() {
+    use super::vpx_bool_writer::VPXBoolWriter;
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([9u8; 32]);
+
+    for num_bits in [0, 1, 7, 100, 1000, 20000, 200000] {
+        let bits: Vec<bool> = (0..num_bits).map(|_| rng.gen_range(0..4) == 0).collect();
+
+        let mut stream = Vec::new();
+        let mut writer = VPXBoolWriter::new(&mut stream).unwrap();
+        let mut branch = Branch::new();
+        for &bit in &bits {
+            writer.put(bit, &mut branch, ModelComponent::Dummy).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = VPXBoolReader::new(&stream[..]).unwrap();
+        let mut branch = Branch::new();
+        for &bit in &bits {
+            assert_eq!(reader.get(&mut branch, ModelComponent::Dummy).unwrap(), bit);
+        }
+
+        let consumed = reader.bytes_consumed();
+        let len = stream.len() as u64;
+        assert!(
+            consumed <= len && consumed + 5 >= len,
+            "consumed {0} of {1} bytes",
+            consumed,
+            len
+        );
+    }
+}
+
+// peeking with any number of branches and then committing one of them decodes exactly the same
+// bits as plain gets with the committed branches
+#[test]
+fn peek_then_commit_matches_get() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([10u8; 32]);
+    let mut stream = vec![0u8; 1024];
+    rng.fill(&mut stream[..]);
+
+    let mut peek_reader = VPXBoolReader::new(&stream[..]).unwrap();
+    let mut get_reader = VPXBoolReader::new(&stream[..]).unwrap();
+
+    let mut peek_branches = [Branch::new(), Branch::new(), Branch::new(), Branch::new()];
+    let mut get_branches = [Branch::new(), Branch::new(), Branch::new(), Branch::new()];
+
+    // read past the end of the stream to cover the refill at the end
+    for _ in 0..20000 {
+        let mut peeked = [false; 4];
+        for (p, b) in peeked.iter_mut().zip(peek_branches.iter()) {
+            *p = peek_reader.peek_bit(b).unwrap();
+        }
+
+        // peeking again gives the same bit
+        let chosen = rng.gen_range(0..4);
+        assert_eq!(
+            peek_reader.peek_bit(&peek_branches[chosen]).unwrap(),
+            peeked[chosen]
+        );
+
+        let committed = peek_reader
+            .commit_bit(&mut peek_branches[chosen], ModelComponent::Dummy)
+            .unwrap();
+        assert_eq!(committed, peeked[chosen]);
+
+        assert_eq!(
+            get_reader
+                .get(&mut get_branches[chosen], ModelComponent::Dummy)
+                .unwrap(),
+            committed
+        );
+        assert_eq!(
+            peek_branches[chosen].get_u64(),
+            get_branches[chosen].get_u64()
+        );
+    }
+
+    assert_eq!(peek_reader.is_past_eof(), get_reader.is_past_eof());
+}
+
+// the range only depends on the probabilities and never on the stream, so adversarial streams
+// with extreme probabilities keep it normalized and non-zero, even past the end of the stream
+#[test]
+fn range_stays_normalized_for_any_stream() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([11u8; 32]);
+    let mut random = vec![0u8; 256];
+    rng.fill(&mut random[..]);
+
+    for stream in [vec![0u8; 256], vec![0xffu8; 256], random] {
+        let mut reader = VPXBoolReader::new(&stream[..]).unwrap();
+
+        for i in 0..10000 {
+            let probability = match i % 3 {
+                0 => 1,
+                1 => 255,
+                _ => rng.gen_range(1..=255),
+            };
+            reader
+                .get_with_probability(probability, ModelComponent::Dummy)
+                .unwrap();
+
+            assert!(reader.range >= 128 << BITS_IN_VALUE_MINUS_LAST_BYTE);
+            assert!(reader.range <= 255 << BITS_IN_VALUE_MINUS_LAST_BYTE);
+            assert_eq!(reader.range & ((1 << BITS_IN_VALUE_MINUS_LAST_BYTE) - 1), 0);
+        }
+
+        assert!(reader.is_past_eof());
+    }
+}
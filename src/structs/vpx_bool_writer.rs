@@ -26,7 +26,7 @@ use std::io::{Result, Write};
 
 use crate::metrics::{Metrics, ModelComponent};
 
-use super::{branch::Branch, simple_hash::SimpleHash};
+use super::{branch::Branch, power_of_two::IsPowerOfTwo, simple_hash::SimpleHash};
 
 pub struct VPXBoolWriter<W> {
     low_value: u32,
@@ -35,6 +35,8 @@ pub struct VPXBoolWriter<W> {
     writer: W,
     buffer: Vec<u8>,
     model_statistics: Metrics,
+    #[cfg(feature = "compression_stats")]
+    stats_color_index: usize,
     pub hash: SimpleHash,
 }
 
@@ -47,6 +49,8 @@ impl<W: Write> VPXBoolWriter<W> {
             buffer: Vec::new(),
             writer: writer,
             model_statistics: Metrics::default(),
+            #[cfg(feature = "compression_stats")]
+            stats_color_index: 0,
             hash: SimpleHash::new(),
         };
 
@@ -60,6 +64,15 @@ impl<W: Write> VPXBoolWriter<W> {
         self.model_statistics.drain()
     }
 
+    /// sets the color plane that the following bits are attributed to in the compression stats
+    #[inline(always)]
+    pub fn set_stats_color_index(&mut self, _color_index: usize) {
+        #[cfg(feature = "compression_stats")]
+        {
+            self.stats_color_index = _color_index;
+        }
+    }
+
     #[inline(never)]
     pub fn put_grid<const A: usize>(
         &mut self,
@@ -67,8 +80,8 @@ impl<W: Write> VPXBoolWriter<W> {
         branches: &mut [Branch; A],
         cmp: ModelComponent,
     ) -> Result<()> {
-        // check if A is a power of 2
-        assert!((A & (A - 1)) == 0);
+        // fails to compile if A is not a power of 2
+        let () = IsPowerOfTwo::<A>::OK;
 
         let mut index = A.ilog2() - 1;
         let mut serialized_so_far = 1;
@@ -98,10 +111,26 @@ impl<W: Write> VPXBoolWriter<W> {
         branches: &mut [Branch; A],
         cmp: ModelComponent,
     ) -> Result<()> {
-        let mut i: i32 = (num_bits - 1) as i32;
-        while i >= 0 {
-            self.put((bits & (1 << i)) != 0, &mut branches[i as usize], cmp)?;
-            i -= 1;
+        assert!(num_bits <= branches.len());
+
+        for i in (0..num_bits).rev() {
+            self.put((bits & (1 << i)) != 0, &mut branches[i], cmp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the lowest `num_bits` bits of `bits` (most significant first) with a fixed
+    /// probability of 128, the counterpart of `VPXBoolReader::get_bypass_bits`.
+    pub fn put_bypass_bits(
+        &mut self,
+        bits: usize,
+        num_bits: usize,
+        cmp: ModelComponent,
+    ) -> Result<()> {
+        for i in (0..num_bits).rev() {
+            // a new branch has the probability of 128 and its update is thrown away
+            self.put((bits & (1 << i)) != 0, &mut Branch::new(), cmp)?;
         }
 
         Ok(())
@@ -172,8 +201,13 @@ impl<W: Write> VPXBoolWriter<W> {
 
         #[cfg(feature = "compression_stats")]
         {
-            self.model_statistics
-                .record_compression_stats(_cmp, 1, i64::from(shift));
+            self.model_statistics.record_compression_stats(
+                _cmp,
+                self.stats_color_index,
+                None,
+                1,
+                i64::from(shift),
+            );
         }
 
         tmp_range <<= shift;
@@ -328,6 +362,42 @@ fn test_roundtrip_vpxboolwriter_unary() {
     }
 }
 
+#[test]
+fn test_roundtrip_vpxboolwriter_bypass_bits() {
+    let mut buffer = Vec::new();
+    let mut writer = VPXBoolWriter::new(&mut buffer).unwrap();
+
+    let mut branch = Branch::default();
+
+    // mixed with adapted branches, so that the bypass bits aren't coded at the start of a range
+    for i in 0..1024 {
+        writer
+            .put(i % 3 == 0, &mut branch, ModelComponent::Dummy)
+            .unwrap();
+        writer
+            .put_bypass_bits(i * 37, i % 13, ModelComponent::Dummy)
+            .unwrap();
+    }
+
+    writer.finish().unwrap();
+
+    let mut branch = Branch::default();
+
+    let mut reader = VPXBoolReader::new(&buffer[..]).unwrap();
+    for i in 0..1024 {
+        assert_eq!(
+            reader.get(&mut branch, ModelComponent::Dummy).unwrap(),
+            i % 3 == 0
+        );
+        assert_eq!(
+            reader
+                .get_bypass_bits(i % 13, ModelComponent::Dummy)
+                .unwrap(),
+            (i * 37) & ((1 << (i % 13)) - 1)
+        );
+    }
+}
+
 #[test]
 fn test_roundtrip_vpxboolwriter_grid() {
     #[derive(Default)]
@@ -382,3 +452,100 @@ fn test_roundtrip_vpxboolwriter_single_bit() {
         assert_eq!(read_value, i % 10 == 0);
     }
 }
+
+// every value in range has to round trip through the grid, unary and n bits coding starting
+// from random branch states, and the reader has to leave the branches in the same state as
+// the writer
+#[test]
+fn test_roundtrip_vpxboolwriter_random_branch_states() {
+    use rand::Rng;
+
+    let mut rng = crate::helpers::get_rand_from_seed([15u8; 32]);
+
+    // both counts of a branch are at least 1
+    let mut random_counts = |n: usize| -> Vec<u16> {
+        (0..n)
+            .map(|_| (rng.gen_range(1..=255u16) << 8) | rng.gen_range(1..=255u16))
+            .collect()
+    };
+    let grid_counts = random_counts(16);
+    let unary_counts = random_counts(14);
+    let n_bits_counts = random_counts(10);
+
+    fn branches<const A: usize>(counts: &[u16]) -> [Branch; A] {
+        core::array::from_fn(|i| {
+            let mut b = Branch::new();
+            b.set_count(counts[i]);
+            b
+        })
+    }
+
+    let mut buffer = Vec::new();
+    let mut writer = VPXBoolWriter::new(&mut buffer).unwrap();
+
+    let mut grid: [Branch; 16] = branches(&grid_counts);
+    let mut unary: [Branch; 14] = branches(&unary_counts);
+    let mut n_bits: [Branch; 10] = branches(&n_bits_counts);
+
+    for _ in 0..4 {
+        for v in 0..16 {
+            writer
+                .put_grid(v, &mut grid, ModelComponent::Dummy)
+                .unwrap();
+        }
+        for v in 0..=14 {
+            writer
+                .put_unary_encoded(v, &mut unary, ModelComponent::Dummy)
+                .unwrap();
+        }
+        for n in 0..=10 {
+            for v in 0..1 << n {
+                writer
+                    .put_n_bits(v, n, &mut n_bits, ModelComponent::Dummy)
+                    .unwrap();
+            }
+        }
+    }
+
+    writer.finish().unwrap();
+
+    let mut reader = VPXBoolReader::new(&buffer[..]).unwrap();
+
+    let mut read_grid: [Branch; 16] = branches(&grid_counts);
+    let mut read_unary: [Branch; 14] = branches(&unary_counts);
+    let mut read_n_bits: [Branch; 10] = branches(&n_bits_counts);
+
+    for _ in 0..4 {
+        for v in 0..16 {
+            assert_eq!(
+                reader
+                    .get_grid(&mut read_grid, ModelComponent::Dummy)
+                    .unwrap(),
+                v
+            );
+        }
+        for v in 0..=14 {
+            assert_eq!(
+                reader
+                    .get_unary_encoded(&mut read_unary, ModelComponent::Dummy)
+                    .unwrap(),
+                v
+            );
+        }
+        for n in 0..=10 {
+            for v in 0..1 << n {
+                assert_eq!(
+                    reader
+                        .get_n_bits(n, &mut read_n_bits, ModelComponent::Dummy)
+                        .unwrap(),
+                    v
+                );
+            }
+        }
+    }
+
+    let counts = |b: &[Branch]| b.iter().map(|x| x.get_count()).collect::<Vec<_>>();
+    assert_eq!(counts(&read_grid), counts(&grid));
+    assert_eq!(counts(&read_unary), counts(&unary));
+    assert_eq!(counts(&read_n_bits), counts(&n_bits));
+}
@@ -8,13 +8,16 @@ use core::result::Result;
 use std::{io::Cursor, path::Path};
 
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 
+use lepton_jpeg::enabled_features::ModelVersion;
 use lepton_jpeg::metrics::Metrics;
 use lepton_jpeg::{
-    decode_lepton, encode_lepton, encode_lepton_verify,
-    lepton_error::{ExitCode, LeptonError},
-    EnabledFeatures,
+    decode_lepton, decode_lepton_classified, decode_lepton_dropbox, encode_lepton,
+    encode_lepton_size, encode_lepton_verify, encode_lepton_with_tap, is_legacy_dropbox_lepton,
+    is_likely_jpeg, is_likely_lepton,
+    lepton_error::{DecodeError, ExitCode, LeptonError},
+    read_lepton_file_info, verify_lepton, BatchDecoder, EnabledFeatures, LeptonJpegType,
 };
 use lepton_jpeg::{WrapperCompressImage, WrapperDecompressImage, WrapperDecompressImageEx};
 
@@ -47,6 +50,7 @@ fn verify_decode(
         "colorswap",
         "gray2sf",
         "grayscale",
+        "grayscale12bitwide", // 12 bit image with coefficients of more than 11 bits
         "hq",
         "iphone",
         "iphonecity",
@@ -110,6 +114,62 @@ fn verify_decode_scalar_overflow() {
     assert!(output[..] == expected[..]);
 }
 
+/// files written by the Dropbox c++ lepton don't say which math they were encoded with, so
+/// the SIMD (16 bit) and the scalar files both have to be detected and decoded without
+/// passing any features, while files written by this library are decoded as usual
+#[rstest]
+#[case("mathoverflow_16", "mathoverflow", true)]
+#[case("mathoverflow_scalar", "mathoverflow_scalar", true)]
+#[case("android", "android", true)]
+#[case("iphoneprogressive", "iphoneprogressive", true)]
+#[case("eof_and_trailingrst", "eof_and_trailingrst", false)]
+#[case("zeros_in_dqt_tables", "zeros_in_dqt_tables", false)]
+fn verify_decode_dropbox(#[case] file: &str, #[case] jpg_file: &str, #[case] legacy: bool) {
+    let input = read_file(file, ".lep");
+    let expected = read_file(jpg_file, ".jpg");
+
+    let mut reader = Cursor::new(&input);
+    assert_eq!(is_legacy_dropbox_lepton(&mut reader).unwrap(), legacy);
+    assert_eq!(reader.position(), 0);
+
+    let mut output = Vec::new();
+    decode_lepton_dropbox(&mut reader, &mut output, 8).unwrap();
+
+    assert!(output[..] == expected[..]);
+}
+
+/// the format sniffing only looks at the first bytes and tells JPEG and Lepton files apart
+/// from each other and from random data
+#[rstest]
+fn verify_is_likely_format(
+    #[values("android", "iphoneprogressive", "grayscale", "slrcity")] file: &str,
+) {
+    use rand::{Rng, SeedableRng};
+
+    let jpeg = read_file(file, ".jpg");
+    assert!(is_likely_jpeg(&jpeg));
+    assert!(!is_likely_lepton(&jpeg));
+    assert!(is_likely_jpeg(&jpeg[..4]));
+    assert!(!is_likely_jpeg(&jpeg[..3]));
+
+    let lepton = read_file(file, ".lep");
+    assert!(is_likely_lepton(&lepton));
+    assert!(!is_likely_jpeg(&lepton));
+    assert!(is_likely_lepton(&lepton[..4]));
+    assert!(!is_likely_lepton(&lepton[..3]));
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1366);
+    for _ in 0..1000 {
+        let mut random = [0u8; 16];
+        rng.fill(&mut random[..]);
+        assert!(!is_likely_jpeg(&random) || random[..2] == [0xFF, 0xD8]);
+        assert!(!is_likely_lepton(&random) || random[..2] == [0xCF, 0x84]);
+    }
+
+    assert!(!is_likely_jpeg(&[]));
+    assert!(!is_likely_lepton(&[]));
+}
+
 /// Verifies that the decode will accept existing Lepton files and generate
 /// exactly the same jpeg from them when called by an external interface
 /// with use_16bit_dc_estimate=true for C++ backward compatibility.
@@ -193,9 +253,12 @@ fn verify_encode(
             "androidprogressive",
             "androidprogressive_garbage",
             "androidtrail",
+            "arithmetic",
             "colorswap",
             "gray2sf",
             "grayscale",
+            "grayscale12bit",
+            "grayscale12bitwide",
             "hq",
             "iphone",
             "iphonecity",
@@ -205,6 +268,7 @@ fn verify_encode(
             "iphonecrop2",
             "iphoneprogressive",
             "iphoneprogressive2",
+            "noninterleaved",
             "progressive_late_dht", // image has huffman tables that come very late which caused a verification failure 
             "out_of_order_dqt",
             //"narrowrst",
@@ -232,47 +296,657 @@ fn verify_encode(
     )
     .unwrap();
 
-    decode_lepton(
-        &mut Cursor::new(lepton),
+    decode_lepton(
+        &mut Cursor::new(lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    assert!(input[..] == output[..]);
+}
+
+/// a file written with the Dropbox compatible preset must decode with the same preset,
+/// and with the conservative preset which uses the same math
+#[rstest]
+fn verify_encode_compat_lepton_dropbox(#[values("android", "iphone", "slrcity")] file: &str) {
+    let input = read_file(file, ".jpg");
+
+    let mut lepton = Vec::new();
+
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &EnabledFeatures::compat_lepton_dropbox(),
+    )
+    .unwrap();
+
+    for features in [
+        EnabledFeatures::compat_lepton_dropbox(),
+        EnabledFeatures::conservative(),
+    ] {
+        let mut output = Vec::new();
+
+        decode_lepton(&mut Cursor::new(&lepton), &mut output, 8, &features).unwrap();
+
+        assert!(input[..] == output[..]);
+    }
+}
+
+/// files written by the Dropbox c++ lepton decode with the Dropbox preset
+#[rstest]
+#[case("mathoverflow_16", "mathoverflow")]
+#[case("android", "android")]
+#[case("iphoneprogressive", "iphoneprogressive")]
+fn verify_decode_compat_lepton_dropbox(#[case] file: &str, #[case] jpg_file: &str) {
+    let input = read_file(file, ".lep");
+    let expected = read_file(jpg_file, ".jpg");
+
+    let mut output = Vec::new();
+
+    decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_dropbox(),
+    )
+    .unwrap();
+
+    assert!(output[..] == expected[..]);
+}
+
+/// the feature flags stored in the header are used for decoding, and conflicting
+/// flags are rejected if the caller insists on them
+#[test]
+fn verify_stream_feature_flags() {
+    let input = read_file("iphone", ".jpg");
+
+    let mut lepton = Vec::new();
+
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &EnabledFeatures::modern_best(),
+    )
+    .unwrap();
+
+    // by default the flags from the file win
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert!(input[..] == output[..]);
+
+    // insisting on the 16 bit math fails cleanly
+    assert_exception(
+        ExitCode::VersionUnsupported,
+        decode_lepton(
+            &mut Cursor::new(&lepton),
+            &mut Vec::new(),
+            8,
+            &EnabledFeatures {
+                require_matching_stream_flags: true,
+                ..EnabledFeatures::compat_lepton_vector_read()
+            },
+        ),
+    );
+
+    // files without the flags (such as c++ lepton files) use the flags of the caller
+    lepton[13] = 0;
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures {
+            require_matching_stream_flags: true,
+            ..EnabledFeatures::modern_best()
+        },
+    )
+    .unwrap();
+    assert!(input[..] == output[..]);
+}
+
+/// a file records the version of the model it was encoded with and decodes with the logic of
+/// that version, while unknown versions fail instead of decoding to garbage
+#[test]
+fn verify_model_version() {
+    let lepton = read_file("androidcrop_modelv1", ".lep");
+    let expected = read_file("androidcrop", ".jpg");
+
+    // the model version is stored after the feature flags in the header
+    assert_eq!(lepton[15], 1);
+
+    let v1_features = EnabledFeatures {
+        model_version: ModelVersion::V1,
+        require_matching_stream_flags: true,
+        ..EnabledFeatures::compat_lepton_vector_read()
+    };
+
+    let mut output = Vec::new();
+    decode_lepton(&mut Cursor::new(&lepton), &mut output, 8, &v1_features).unwrap();
+    assert!(output[..] == expected[..]);
+
+    // files from before the version was stored are decoded with the version of the caller
+    let mut unversioned = lepton.clone();
+    unversioned[15] = 0;
+
+    let mut output = Vec::new();
+    decode_lepton(&mut Cursor::new(&unversioned), &mut output, 8, &v1_features).unwrap();
+    assert!(output[..] == expected[..]);
+
+    let mut unknown = lepton.clone();
+    unknown[15] = u8::MAX;
+
+    assert_exception(
+        ExitCode::VersionUnsupported,
+        decode_lepton(
+            &mut Cursor::new(&unknown),
+            &mut Vec::new(),
+            8,
+            &EnabledFeatures::compat_lepton_vector_read(),
+        ),
+    );
+}
+
+/// 12 bit images are written with the wide version of the model, which codes coefficients of
+/// more than 11 bits and is rejected by decoders that only know V1
+#[test]
+fn verify_12bit_wide_coefficients() {
+    let lepton = read_file("grayscale12bitwide", ".lep");
+    let expected = read_file("grayscale12bitwide", ".jpg");
+
+    assert_eq!(lepton[15], 2);
+
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(info.precision, 12);
+    assert_eq!(info.model_version, Some(ModelVersion::V1WideCoefficients));
+
+    // asking for V1 also asks for its wide version for 12 bit images
+    let v1_features = EnabledFeatures {
+        model_version: ModelVersion::V1,
+        require_matching_stream_flags: true,
+        ..EnabledFeatures::compat_lepton_vector_read()
+    };
+
+    let mut output = Vec::new();
+    decode_lepton(&mut Cursor::new(&lepton), &mut output, 8, &v1_features).unwrap();
+    assert!(output[..] == expected[..]);
+
+    // the coefficients that don't fit V1 make the stream unreadable without the escapes
+    let mut v1 = lepton.clone();
+    v1[15] = 1;
+
+    assert!(decode_lepton(
+        &mut Cursor::new(&v1),
+        &mut Vec::new(),
+        8,
+        &EnabledFeatures::compat_lepton_vector_read()
+    )
+    .is_err());
+
+    // an encoder asked for V1 writes 12 bit images with the wide version, which the strict
+    // verification accepts
+    let (encoded, _metrics) = encode_lepton_verify(
+        &expected,
+        8,
+        &EnabledFeatures {
+            model_version: ModelVersion::V1,
+            require_matching_stream_flags: true,
+            ..EnabledFeatures::compat_lepton_vector_write()
+        },
+    )
+    .unwrap();
+    assert_eq!(encoded[15], 2);
+}
+
+/// the optional CRC32 trailer round trips and catches corruption that would otherwise
+/// decode to a different JPEG without an error
+#[test]
+fn verify_crc32_trailer() {
+    let input = read_file("iphone", ".jpg");
+
+    let mut lepton = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &EnabledFeatures {
+            write_crc32_trailer: true,
+            ..EnabledFeatures::compat_lepton_vector_write()
+        },
+    )
+    .unwrap();
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert!(input[..] == output[..]);
+
+    // flip a bit near the end of the coefficient stream, in front of the CRC32 and
+    // the file size. This still decodes, but to a different JPEG.
+    let mut corrupt = lepton.clone();
+    let offset = corrupt.len() - 8 - 10;
+    corrupt[offset] ^= 0x01;
+
+    assert_exception(
+        ExitCode::ChecksumMismatch,
+        decode_lepton(
+            &mut Cursor::new(&corrupt),
+            &mut Vec::new(),
+            8,
+            &EnabledFeatures::compat_lepton_vector_read(),
+        ),
+    );
+
+    // without the trailer the same corruption goes unnoticed
+    let mut lepton = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &EnabledFeatures::compat_lepton_vector_write(),
+    )
+    .unwrap();
+
+    let offset = lepton.len() - 4 - 10;
+    lepton[offset] ^= 0x01;
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert!(input[..] != output[..]);
+}
+
+/// the size reported without writing the output has to match the length of the actual
+/// Lepton file, including the header and the optional trailer and row index
+#[rstest]
+fn verify_encode_size(
+    #[values("android", "androidprogressive", "grayscale", "trailingrst")] file: &str,
+    #[values(false, true)] write_crc32_trailer: bool,
+    #[values(false, true)] row_seekable: bool,
+) {
+    let input = read_file(file, ".jpg");
+
+    let features = EnabledFeatures {
+        write_crc32_trailer,
+        row_seekable,
+        ..EnabledFeatures::compat_lepton_vector_write()
+    };
+
+    let mut lepton = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &features,
+    )
+    .unwrap();
+
+    let (size, _metrics) = encode_lepton_size(&mut Cursor::new(&input), 8, &features).unwrap();
+    assert_eq!(size, lepton.len() as u64);
+}
+
+/// the tap sees every byte of the JPEG exactly once and in order, even for files where the
+/// parser seeks backwards, and doesn't change the Lepton output
+#[rstest]
+fn verify_encode_with_tap(
+    #[values(
+        "android",
+        "androidprogressive_garbage",
+        "eof_and_trailinghdrdata",
+        "iphonecity_with_16KGarbage"
+    )]
+    file: &str,
+) {
+    use siphasher::sip::SipHasher13;
+    use std::hash::Hasher;
+
+    let input = read_file(file, ".jpg");
+
+    // the CRC32 trailer rereads the whole file after parsing it
+    let features = EnabledFeatures {
+        write_crc32_trailer: true,
+        ..EnabledFeatures::compat_lepton_vector_write()
+    };
+
+    let mut tapped_hash = SipHasher13::new();
+    let mut lepton = Vec::new();
+    encode_lepton_with_tap(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &features,
+        &mut |data| tapped_hash.write(data),
+    )
+    .unwrap();
+
+    let mut file_hash = SipHasher13::new();
+    file_hash.write(&input);
+    assert_eq!(tapped_hash.finish(), file_hash.finish());
+
+    let mut untapped = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut untapped),
+        8,
+        &features,
+    )
+    .unwrap();
+    assert!(lepton[..] == untapped[..]);
+}
+
+/// a custom mapping of the DC uncertainties to the model bins has to round trip when the
+/// decoder uses the same mapping, and changes the encoded stream compared to the default
+#[test]
+fn verify_custom_dc_uncertainty_bins() {
+    use lepton_jpeg::enabled_features::DcUncertaintyBins;
+
+    #[derive(Debug)]
+    struct HalvedBins;
+
+    impl DcUncertaintyBins for HalvedBins {
+        fn get_bins(&self, uncertainty: i16, uncertainty2: i16) -> (usize, usize) {
+            (
+                (16 - uncertainty.unsigned_abs().leading_zeros() as usize) / 2,
+                (16 - uncertainty2.unsigned_abs().leading_zeros() as usize) / 2,
+            )
+        }
+    }
+
+    let features = EnabledFeatures {
+        dc_uncertainty_bins: &HalvedBins,
+        ..EnabledFeatures::compat_lepton_vector_write()
+    };
+
+    let input = read_file("android", ".jpg");
+
+    let mut lepton = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &features,
+    )
+    .unwrap();
+
+    let mut default_lepton = Vec::new();
+    encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut default_lepton),
+        8,
+        &EnabledFeatures::compat_lepton_vector_write(),
+    )
+    .unwrap();
+    assert!(lepton != default_lepton);
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures {
+            dc_uncertainty_bins: &HalvedBins,
+            ..EnabledFeatures::compat_lepton_vector_read()
+        },
+    )
+    .unwrap();
+    assert!(input[..] == output[..]);
+}
+
+#[test]
+fn verify_16bitmath() {
+    // verifies that we can decode 16 bit encoded images from the C++ version
+    {
+        let input = read_file("mathoverflow_16", ".lep");
+        let expected = read_file("mathoverflow", ".jpg");
+
+        let mut output = Vec::new();
+
+        let features = EnabledFeatures::compat_lepton_vector_read();
+
+        decode_lepton(&mut Cursor::new(input), &mut output, 8, &features).unwrap();
+
+        assert!(output[..] == expected[..]);
+    }
+
+    // verify that we can decode the one generated by the Rust version
+    {
+        let input = read_file("mathoverflow_32", ".lep");
+        let expected = read_file("mathoverflow", ".jpg");
+
+        let mut output = Vec::new();
+
+        let mut features = EnabledFeatures::compat_lepton_vector_read();
+        features.use_16bit_dc_estimate = false;
+
+        decode_lepton(&mut Cursor::new(input), &mut output, 8, &features).unwrap();
+
+        assert!(output[..] == expected[..]);
+    }
+}
+
+/// verifies that the compressed bits attributed to each color plane add up to the total
+#[cfg(feature = "compression_stats")]
+#[test]
+fn verify_per_color_compression_stats() {
+    let input = read_file("iphone", ".lep");
+
+    let mut output = Vec::new();
+
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let per_color = metrics.per_color_bits();
+    for bits in per_color {
+        assert!(bits > 0);
+    }
+
+    assert_eq!(
+        per_color.iter().sum::<i64>(),
+        metrics.get_total_compressed_bits()
+    );
+}
+
+/// verifies that the branch visits are collected over all threads. The number of non-zero
+/// coefficients is coded as a binary tree over its branches starting at index 1, so the first
+/// branch of the model is never used while the second one is the root of the tree for blocks
+/// without non-zero neighbors.
+#[cfg(feature = "branch_visits")]
+#[test]
+fn verify_branch_visits() {
+    let input = read_file("iphone", ".lep");
+
+    let mut output = Vec::new();
+
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let visits = metrics.branch_visits();
+    assert_eq!(visits[0], 0);
+    assert!(visits[1] > 0);
+
+    let histogram = metrics.branch_visit_histogram();
+    assert_eq!(histogram.iter().sum::<u64>(), visits.len() as u64);
+    assert!(histogram[0] > 0 && histogram[1..].iter().any(|&x| x > 0));
+
+    // a branch can only overflow when it is visited
+    let renormalizations = metrics.branch_renormalizations();
+    assert_eq!(renormalizations.len(), visits.len());
+    assert!(renormalizations.iter().zip(visits).all(|(r, v)| r <= v));
+    assert!(renormalizations.iter().any(|&r| r > 0));
+}
+
+/// with the `phase_timing` feature the time of each decoding phase is collected, and the time
+/// of reading the whole block includes the edges and the DC prediction
+#[cfg(feature = "phase_timing")]
+#[test]
+fn verify_phase_timing() {
+    use lepton_jpeg::metrics::DecodePhase;
+
+    let input = read_file("iphone", ".lep");
+
+    let mut output = Vec::new();
+
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let block = metrics.phase_time(DecodePhase::ReadCoefficientBlock);
+    let edge = metrics.phase_time(DecodePhase::DecodeEdge);
+    let dc = metrics.phase_time(DecodePhase::PredictDC);
+
+    assert!(!edge.is_zero());
+    assert!(!dc.is_zero());
+    assert!(block > edge + dc);
+}
+
+/// without the `phase_timing` feature nothing is timed
+#[cfg(not(feature = "phase_timing"))]
+#[test]
+fn verify_phase_timing_disabled() {
+    use lepton_jpeg::metrics::DecodePhase;
+
+    let input = read_file("iphone", ".lep");
+
+    let mut output = Vec::new();
+
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    for phase in [
+        DecodePhase::ReadCoefficientBlock,
+        DecodePhase::DecodeEdge,
+        DecodePhase::PredictDC,
+    ] {
+        assert!(metrics.phase_time(phase).is_zero());
+    }
+}
+
+/// verifies that the compressed bits attributed to each zigzag index add up to the total, apart
+/// from the counts of non-zero coefficients which don't belong to a single coefficient
+#[cfg(feature = "compression_stats")]
+#[test]
+fn verify_per_zigzag_compression_stats() {
+    use lepton_jpeg::metrics::ModelComponent;
+
+    let input = read_file("iphone", ".lep");
+
+    let mut output = Vec::new();
+
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
         &mut output,
         8,
         &EnabledFeatures::compat_lepton_vector_read(),
     )
     .unwrap();
 
-    assert!(input[..] == output[..]);
-}
-
-#[test]
-fn verify_16bitmath() {
-    // verifies that we can decode 16 bit encoded images from the C++ version
-    {
-        let input = read_file("mathoverflow_16", ".lep");
-        let expected = read_file("mathoverflow", ".jpg");
+    let per_zigzag = metrics.bits_per_zigzag();
 
-        let mut output = Vec::new();
+    // DC and the lowest frequencies always take up space
+    assert!(per_zigzag[0] > 0 && per_zigzag[1] > 0 && per_zigzag[2] > 0);
 
-        let features = EnabledFeatures::compat_lepton_vector_read();
+    let unattributed = metrics.get_compressed_bits(ModelComponent::Dummy)
+        + metrics.get_compressed_bits(ModelComponent::NonZero7x7Count)
+        + metrics.get_compressed_bits(ModelComponent::NonZeroEdgeCount);
 
-        decode_lepton(&mut Cursor::new(input), &mut output, 8, &features).unwrap();
+    assert_eq!(
+        per_zigzag.iter().sum::<i64>() + unattributed,
+        metrics.get_total_compressed_bits()
+    );
+}
 
-        assert!(output[..] == expected[..]);
-    }
+/// verifies that the compressed bits of each model are the sum of its sub components, and that
+/// the models add up to the total
+#[cfg(feature = "compression_stats")]
+#[test]
+fn verify_per_model_compression_stats() {
+    use lepton_jpeg::metrics::{ModelComponent, ModelSubComponent};
 
-    // verify that we can decode the one generated by the Rust version
-    {
-        let input = read_file("mathoverflow_32", ".lep");
-        let expected = read_file("mathoverflow", ".jpg");
+    let input = read_file("iphone", ".lep");
 
-        let mut output = Vec::new();
+    let mut output = Vec::new();
 
-        let mut features = EnabledFeatures::compat_lepton_vector_read();
-        features.use_16bit_dc_estimate = false;
+    let metrics = decode_lepton(
+        &mut Cursor::new(input),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
 
-        decode_lepton(&mut Cursor::new(input), &mut output, 8, &features).unwrap();
+    let subs = [
+        ModelSubComponent::Exp,
+        ModelSubComponent::Sign,
+        ModelSubComponent::Residual,
+        ModelSubComponent::Noise,
+    ];
+
+    let mut total = metrics.bits_for(ModelComponent::Dummy);
+    for model in [
+        ModelComponent::Coef,
+        ModelComponent::DC,
+        ModelComponent::Edge,
+    ] {
+        let bits = metrics.bits_for(model(ModelSubComponent::Exp));
+        assert!(bits > 0);
+        assert_eq!(
+            bits,
+            subs.iter()
+                .map(|&sub| metrics.get_compressed_bits(model(sub)))
+                .sum::<i64>()
+        );
+        total += bits;
+    }
 
-        assert!(output[..] == expected[..]);
+    for count in [
+        ModelComponent::NonZero7x7Count,
+        ModelComponent::NonZeroEdgeCount,
+    ] {
+        assert!(metrics.bits_for(count) > 0);
+        assert_eq!(metrics.bits_for(count), metrics.get_compressed_bits(count));
+        total += metrics.bits_for(count);
     }
+
+    assert_eq!(total, metrics.get_total_compressed_bits());
 }
 
 #[test]
@@ -317,6 +991,284 @@ fn verify_encode_verify(#[values("slrcity")] file: &str) {
     .unwrap();
 }
 
+/// a large image is split into several row bands that are encoded in parallel and decoded back
+/// to the identical JPEG, and the throughput metrics count every block exactly once on both sides
+#[test]
+fn verify_encode_multiple_threads() {
+    let input = read_file("iphone", ".jpg");
+
+    let mut lepton = Vec::new();
+    let encode_metrics = encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        4,
+        &EnabledFeatures::compat_lepton_vector_write(),
+    )
+    .unwrap();
+
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(info.thread_segments.len(), 4);
+
+    let blocks: u64 = info
+        .components
+        .iter()
+        .map(|c| u64::from(c.block_width * c.block_height))
+        .sum();
+    assert_eq!(encode_metrics.blocks_processed(), blocks);
+
+    let mut output = Vec::new();
+    let decode_metrics = decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        4,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert_eq!(decode_metrics.blocks_processed(), blocks);
+    assert!(decode_metrics.throughput().unwrap() > 0.0);
+
+    assert!(output[..] == input[..]);
+}
+
+/// converting to coefficients and back gives the identical file for every kind of image the
+/// reader supports: baseline, progressive, arithmetic, non-interleaved, restart markers,
+/// truncated files and data after the end of the image
+#[cfg(feature = "image-interop")]
+#[rstest]
+fn verify_jpeg_bytes_roundtrip(
+    #[values(
+        "android",
+        "androidcropoptions",
+        "arithmetic",
+        "eof_and_trailingrst",
+        "gray2sf",
+        "iphonecity_with_16KGarbage",
+        "iphoneprogressive",
+        "noninterleaved",
+        "progressive_late_dht",
+        "trailingrst",
+        "trailingrst2",
+        "trunc"
+    )]
+    file: &str,
+) {
+    use lepton_jpeg::image_interop::{from_jpeg_bytes, to_jpeg_bytes};
+
+    let input = read_file(file, ".jpg");
+
+    let (header, image_data) = from_jpeg_bytes(&input).unwrap();
+    assert!(!image_data.is_empty());
+
+    let jpeg = to_jpeg_bytes(&header, &image_data).unwrap();
+    assert!(jpeg[..] == input[..]);
+}
+
+/// reader that fails with a connection reset once it gets to `fail_at`
+struct FailingReader<'a> {
+    inner: Cursor<&'a [u8]>,
+    fail_at: u64,
+}
+
+impl Read for FailingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.inner.position() >= self.fail_at {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset",
+            ));
+        }
+
+        let len = buf
+            .len()
+            .min((self.fail_at - self.inner.position()) as usize);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Seek for FailingReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// a failing reader is reported as an I/O error with the original error, while a complete
+/// but corrupt file is reported as a stream error, even though the zlib decoder of the
+/// header returns its own I/O error for it
+#[test]
+fn verify_decode_classified_errors() {
+    let lepton = read_file("iphone", ".lep");
+
+    let mut reader = FailingReader {
+        inner: Cursor::new(&lepton[..]),
+        fail_at: lepton.len() as u64 / 2,
+    };
+
+    match decode_lepton_classified(
+        &mut reader,
+        &mut Vec::new(),
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    ) {
+        Err(DecodeError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        r => panic!("expected an I/O error, got {0:?}", r),
+    }
+
+    // corrupt the zlib compressed part of the header
+    let mut corrupt = lepton.clone();
+    corrupt[30] ^= 0xff;
+
+    match decode_lepton_classified(
+        &mut Cursor::new(&corrupt),
+        &mut Vec::new(),
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    ) {
+        Err(DecodeError::Stream(_)) => {}
+        r => panic!("expected a stream error, got {0:?}", r),
+    }
+}
+
+/// the batch decoder reuses the models of its threads from one file to the next, so mixing
+/// color and grayscale files and decoding each of them more than once must still give exactly
+/// the same output as decoding every file on its own
+#[test]
+fn verify_batch_decode() {
+    let features = EnabledFeatures::compat_lepton_vector_read();
+
+    let mut files = Vec::new();
+    for _ in 0..2 {
+        for name in ["android", "grayscale", "tiny", "iphone", "gray2sf"] {
+            files.push(read_file(name, ".lep"));
+        }
+    }
+
+    // a broken file in the middle of the batch must not affect the others
+    let mut corrupt = files[0].clone();
+    corrupt[30] ^= 0xff;
+    files.insert(3, corrupt);
+
+    let decoder = BatchDecoder::new(3, &features).unwrap();
+    let inputs: Vec<&[u8]> = files.iter().map(|f| &f[..]).collect();
+    let results = decoder.decode_batch(&inputs);
+
+    assert_eq!(results.len(), files.len());
+    for (input, result) in files.iter().zip(results) {
+        let mut expected = Vec::new();
+        match decode_lepton(&mut Cursor::new(input), &mut expected, 8, &features) {
+            Ok(_) => assert!(result.unwrap() == expected),
+            Err(e) => assert_eq!(result.unwrap_err().exit_code, e.exit_code),
+        }
+    }
+
+    assert!(decoder.decode(&files[1]).unwrap() == read_file("grayscale", ".jpg"));
+}
+
+/// Builds a baseline JPEG with the given sampling factors (high nibble horizontal, low nibble
+/// vertical) per component. The Huffman tables only have DC differences and AC values of -1,
+/// 0 and 1, which is enough to fill the blocks with random coefficients.
+fn synthetic_jpeg(sampling: &[u8], width: u16, height: u16) -> Vec<u8> {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1234);
+    let ncomp = sampling.len();
+
+    let mut jpeg = vec![0xFF, 0xD8];
+
+    // quantization table 0, all ones
+    jpeg.extend_from_slice(&[0xFF, 0xDB, 0, 67, 0]);
+    jpeg.extend_from_slice(&[1; 64]);
+
+    jpeg.extend_from_slice(&[0xFF, 0xC0, 0, 8 + 3 * ncomp as u8, 8]);
+    jpeg.extend_from_slice(&height.to_be_bytes());
+    jpeg.extend_from_slice(&width.to_be_bytes());
+    jpeg.push(ncomp as u8);
+    for (i, &s) in sampling.iter().enumerate() {
+        jpeg.extend_from_slice(&[i as u8 + 1, s, 0]);
+    }
+
+    // DC table 0: category 0 is '0', category 1 is '10'
+    // AC table 0: EOB is '0', run 0 size 1 is '10'
+    jpeg.extend_from_slice(&[0xFF, 0xC4, 0, 40]);
+    for (class, values) in [(0x00, [0, 1]), (0x10, [0x00, 0x01])] {
+        jpeg.push(class);
+        jpeg.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        jpeg.extend_from_slice(&values);
+    }
+
+    jpeg.extend_from_slice(&[0xFF, 0xDA, 0, 6 + 2 * ncomp as u8, ncomp as u8]);
+    for i in 0..ncomp {
+        jpeg.extend_from_slice(&[i as u8 + 1, 0]);
+    }
+    jpeg.extend_from_slice(&[0, 63, 0]);
+
+    let mut bits = Vec::new();
+    let put_value = |bits: &mut Vec<bool>, rng: &mut rand::rngs::StdRng| {
+        // '10' followed by 1 for +1 or 0 for -1
+        bits.extend_from_slice(&[true, false, rng.gen()]);
+    };
+
+    let hmax = sampling.iter().map(|s| u16::from(s >> 4)).max().unwrap();
+    let vmax = sampling.iter().map(|s| u16::from(s & 15)).max().unwrap();
+    let mcus = ((width + 8 * hmax - 1) / (8 * hmax)) * ((height + 8 * vmax - 1) / (8 * vmax));
+
+    for _ in 0..mcus {
+        for &s in sampling {
+            for _ in 0..(s >> 4) * (s & 15) {
+                if rng.gen_range(0..3) == 0 {
+                    bits.push(false);
+                } else {
+                    put_value(&mut bits, &mut rng);
+                }
+
+                for _ in 0..rng.gen_range(0..10) {
+                    put_value(&mut bits, &mut rng);
+                }
+                bits.push(false);
+            }
+        }
+    }
+
+    // pad with ones and stuff the 0xFF bytes
+    while bits.len() % 8 != 0 {
+        bits.push(true);
+    }
+    for byte in bits.chunks(8) {
+        let b = byte
+            .iter()
+            .fold(0u8, |acc, &bit| (acc << 1) | u8::from(bit));
+        jpeg.push(b);
+        if b == 0xFF {
+            jpeg.push(0);
+        }
+    }
+
+    jpeg.extend_from_slice(&[0xFF, 0xD9]);
+    jpeg
+}
+
+/// chroma can be sampled at a higher rate than luma in one direction, which is legal but
+/// unusual. These images have to round trip like any other.
+#[rstest]
+fn verify_chroma_sampled_above_luma(
+    #[values([0x11, 0x21, 0x11], [0x11, 0x12, 0x11], [0x12, 0x21, 0x21])] sampling: [u8; 3],
+) {
+    let input = synthetic_jpeg(&sampling, 80, 56);
+
+    encode_lepton_verify(&input, 8, &EnabledFeatures::compat_lepton_vector_write()).unwrap();
+
+    // row seekable files split the image into a range per MCU row, which exercises the
+    // row ranges of the components that have more block rows than luma
+    encode_lepton_verify(
+        &input,
+        8,
+        &EnabledFeatures {
+            row_seekable: true,
+            ..EnabledFeatures::compat_lepton_vector_write()
+        },
+    )
+    .unwrap();
+}
+
 fn assert_exception(expected_error: ExitCode, result: Result<Metrics, LeptonError>) {
     match result {
         Ok(_) => panic!("failure was expected"),
@@ -381,6 +1333,27 @@ fn verify_encode_image_with_zeros_in_dqt_tables() {
     );
 }
 
+/// lossless and differential JPEGs cannot be recompressed, so they are rejected up front with an
+/// error that names the SOF type
+#[rstest]
+#[case("lossless", "SOF3")]
+#[case("differential", "SOF5")]
+fn verify_encode_unsupported_sof(#[case] file: &str, #[case] sof_type: &str) {
+    let input = read_file(file, ".jpg");
+    let mut lepton = Vec::new();
+
+    let e = encode_lepton(
+        &mut Cursor::new(&input),
+        &mut Cursor::new(&mut lepton),
+        8,
+        &EnabledFeatures::compat_lepton_vector_write(),
+    )
+    .unwrap_err();
+
+    assert_eq!(e.exit_code, ExitCode::UnsupportedCompression, "{0:?}", e);
+    assert!(e.message.contains(sof_type), "{0}", e.message);
+}
+
 #[test]
 fn extern_interface() {
     let input = read_file("slrcity", ".jpg");
@@ -477,3 +1450,339 @@ fn verify_extern_interface_supports_decompression_with_zeros_in_dqt_tables(
     assert_eq!(original.len() as u64, decompressed_size);
     assert_eq!(original[..], decompressed[..(decompressed_size as usize)]);
 }
+
+/// verifies the description of a known file that is read from its header alone
+#[test]
+fn verify_lepton_file_info() {
+    let input = read_file("iphone", ".lep");
+
+    let info = read_lepton_file_info(&mut Cursor::new(&input)).unwrap();
+
+    assert_eq!(info.version, 1);
+    assert_eq!(info.jpeg_type, LeptonJpegType::Baseline);
+    assert!(!info.arithmetic_coding);
+    assert_eq!((info.width, info.height), (3264, 2448));
+    assert_eq!(info.jpeg_file_size, 2236391);
+
+    let layout: Vec<_> = info
+        .components
+        .iter()
+        .map(|c| {
+            (
+                c.id,
+                c.horizontal_sampling,
+                c.vertical_sampling,
+                c.block_width,
+                c.block_height,
+            )
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, 2, 2, 408, 306),
+            (2, 1, 1, 204, 153),
+            (3, 1, 1, 204, 153)
+        ]
+    );
+    assert_eq!(
+        info.components[0].quantization_table[..8],
+        [1, 1, 1, 1, 1, 1, 2, 1]
+    );
+
+    assert_eq!(
+        info.thread_segments,
+        [
+            0..42,
+            42..78,
+            78..110,
+            110..146,
+            146..182,
+            182..224,
+            224..264,
+            264..306
+        ]
+    );
+
+    // the file predates the math flags in the header
+    assert_eq!(info.use_16bit_dc_estimate, None);
+    assert_eq!(info.use_16bit_adv_predict, None);
+    assert_eq!(info.model_version, None);
+    assert!(!info.has_crc32_trailer);
+    assert!(!info.row_seekable);
+    assert_eq!(info.trailing_data_size, Some(0));
+
+    // a file written now records the math it was encoded with
+    let jpeg = read_file("tiny", ".jpg");
+    let features = EnabledFeatures::compat_lepton_vector_write();
+    let (lepton, _metrics) = encode_lepton_verify(&jpeg, 1, &features).unwrap();
+
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(info.jpeg_file_size, jpeg.len() as u32);
+    assert_eq!(info.thread_segments.len(), 1);
+    assert_eq!(
+        info.use_16bit_dc_estimate,
+        Some(features.use_16bit_dc_estimate)
+    );
+    assert_eq!(
+        info.use_16bit_adv_predict,
+        Some(features.use_16bit_adv_predict)
+    );
+    assert_eq!(info.model_version, Some(ModelVersion::V1));
+}
+
+/// verifies that a Lepton file is checked against its original JPEG and that a mismatch reports
+/// the offset of the first differing byte
+#[test]
+fn verify_lepton_against_original() {
+    let lepton = read_file("tiny", ".lep");
+    let mut jpeg = read_file("tiny", ".jpg");
+
+    let features = EnabledFeatures::compat_lepton_vector_read();
+
+    verify_lepton(&lepton, &jpeg, 8, &features).unwrap();
+
+    let offset = jpeg.len() / 2;
+    jpeg[offset] ^= 1;
+
+    let e = verify_lepton(&lepton, &jpeg, 8, &features).unwrap_err();
+    assert_eq!(e.exit_code, ExitCode::VerificationContentMismatch);
+    assert_eq!(e.location.jpeg_offset, Some(offset as u64));
+
+    // an original that is cut short differs where it ends
+    jpeg.truncate(offset);
+
+    let e = verify_lepton(&lepton, &jpeg, 8, &features).unwrap_err();
+    assert_eq!(e.exit_code, ExitCode::VerificationLengthMismatch);
+    assert_eq!(e.location.jpeg_offset, Some(offset as u64));
+}
+
+/// verifies that bytes after the EOI marker, like a thumbnail appended by a phone, survive the
+/// round trip and are reported by the file info, while a truncated JPEG has no EOI at all
+#[rstest]
+fn verify_trailing_data_after_eoi(#[values("tiny", "androidprogressive")] file: &str) {
+    let mut jpeg = read_file(file, ".jpg");
+    assert_eq!(jpeg[jpeg.len() - 2..], [0xff, 0xd9]);
+
+    jpeg.extend((0..100).map(|i| i as u8));
+
+    let (lepton, _metrics) =
+        encode_lepton_verify(&jpeg, 8, &EnabledFeatures::compat_lepton_vector_write()).unwrap();
+
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(info.trailing_data_size, Some(100));
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert_eq!(output, jpeg);
+}
+
+#[test]
+fn verify_truncated_jpeg_has_no_trailing_data() {
+    let lepton = read_file("eof_and_trailinghdrdata", ".lep");
+
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(info.trailing_data_size, None);
+}
+
+/// returns the offset, marker and total length of every marker segment of a JPEG in file order,
+/// skipping over the entropy coded data of the scans
+fn jpeg_segments(jpeg: &[u8]) -> Vec<(usize, u8, usize)> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos + 1] != 0xd9 {
+        let marker = jpeg[pos + 1];
+        let len = (usize::from(jpeg[pos + 2]) << 8 | usize::from(jpeg[pos + 3])) + 2;
+        segments.push((pos, marker, len));
+        pos += len;
+
+        if marker == 0xda {
+            // stuffed zeros and restart markers are part of the scan
+            while !(jpeg[pos] == 0xff
+                && jpeg[pos + 1] != 0
+                && !(0xd0..=0xd7).contains(&jpeg[pos + 1]))
+            {
+                pos += 1;
+            }
+        }
+    }
+    segments
+}
+
+/// verifies that tables defined more than once are reproduced exactly as they appear in the
+/// file, rather than just the last definition of each table, and that a table redefined
+/// between the scans of a progressive image doesn't change the coding of the coefficients
+#[rstest]
+fn verify_redefined_tables_roundtrip(#[values("tiny", "androidprogressive")] file: &str) {
+    let original = read_file(file, ".jpg");
+    let segments = jpeg_segments(&original);
+
+    let find = |marker: u8, n: usize| {
+        segments
+            .iter()
+            .filter(|s| s.1 == marker)
+            .nth(n)
+            .copied()
+            .unwrap()
+    };
+
+    let (dqt_pos, _, dqt_len) = find(0xdb, 0);
+    let (dht_pos, _, dht_len) = find(0xc4, 0);
+
+    // a different table 0 that is overridden by the real one that follows it
+    let mut other_dqt = original[dqt_pos..dqt_pos + dqt_len].to_vec();
+    other_dqt[5..].fill(1);
+
+    let mut jpeg = Vec::new();
+    jpeg.extend_from_slice(&original[..dqt_pos]);
+    jpeg.extend_from_slice(&other_dqt);
+    jpeg.extend_from_slice(&original[dqt_pos..dht_pos]);
+    // the same huffman table twice
+    jpeg.extend_from_slice(&original[dht_pos..dht_pos + dht_len]);
+    if file == "tiny" {
+        jpeg.extend_from_slice(&original[dht_pos..]);
+    } else {
+        // the table redefined again before the second scan
+        let (second_sos_pos, _, _) = find(0xda, 1);
+        jpeg.extend_from_slice(&original[dht_pos..second_sos_pos]);
+        jpeg.extend_from_slice(&other_dqt);
+        jpeg.extend_from_slice(&original[second_sos_pos..]);
+    }
+
+    let (lepton, _metrics) =
+        encode_lepton_verify(&jpeg, 8, &EnabledFeatures::compat_lepton_vector_write()).unwrap();
+
+    // the definition in front of the first scan is the one that is used for the image
+    let info = read_lepton_file_info(&mut Cursor::new(&lepton)).unwrap();
+    assert_eq!(
+        info.components[0].quantization_table[..],
+        original[dqt_pos + 5..dqt_pos + dqt_len]
+            .iter()
+            .map(|&q| u16::from(q))
+            .collect::<Vec<_>>()[..]
+    );
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert_eq!(output, jpeg);
+}
+
+/// verifies that the huffman tables are written back exactly as they appear in the file, both
+/// for the standard tables of Annex K (tiny) and for optimized tables (androidcrop), and also
+/// when all the tables are grouped into a single DHT segment rather than one segment each
+#[rstest]
+fn verify_huffman_tables_roundtrip(
+    #[values("tiny", "androidcrop")] file: &str,
+    #[values(false, true)] single_dht: bool,
+) {
+    let original = read_file(file, ".jpg");
+    let segments = jpeg_segments(&original);
+
+    let dhts: Vec<_> = segments.iter().filter(|s| s.1 == 0xc4).collect();
+    assert_eq!(dhts.len(), 4);
+
+    // Annex K luminance DC table, bit counts followed by the symbols
+    let standard_dc = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(
+        original[dhts[0].0 + 5..dhts[0].0 + 21] == standard_dc,
+        file == "tiny"
+    );
+
+    let jpeg = if single_dht {
+        let (first_pos, _, _) = *dhts[0];
+        let (last_pos, _, last_len) = *dhts[3];
+
+        let tables: Vec<u8> = dhts
+            .iter()
+            .flat_map(|&&(pos, _, len)| original[pos + 4..pos + len].iter().copied())
+            .collect();
+
+        let mut jpeg = original[..first_pos].to_vec();
+        jpeg.extend_from_slice(&[0xff, 0xc4]);
+        jpeg.extend_from_slice(&(tables.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(&tables);
+        jpeg.extend_from_slice(&original[last_pos + last_len..]);
+        jpeg
+    } else {
+        original
+    };
+
+    let (lepton, _metrics) =
+        encode_lepton_verify(&jpeg, 8, &EnabledFeatures::compat_lepton_vector_write()).unwrap();
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+
+    let dht_bytes = |jpeg: &[u8]| -> Vec<Vec<u8>> {
+        jpeg_segments(jpeg)
+            .iter()
+            .filter(|s| s.1 == 0xc4)
+            .map(|&(pos, _, len)| jpeg[pos..pos + len].to_vec())
+            .collect()
+    };
+    assert_eq!(dht_bytes(&output), dht_bytes(&jpeg));
+    assert_eq!(output, jpeg);
+}
+
+/// noninterleaved is androidcrop with each component coded in its own scan. The scans have to
+/// come back in the same order, and the chroma scans must be Lepton coded like the luma scan
+/// rather than stored as garbage, so the file compresses as well as the interleaved original.
+#[test]
+fn verify_noninterleaved_scans() {
+    let jpeg = read_file("noninterleaved", ".jpg");
+
+    // the first scan in the file belongs to the thumbnail in the EXIF data
+    let scans: Vec<_> = jpeg_segments(&jpeg)
+        .iter()
+        .filter(|s| s.1 == 0xda)
+        .map(|&(pos, _, _)| jpeg[pos + 4..pos + 5 + 2 * usize::from(jpeg[pos + 4])].to_vec())
+        .collect();
+    assert_eq!(
+        scans[scans.len() - 3..],
+        [[1, 1, 0], [1, 2, 0x11], [1, 3, 0x11]]
+    );
+
+    let (lepton, _metrics) =
+        encode_lepton_verify(&jpeg, 8, &EnabledFeatures::compat_lepton_vector_write()).unwrap();
+
+    let mut output = Vec::new();
+    decode_lepton(
+        &mut Cursor::new(&lepton),
+        &mut output,
+        8,
+        &EnabledFeatures::compat_lepton_vector_read(),
+    )
+    .unwrap();
+    assert_eq!(output, jpeg);
+
+    let (interleaved, _metrics) = encode_lepton_verify(
+        &read_file("androidcrop", ".jpg"),
+        8,
+        &EnabledFeatures::compat_lepton_vector_write(),
+    )
+    .unwrap();
+    assert!(
+        lepton.len() < interleaved.len() * 101 / 100,
+        "{} vs {}",
+        lepton.len(),
+        interleaved.len()
+    );
+}
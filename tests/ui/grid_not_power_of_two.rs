@@ -0,0 +1,13 @@
+// the grids of branches are read and written as binary trees, so a size that is not a power
+// of two has to be rejected when the crate is built rather than at runtime
+
+#[path = "../../src/structs/power_of_two.rs"]
+#[allow(dead_code)]
+mod power_of_two;
+
+use power_of_two::IsPowerOfTwo;
+
+const _: () = IsPowerOfTwo::<8>::OK;
+const _: () = IsPowerOfTwo::<6>::OK;
+
+fn main() {}